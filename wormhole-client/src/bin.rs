@@ -0,0 +1,801 @@
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use futures_util::StreamExt;
+use log::{debug, error, warn};
+use rustyline::error::ReadlineError;
+use std::sync::{Arc, Mutex};
+use std::{fmt::Display, ops::ControlFlow, path::PathBuf, process, time::Duration};
+use tokio::{net::TcpStream, signal};
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest, protocol::Message};
+use tokio_tungstenite::{client_async, connect_async, MaybeTlsStream, WebSocketStream};
+use wormhole_core::message::{Mood, ServerMessage};
+use wormhole_core::socks::{self, SocksProxy};
+use wormhole_core::transit::RelayHint;
+
+use client::*;
+use json::Event;
+use throttle::Rate;
+use trace::{Direction, ProtocolTrace};
+use transfer::{
+    Features, Mode, Payload, Permission, ReceiveOptions, SendOptions, TransferError, Wormhole,
+};
+
+mod client;
+mod clipboard;
+mod input;
+mod json;
+mod permission;
+mod ping;
+mod throttle;
+mod trace;
+mod transfer;
+mod words;
+
+/// Exit status when no peer turned up in time, so scripts can tell it from other failures.
+const TIMED_OUT_STATUS: i32 = 3;
+
+/// The longest to wait between attempts to reconnect to the mailbox server.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait for what's left to go to the mailbox server, before giving up on closing
+/// the connection cleanly.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait before opening our mailbox again for the next receiver, while the last one
+/// still has it open.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// How wide to wrap the server's message of the day, unless shown on a terminal.
+const MOTD_WIDTH: usize = 80;
+
+#[derive(Parser, Debug, Clone)]
+#[command(arg_required_else_help = true)]
+#[command(
+    version,
+    about = "Create a Magic Wormhole and communicate through it.",
+    long_about = "Create a Magic Wormhole and communicate through it.
+
+Wormholes are created by speaking the same magic CODE in two different
+places at the same time. Wormholes are secure against anyone who doesn't
+use the same code."
+)]
+struct Cli {
+    /// Application namespace ID to use, by default the one other Magic Wormhole clients use to
+    /// send text and files
+    #[arg(long, default_value = "lothar.com/wormhole/text-or-file-xfer")]
+    app_id: String,
+
+    /// Mailbox server to use
+    #[arg(long, value_name = "URL", default_value = "ws://127.0.0.1:4000/")]
+    relay_url: String,
+
+    /// Token to present to mailbox servers which require one
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// Transit relay to offer the peer for file transfers
+    #[arg(long, value_name = "tcp:HOST:PORT")]
+    transit_helper: Option<RelayHint>,
+
+    /// Limit file and directory transfers to RATE bytes per second (e.g. 800K or 1.5M)
+    #[arg(long, value_name = "RATE")]
+    throttle: Option<Rate>,
+
+    /// Don't show the progress of file and directory transfers
+    #[arg(long, short)]
+    quiet: bool,
+
+    /// Connect to the mailbox server, transit relays and peer through a SOCKS5 proxy
+    #[arg(long, value_name = "socks5://HOST:PORT")]
+    proxy: Option<SocksProxy>,
+
+    /// Connect through Tor, using its SOCKS5 proxy on port 9050
+    #[arg(long, conflicts_with = "proxy")]
+    tor: bool,
+
+    /// Report what's happening as JSON, one event per line on standard output, for scripts
+    /// and GUIs
+    #[arg(long)]
+    json: bool,
+
+    /// Write every message exchanged with the mailbox server to PATH, one per line as JSON,
+    /// with message bodies hashed and tokens removed
+    #[arg(long, value_name = "PATH")]
+    debug_protocol: Option<PathBuf>,
+
+    /// Don't show the mailbox server's message of the day
+    #[arg(long)]
+    no_motd: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Receive a text message, file or directory (from "wormhole send")
+    Receive {
+        /// Code given by the sender, which is asked for if left out
+        #[arg(value_name = "CODE")]
+        code: Option<String>,
+
+        /// Accept files and directories without asking first
+        #[arg(long, short, visible_alias = "accept-file")]
+        yes: bool,
+
+        /// Where to put a received file or directory
+        #[arg(long, short, value_name = "PATH")]
+        output_file: Option<PathBuf>,
+
+        /// Overwrite anything already where a received file or directory goes
+        #[arg(long)]
+        force: bool,
+
+        /// Write a received file, or a directory's zip archive, to standard output
+        #[arg(long, conflicts_with_all = ["output_file", "force"])]
+        stdout: bool,
+
+        /// Accept only text messages, refusing any file or directory
+        #[arg(long, conflicts_with_all = ["output_file", "force", "stdout"])]
+        only_text: bool,
+
+        /// Give up if the sender hasn't turned up after this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+
+        /// Show the verifier, and wait for confirmation the sender shows the same before
+        /// accepting anything
+        #[arg(long)]
+        verify: bool,
+    },
+
+    /// Send a text message, file or directory
+    Send {
+        /// Text message to send
+        #[arg(long, value_name = "MESSAGE")]
+        text: Option<String>,
+
+        /// Send standard input as a file, as it's read
+        #[arg(long, conflicts_with = "text")]
+        stdin: bool,
+
+        /// What to call the file sent from standard input
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["text", "files"])]
+        name: Option<String>,
+
+        /// Keep sending to one receiver after another, all using the same code
+        #[arg(long, conflicts_with = "stdin")]
+        many: bool,
+
+        /// Stop sending to many receivers after this many
+        #[arg(long, value_name = "N", requires = "many")]
+        count: Option<usize>,
+
+        /// Give up if a receiver hasn't turned up after this many seconds, or with --many,
+        /// stop waiting for more
+        #[arg(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+
+        /// Code to use, agreed with the receiver beforehand, instead of making one up
+        #[arg(long, value_name = "CODE", value_parser = parse_code)]
+        code: Option<String>,
+
+        /// How many words to put in the code, for more or less protection against guessing
+        #[arg(
+            long,
+            conflicts_with = "code",
+            value_name = "N",
+            default_value_t = words::CODE_WORDS as u8,
+            value_parser = clap::value_parser!(u8).range(1..)
+        )]
+        code_length: u8,
+
+        /// Copy the code to the clipboard, until the receiver has used it
+        #[arg(long)]
+        clip: bool,
+
+        /// Show the verifier, and wait for confirmation the receiver shows the same before
+        /// sending
+        #[arg(long)]
+        verify: bool,
+
+        /// Files or directories to send
+        #[arg(
+            value_name = "FILE",
+            required_unless_present_any = ["text", "stdin"],
+            conflicts_with_all = ["text", "stdin"]
+        )]
+        files: Vec<PathBuf>,
+    },
+
+    /// Check the mailbox server is working, showing its welcome and timing pings to it
+    Ping {
+        /// How many pings to send
+        #[arg(
+            long,
+            short,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        count: u32,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    let proxy = if cli.tor {
+        Some(socks::TOR.parse().unwrap())
+    } else {
+        cli.proxy.clone()
+    };
+    if let Some(Command::Ping { count }) = cli.command {
+        let ws_stream = connect(&cli.relay_url, proxy.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                fail(
+                    cli.json,
+                    format!("Couldn't connect to {}: {}", cli.relay_url, e),
+                    1,
+                )
+            });
+        if let Err(e) = ping::ping(ws_stream, count).await {
+            fail(cli.json, e, 1);
+        }
+        return;
+    }
+
+    let mut receive_options = ReceiveOptions::default();
+    let mut send_options = SendOptions::default();
+    let mut code = None;
+    let mut code_length = words::CODE_WORDS;
+    let mut clip = false;
+    if let Some(Command::Send {
+        many,
+        count,
+        timeout,
+        code: given,
+        code_length: length,
+        clip: copy,
+        verify,
+        ..
+    }) = &cli.command
+    {
+        code = given.clone();
+        code_length = usize::from(*length);
+        clip = *copy;
+        send_options = SendOptions {
+            many: *many,
+            count: *count,
+            timeout: timeout.map(Duration::from_secs),
+            verify: *verify,
+        };
+    }
+    // Nameplates in use, listed by the server to complete the code the user is entering
+    let nameplates = Arc::new(Mutex::new(Vec::new()));
+    let (list_tx, mut list_rx) = unbounded();
+    let mut entering = None;
+    let (mode, payload) = match cli.command.clone().unwrap() {
+        Command::Send {
+            text: Some(text), ..
+        } => {
+            let msg_size = text.len();
+            eprintln!("Sending text message ({} bytes)", msg_size);
+            debug!("Sending {:?} {:?}", text, text.as_bytes());
+            (ClientCommand::Send, Some(Payload::Text(text)))
+        }
+        Command::Send {
+            stdin: true, name, ..
+        } => {
+            let name = name.unwrap_or_else(|| "stdin".to_string());
+            (ClientCommand::Send, Some(Payload::Stdin(name)))
+        }
+        Command::Send { mut files, .. } if files.len() == 1 => {
+            (ClientCommand::Send, Some(Payload::File(files.remove(0))))
+        }
+        Command::Send { files, .. } => (ClientCommand::Send, Some(Payload::Files(files))),
+        Command::Receive {
+            code: given,
+            yes,
+            output_file,
+            force,
+            stdout,
+            only_text,
+            timeout,
+            verify,
+        } => {
+            if stdout && cli.json {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "the argument '--stdout' cannot be used with '--json'",
+                    )
+                    .exit();
+            }
+            match &given {
+                Some(code) => {
+                    // Catch a mistyped code before it's tried, and the nameplate is used up
+                    if let Err(typo) = words::check_code(code) {
+                        fail(cli.json, typo, 1);
+                    }
+                    debug!("Receiving with code {:?}", code);
+                }
+                None => {
+                    // Ask for it while connecting, so the server can list nameplates to complete
+                    let nameplates = nameplates.clone();
+                    entering = Some(tokio::task::spawn_blocking(move || {
+                        input::read_code(nameplates, list_tx)
+                    }));
+                }
+            }
+            code = given;
+            receive_options = ReceiveOptions {
+                accept: yes,
+                output: output_file,
+                force,
+                stdout,
+                only_text,
+                timeout: timeout.map(Duration::from_secs),
+                verify,
+            };
+            (ClientCommand::Receive, None)
+        }
+        Command::Ping { .. } => unreachable!(),
+    };
+
+    let trace = cli.debug_protocol.as_ref().map(|path| {
+        ProtocolTrace::create(path).unwrap_or_else(|e| {
+            fail(
+                cli.json,
+                format!("Couldn't create {}: {}", path.display(), e),
+                1,
+            )
+        })
+    });
+    let trace = trace.map(Arc::new);
+    let mut ws_stream = connect(&cli.relay_url, proxy.as_ref())
+        .await
+        .expect("failed to connect");
+    debug!("websocket handshake has been successfully completed");
+    let (tx, mut rx) = unbounded();
+    let (events_tx, events_rx) = unbounded();
+    let (requests_tx, mut requests_rx) = unbounded();
+    let features = Features::ours(
+        match mode {
+            ClientCommand::Send => Mode::Send,
+            ClientCommand::Receive => Mode::Receive,
+        },
+        if receive_options.accept {
+            Permission::Yes
+        } else {
+            Permission::Ask
+        },
+    );
+    let mut client = Client::new(mode, cli.app_id.clone(), tx, events_tx.clone())
+        .with_app_versions(features.app_versions())
+        // Files are offered over the mailbox to a receiver which can't dilate, so it can refuse
+        // them without connecting to the sender
+        .with_dilation(!receive_options.only_text)
+        .with_code(code)
+        .with_code_length(code_length)
+        .with_many(send_options.many)
+        .with_clip(clip)
+        .with_json(cli.json);
+
+    let wormhole = Wormhole::new(
+        cli.app_id.clone(),
+        client.side.to_string(),
+        events_rx,
+        requests_tx,
+        cli.transit_helper.iter().cloned().collect(),
+        features,
+    )
+    .with_quiet(cli.quiet)
+    .with_json(cli.json)
+    .with_throttle(cli.throttle)
+    .with_proxy(proxy.clone());
+    let transfer = tokio::spawn(async move {
+        match payload {
+            Some(payload) => transfer::send(wormhole, payload, send_options).await,
+            None => transfer::receive(wormhole, receive_options).await,
+        }
+    });
+
+    let mut cancelled = false;
+    let mut scared = false;
+    let mut forwarding;
+    let mut ws_receiver;
+    loop {
+        let ws_sender;
+        (ws_sender, ws_receiver) = ws_stream.split();
+        let sent = trace.clone();
+        let traced = rx.inspect(move |msg| {
+            if let Some(trace) = &sent {
+                trace.record(Direction::Sent, msg);
+            }
+        });
+        // Closes the connection once the client stops sending
+        forwarding = tokio::spawn(traced.map(Ok).forward(ws_sender));
+
+        let mut dropped = false;
+        let mut reopen_at = None;
+        loop {
+            tokio::select! {
+                ws_msg = ws_receiver.next() => match ws_msg {
+                    Some(Ok(ws_msg)) => {
+                        if let Some(trace) = &trace {
+                            trace.record(Direction::Received, &ws_msg);
+                        }
+                        if handle_message(&cli, &mut client, &events_tx, &nameplates, ws_msg)
+                            .is_break()
+                        {
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("Mailbox server connection failed: {}", e);
+                        dropped = true;
+                        break;
+                    }
+                    None => {
+                        dropped = true;
+                        break;
+                    }
+                },
+                Some(entered) = async {
+                    match &mut entering {
+                        Some(entering) => Some(entering.await),
+                        None => None,
+                    }
+                } => {
+                    entering = None;
+                    match entered {
+                        Ok(Ok(code)) => {
+                            debug!("Receiving with code {:?}", code);
+                            if client.enter_code(code).is_err() {
+                                error!("Claim failed");
+                            }
+                        }
+                        Ok(Err(ReadlineError::Interrupted | ReadlineError::Eof)) => {
+                            // Given up on before anything was claimed, so there's nothing to
+                            // tell the peer
+                            cancelled = true;
+                            transfer.abort();
+                            if cancel(&mut client).is_err() {
+                                error!("Cancelling the transfer failed");
+                            }
+                        }
+                        Ok(Err(e)) => fail(cli.json, format!("Couldn't read the code: {}", e), 1),
+                        Err(e) => fail(cli.json, e, 1),
+                    }
+                }
+                Some(()) = async {
+                    match reopen_at {
+                        Some(reopen_at) => {
+                            tokio::time::sleep_until(reopen_at).await;
+                            Some(())
+                        }
+                        None => None,
+                    }
+                } => {
+                    reopen_at = None;
+                    if client.is_reopening() && client.open_again().is_err() {
+                        error!("Opening the mailbox again failed");
+                    }
+                }
+                Some(()) = list_rx.next() => {
+                    // The user wants to complete a nameplate, so make sure the list is current
+                    if client.is_entering_code() && client.list().is_err() {
+                        error!("Listing nameplates failed");
+                    }
+                }
+                Some(request) = requests_rx.next(), if !cancelled && !scared => {
+                    let result = match request {
+                        ClientRequest::Send(msg) => client.send(&msg),
+                        ClientRequest::Dilate(msg) => client.dilate(&msg),
+                        ClientRequest::Close(mood) => client.close(mood),
+                        ClientRequest::Reopen(mood) => client.reopen(mood),
+                    };
+                    if result.is_err() {
+                        error!("Sending to peer failed");
+                    }
+                }
+                _ = signal::ctrl_c() => {
+                    if cancelled {
+                        // Don't wait any longer for the server
+                        process::exit(1);
+                    }
+                    cancelled = true;
+                    transfer.abort();
+                    if cancel(&mut client).is_err() {
+                        error!("Cancelling the transfer failed");
+                    }
+                }
+            }
+            if client.is_scared() && !scared {
+                // Whatever the transfer is waiting for won't come from a peer without our key
+                scared = true;
+                transfer.abort();
+            }
+            if client.is_closed() && client.is_reopening() && reopen_at.is_none() {
+                // Our mailbox is only empty for the next receiver once the last has closed it
+                let delay = if client.is_stale() {
+                    REOPEN_DELAY
+                } else {
+                    Duration::ZERO
+                };
+                reopen_at = Some(tokio::time::Instant::now() + delay);
+            }
+            if client.is_closed() && !client.is_reopening() {
+                break;
+            }
+        }
+
+        if cancelled {
+            break;
+        }
+        if dropped && (!client.is_closing() || client.is_reopening()) {
+            // Carry on where we left off, once we can reach the server again
+            tokio::select! {
+                reconnected = reconnect(&cli.relay_url, proxy.as_ref()) => {
+                    ws_stream = reconnected;
+                }
+                _ = signal::ctrl_c() => {
+                    cancelled = true;
+                    transfer.abort();
+                    break;
+                }
+            }
+            let tx;
+            (tx, rx) = unbounded();
+            client.resume(tx);
+        } else {
+            break;
+        }
+    }
+
+    // Let the server see us go, rather than the connection drop, waiting for it to answer
+    // our closing the connection
+    client.disconnect();
+    let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+        let _ = forwarding.await;
+        while let Some(Ok(_)) = ws_receiver.next().await {}
+    })
+    .await;
+
+    if cancelled {
+        // Once it's stopped, and left whatever it was showing
+        let _ = transfer.await;
+        fail(cli.json, transfer::CANCELLED, 1);
+    }
+    if scared {
+        let _ = transfer.await;
+        let reason = if client.is_wrong_code() {
+            WRONG_CODE
+        } else {
+            SCARY
+        };
+        fail(cli.json, reason, 1);
+    }
+
+    // Let the transfer see the client has gone, if it hasn't finished already
+    drop(client);
+    drop(events_tx);
+    match transfer.await {
+        Ok(Ok(())) => {
+            if cli.json {
+                Event::Done.emit();
+            }
+        }
+        Ok(Err(e @ TransferError::TimedOut)) => fail(cli.json, e, TIMED_OUT_STATUS),
+        Ok(Err(e)) => fail(cli.json, e, 1),
+        Err(e) => fail(cli.json, e, 1),
+    }
+}
+
+/// Tell the user why we're giving up, and whatever's driving us too if we're reporting as
+/// JSON, then exit with the given status.
+fn fail(json: bool, error: impl Display, status: i32) -> ! {
+    let error = error.to_string();
+    eprintln!("{}", error);
+    if json {
+        Event::Error { error: &error }.emit();
+    }
+    process::exit(status);
+}
+
+/// Connect to the mailbox server, through the proxy if there is one.
+async fn connect(
+    url: &str,
+    proxy: Option<&SocksProxy>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Error> {
+    let Some(proxy) = proxy else {
+        let (ws_stream, _) = connect_async(url).await?;
+        return Ok(ws_stream);
+    };
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    if uri.scheme_str() == Some("wss") {
+        return Err(tungstenite::error::UrlError::TlsFeatureNotEnabled.into());
+    }
+    let host = uri
+        .host()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let port = uri.port_u16().unwrap_or(80);
+    let stream = proxy.connect(&host, port).await?;
+    let (ws_stream, _) = client_async(request, MaybeTlsStream::Plain(stream)).await?;
+    Ok(ws_stream)
+}
+
+/// Connect to the mailbox server again after losing the connection, waiting twice as long
+/// after each failed attempt, up to a limit.
+async fn reconnect(
+    url: &str,
+    proxy: Option<&SocksProxy>,
+) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match connect(url, proxy).await {
+            Ok(ws_stream) => {
+                debug!("Reconnected to the mailbox server");
+                return ws_stream;
+            }
+            Err(e) => {
+                warn!("Reconnecting failed, trying again in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+/// Tell the peer the user has cancelled the transfer, if there is one yet, and close the
+/// mailbox.
+fn cancel(client: &mut Client) -> Result<(), ClientError> {
+    if client.is_connected() {
+        client.send(&ApplicationMessage::Error(transfer::CANCELLED.into()))?;
+        client.close(Mood::Errory)
+    } else {
+        client.close(Mood::Lonely)
+    }
+}
+
+/// The server's message of the day, wrapped to fit the terminal it's shown on.
+fn wrap_motd(motd: &str) -> String {
+    let width = console::Term::stderr()
+        .size_checked()
+        .map_or(MOTD_WIDTH, |(_, columns)| usize::from(columns));
+    textwrap::fill(motd, width)
+}
+
+/// Handle a message from the mailbox server, breaking if the connection should end.
+fn handle_message(
+    cli: &Cli,
+    client: &mut Client,
+    events: &UnboundedSender<ClientEvent>,
+    nameplates: &Mutex<Vec<usize>>,
+    ws_msg: Message,
+) -> ControlFlow<()> {
+    let msg = match ws_msg {
+        Message::Text(s) => serde_json::from_str::<ServerMessage>(&s),
+        Message::Binary(v) => serde_json::from_slice::<ServerMessage>(&v),
+        _ => return ControlFlow::Continue(()),
+    };
+
+    if msg.is_err() {
+        eprintln!("Failed to decode message: {:?}", msg.err());
+        return ControlFlow::Continue(());
+    }
+    let msg = msg.unwrap();
+
+    match &msg.ty {
+        wormhole_core::message::ServerMessageType::Ack => {
+            debug!("Recieved Ack for {:?}", msg.id.unwrap());
+        }
+        ty => debug!("Recieved {:?}", ty),
+    }
+
+    match &msg.ty {
+        wormhole_core::message::ServerMessageType::Welcome { welcome } => {
+            if let Some(motd) = welcome.motd.as_ref().filter(|_| !cli.no_motd) {
+                eprintln!("{}", wrap_motd(motd));
+            }
+            if let Some(current) = &welcome.current_cli_version {
+                if is_outdated(current) {
+                    eprintln!(
+                        "Your client ({}) is outdated, the latest version is {}",
+                        env!("CARGO_PKG_VERSION"),
+                        current
+                    );
+                }
+            }
+            if let Some(error) = &welcome.error {
+                eprintln!("{}", error);
+                return ControlFlow::Break(());
+            }
+            let _ = events.unbounded_send(ClientEvent::Welcome(welcome.clone()));
+
+            // Satisfy the first permission method we can before binding
+            let authenticators = permission::authenticators(cli.token.clone());
+            match permission::negotiate(&authenticators, &welcome.permission_required) {
+                Ok(Some(permission)) => {
+                    if client.submit_permissions(permission).is_err() {
+                        error!("Submitting permissions failed");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => fail(cli.json, e, 1),
+            }
+
+            // Bind
+            if client.bind().is_err() {
+                error!("Bind failed");
+            } else if client.is_resuming() {
+                // Find our way back to our mailbox
+                if client.rejoin().is_err() {
+                    error!("Rejoin failed");
+                }
+            } else {
+                // TODO: This logic should live inside Client
+                if matches!(client.command, ClientCommand::Send) && !client.has_code() {
+                    // Try to allocate a nameplate, unless our code has one already
+                    if client.allocate().is_err() {
+                        error!("Allocate failed");
+                    };
+                } else if client.is_entering_code() {
+                    // List the nameplates in use, to complete the code being entered
+                    if client.list().is_err() {
+                        error!("List failed");
+                    }
+                } else {
+                    // Try to claim the nameplate from our code
+                    if client.claim(None).is_err() {
+                        error!("Claim failed");
+                    }
+                }
+            }
+        }
+        wormhole_core::message::ServerMessageType::Nameplates { nameplates: listed } => {
+            *nameplates.lock().unwrap() = listed.iter().map(|nameplate| nameplate.id).collect();
+        }
+        wormhole_core::message::ServerMessageType::Allocated { nameplate_id } => {
+            if client.allocated(*nameplate_id).is_err() {
+                error!("Allocated failed");
+            };
+        }
+        wormhole_core::message::ServerMessageType::Claimed { mailbox_id } => {
+            if client.claimed(mailbox_id).is_err() {
+                error!("Claimed failed");
+            };
+        }
+        wormhole_core::message::ServerMessageType::Released => {}
+        wormhole_core::message::ServerMessageType::Message { side, phase, body } => {
+            if client.message(&side.as_str().into(), phase, body).is_err() {
+                error!("Message reception failed");
+            };
+        }
+        wormhole_core::message::ServerMessageType::Closed => {
+            client.closed();
+        }
+        wormhole_core::message::ServerMessageType::Ack => {}
+        wormhole_core::message::ServerMessageType::Pong { ping } => {
+            if client.pong(*ping).is_err() {
+                error!("Opening the mailbox again failed");
+            }
+        }
+        wormhole_core::message::ServerMessageType::Error { error, orig } => {
+            match client.refused(error, &orig.ty) {
+                Ok(true) => debug!("Server refused {:?} after reconnecting", orig.ty),
+                _ => error!("Server returned error: {:?}", error),
+            }
+        }
+    }
+
+    ControlFlow::Continue(())
+}
@@ -0,0 +1,1835 @@
+use futures_channel::mpsc::UnboundedSender;
+use log::{debug, warn};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::serde_as;
+use spake2::{Ed25519Group, Spake2};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::clipboard::Clipboard;
+use crate::json::Event;
+use crate::words::{make_code, CODE_WORDS};
+use wormhole_core::crypto::{
+    decrypt_message, encrypt_message, finish_pake, start_pake, CryptoError, SessionKey,
+};
+use wormhole_core::dilation::DilationMessage;
+use wormhole_core::message::{
+    ClientMessage, ClientMessageType, Mood, Permission, Phase, WelcomeInfo,
+};
+use wormhole_core::transit::{Ability, TransitInfo};
+
+/// The dilation protocol versions we support.
+const DILATION_VERSIONS: &[&str] = &["1"];
+
+/// A message sent between peers for the purpose of setting up their connection.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum PeerMessage {
+    /// The initial PAKE message.
+    Pake {
+        #[serde_as(as = "serde_with::hex::Hex")]
+        pake_v1: Vec<u8>,
+    },
+    /// Information about the sender.
+    Version {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        abilities: Option<Vec<String>>,
+        /// Which versions of dilation the sender supports, if any.
+        #[serde(
+            rename = "can-dilate",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        can_dilate: Option<Vec<String>>,
+        /// How the sender can be reached for a dilated connection.
+        #[serde(
+            rename = "dilation-abilities",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        dilation_abilities: Option<Vec<Ability>>,
+        /// What the sender's application supports, for it to agree on with ours. Peers
+        /// which have nothing to say may leave it out.
+        #[serde(default)]
+        app_versions: HashMap<String, Value>,
+    },
+}
+
+/// An application-specific message sent between clients.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ApplicationMessage {
+    /// How to reach the sender for a transit connection.
+    Transit(TransitInfo),
+    /// An offer of something to send.
+    Offer(Offer),
+    /// A response to an offer.
+    Answer(Answer),
+    /// Something went wrong, e.g. the offer was refused.
+    Error(String),
+    /// The receiver already has the start of an offered file, from an interrupted transfer.
+    Resume(Resume),
+    /// Where the sender will resume sending the file from, zero if the receiver's start of it
+    /// didn't match.
+    ResumeAck { offset: u64 },
+}
+
+/// Something a sender offers to send.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Offer {
+    /// A text message, sent with the offer itself.
+    Message(String),
+    /// A file, sent over transit once accepted.
+    File {
+        filename: String,
+        filesize: u64,
+        /// Whether the sender can resume an interrupted transfer of the file.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resume: bool,
+        /// Whether the file's size isn't known until it has all been sent, in which case
+        /// `filesize` is zero and the end of the file is marked by an empty record.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        stream: bool,
+    },
+    /// A directory, sent over transit as an archive once accepted.
+    Directory(DirectoryOffer),
+}
+
+/// The details of a directory offered by a sender.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct DirectoryOffer {
+    pub dirname: String,
+    /// How the archive is packed, always "zipfile/deflated".
+    pub mode: String,
+    pub zipsize: u64,
+    /// Total size of the files in the archive once unpacked.
+    pub numbytes: u64,
+    pub numfiles: u64,
+    /// What's at the top of the directory, when several files are sent as one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+}
+
+/// How much of an offered file the receiver already has.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Resume {
+    /// How many bytes from the start of the file.
+    pub offset: u64,
+    /// The SHA-256 digest of those bytes, hex encoded.
+    pub sha256: String,
+}
+
+/// A receiver's response to an offer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Answer {
+    /// The text message was received.
+    MessageAck(String),
+    /// The file should be sent.
+    FileAck(String),
+}
+
+/// Something for the application to act on.
+#[derive(Debug)]
+pub(crate) enum ClientEvent {
+    /// The mailbox server welcomed us.
+    Welcome(WelcomeInfo),
+    /// A shared key has been agreed with the peer.
+    Connected {
+        key: SessionKey,
+        /// What the peer's application told us about itself.
+        app_versions: HashMap<String, Value>,
+        /// Whether both sides can dilate the connection.
+        can_dilate: bool,
+    },
+    /// The peer sent an application message.
+    Message(ApplicationMessage),
+    /// The peer sent a message setting up a dilated connection.
+    Dilation(DilationMessage),
+}
+
+/// Something the application asks the client to do.
+#[derive(Debug)]
+pub(crate) enum ClientRequest {
+    /// Send an application message to the peer.
+    Send(ApplicationMessage),
+    /// Send a message setting up a dilated connection to the peer.
+    Dilate(DilationMessage),
+    /// Close the mailbox in the given mood.
+    Close(Mood),
+    /// Close the mailbox in the given mood, and open a fresh one for the next peer to use the
+    /// same code.
+    Reopen(Mood),
+}
+
+/// What to tell the user when the peer's version can't be decrypted, so its code isn't ours.
+pub(crate) const WRONG_CODE: &str = "Incorrect code. Either you or your correspondent typed \
+the code wrong, or a would-be man-in-the-middle attacker guessed incorrectly. You could try \
+again, giving both your correspondent and the attacker another chance.";
+
+/// What to tell the user when the peer proved it has our key, but a later message from it
+/// can't be decrypted.
+pub(crate) const SCARY: &str = "A message from your correspondent couldn't be decrypted, so it \
+may have been tampered with on the way. Giving up on the transfer.";
+
+/// Is this client older than the given version, as advertised by the server? Versions are
+/// compared by their dotted numeric components; anything unparseable is never considered newer.
+pub(crate) fn is_outdated(current_cli_version: &str) -> bool {
+    fn parse(version: &str) -> Option<Vec<u64>> {
+        version
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect()
+    }
+
+    match (parse(env!("CARGO_PKG_VERSION")), parse(current_cli_version)) {
+        (Some(ours), Some(current)) => ours < current,
+        _ => false,
+    }
+}
+
+/// Check a code given by the user starts with a nameplate, and has something after it.
+pub(crate) fn parse_code(code: &str) -> Result<String, String> {
+    match code.split_once('-') {
+        Some((nameplate, rest)) if nameplate.parse::<usize>().is_ok() && !rest.is_empty() => {
+            Ok(code.to_owned())
+        }
+        _ => Err("expected a nameplate and words, like 7-crossover-clockwork".into()),
+    }
+}
+
+/// A command for the client to execute.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ClientCommand {
+    /// Allocate a nameplate and make up a code for the receiver.
+    Send,
+    /// Receive using the code given with [`Client::with_code`] or [`Client::enter_code`].
+    Receive,
+}
+
+/// State of the client.
+#[derive(Debug, Default, PartialEq)]
+enum ClientState {
+    /// Initial state.
+    #[default]
+    Init,
+    /// Sent bound message.
+    Bound,
+    /// Sent allocate message.
+    Allocating,
+    /// Sent claim message.
+    Claiming,
+    /// Sent open and ping messages for our mailbox again, to find out from what comes before
+    /// the pong whether it's been freed since we closed it for the last peer.
+    Reopening,
+    /// Sent PAKE message.
+    Pake,
+    /// Sent version message.
+    Version,
+    /// Connected to peer and successfully derived an encryption key,
+    /// ready to communicate.
+    Connected,
+    /// Client is closing the mailbox.
+    Closing,
+    /// Mailbox is closed, client is done and ready to terminate the connection.
+    Closed,
+}
+
+/// Errors generated by the client.
+#[derive(Error, Debug)]
+pub(crate) enum ClientError {
+    #[error("failed to create or parse message")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("failed to send websocket message")]
+    ChannelError(
+        #[from] futures_channel::mpsc::TrySendError<tokio_tungstenite::tungstenite::Message>,
+    ),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+}
+
+/// The random identifier a client binds to the server with. The server tags every message in
+/// a mailbox with the side which added it, including our own, which it sends back to us.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Side(String);
+
+impl Side {
+    /// Generate a random 16-character hex identifier.
+    fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut buffer = [0u8; 8];
+        rng.fill_bytes(&mut buffer);
+        Side(hex::encode(buffer))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Side {
+    fn from(side: &str) -> Self {
+        Side(side.to_owned())
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A wormhole client.
+#[derive(Debug)]
+pub(crate) struct Client {
+    /// Application namespace.
+    pub app_id: String,
+    /// The client's ID string.
+    pub side: Side,
+    /// The command the client is executing.
+    pub command: ClientCommand,
+    /// The client's current mood.
+    mood: Mood,
+    /// A transmission channel for sending messages to the server.
+    sender: UnboundedSender<Message>,
+    /// A transmission channel for passing events on to the application.
+    events: UnboundedSender<ClientEvent>,
+    /// The client's current state.
+    state: ClientState,
+    /// The currently associated nameplate ID.
+    nameplate_id: Option<usize>,
+    /// The currently open mailbox ID.
+    mailbox_id: Option<String>,
+    /// The code, once known.
+    code: Option<String>,
+    /// Whether we've shown the user the code to send with.
+    code_shown: bool,
+    /// Whether to copy the code to send with to the clipboard when it's shown.
+    clip: bool,
+    /// The clipboard holding our code, until the peer has connected with it.
+    clipboard: Option<Clipboard>,
+    /// Whether to report the code as JSON on standard output too.
+    json: bool,
+    /// PAKE algorithm.
+    spake: Option<Spake2<Ed25519Group>>,
+    /// The PAKE-derived key used for encryption, once computed.
+    key: Option<SessionKey>,
+    /// Phase number of the next application message we send.
+    next_phase: usize,
+    /// Phase number of the next dilation message we send.
+    next_dilate_phase: usize,
+    /// What to tell the peer's application about ours.
+    app_versions: HashMap<String, Value>,
+    /// Whether to tell the peer we can dilate.
+    dilation: bool,
+    /// How many words to put in the code, if we make it.
+    code_length: usize,
+    /// Whether to keep our nameplate for one peer after another, until we close.
+    many: bool,
+    /// Whether we're closing our mailbox only to open it again, empty, for the next peer.
+    reopening: bool,
+    /// Whether our mailbox still held our messages to the last peer when we opened it again.
+    stale: bool,
+    /// The number of the next ping we send the server.
+    next_ping: u32,
+    /// Whether we gave up on the peer because its version couldn't be decrypted, which means
+    /// it used a different code.
+    wrong_code: bool,
+    /// Whether we're finding our way back to our mailbox on a new connection to the server,
+    /// after the last one dropped.
+    resuming: bool,
+    /// Our messages added to our mailbox, to add again if the server never got them.
+    sent: Vec<(Phase, String)>,
+    /// Phases of our messages the server has echoed back, so has in our mailbox.
+    echoed: HashSet<Phase>,
+    /// Sides and phases of the peer's messages we've had, so any the server sends us again are
+    /// ignored rather than handled twice.
+    received: HashSet<(Side, Phase)>,
+    /// The peer's messages which arrived ahead of earlier ones, by phase, with the side which
+    /// sent them, until it's their turn.
+    pending: HashMap<Phase, (Side, Vec<u8>)>,
+    /// Phase number of the next application message we expect from the peer.
+    next_peer_phase: usize,
+    /// Phase number of the next dilation message we expect from the peer.
+    next_peer_dilate_phase: usize,
+}
+
+impl Client {
+    /// Create a new client and run the given command.
+    pub(crate) fn new(
+        command: ClientCommand,
+        app_id: String,
+        sender: UnboundedSender<Message>,
+        events: UnboundedSender<ClientEvent>,
+    ) -> Self {
+        let side = Side::generate();
+        Client {
+            app_id,
+            side,
+            sender,
+            events,
+            command,
+            mood: Mood::Lonely,
+            state: ClientState::default(),
+            nameplate_id: None,
+            mailbox_id: None,
+            code: None,
+            code_shown: false,
+            clip: false,
+            clipboard: None,
+            json: false,
+            spake: None,
+            key: None,
+            next_phase: 0,
+            next_dilate_phase: 0,
+            app_versions: HashMap::new(),
+            dilation: false,
+            code_length: CODE_WORDS,
+            many: false,
+            reopening: false,
+            stale: false,
+            next_ping: 0,
+            wrong_code: false,
+            resuming: false,
+            sent: Vec::new(),
+            echoed: HashSet::new(),
+            received: HashSet::new(),
+            pending: HashMap::new(),
+            next_peer_phase: 0,
+            next_peer_dilate_phase: 0,
+        }
+    }
+
+    /// Tell the peer's application about ours in the version message.
+    pub(crate) fn with_app_versions(mut self, app_versions: HashMap<String, Value>) -> Self {
+        self.app_versions = app_versions;
+        self
+    }
+
+    /// Tell the peer we can dilate the connection in the version message.
+    pub(crate) fn with_dilation(mut self, dilation: bool) -> Self {
+        self.dilation = dilation;
+        self
+    }
+
+    /// Use the given code, instead of making one up or waiting for the user to enter it.
+    pub(crate) fn with_code(mut self, code: Option<String>) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Put this many words in the code, if we make it.
+    pub(crate) fn with_code_length(mut self, code_length: usize) -> Self {
+        self.code_length = code_length;
+        self
+    }
+
+    /// Keep our nameplate once a peer has joined, to use the same code with peer after peer,
+    /// releasing it only once we close.
+    pub(crate) fn with_many(mut self, many: bool) -> Self {
+        self.many = many;
+        self
+    }
+
+    /// Copy the code to the clipboard when it's shown, clearing it once the peer has connected.
+    pub(crate) fn with_clip(mut self, clip: bool) -> Self {
+        self.clip = clip;
+        self
+    }
+
+    /// Report the code to send with as JSON on standard output, as well as showing it.
+    pub(crate) fn with_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// Has a key been agreed with the peer, so messages can be sent to it?
+    pub(crate) fn is_connected(&self) -> bool {
+        self.state == ClientState::Connected
+    }
+
+    /// Is the client ready for the connection to be terminated?
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state == ClientState::Closed
+    }
+
+    /// Has the client started closing its mailbox, if it hasn't closed it already?
+    pub(crate) fn is_closing(&self) -> bool {
+        matches!(self.state, ClientState::Closing | ClientState::Closed)
+    }
+
+    /// Did we give up on the peer because its messages couldn't be decrypted?
+    pub(crate) fn is_scared(&self) -> bool {
+        matches!(self.mood, Mood::Scary)
+    }
+
+    /// Did we give up on the peer because it used a different code from ours?
+    pub(crate) fn is_wrong_code(&self) -> bool {
+        self.wrong_code
+    }
+
+    /// Send proof of permission to use the server, ahead of binding.
+    pub(crate) fn submit_permissions(&mut self, permission: Permission) -> Result<(), ClientError> {
+        assert_eq!(self.state, ClientState::Init);
+
+        let permissions_msg = ClientMessage::new(ClientMessageType::SubmitPermissions(permission));
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&permissions_msg)?))?;
+        debug!("Sent {:?}, {:?}", permissions_msg.id, permissions_msg.ty);
+
+        Ok(())
+    }
+
+    /// Send a bind message to the server.
+    pub(crate) fn bind(&mut self) -> Result<(), ClientError> {
+        // Binding the same side again after reconnecting picks up where we left off
+        assert!(self.state == ClientState::Init || self.resuming);
+
+        let bind_msg = ClientMessage::new(ClientMessageType::Bind {
+            app_id: self.app_id.clone(),
+            side: self.side.to_string(),
+        });
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&bind_msg)?))?;
+        debug!("Sent {:?}, {:?}", bind_msg.id, bind_msg.ty);
+        if !self.resuming {
+            self.state = ClientState::Bound;
+        }
+
+        Ok(())
+    }
+
+    /// Request a nameplate from the server.
+    pub(crate) fn allocate(&mut self) -> Result<(), ClientError> {
+        assert_eq!(self.state, ClientState::Bound);
+
+        self.state = ClientState::Allocating;
+        let allocate_msg = ClientMessage::new(ClientMessageType::Allocate);
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&allocate_msg)?))?;
+        debug!("Sent {:?}, {:?}", allocate_msg.id, allocate_msg.ty);
+
+        Ok(())
+    }
+
+    /// Handle a nameplate allocation from the server.
+    pub(crate) fn allocated(&mut self, nameplate_id: usize) -> Result<(), ClientError> {
+        assert_eq!(self.state, ClientState::Allocating);
+        self.claim(Some(nameplate_id))
+    }
+
+    /// Ask the server which nameplates are in use, while the user is still entering a code.
+    pub(crate) fn list(&mut self) -> Result<(), ClientError> {
+        assert!(self.is_entering_code());
+
+        let list_msg = ClientMessage::new(ClientMessageType::List);
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&list_msg)?))?;
+        debug!("Sent {:?}, {:?}", list_msg.id, list_msg.ty);
+
+        Ok(())
+    }
+
+    /// Request to claim a nameplate. If a `nameplate_id` is given, claim that one.
+    /// Otherwise, claim the nameplate derived from our code, which we only have before
+    /// claiming if we are receiving, sending with a code we were given, or sending again to
+    /// the next peer.
+    pub(crate) fn claim(&mut self, nameplate_id: Option<usize>) -> Result<(), ClientError> {
+        if let Some(nameplate_id) = nameplate_id {
+            // Claim the given nameplate (from an allocation)
+            assert_eq!(self.state, ClientState::Allocating);
+            self.nameplate_id = Some(nameplate_id);
+        } else {
+            // Claim the nameplate from our code
+            assert_eq!(self.state, ClientState::Bound);
+            let code = self.code.as_ref().expect("no code to claim");
+            let mut parts = code.split('-');
+            let nameplate_id = parts.next().unwrap().parse::<usize>().unwrap();
+            self.nameplate_id = Some(nameplate_id);
+        }
+
+        self.state = ClientState::Claiming;
+        self.send_claim()
+    }
+
+    /// Send a claim for our nameplate to the server.
+    fn send_claim(&mut self) -> Result<(), ClientError> {
+        let claim_msg = ClientMessage::new(ClientMessageType::Claim {
+            nameplate_id: *self.nameplate_id.as_ref().unwrap(),
+        });
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&claim_msg)?))?;
+        debug!("Sent {:?}, {:?}", claim_msg.id, claim_msg.ty);
+
+        Ok(())
+    }
+
+    /// Handle a nameplate claim from the server. Will initiate the PAKE sequence to establish
+    /// a shared encryption key with a peer.
+    pub(crate) fn claimed(&mut self, mailbox_id: &str) -> Result<(), ClientError> {
+        if self.resuming && self.state != ClientState::Claiming {
+            // Our nameplate was claimed again after reconnecting. It only has a new mailbox if
+            // the server freed it while we were gone, in which case nothing we sent is in it.
+            if self.mailbox_id.as_deref() != Some(mailbox_id) {
+                self.mailbox_id = Some(mailbox_id.to_owned());
+                self.echoed.clear();
+            }
+            self.open()?;
+            return self.rejoined();
+        }
+        assert_eq!(self.state, ClientState::Claiming);
+        self.resuming = false;
+
+        self.mailbox_id = Some(mailbox_id.to_owned());
+        self.open()?;
+        self.start_pake()
+    }
+
+    /// Add our PAKE message to our mailbox, showing the code to send with if we haven't yet.
+    fn start_pake(&mut self) -> Result<(), ClientError> {
+        self.state = ClientState::Pake;
+        let code = self
+            .code
+            // Choose a random code, unless we were given one
+            .get_or_insert_with(|| make_code(self.nameplate_id.unwrap(), self.code_length))
+            .clone();
+
+        let (spake, raw_msg) = start_pake(&code, &self.app_id);
+        let body = serde_json::to_string(&PeerMessage::Pake { pake_v1: raw_msg })?;
+        self.spake = Some(spake);
+        self.post(Phase::Pake, body.into_bytes())?;
+
+        // TODO: We probably shouldn't print this until we've actually sent the message
+        if self.command == ClientCommand::Send && !self.code_shown {
+            self.code_shown = true;
+            eprintln!("Wormhole code is {}", code);
+            eprintln!("On the other computer, please run:");
+            eprintln!();
+            eprintln!("wormhole receive {}", code);
+            if self.json {
+                Event::Code { code: &code }.emit();
+            }
+            if self.clip {
+                match Clipboard::copy(&code) {
+                    Ok(clipboard) => {
+                        eprintln!("(the code has been copied to the clipboard)");
+                        self.clipboard = Some(clipboard);
+                    }
+                    Err(e) => eprintln!("Couldn't copy the code to the clipboard: {}", e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open our mailbox, to receive the messages in it.
+    fn open(&mut self) -> Result<(), ClientError> {
+        let open_msg = ClientMessage::new(ClientMessageType::Open {
+            mailbox_id: self.mailbox_id.clone().unwrap(),
+        });
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&open_msg)?))?;
+        debug!("Sent {:?}, {:?}", open_msg.id, open_msg.ty);
+
+        Ok(())
+    }
+
+    /// Release our nameplate, once the peer has proved it knows the code, or we're closing.
+    fn release(&mut self) -> Result<(), ClientError> {
+        let release_msg = ClientMessage::new(ClientMessageType::Release {
+            nameplate_id: Some(self.nameplate_id.take().unwrap()),
+        });
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&release_msg)?))?;
+        debug!("Sent {:?}, {:?}", release_msg.id, release_msg.ty);
+
+        Ok(())
+    }
+
+    /// Handle mailbox message reception. The peer's messages are handled in the order of their
+    /// phases, whatever order they arrive in: the PAKE message, then its version, then the
+    /// numbered application and dilation messages, each in turn.
+    pub(crate) fn message(
+        &mut self,
+        side: &Side,
+        phase: &Phase,
+        body: &[u8],
+    ) -> Result<(), ClientError> {
+        if *side == self.side && self.state == ClientState::Reopening {
+            // Left from the last peer, so the server hasn't freed our mailbox yet
+            self.stale = true;
+            return Ok(());
+        }
+        if *side == self.side {
+            // Just an echo of our own message, which the server has now
+            self.echoed.insert(phase.clone());
+            return Ok(());
+        }
+        if let Phase::Unknown(phase) = phase {
+            // Only a newer client would know what to make of it
+            warn!("Ignoring message from peer in unknown phase {:?}", phase);
+            return Ok(());
+        }
+        if !self.received.insert((side.clone(), phase.clone())) {
+            // Servers may deliver messages again, and opening our mailbox again after
+            // reconnecting sends us everything in it
+            debug!("Ignoring repeated {:?} from {}", phase, side);
+            return Ok(());
+        }
+
+        self.pending
+            .entry(phase.clone())
+            .or_insert_with(|| (side.clone(), body.to_vec()));
+        while let Some((phase, (side, body))) = self.next_pending() {
+            self.handle(&side, &phase, &body)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take the peer's message which is next in turn, if it's arrived.
+    fn next_pending(&mut self) -> Option<(Phase, (Side, Vec<u8>))> {
+        let expected = match self.state {
+            ClientState::Pake => vec![Phase::Pake],
+            ClientState::Version => vec![Phase::Version],
+            ClientState::Connected => vec![
+                Phase::Message(self.next_peer_phase),
+                Phase::Dilate(self.next_peer_dilate_phase),
+            ],
+            // The peer may still be sending once we've decided to close
+            _ => Vec::new(),
+        };
+        expected
+            .into_iter()
+            .find_map(|phase| self.pending.remove_entry(&phase))
+    }
+
+    /// Handle the peer's message which is next in turn.
+    fn handle(&mut self, side: &Side, phase: &Phase, body: &[u8]) -> Result<(), ClientError> {
+        match self.state {
+            ClientState::Pake => {
+                assert_eq!(*phase, Phase::Pake);
+                assert!(self.spake.is_some());
+                let pake_msg = serde_json::from_slice::<PeerMessage>(body);
+                match pake_msg {
+                    Ok(PeerMessage::Pake { pake_v1 }) => {
+                        let key = match finish_pake(self.spake.take().unwrap(), &pake_v1) {
+                            Ok(key) => key,
+                            Err(e) => {
+                                warn!("Peer's PAKE message is invalid: {:?}", e);
+                                return self.close(Mood::Errory);
+                            }
+                        };
+                        self.key = Some(key);
+                        self.state = ClientState::Version;
+
+                        let body = serde_json::to_string(&PeerMessage::Version {
+                            abilities: None,
+                            can_dilate: self
+                                .dilation
+                                .then(|| DILATION_VERSIONS.iter().map(|v| v.to_string()).collect()),
+                            dilation_abilities: self
+                                .dilation
+                                .then(|| vec![Ability::DirectTcpV1, Ability::RelayV1]),
+                            app_versions: self.app_versions.clone(),
+                        })?;
+                        let encrypted_body = encrypt_message(
+                            body.as_bytes(),
+                            self.key.as_ref().unwrap().as_bytes(),
+                            self.side.as_str(),
+                            &Phase::Version,
+                        )?;
+                        self.post(Phase::Version, encrypted_body)?;
+                    }
+                    _ => {
+                        warn!("Peer's message isn't a PAKE message");
+                        return self.close(Mood::Errory);
+                    }
+                }
+            }
+            ClientState::Version => {
+                assert_eq!(*phase, Phase::Version);
+                let decrypted_body = match decrypt_message(
+                    body,
+                    self.key.as_ref().unwrap().as_bytes(),
+                    side.as_str(),
+                    phase,
+                ) {
+                    Ok(msg) => msg,
+                    Err(e) if e.is_undecryptable() => return self.scared(phase),
+                    Err(e) => return Err(e.into()),
+                };
+                let version_msg = serde_json::from_slice::<PeerMessage>(&decrypted_body);
+                let (app_versions, can_dilate) = match version_msg {
+                    Ok(PeerMessage::Version {
+                        app_versions,
+                        can_dilate,
+                        ..
+                    }) => (app_versions, can_dilate),
+                    Ok(_) => {
+                        warn!("Peer's message isn't a version message");
+                        return self.close(Mood::Errory);
+                    }
+                    Err(e) => {
+                        warn!("Peer's version message is invalid: {:?}", e);
+                        return self.close(Mood::Errory);
+                    }
+                };
+                debug!("Got version message: {:?}", app_versions);
+                self.mood = Mood::Happy;
+                self.state = ClientState::Connected;
+                // The peer has used the code, so it needn't be pasted again, and nobody else
+                // needs the nameplate to find our mailbox, unless we're keeping it for the next
+                // peer
+                self.clipboard = None;
+                if self.nameplate_id.is_some() && !self.many {
+                    self.release()?;
+                }
+                let can_dilate = self.dilation
+                    && can_dilate.is_some_and(|versions| {
+                        versions
+                            .iter()
+                            .any(|v| DILATION_VERSIONS.contains(&v.as_str()))
+                    });
+                // The application may have gone away, in which case there's nobody to tell
+                let _ = self.events.unbounded_send(ClientEvent::Connected {
+                    key: self.key.clone().unwrap(),
+                    app_versions,
+                    can_dilate,
+                });
+            }
+            ClientState::Connected => {
+                debug!("Got message phase {:?}", phase);
+                match phase {
+                    Phase::Dilate(_) => self.next_peer_dilate_phase += 1,
+                    _ => self.next_peer_phase += 1,
+                }
+                let decrypted_body = match decrypt_message(
+                    body,
+                    self.key.as_ref().unwrap().as_bytes(),
+                    side.as_str(),
+                    phase,
+                ) {
+                    Ok(msg) => msg,
+                    Err(e) if e.is_undecryptable() => return self.scared(phase),
+                    Err(e) => return Err(e.into()),
+                };
+                debug!(
+                    "Decrypted message: {:?}",
+                    String::from_utf8_lossy(&decrypted_body)
+                );
+                let event = match phase {
+                    Phase::Dilate(_) => serde_json::from_slice::<DilationMessage>(&decrypted_body)
+                        .map(ClientEvent::Dilation),
+                    _ => serde_json::from_slice::<ApplicationMessage>(&decrypted_body)
+                        .map(ClientEvent::Message),
+                };
+                match event {
+                    Ok(event) => {
+                        let _ = self.events.unbounded_send(event);
+                    }
+                    Err(e) => warn!("Ignoring unrecognised message from peer: {}", e),
+                }
+            }
+            _ => unreachable!("no message expected"),
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt and send an application message to the peer.
+    pub(crate) fn send(&mut self, message: &ApplicationMessage) -> Result<(), ClientError> {
+        let phase = Phase::Message(self.next_phase);
+        self.next_phase += 1;
+        self.add(phase, &serde_json::to_string(message)?)
+    }
+
+    /// Encrypt and send a message setting up a dilated connection to the peer.
+    pub(crate) fn dilate(&mut self, message: &DilationMessage) -> Result<(), ClientError> {
+        let phase = Phase::Dilate(self.next_dilate_phase);
+        self.next_dilate_phase += 1;
+        self.add(phase, &serde_json::to_string(message)?)
+    }
+
+    /// Encrypt a message and add it to the mailbox in the given phase.
+    fn add(&mut self, phase: Phase, body: &str) -> Result<(), ClientError> {
+        assert_eq!(self.state, ClientState::Connected);
+
+        let encrypted_body = encrypt_message(
+            body.as_bytes(),
+            self.key.as_ref().unwrap().as_bytes(),
+            self.side.as_str(),
+            &phase,
+        )?;
+        self.post(phase, encrypted_body)
+    }
+
+    /// Add a message to our mailbox in the given phase, keeping it to add again if our
+    /// connection to the server drops before it's echoed back to us.
+    fn post(&mut self, phase: Phase, body: Vec<u8>) -> Result<(), ClientError> {
+        let msg = ClientMessage::new(ClientMessageType::Add {
+            phase: phase.clone(),
+            body,
+        });
+        let text = serde_json::to_string(&msg)?;
+        self.sent.push((phase, text.clone()));
+        if let Err(e) = self.sender.unbounded_send(Message::Text(text)) {
+            // It's sent again once we've reconnected
+            if !e.is_disconnected() {
+                return Err(e.into());
+            }
+        }
+        debug!("Sent {:?}, {:?}", msg.id, msg.ty);
+
+        Ok(())
+    }
+
+    /// Close our mailbox in the given mood, if it's open. Our nameplate is released too, if we
+    /// still have it because no peer turned up to use it, or we kept it for the next.
+    pub(crate) fn close(&mut self, mood: Mood) -> Result<(), ClientError> {
+        if self.reopening {
+            self.reopening = false;
+            if self.state == ClientState::Closed {
+                // Closed already, and waiting to open it again
+                self.mailbox_id = None;
+            }
+        }
+        if self.nameplate_id.is_some() {
+            self.release()?;
+        }
+        self.close_mailbox(mood)
+    }
+
+    /// Close our mailbox in the given mood, if it's open. While reopening, we hold on to its ID
+    /// until it's closed, in case we have to close it again after reconnecting.
+    fn close_mailbox(&mut self, mood: Mood) -> Result<(), ClientError> {
+        self.mood = mood;
+        self.clipboard = None;
+        let mailbox_id = if self.reopening {
+            self.mailbox_id.clone()
+        } else {
+            self.mailbox_id.take()
+        };
+        if let Some(mailbox_id) = mailbox_id {
+            let close_msg = ClientMessage::new(ClientMessageType::Close {
+                mailbox_id,
+                mood: self.mood.clone(),
+            });
+            self.sender
+                .unbounded_send(Message::Text(serde_json::to_string(&close_msg)?))?;
+            debug!("Sent {:?}, {:?}", close_msg.id, close_msg.ty);
+            self.state = ClientState::Closing;
+        } else {
+            self.state = ClientState::Closed;
+        }
+
+        Ok(())
+    }
+
+    /// Give up on a peer whose message in the given phase can't be decrypted, as it doesn't
+    /// have our key, closing our mailbox in the Scary mood and releasing the nameplate so the
+    /// code can't be tried again. The version is the first message encrypted with the key, so
+    /// if that's the one, the peer's code was wrong.
+    fn scared(&mut self, phase: &Phase) -> Result<(), ClientError> {
+        warn!("Couldn't decrypt peer's {:?} message", phase);
+        self.wrong_code = *phase == Phase::Version;
+        self.close(Mood::Scary)
+    }
+
+    /// Close our mailbox in the given mood, keeping our nameplate, which still leads to the
+    /// mailbox, so the next peer can use the same code. Once the last peer has closed the
+    /// mailbox too, the server frees it, and we open it again with nothing in it, as
+    /// [`Client::open_again`].
+    pub(crate) fn reopen(&mut self, mood: Mood) -> Result<(), ClientError> {
+        self.reopening = true;
+        self.close_mailbox(mood)
+    }
+
+    /// Is the client closing its mailbox only to reopen it for the next peer?
+    pub(crate) fn is_reopening(&self) -> bool {
+        self.reopening
+    }
+
+    /// Did our mailbox still hold what we sent the last peer when we last opened it again, so
+    /// we've had to close it again, and wait for the last peer to close it too?
+    pub(crate) fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Once our mailbox is closed for reopening, open it again for the next peer, with the same
+    /// code as before. Servers create a mailbox which is opened once it's been freed, so if the
+    /// last peer has closed it too, it's empty. What arrives before the pong for the ping sent
+    /// after opening it tells us whether it is.
+    pub(crate) fn open_again(&mut self) -> Result<(), ClientError> {
+        assert!(self.reopening && self.is_closed());
+        self.mood = Mood::Lonely;
+        self.resuming = false;
+        self.spake = None;
+        self.key = None;
+        self.next_phase = 0;
+        self.next_dilate_phase = 0;
+        self.stale = false;
+        self.sent.clear();
+        self.echoed.clear();
+        self.received.clear();
+        self.pending.clear();
+        self.next_peer_phase = 0;
+        self.next_peer_dilate_phase = 0;
+        self.state = ClientState::Reopening;
+        self.open()?;
+
+        let ping_msg = ClientMessage::new(ClientMessageType::Ping {
+            ping: self.next_ping,
+        });
+        self.next_ping += 1;
+        self.sender
+            .unbounded_send(Message::Text(serde_json::to_string(&ping_msg)?))?;
+        debug!("Sent {:?}, {:?}", ping_msg.id, ping_msg.ty);
+
+        Ok(())
+    }
+
+    /// Handle the server answering a ping. If it's the one sent after opening our mailbox again,
+    /// everything in the mailbox has arrived before it, so if any of it was ours, the last peer
+    /// hasn't closed it yet, and we close it again to try later. Otherwise it's empty but for
+    /// anything the next peer has sent already, and we start again with the next peer.
+    pub(crate) fn pong(&mut self, ping: u32) -> Result<(), ClientError> {
+        if self.state != ClientState::Reopening || ping + 1 != self.next_ping {
+            return Ok(());
+        }
+        if self.stale {
+            debug!("Mailbox {:?} isn't free yet", self.mailbox_id);
+            return self.close_mailbox(self.mood.clone());
+        }
+        debug!("Reopened mailbox {:?}", self.mailbox_id);
+        self.reopening = false;
+        self.start_pake()?;
+        while let Some((phase, (side, body))) = self.next_pending() {
+            self.handle(&side, &phase, &body)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pick up where we left off on a new connection to the server, after the last one
+    /// dropped. Unless we'd yet to claim a nameplate, we bind the same side as before, then
+    /// [`Client::rejoin`] our mailbox.
+    pub(crate) fn resume(&mut self, sender: UnboundedSender<Message>) {
+        self.sender = sender;
+        if self.nameplate_id.is_some() || self.mailbox_id.is_some() {
+            self.resuming = true;
+        } else {
+            // Nothing to lose by starting again
+            self.state = ClientState::Init;
+        }
+    }
+
+    /// Stop sending to the server, once everything already sent has gone, so the connection
+    /// can be closed cleanly.
+    pub(crate) fn disconnect(&mut self) {
+        self.sender.close_channel();
+    }
+
+    /// Is the client finding its way back to its mailbox after reconnecting?
+    pub(crate) fn is_resuming(&self) -> bool {
+        self.resuming
+    }
+
+    /// Once bound again after reconnecting, claim our nameplate again if we still hold it,
+    /// to find out our mailbox, or otherwise open our mailbox again.
+    pub(crate) fn rejoin(&mut self) -> Result<(), ClientError> {
+        assert!(self.resuming);
+        if self.reopening {
+            // The server may not have closed our mailbox before the connection dropped
+            return self.close_mailbox(self.mood.clone());
+        }
+        if self.nameplate_id.is_some() {
+            self.send_claim()
+        } else {
+            self.open()?;
+            self.rejoined()
+        }
+    }
+
+    /// Carry on as before our connection to the server dropped, adding again any of our
+    /// messages the server hadn't echoed back, which it may never have got. The peer ignores
+    /// any it gets twice.
+    fn rejoined(&mut self) -> Result<(), ClientError> {
+        self.resuming = false;
+        for (phase, text) in &self.sent {
+            if !self.echoed.contains(phase) {
+                self.sender.unbounded_send(Message::Text(text.clone()))?;
+                debug!("Sent {:?} again", phase);
+            }
+        }
+        debug!("Rejoined mailbox {:?}", self.mailbox_id);
+
+        Ok(())
+    }
+
+    /// Handle the server refusing one of our messages, returning whether it was expected.
+    /// If the server held on to our nameplate and mailbox while we were reconnecting, claiming
+    /// and opening them again is refused, and we carry on as we were. If it freed our mailbox
+    /// instead, as the peer had gone too, there's no mailbox left to close, or to close again
+    /// if we were reopening.
+    pub(crate) fn refused(
+        &mut self,
+        error: &str,
+        orig: &ClientMessageType,
+    ) -> Result<bool, ClientError> {
+        match (error, orig) {
+            ("already claimed", ClientMessageType::Claim { .. }) => {
+                // Unless it had already told us our mailbox, before we claimed it again
+                if self.resuming {
+                    self.rejoined()?;
+                }
+                Ok(true)
+            }
+            ("only one mailbox per connection", ClientMessageType::Open { .. }) => Ok(true),
+            ("invalid mailbox", ClientMessageType::Open { mailbox_id })
+                if self.mailbox_id.as_ref() == Some(mailbox_id) =>
+            {
+                self.mailbox_id = None;
+                Ok(false)
+            }
+            ("invalid mailbox", ClientMessageType::Close { .. }) if self.is_closing() => {
+                self.closed();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Do we have a code already, given to us or from before we reconnected for the next
+    /// peer?
+    pub(crate) fn has_code(&self) -> bool {
+        self.code.is_some()
+    }
+
+    /// Whether we're bound and waiting for the user to enter the code to receive with.
+    pub(crate) fn is_entering_code(&self) -> bool {
+        self.state == ClientState::Bound && self.code.is_none()
+    }
+
+    /// Use the code the user entered, claiming its nameplate if we're bound already.
+    /// Otherwise, it's claimed once we are.
+    pub(crate) fn enter_code(&mut self, code: String) -> Result<(), ClientError> {
+        self.code = Some(code);
+        if self.state == ClientState::Bound {
+            self.claim(None)?;
+        }
+        Ok(())
+    }
+
+    /// Handle confirmation of mailbox closure from server.
+    pub(crate) fn closed(&mut self) {
+        self.state = ClientState::Closed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // TODO: Tests for Client
+
+    use super::{
+        is_outdated, parse_code, Answer, ApplicationMessage, Client, ClientCommand, ClientEvent,
+        ClientState, DirectoryOffer, Offer, PeerMessage, Resume, Side,
+    };
+    use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tokio_tungstenite::tungstenite::Message;
+    use wormhole_core::crypto::encrypt_message;
+    use wormhole_core::dilation::DilationMessage;
+    use wormhole_core::message::{ClientMessage, ClientMessageType, Mood, Phase};
+    use wormhole_core::transit::Ability;
+
+    /// The types of the messages the client has sent to the server so far.
+    fn sent(receiver: &mut UnboundedReceiver<Message>) -> Vec<ClientMessageType> {
+        let mut sent = Vec::new();
+        while let Ok(Some(Message::Text(text))) = receiver.try_next() {
+            sent.push(serde_json::from_str::<ClientMessage>(&text).unwrap().ty);
+        }
+        sent
+    }
+
+    /// A client which has claimed the code's nameplate and opened its mailbox, with what it's
+    /// sent to the server and the events it's passed on.
+    fn opened(
+        code: &str,
+    ) -> (
+        Client,
+        UnboundedReceiver<Message>,
+        UnboundedReceiver<ClientEvent>,
+    ) {
+        let (tx, rx) = unbounded();
+        let (events, events_rx) = unbounded();
+        let mut client = Client::new(ClientCommand::Receive, "app".into(), tx, events)
+            .with_code(Some(code.into()));
+        client.bind().unwrap();
+        client.claim(None).unwrap();
+        client.claimed("mailbox").unwrap();
+        (client, rx, events_rx)
+    }
+
+    /// The messages a client has added to its mailbox, by phase.
+    fn added(receiver: &mut UnboundedReceiver<Message>) -> Vec<(Phase, Vec<u8>)> {
+        sent(receiver)
+            .into_iter()
+            .filter_map(|ty| match ty {
+                ClientMessageType::Add { phase, body } => Some((phase, body)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Pass the messages one client has added to its mailbox on to another.
+    fn deliver(from: &mut UnboundedReceiver<Message>, side: &Side, to: &mut Client) {
+        for (phase, body) in added(from) {
+            to.message(side, &phase, &body).unwrap();
+        }
+    }
+
+    #[test]
+    fn outdated_version() {
+        assert!(!is_outdated(env!("CARGO_PKG_VERSION")));
+        assert!(is_outdated("999.0.0"));
+        assert!(is_outdated("v999.0"));
+        assert!(!is_outdated("0.0.1"));
+        assert!(!is_outdated("not-a-version"));
+    }
+
+    #[test]
+    fn code_parsing() {
+        assert_eq!(
+            parse_code("7-crossover-clockwork"),
+            Ok("7-crossover-clockwork".to_string())
+        );
+        assert_eq!(parse_code("12-x"), Ok("12-x".to_string()));
+        assert!(parse_code("7").is_err());
+        assert!(parse_code("7-").is_err());
+        assert!(parse_code("crossover-clockwork").is_err());
+    }
+
+    #[test]
+    fn resuming() {
+        let (tx, mut rx) = unbounded();
+        let (events, _events) = unbounded();
+        let mut client = Client::new(ClientCommand::Receive, "app".into(), tx, events)
+            .with_code(Some("7-crossover-clockwork".into()));
+        client.bind().unwrap();
+        client.claim(None).unwrap();
+        client.claimed("mailbox").unwrap();
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [
+                ClientMessageType::Bind { .. },
+                ClientMessageType::Claim { nameplate_id: 7 },
+                ClientMessageType::Open { .. },
+                ClientMessageType::Add {
+                    phase: Phase::Pake,
+                    ..
+                },
+            ]
+        ));
+
+        // Our PAKE message never reached the server, so it's sent again
+        let (tx, mut rx) = unbounded();
+        client.resume(tx);
+        client.bind().unwrap();
+        client.rejoin().unwrap();
+        client.claimed("mailbox").unwrap();
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [
+                ClientMessageType::Bind { .. },
+                ClientMessageType::Claim { nameplate_id: 7 },
+                ClientMessageType::Open { .. },
+                ClientMessageType::Add {
+                    phase: Phase::Pake,
+                    ..
+                },
+            ]
+        ));
+        assert!(!client.is_resuming());
+
+        // Now it has, so it isn't, unless our mailbox has gone and we've got a new one
+        let side = client.side.clone();
+        client.message(&side, &Phase::Pake, b"").unwrap();
+        for (mailbox, resent) in [("mailbox", false), ("elsewhere", true)] {
+            let (tx, mut rx) = unbounded();
+            client.resume(tx);
+            client.bind().unwrap();
+            client.rejoin().unwrap();
+            client.claimed(mailbox).unwrap();
+            let sent = sent(&mut rx);
+            assert_eq!(sent.len(), if resent { 4 } else { 3 });
+            assert!(matches!(
+                sent[2],
+                ClientMessageType::Open { ref mailbox_id } if mailbox_id == mailbox
+            ));
+        }
+    }
+
+    #[test]
+    fn peer_messages() {
+        let (mut client, mut rx, mut events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+
+        // Phases from newer clients are ignored, whatever state we're in
+        let peer_side = peer.side.clone();
+        client
+            .message(&peer_side, &Phase::Unknown("future".into()), b"")
+            .unwrap();
+
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        deliver(&mut rx, &client.side.clone(), &mut peer);
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        assert!(client.is_connected());
+        assert!(matches!(
+            events_rx.try_next(),
+            Ok(Some(ClientEvent::Connected { .. }))
+        ));
+
+        // The peer's errors are passed on to the application
+        peer.send(&ApplicationMessage::Error("transfer rejected".into()))
+            .unwrap();
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        assert!(matches!(
+            events_rx.try_next(),
+            Ok(Some(ClientEvent::Message(ApplicationMessage::Error(error))))
+                if error == "transfer rejected"
+        ));
+    }
+
+    #[test]
+    fn lifecycle() {
+        let (mut client, mut rx, _events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+        let peer_side = peer.side.clone();
+        deliver(&mut rx, &client.side.clone(), &mut peer);
+
+        // Anyone could have sent the PAKE message, so we hold on to the nameplate until the
+        // peer's version shows it knows the code
+        let mut mailbox = added(&mut peer_rx).into_iter();
+        let (phase, body) = mailbox.next().unwrap();
+        client.message(&peer_side, &phase, &body).unwrap();
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [ClientMessageType::Add {
+                phase: Phase::Version,
+                ..
+            }]
+        ));
+        for (phase, body) in mailbox {
+            client.message(&peer_side, &phase, &body).unwrap();
+        }
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [ClientMessageType::Release {
+                nameplate_id: Some(7)
+            }]
+        ));
+
+        // The mailbox stays open until the application is done with it
+        assert!(!client.is_closing());
+        client.close(Mood::Happy).unwrap();
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [ClientMessageType::Close {
+                mood: Mood::Happy,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn echoes() {
+        let (mut client, mut rx, mut events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+        let side = client.side.clone();
+        let peer_side = peer.side.clone();
+
+        // The server echoing our own PAKE message back isn't taken for the peer's, and we
+        // hold on to the nameplate until the peer turns up
+        let mailbox = added(&mut rx);
+        for (phase, body) in &mailbox {
+            client.message(&side, phase, body).unwrap();
+        }
+        assert!(!client.is_connected());
+        assert!(sent(&mut rx).is_empty());
+        assert!(events_rx.try_next().is_err());
+
+        // Nor are our later messages, however they interleave with the peer's
+        peer.message(&side, &mailbox[0].0, &mailbox[0].1).unwrap();
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        for (phase, body) in added(&mut rx) {
+            client.message(&side, &phase, &body).unwrap();
+            peer.message(&side, &phase, &body).unwrap();
+            client.message(&side, &phase, &body).unwrap();
+        }
+        assert!(client.is_connected());
+        assert!(peer.is_connected());
+
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = events_rx.try_next() {
+            events.push(event);
+        }
+        assert!(matches!(&events[..], [ClientEvent::Connected { .. }]));
+    }
+
+    #[test]
+    fn wrong_code() {
+        let (mut client, mut rx, mut events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwerk");
+        let peer_side = peer.side.clone();
+
+        deliver(&mut rx, &client.side.clone(), &mut peer);
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        assert!(client.is_scared());
+        assert!(client.is_wrong_code());
+        assert!(client.is_closing());
+        // The nameplate is given up straight away, so the code can't be tried again
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [
+                ClientMessageType::Add {
+                    phase: Phase::Version,
+                    ..
+                },
+                ClientMessageType::Release { .. },
+                ClientMessageType::Close {
+                    mood: Mood::Scary,
+                    ..
+                },
+            ]
+        ));
+        assert!(events_rx.try_next().is_err());
+
+        // Nothing more from the peer is looked at once we've given up on it
+        peer.key = Some(vec![0; 32].into());
+        peer.state = ClientState::Connected;
+        peer.send(&ApplicationMessage::Error("hello".into()))
+            .unwrap();
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        assert!(events_rx.try_next().is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let (mut client, mut rx, _events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+        let peer_side = peer.side.clone();
+
+        deliver(&mut rx, &client.side.clone(), &mut peer);
+        deliver(&mut peer_rx, &peer_side, &mut client);
+        deliver(&mut rx, &client.side.clone(), &mut peer);
+        assert!(client.is_connected());
+        assert!(peer.is_connected());
+
+        peer.send(&ApplicationMessage::Error("hello".into()))
+            .unwrap();
+        let (phase, mut body) = added(&mut peer_rx).pop().unwrap();
+        *body.last_mut().unwrap() ^= 1;
+        client.message(&peer_side, &phase, &body).unwrap();
+        assert!(client.is_scared());
+        assert!(!client.is_wrong_code());
+        assert!(client.is_closing());
+    }
+
+    #[test]
+    fn bad_pake() {
+        for body in [&br#"{"pake_v1":"abcd"}"#[..], b"{}"] {
+            let (mut client, mut rx, _events_rx) = opened("7-crossover-clockwork");
+            sent(&mut rx);
+            client.message(&"peer".into(), &Phase::Pake, body).unwrap();
+            assert!(client.is_closing());
+            assert!(matches!(
+                sent(&mut rx).last(),
+                Some(ClientMessageType::Close {
+                    mood: Mood::Errory,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_version() {
+        for body in [
+            &b"not json"[..],
+            br#"{"app_versions":[]}"#,
+            br#"{"pake_v1":"abcd"}"#,
+        ] {
+            let (mut client, mut rx, mut events_rx) = opened("7-crossover-clockwork");
+            let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+            let side = client.side.clone();
+            let peer_side = peer.side.clone();
+            deliver(&mut rx, &side, &mut peer);
+            let (phase, pake) = added(&mut peer_rx).remove(0);
+            client.message(&peer_side, &phase, &pake).unwrap();
+            sent(&mut rx);
+
+            let key = client.key.as_ref().unwrap().as_bytes();
+            let body = encrypt_message(body, key, peer_side.as_str(), &Phase::Version).unwrap();
+            client.message(&peer_side, &Phase::Version, &body).unwrap();
+            assert!(client.is_closing());
+            assert!(matches!(
+                sent(&mut rx).last(),
+                Some(ClientMessageType::Close {
+                    mood: Mood::Errory,
+                    ..
+                })
+            ));
+            assert!(events_rx.try_next().is_err());
+        }
+    }
+
+    #[test]
+    fn many() {
+        let (tx, mut rx) = unbounded();
+        let (events, _events_rx) = unbounded();
+        let mut client = Client::new(ClientCommand::Receive, "app".into(), tx, events)
+            .with_code(Some("7-crossover-clockwork".into()))
+            .with_many(true);
+        client.bind().unwrap();
+        client.claim(None).unwrap();
+        client.claimed("mailbox1").unwrap();
+        let side = client.side.clone();
+
+        for ping in [0, 2] {
+            // Our nameplate is kept once a peer has joined
+            let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+            let peer_side = peer.side.clone();
+            deliver(&mut rx, &side, &mut peer);
+            deliver(&mut peer_rx, &peer_side, &mut client);
+            assert!(client.is_connected());
+            assert!(!sent(&mut rx)
+                .iter()
+                .any(|ty| matches!(ty, ClientMessageType::Release { .. })));
+
+            // And our mailbox is opened again for the next once it's closed
+            client.reopen(Mood::Happy).unwrap();
+            assert!(matches!(
+                &sent(&mut rx)[..],
+                [ClientMessageType::Close { mailbox_id, mood: Mood::Happy }]
+                    if mailbox_id == "mailbox1"
+            ));
+            client.closed();
+            client.open_again().unwrap();
+            assert!(matches!(
+                &sent(&mut rx)[..],
+                [
+                    ClientMessageType::Open { mailbox_id },
+                    ClientMessageType::Ping { ping: sent },
+                ] if mailbox_id == "mailbox1" && *sent == ping
+            ));
+
+            // Closed again if the last peer hasn't closed it too, so it still has our messages
+            client.message(&side, &Phase::Pake, b"{}").unwrap();
+            client.message(&peer_side, &Phase::Pake, b"{}").unwrap();
+            client.pong(ping).unwrap();
+            assert!(client.is_stale());
+            assert!(matches!(
+                &sent(&mut rx)[..],
+                [ClientMessageType::Close { mailbox_id, .. }] if mailbox_id == "mailbox1"
+            ));
+            client.closed();
+            assert!(client.is_reopening());
+
+            // Until it's been freed, and we start again with the next
+            client.open_again().unwrap();
+            assert_eq!(sent(&mut rx).len(), 2);
+            client.pong(ping + 1).unwrap();
+            assert!(!client.is_stale());
+            assert!(!client.is_reopening());
+        }
+
+        // Until we close for good
+        client.close(Mood::Lonely).unwrap();
+        assert!(matches!(
+            &sent(&mut rx)[..],
+            [
+                ClientMessageType::Add {
+                    phase: Phase::Pake,
+                    ..
+                },
+                ClientMessageType::Release {
+                    nameplate_id: Some(7)
+                },
+                ClientMessageType::Close { .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn reordering() {
+        let (mut client, mut rx, mut events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+        let side = client.side.clone();
+        let peer_side = peer.side.clone();
+
+        // The peer's version can't be decrypted until we have its PAKE message
+        deliver(&mut rx, &side, &mut peer);
+        for (phase, body) in added(&mut peer_rx).into_iter().rev() {
+            client.message(&peer_side, &phase, &body).unwrap();
+        }
+        assert!(client.is_connected());
+        deliver(&mut rx, &side, &mut peer);
+
+        peer.send(&ApplicationMessage::Error("first".into()))
+            .unwrap();
+        peer.send(&ApplicationMessage::Error("second".into()))
+            .unwrap();
+        peer.dilate(&DilationMessage::Reconnect).unwrap();
+        for (phase, body) in added(&mut peer_rx).into_iter().rev() {
+            client.message(&peer_side, &phase, &body).unwrap();
+        }
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = events_rx.try_next() {
+            events.push(event);
+        }
+        assert!(matches!(
+            &events[..],
+            [
+                ClientEvent::Connected { .. },
+                ClientEvent::Dilation(DilationMessage::Reconnect),
+                ClientEvent::Message(ApplicationMessage::Error(first)),
+                ClientEvent::Message(ApplicationMessage::Error(second)),
+            ] if first == "first" && second == "second"
+        ));
+    }
+
+    #[test]
+    fn duplicates() {
+        let (mut client, mut rx, mut events_rx) = opened("7-crossover-clockwork");
+        let (mut peer, mut peer_rx, _) = opened("7-crossover-clockwork");
+        let side = client.side.clone();
+        let peer_side = peer.side.clone();
+
+        deliver(&mut rx, &side, &mut peer);
+        let mut mailbox = added(&mut peer_rx);
+        for (phase, body) in &mailbox {
+            client.message(&peer_side, phase, body).unwrap();
+            client.message(&peer_side, phase, body).unwrap();
+        }
+        assert!(client.is_connected());
+        deliver(&mut rx, &side, &mut peer);
+
+        // Repeats of a message waiting its turn are dropped too
+        peer.send(&ApplicationMessage::Error("first".into()))
+            .unwrap();
+        peer.send(&ApplicationMessage::Error("second".into()))
+            .unwrap();
+        let messages = added(&mut peer_rx);
+        for (phase, body) in [&messages[1], &messages[1], &messages[0]] {
+            client.message(&peer_side, phase, body).unwrap();
+        }
+
+        // Everything in the mailbox is sent again when it's opened after reconnecting
+        mailbox.extend(messages);
+        let (tx, _rx) = unbounded();
+        client.resume(tx);
+        client.bind().unwrap();
+        client.rejoin().unwrap();
+        for (phase, body) in &mailbox {
+            client.message(&peer_side, phase, body).unwrap();
+        }
+
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = events_rx.try_next() {
+            events.push(event);
+        }
+        assert!(matches!(
+            &events[..],
+            [
+                ClientEvent::Connected { .. },
+                ClientEvent::Message(ApplicationMessage::Error(first)),
+                ClientEvent::Message(ApplicationMessage::Error(second)),
+            ] if first == "first" && second == "second"
+        ));
+    }
+
+    #[test]
+    fn side_id_generation() {
+        let side = Side::generate();
+        assert_eq!(side.as_str().len(), 16);
+        assert_ne!(side, Side::generate());
+    }
+
+    #[test]
+    fn serialization() {
+        let msg = PeerMessage::Version {
+            abilities: None,
+            can_dilate: None,
+            dilation_abilities: None,
+            app_versions: HashMap::new(),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, "{\"app_versions\":{}}");
+
+        let msg = PeerMessage::Version {
+            abilities: None,
+            can_dilate: Some(vec!["1".into()]),
+            dilation_abilities: Some(vec![Ability::DirectTcpV1, Ability::RelayV1]),
+            app_versions: HashMap::new(),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"can-dilate":["1"],"dilation-abilities":[{"type":"direct-tcp-v1"},{"type":"relay-v1"}],"app_versions":{}}"#
+        );
+        assert_eq!(serde_json::from_str::<PeerMessage>(&json).unwrap(), msg);
+
+        // Peers with nothing to say about their application may leave it out
+        assert_eq!(
+            serde_json::from_str::<PeerMessage>("{}").unwrap(),
+            PeerMessage::Version {
+                abilities: None,
+                can_dilate: None,
+                dilation_abilities: None,
+                app_versions: HashMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn application_messages() {
+        let messages = [
+            (
+                ApplicationMessage::Offer(Offer::Message("hello".into())),
+                r#"{"offer":{"message":"hello"}}"#,
+            ),
+            (
+                ApplicationMessage::Offer(Offer::File {
+                    filename: "file.txt".into(),
+                    filesize: 12,
+                    resume: false,
+                    stream: false,
+                }),
+                r#"{"offer":{"file":{"filename":"file.txt","filesize":12}}}"#,
+            ),
+            (
+                ApplicationMessage::Offer(Offer::File {
+                    filename: "file.txt".into(),
+                    filesize: 12,
+                    resume: true,
+                    stream: false,
+                }),
+                r#"{"offer":{"file":{"filename":"file.txt","filesize":12,"resume":true}}}"#,
+            ),
+            (
+                ApplicationMessage::Offer(Offer::File {
+                    filename: "db.sql".into(),
+                    filesize: 0,
+                    resume: false,
+                    stream: true,
+                }),
+                r#"{"offer":{"file":{"filename":"db.sql","filesize":0,"stream":true}}}"#,
+            ),
+            (
+                ApplicationMessage::Resume(Resume {
+                    offset: 4,
+                    sha256: "abcd".into(),
+                }),
+                r#"{"resume":{"offset":4,"sha256":"abcd"}}"#,
+            ),
+            (
+                ApplicationMessage::ResumeAck { offset: 4 },
+                r#"{"resume_ack":{"offset":4}}"#,
+            ),
+            (
+                ApplicationMessage::Offer(Offer::Directory(DirectoryOffer {
+                    dirname: "photos".into(),
+                    mode: "zipfile/deflated".into(),
+                    zipsize: 100,
+                    numbytes: 120,
+                    numfiles: 2,
+                    files: Vec::new(),
+                })),
+                r#"{"offer":{"directory":{"dirname":"photos","mode":"zipfile/deflated","zipsize":100,"numbytes":120,"numfiles":2}}}"#,
+            ),
+            (
+                ApplicationMessage::Offer(Offer::Directory(DirectoryOffer {
+                    dirname: "wormhole-files".into(),
+                    mode: "zipfile/deflated".into(),
+                    zipsize: 100,
+                    numbytes: 120,
+                    numfiles: 2,
+                    files: vec!["a.txt".into(), "b.png".into()],
+                })),
+                r#"{"offer":{"directory":{"dirname":"wormhole-files","mode":"zipfile/deflated","zipsize":100,"numbytes":120,"numfiles":2,"files":["a.txt","b.png"]}}}"#,
+            ),
+            (
+                ApplicationMessage::Answer(Answer::MessageAck("ok".into())),
+                r#"{"answer":{"message_ack":"ok"}}"#,
+            ),
+            (
+                ApplicationMessage::Answer(Answer::FileAck("ok".into())),
+                r#"{"answer":{"file_ack":"ok"}}"#,
+            ),
+            (
+                ApplicationMessage::Error("transfer rejected".into()),
+                r#"{"error":"transfer rejected"}"#,
+            ),
+        ];
+        for (message, json) in messages {
+            assert_eq!(serde_json::to_string(&message).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<ApplicationMessage>(json).unwrap(),
+                message
+            );
+        }
+
+        // As the Python client writes them
+        assert_eq!(
+            serde_json::from_str::<ApplicationMessage>(r#"{"offer": {"message": "hello"}}"#)
+                .unwrap(),
+            ApplicationMessage::Offer(Offer::Message("hello".into()))
+        );
+        assert_eq!(
+            serde_json::from_str::<ApplicationMessage>(r#"{"answer": {"message_ack": "ok"}}"#)
+                .unwrap(),
+            ApplicationMessage::Answer(Answer::MessageAck("ok".into()))
+        );
+    }
+
+    #[test]
+    fn deserialisation() {
+        let json = "{\"app_versions\":{}}";
+        let msg = serde_json::from_str::<PeerMessage>(json).unwrap();
+        assert_eq!(
+            msg,
+            PeerMessage::Version {
+                abilities: None,
+                can_dilate: None,
+                dilation_abilities: None,
+                app_versions: HashMap::new(),
+            }
+        );
+
+        // Other clients may describe themselves with more than strings
+        let json = r#"{"app_versions":{"compression":"zstd","transfer":{"features":[]}}}"#;
+        let msg = serde_json::from_str::<PeerMessage>(json).unwrap();
+        assert_eq!(
+            msg,
+            PeerMessage::Version {
+                abilities: None,
+                can_dilate: None,
+                dilation_abilities: None,
+                app_versions: HashMap::from([
+                    ("compression".to_string(), json!("zstd")),
+                    ("transfer".to_string(), json!({ "features": [] })),
+                ]),
+            }
+        );
+    }
+}
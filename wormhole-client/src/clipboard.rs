@@ -0,0 +1,41 @@
+/// Copying the code to the system clipboard, so the user can paste it to the receiver.
+use std::fmt;
+
+/// The system clipboard, holding a code until it's dropped.
+pub(crate) struct Clipboard {
+    clipboard: arboard::Clipboard,
+    code: String,
+}
+
+impl Clipboard {
+    /// Copy the code to the clipboard.
+    pub(crate) fn copy(code: &str) -> Result<Self, arboard::Error> {
+        let mut clipboard = arboard::Clipboard::new()?;
+        clipboard.set_text(code)?;
+        Ok(Self {
+            clipboard,
+            code: code.to_owned(),
+        })
+    }
+}
+
+impl Drop for Clipboard {
+    fn drop(&mut self) {
+        // Clear the code, unless the user has copied something else since
+        if self
+            .clipboard
+            .get_text()
+            .is_ok_and(|text| text == self.code)
+        {
+            let _ = self.clipboard.clear();
+        }
+    }
+}
+
+impl fmt::Debug for Clipboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clipboard")
+            .field("code", &self.code)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,149 @@
+/// Asking the user for a code, completing its nameplate and words with tab as they're typed.
+use futures_channel::mpsc::UnboundedSender;
+use rustyline::completion::{Completer, Pair};
+use rustyline::config::{Behavior, CompletionType, Config};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::sync::{Arc, Mutex};
+
+use crate::words::{check_code, complete_word, CODE_WORDS};
+
+/// Completes the nameplate of a code from those the server has listed, and its words from
+/// whichever word list each word's position uses.
+struct CodeHelper {
+    nameplates: Arc<Mutex<Vec<usize>>>,
+    /// Asks for the nameplates to be listed again.
+    list: UnboundedSender<()>,
+}
+
+impl Completer for CodeHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        if !line.contains('-') {
+            // Nameplates come and go, so the next completion sees any that have since
+            let _ = self.list.unbounded_send(());
+        }
+        Ok(completions(line, &self.nameplates.lock().unwrap()))
+    }
+}
+
+impl Hinter for CodeHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CodeHelper {}
+
+impl Validator for CodeHelper {}
+
+impl Helper for CodeHelper {}
+
+/// Where the word being typed at the end of `line` starts, and the words it could be. Before
+/// the first hyphen, that's one of the `nameplates` in use. Nameplates are followed by a hyphen,
+/// and so are words, unless they'd end a code of the usual length.
+fn completions(line: &str, nameplates: &[usize]) -> (usize, Vec<Pair>) {
+    let Some(index) = line.matches('-').count().checked_sub(1) else {
+        let mut nameplates: Vec<_> = nameplates
+            .iter()
+            .map(|nameplate| nameplate.to_string())
+            .filter(|nameplate| nameplate.starts_with(line))
+            .collect();
+        nameplates.sort();
+        let candidates = nameplates
+            .into_iter()
+            .map(|nameplate| Pair {
+                replacement: format!("{}-", nameplate),
+                display: nameplate,
+            })
+            .collect();
+        return (0, candidates);
+    };
+    let start = line.rfind('-').unwrap() + 1;
+    let prefix = line[start..].to_lowercase();
+    let candidates = complete_word(index, &prefix)
+        .into_iter()
+        .map(|word| Pair {
+            display: word.to_string(),
+            replacement: if index + 1 < CODE_WORDS {
+                format!("{}-", word)
+            } else {
+                word.to_string()
+            },
+        })
+        .collect();
+    (start, candidates)
+}
+
+/// Ask the user for a code, until they give one. Codes which look mistyped are asked for
+/// again, unless the user gives the same one twice. The terminal is used if there is one, so
+/// the prompt doesn't end up in what's written to standard output. Completing a nameplate
+/// sends on `list`, for `nameplates` to be updated.
+pub(crate) fn read_code(
+    nameplates: Arc<Mutex<Vec<usize>>>,
+    list: UnboundedSender<()>,
+) -> Result<String, ReadlineError> {
+    let config = Config::builder()
+        .behavior(Behavior::PreferTerm)
+        .completion_type(CompletionType::List)
+        .auto_add_history(false)
+        .build();
+    let mut editor = Editor::<CodeHelper, DefaultHistory>::with_config(config)?;
+    editor.set_helper(Some(CodeHelper { nameplates, list }));
+    let mut mistyped = None;
+    loop {
+        let code = editor.readline("Enter receive wormhole code: ")?;
+        let code = code.trim().to_string();
+        if code.is_empty() {
+            continue;
+        }
+        match check_code(&code) {
+            Err(typo) if mistyped.as_ref() != Some(&code) => {
+                eprintln!("{} Enter the same code again to use it anyway.", typo);
+                mistyped = Some(code);
+            }
+            _ => return Ok(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::completions;
+
+    #[test]
+    fn completing_codes() {
+        let (start, candidates) = completions("", &[]);
+        assert_eq!(start, 0);
+        assert!(candidates.is_empty());
+
+        let (start, candidates) = completions("1", &[7, 12, 1, 21]);
+        assert_eq!(start, 0);
+        let displayed: Vec<_> = candidates.iter().map(|c| c.display.as_str()).collect();
+        assert_eq!(displayed, ["1", "12"]);
+        assert_eq!(candidates[1].replacement, "12-");
+
+        let (start, candidates) = completions("7-Cross", &[7]);
+        assert_eq!(start, 2);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].display, "crossover");
+        assert_eq!(candidates[0].replacement, "crossover-");
+
+        let (start, candidates) = completions("7-crossover-clo", &[7]);
+        assert_eq!(start, 12);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].replacement, "clockwork");
+
+        let (_, candidates) = completions("7-clo", &[7]);
+        assert!(candidates.iter().all(|c| c.display != "clockwork"));
+    }
+}
@@ -0,0 +1,70 @@
+/// Reporting what's happening as JSON, one event per line on standard output, for scripts and
+/// GUIs driving the client with --json.
+use serde::Serialize;
+use std::path::Path;
+
+/// Something that's happened, worth telling whatever's driving the client about.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    /// The code for the receiver to use has been made up, or given.
+    Code { code: &'a str },
+    /// A key has been agreed with the peer.
+    Connected,
+    /// The verifier of the key, which the peer can show too.
+    Verifier { verifier: &'a str },
+    /// How much of a file or directory has been transferred, out of its size if known.
+    Progress { bytes: u64, total: Option<u64> },
+    /// A text message has been received.
+    Message { text: &'a str },
+    /// A received file or directory has been written.
+    Received { path: &'a Path },
+    /// Everything has been transferred.
+    Done,
+    /// The transfer failed, or was cancelled.
+    Error { error: &'a str },
+}
+
+impl Event<'_> {
+    /// Write the event to standard output.
+    pub(crate) fn emit(&self) {
+        println!("{}", serde_json::to_string(self).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+    use std::path::Path;
+
+    #[test]
+    fn events() {
+        let json = |event: Event| serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json(Event::Code {
+                code: "7-crossover-clockwork"
+            }),
+            r#"{"event":"code","code":"7-crossover-clockwork"}"#
+        );
+        assert_eq!(json(Event::Connected), r#"{"event":"connected"}"#);
+        assert_eq!(
+            json(Event::Progress {
+                bytes: 10,
+                total: None
+            }),
+            r#"{"event":"progress","bytes":10,"total":null}"#
+        );
+        assert_eq!(
+            json(Event::Received {
+                path: Path::new("dir/file.txt")
+            }),
+            r#"{"event":"received","path":"dir/file.txt"}"#
+        );
+        assert_eq!(
+            json(Event::Error {
+                error: "transfer cancelled"
+            }),
+            r#"{"event":"error","error":"transfer cancelled"}"#
+        );
+    }
+}
@@ -0,0 +1,150 @@
+/// Proving permission to use a mailbox server, by whichever of the methods asked for in its
+/// welcome we can. Each method is handled by its own [`Authenticator`], so supporting another
+/// only means adding one to [`authenticators`].
+use log::debug;
+use thiserror::Error;
+use wormhole_core::hashcash;
+use wormhole_core::message::{Permission, PermissionMethod};
+
+#[derive(Error, Debug, PartialEq)]
+pub(crate) enum PermissionError {
+    #[error("server requires unsupported auth: {}", .0.join(", "))]
+    Unsupported(Vec<String>),
+    #[error("server requires a token, given with --token")]
+    NoToken,
+}
+
+/// A way of proving permission to use a mailbox server.
+pub(crate) trait Authenticator {
+    /// Does this handle the given method?
+    fn handles(&self, method: &PermissionMethod) -> bool;
+
+    /// Prove permission by the given method, which this handles, returning what to submit
+    /// before binding, if anything.
+    fn satisfy(&self, method: &PermissionMethod) -> Result<Option<Permission>, PermissionError>;
+}
+
+/// For servers open to anyone.
+struct Open;
+
+impl Authenticator for Open {
+    fn handles(&self, method: &PermissionMethod) -> bool {
+        matches!(method, PermissionMethod::None)
+    }
+
+    fn satisfy(&self, _method: &PermissionMethod) -> Result<Option<Permission>, PermissionError> {
+        Ok(None)
+    }
+}
+
+/// Presenting a token shared out by the server's operator, if the user gave us one.
+struct Token(Option<String>);
+
+impl Authenticator for Token {
+    fn handles(&self, method: &PermissionMethod) -> bool {
+        matches!(method, PermissionMethod::Token)
+    }
+
+    fn satisfy(&self, _method: &PermissionMethod) -> Result<Option<Permission>, PermissionError> {
+        let token = self.0.clone().ok_or(PermissionError::NoToken)?;
+        Ok(Some(Permission::Token { token }))
+    }
+}
+
+/// Minting a hashcash stamp for the server's challenge.
+struct Hashcash;
+
+impl Authenticator for Hashcash {
+    fn handles(&self, method: &PermissionMethod) -> bool {
+        matches!(method, PermissionMethod::Hashcash { .. })
+    }
+
+    fn satisfy(&self, method: &PermissionMethod) -> Result<Option<Permission>, PermissionError> {
+        let PermissionMethod::Hashcash { bits, resource } = method else {
+            unreachable!("not a hashcash challenge");
+        };
+        debug!("Minting hashcash stamp with {} bits", bits);
+        let stamp = hashcash::mint(resource, *bits);
+        Ok(Some(Permission::Hashcash { stamp }))
+    }
+}
+
+/// Every method we support, presenting the given token if a server asks for one.
+pub(crate) fn authenticators(token: Option<String>) -> Vec<Box<dyn Authenticator>> {
+    vec![Box::new(Open), Box::new(Token(token)), Box::new(Hashcash)]
+}
+
+/// Satisfy the first of the server's methods we can, returning what to submit before binding,
+/// if anything. If we can't satisfy any, the error is why not for the first we support, or
+/// that we support none of them.
+pub(crate) fn negotiate(
+    authenticators: &[Box<dyn Authenticator>],
+    methods: &[PermissionMethod],
+) -> Result<Option<Permission>, PermissionError> {
+    if methods.is_empty() {
+        return Ok(None);
+    }
+    let mut failure = None;
+    for method in methods {
+        let Some(authenticator) = authenticators.iter().find(|a| a.handles(method)) else {
+            continue;
+        };
+        match authenticator.satisfy(method) {
+            Ok(permission) => return Ok(permission),
+            Err(e) => {
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    Err(failure.unwrap_or_else(|| {
+        PermissionError::Unsupported(methods.iter().map(PermissionMethod::name).collect())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticators, negotiate, PermissionError};
+    use serde_json::json;
+    use wormhole_core::message::{Permission, PermissionMethod};
+
+    #[test]
+    fn negotiation() {
+        let hashcash = PermissionMethod::Hashcash {
+            bits: 1,
+            resource: "abcd".into(),
+        };
+        let unknown = PermissionMethod::Unknown(json!({"argon2": {"memory": 1024}}));
+        let without_token = authenticators(None);
+        let with_token = authenticators(Some("secret".into()));
+
+        assert_eq!(negotiate(&without_token, &[]), Ok(None));
+        assert_eq!(
+            negotiate(&without_token, &[PermissionMethod::None]),
+            Ok(None)
+        );
+        assert!(matches!(
+            negotiate(&without_token, &[unknown.clone(), hashcash.clone()]),
+            Ok(Some(Permission::Hashcash { .. }))
+        ));
+
+        // The first method we can satisfy is used, skipping any we can't
+        assert_eq!(
+            negotiate(&with_token, &[PermissionMethod::Token, hashcash.clone()]),
+            Ok(Some(Permission::Token {
+                token: "secret".into()
+            }))
+        );
+        assert!(matches!(
+            negotiate(&without_token, &[PermissionMethod::Token, hashcash]),
+            Ok(Some(Permission::Hashcash { .. }))
+        ));
+
+        assert_eq!(
+            negotiate(&without_token, &[unknown.clone(), PermissionMethod::Token]),
+            Err(PermissionError::NoToken)
+        );
+        let err = negotiate(&with_token, &[unknown]).unwrap_err();
+        assert_eq!(err, PermissionError::Unsupported(vec!["argon2".into()]));
+        assert_eq!(err.to_string(), "server requires unsupported auth: argon2");
+    }
+}
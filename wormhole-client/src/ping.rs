@@ -0,0 +1,161 @@
+/// Checking a mailbox server is working: showing what it says when we connect, and timing how
+/// long it takes to answer pings.
+use futures_util::{SinkExt, StreamExt};
+use log::warn;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::{
+    net::TcpStream,
+    time::{timeout, Instant},
+};
+use tokio_tungstenite::{
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use wormhole_core::message::{
+    ClientMessage, ClientMessageType, PermissionMethod, ServerMessage, ServerMessageType,
+    WelcomeInfo,
+};
+
+/// How long to wait between pings.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for an answer to a ping, before counting it as lost.
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors generated while pinging a mailbox server.
+#[derive(Error, Debug)]
+pub(crate) enum PingError {
+    #[error("the server closed the connection")]
+    Closed,
+    #[error("the server didn't welcome us")]
+    NotWelcomed,
+    #[error("the server refused us: {0}")]
+    Refused(String),
+    #[error("the server didn't answer any pings")]
+    NoPongs,
+    #[error("connection to the server failed: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+    #[error("failed to encode a message: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Show the server's welcome, then ping it `count` times, showing how long each took to be
+/// answered, and a summary.
+pub(crate) async fn ping(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    count: u32,
+) -> Result<(), PingError> {
+    let welcome = timeout(PONG_TIMEOUT, next_message(&mut ws_stream))
+        .await
+        .map_err(|_| PingError::NotWelcomed)??;
+    let ServerMessageType::Welcome { welcome } = welcome.ty else {
+        return Err(PingError::NotWelcomed);
+    };
+    describe_welcome(&welcome);
+    if let Some(error) = welcome.error {
+        return Err(PingError::Refused(error));
+    }
+
+    let mut times = Vec::new();
+    for ping in 1..=count {
+        if ping > 1 {
+            tokio::time::sleep(PING_INTERVAL).await;
+        }
+        let msg = ClientMessage::new(ClientMessageType::Ping { ping });
+        let sent = Instant::now();
+        ws_stream
+            .send(Message::Text(serde_json::to_string(&msg)?))
+            .await?;
+        let answered = timeout(PONG_TIMEOUT, async {
+            loop {
+                if let ServerMessageType::Pong { ping: pong } =
+                    next_message(&mut ws_stream).await?.ty
+                {
+                    if pong == ping {
+                        return Ok::<_, PingError>(sent.elapsed());
+                    }
+                }
+            }
+        })
+        .await;
+        match answered {
+            Ok(elapsed) => {
+                let elapsed = elapsed?;
+                println!("pong {}: {:.1} ms", ping, millis(elapsed));
+                times.push(elapsed);
+            }
+            Err(_) => println!("pong {}: no answer after {:?}", ping, PONG_TIMEOUT),
+        }
+    }
+    let _ = ws_stream.close(None).await;
+
+    println!(
+        "{} pings sent, {} answered ({:.0}% lost)",
+        count,
+        times.len(),
+        100.0 * f64::from(count - times.len() as u32) / f64::from(count)
+    );
+    let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) else {
+        return Err(PingError::NoPongs);
+    };
+    let average = times.iter().sum::<Duration>() / times.len() as u32;
+    println!(
+        "round trip min/avg/max = {:.1}/{:.1}/{:.1} ms",
+        millis(*min),
+        millis(average),
+        millis(*max)
+    );
+    Ok(())
+}
+
+/// Show everything the server told us when we connected.
+fn describe_welcome(welcome: &WelcomeInfo) {
+    println!("Welcome from the server:");
+    if let Some(motd) = &welcome.motd {
+        println!("  message of the day: {}", motd);
+    }
+    if let Some(current) = &welcome.current_cli_version {
+        println!("  current client version: {}", current);
+    }
+    if let Some(error) = &welcome.error {
+        println!("  error: {}", error);
+    }
+    let permissions: Vec<_> = welcome
+        .permission_required
+        .iter()
+        .map(|method| match method {
+            PermissionMethod::Hashcash { bits, .. } => format!("hashcash ({} bits)", bits),
+            method => method.name(),
+        })
+        .collect();
+    if !permissions.is_empty() {
+        println!("  permission required: {}", permissions.join(", "));
+    }
+    for relay in &welcome.transit_relays {
+        println!("  transit relay: {}", relay);
+    }
+}
+
+/// Wait for the server's next message, skipping anything that isn't one.
+async fn next_message(
+    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> Result<ServerMessage, PingError> {
+    loop {
+        let msg = match ws_stream.next().await.ok_or(PingError::Closed)?? {
+            Message::Text(s) => serde_json::from_str::<ServerMessage>(&s),
+            Message::Binary(v) => serde_json::from_slice::<ServerMessage>(&v),
+            Message::Close(_) => return Err(PingError::Closed),
+            _ => continue,
+        };
+        match msg {
+            Ok(msg) => return Ok(msg),
+            Err(e) => warn!("Failed to decode message: {}", e),
+        }
+    }
+}
+
+/// The duration in milliseconds, fractions included.
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,113 @@
+/// Limiting how fast transfers go, so they don't use all of a slow connection.
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// A rate to transfer at, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Rate(pub u64);
+
+/// A rate which couldn't be parsed.
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid rate {0:?}, expected bytes per second like 800K or 1.5M")]
+pub(crate) struct InvalidRate(String);
+
+impl FromStr for Rate {
+    type Err = InvalidRate;
+
+    /// Parse a number of bytes per second, optionally with a K, M or G suffix for multiples of
+    /// 1024, and optionally ending in B.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidRate(s.to_owned());
+        let number = s.trim().trim_end_matches(['B', 'b']);
+        let (number, multiplier) = match number.char_indices().last().ok_or_else(invalid)? {
+            (i, 'k' | 'K') => (&number[..i], 1 << 10),
+            (i, 'm' | 'M') => (&number[..i], 1 << 20),
+            (i, 'g' | 'G') => (&number[..i], 1 << 30),
+            _ => (number, 1),
+        };
+        let rate = number.parse::<f64>().map_err(|_| invalid())? * multiplier as f64;
+        if !rate.is_finite() || rate < 1.0 {
+            return Err(invalid());
+        }
+        Ok(Rate(rate as u64))
+    }
+}
+
+/// A token bucket, holding back a transfer when it would go faster than the rate. Up to a
+/// second's worth of bytes can be transferred in a burst after a pause.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    rate: f64,
+    /// How many bytes can be transferred without waiting, negative if more already have been.
+    tokens: f64,
+    /// When the tokens were last topped up.
+    updated: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(rate: Rate) -> Self {
+        Throttle {
+            rate: rate.0 as f64,
+            tokens: rate.0 as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Wait until `len` more bytes can be transferred without going over the rate.
+    pub(crate) async fn consume(&mut self, len: usize) {
+        let delay = self.delay(len, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Take `len` bytes' worth of tokens at `now`, returning how long to wait until they've
+    /// all been paid for.
+    fn delay(&mut self, len: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+        self.tokens -= len as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rate, Throttle};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rates() {
+        assert_eq!("1000".parse(), Ok(Rate(1000)));
+        assert_eq!("800K".parse(), Ok(Rate(800 * 1024)));
+        assert_eq!("800kB".parse(), Ok(Rate(800 * 1024)));
+        assert_eq!("1.5M".parse(), Ok(Rate(3 * 512 * 1024)));
+        assert_eq!("2G".parse(), Ok(Rate(2 << 30)));
+        for invalid in ["", "K", "fast", "0", "-1M", "0.5"] {
+            assert!(invalid.parse::<Rate>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(Rate(1000));
+        throttle.updated = start;
+
+        // A second's worth can go straight away, but then it has to wait
+        assert_eq!(throttle.delay(1000, start), Duration::ZERO);
+        assert_eq!(throttle.delay(500, start), Duration::from_millis(500));
+        // Paying off the wait, then sending as fast as the rate allows
+        let later = start + Duration::from_millis(500);
+        assert_eq!(throttle.delay(100, later), Duration::from_millis(100));
+        // A long pause only saves up a second's worth
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(throttle.delay(1000, much_later), Duration::ZERO);
+        assert_eq!(throttle.delay(1000, much_later), Duration::from_secs(1));
+    }
+}
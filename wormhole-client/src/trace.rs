@@ -0,0 +1,110 @@
+/// Writing every message exchanged with the mailbox server to a file as a line of JSON, with
+/// --debug-protocol, for debugging problems working with other servers and clients.
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio_tungstenite::tungstenite::Message;
+use wormhole_core::message::redact;
+
+/// Which way a traced message was travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    /// From us to the server.
+    Sent,
+    /// From the server to us.
+    Received,
+}
+
+/// One line of the trace file.
+#[derive(Debug, Serialize)]
+struct TracedMessage {
+    /// When the message was sent or received, in seconds since the Unix epoch.
+    time: f64,
+    direction: Direction,
+    /// The message, with bodies replaced by their length and hash and tokens removed.
+    message: Value,
+}
+
+/// Writes every message exchanged with the mailbox server to a file.
+#[derive(Debug)]
+pub(crate) struct ProtocolTrace {
+    file: Mutex<File>,
+}
+
+impl ProtocolTrace {
+    /// Create the file at the given path, replacing any trace already there.
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(ProtocolTrace {
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+
+    /// Write a message sent to or received from the server. Failing to write it is only
+    /// warned about, as the trace shouldn't stop the transfer.
+    pub(crate) fn record(&self, direction: Direction, msg: &Message) {
+        let text = match msg {
+            Message::Text(text) => text.as_str(),
+            Message::Binary(data) => &String::from_utf8_lossy(data),
+            _ => return,
+        };
+        let traced = TracedMessage {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            direction,
+            message: redact(text),
+        };
+        let result = serde_json::to_vec(&traced)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file.lock().unwrap().write_all(&line)
+            });
+        if let Err(e) = result {
+            warn!("Writing the protocol trace failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, ProtocolTrace};
+    use serde_json::Value;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn tracing() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let trace = ProtocolTrace::create(&path).unwrap();
+        trace.record(
+            Direction::Sent,
+            &Message::Text(r#"{"type": "add", "phase": "pake", "body": "deadbeef"}"#.into()),
+        );
+        trace.record(Direction::Received, &Message::Ping(Vec::new()));
+        trace.record(
+            Direction::Received,
+            &Message::Binary(br#"{"type": "claimed", "mailbox": "abc"}"#.to_vec()),
+        );
+
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["direction"], "sent");
+        assert_eq!(lines[0]["message"]["phase"], "pake");
+        assert_eq!(lines[0]["message"]["body"]["len"], 4);
+        assert_eq!(lines[1]["direction"], "received");
+        assert_eq!(lines[1]["message"]["mailbox"], "abc");
+    }
+}
@@ -0,0 +1,2086 @@
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_util::StreamExt;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    slice,
+    time::Duration,
+};
+use tempfile::TempDir;
+use thiserror::Error;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    time::{timeout, Instant},
+};
+use zeroize::Zeroizing;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::client::{
+    Answer, ApplicationMessage, ClientEvent, ClientRequest, DirectoryOffer, Offer, Resume,
+};
+use crate::json::Event;
+use crate::throttle::{Rate, Throttle};
+use wormhole_core::crypto::{derive_verifier, CryptoError, SessionKey};
+use wormhole_core::dilation::{
+    dilation_key, DilatedConnection, Dilation, DilationError, DilationMessage, DilationRole,
+    Subchannel,
+};
+use wormhole_core::message::Mood;
+use wormhole_core::socks::SocksProxy;
+use wormhole_core::transit::{
+    transit_key, RelayHint, Role, Transit, TransitConnection, TransitError, TransitInfo,
+};
+
+mod v2;
+
+/// Size of the records files are sent in.
+const RECORD_SIZE: usize = 256 * 1024;
+
+/// How directories are packed for sending.
+const ZIP_MODE: &str = "zipfile/deflated";
+
+/// What several files sent together are called, as the directory they're offered as.
+const FILES_DIRNAME: &str = "wormhole-files";
+
+/// Where we say how we can compress records. Only copies of this client compress them, so it
+/// has a key of our own, which no other implementation will give a meaning of its own.
+const COMPRESSION_KEY: &str = "magic-wormhole-rs/compression";
+
+/// How records are compressed, if the peer can handle it.
+const COMPRESSION: &str = "zstd";
+
+/// Where we say we check what we receive against a digest the sender sends after it. Only
+/// copies of this client send or expect the digest, as an extra record the Python client
+/// wouldn't understand, so it has a key of our own.
+const CHECKSUM_KEY: &str = "magic-wormhole-rs/checksum";
+
+/// How the sender tells the receiver what it should have received, if the peer can check.
+const CHECKSUM: &str = "sha256";
+
+/// Where we say we can receive a file before its size is known. Only copies of this client
+/// can, and any other peer would take the offer for an empty file, so it has a key of our own.
+const STREAMING_KEY: &str = "magic-wormhole-rs/streaming";
+
+/// How the sender marks the end of a file of unknown size, if the peer can receive one.
+const STREAMING: &str = "empty-record";
+
+/// Where we say how we use file-transfer-v2, sending files over dilation subchannels.
+const TRANSFER_KEY: &str = "transfer";
+
+/// The features of file-transfer-v2 we support, which every peer speaking it must.
+const TRANSFER_FEATURE: &str = "core0";
+
+/// The zstd compression level records are compressed at.
+const ZSTD_LEVEL: i32 = 3;
+
+/// How long to wait for a transit connection with the peer.
+const TRANSIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to report the progress of a transfer as JSON.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to wait for the peer to say why, once a connection with it fails.
+const PEER_ERROR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The error sent to the peer when the user cancels a transfer.
+pub(crate) const CANCELLED: &str = "transfer cancelled";
+
+/// The answer to an offer of a file or directory when only text is accepted.
+const ONLY_TEXT: &str = "sorry, only text messages are accepted";
+
+/// Errors generated while transferring something to or from the peer.
+#[derive(Error, Debug)]
+pub(crate) enum TransferError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Transit(#[from] TransitError),
+    #[error(transparent)]
+    Dilation(#[from] DilationError),
+    #[error("zip archive error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("failed to create or parse message")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("failed to pack record: {0}")]
+    MsgpackEncode(#[from] rmp_serde::encode::Error),
+    #[error("failed to unpack record: {0}")]
+    MsgpackDecode(#[from] rmp_serde::decode::Error),
+    #[error("peer reported an error: {0}")]
+    Peer(String),
+    #[error("transfer cancelled by peer")]
+    Cancelled,
+    #[error("lost contact with the peer")]
+    Disconnected,
+    #[error("no peer turned up in time")]
+    TimedOut,
+    #[error("peer didn't say how to connect to it")]
+    NoTransit,
+    #[error("peer offered a file with a bad name: {0:?}")]
+    BadFilename(String),
+    #[error("refusing to overwrite existing file {0:?}")]
+    FileExists(PathBuf),
+    #[error("what was received doesn't match what was sent")]
+    ChecksumMismatch,
+    #[error("directory unpacks to more than the {0} bytes offered")]
+    TooManyBytes(u64),
+    #[error("directory holds more than the {0} files offered")]
+    TooManyFiles(u64),
+    #[error("can't send more than one file named {0:?}")]
+    DuplicateName(String),
+    #[error("unsupported directory mode {0:?}")]
+    UnsupportedMode(String),
+    #[error("can't write a directory to standard output")]
+    DirectoryToStdout,
+    #[error("transfer rejected")]
+    Rejected,
+    #[error("verification rejected")]
+    Unverified,
+    #[error("refused a file or directory, as only text is accepted")]
+    OnlyText,
+    #[error("unexpected message from peer: {0:?}")]
+    UnexpectedMessage(ApplicationMessage),
+    #[error("unexpected record from peer")]
+    UnexpectedRecord,
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+}
+
+impl TransferError {
+    /// The error the peer reported.
+    fn from_peer(error: String) -> Self {
+        if error == CANCELLED {
+            TransferError::Cancelled
+        } else {
+            TransferError::Peer(error)
+        }
+    }
+
+    /// Does the peer know already, having reported it or been told by us?
+    fn is_known_to_peer(&self) -> bool {
+        matches!(
+            self,
+            TransferError::Peer(_)
+                | TransferError::Cancelled
+                | TransferError::Disconnected
+                | TransferError::BadFilename(_)
+                | TransferError::FileExists(_)
+                | TransferError::UnsupportedMode(_)
+                | TransferError::DirectoryToStdout
+                | TransferError::Rejected
+                | TransferError::OnlyText
+        )
+    }
+}
+
+/// How to receive what the peer offers.
+#[derive(Debug, Default)]
+pub(crate) struct ReceiveOptions {
+    /// Accept files and directories without asking the user first.
+    pub accept: bool,
+    /// Where to put a file or directory, instead of under its own name in the current
+    /// directory.
+    pub output: Option<PathBuf>,
+    /// Overwrite anything already where a file or directory is put.
+    pub force: bool,
+    /// Write a file, or a directory's archive, to standard output instead.
+    pub stdout: bool,
+    /// Refuse files and directories, accepting only text.
+    pub only_text: bool,
+    /// How long to wait for the sender to turn up.
+    pub timeout: Option<Duration>,
+    /// Show the verifier, and wait for the user to confirm the sender shows the same before
+    /// accepting anything.
+    pub verify: bool,
+}
+
+/// How to send the payload.
+#[derive(Debug, Default)]
+pub(crate) struct SendOptions {
+    /// Send it to one receiver after another, all using the same code.
+    pub many: bool,
+    /// How many receivers to send it to, if sending it to many, unless limited by time.
+    pub count: Option<usize>,
+    /// How long to wait for a receiver to turn up, or for more if sending it to many.
+    pub timeout: Option<Duration>,
+    /// Show the verifier, and wait for the user to confirm each receiver shows the same before
+    /// sending to it.
+    pub verify: bool,
+}
+
+/// Something to send to the peer.
+#[derive(Debug)]
+pub(crate) enum Payload {
+    Text(String),
+    /// A file or directory.
+    File(PathBuf),
+    /// Several files or directories, sent together.
+    Files(Vec<PathBuf>),
+    /// Standard input, sent as a file with the given name.
+    Stdin(String),
+}
+
+/// What the receiver of a file sends over transit once it has it all.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct TransitAck {
+    ack: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+/// What the sender of a file sends over transit after it, so the receiver can check it has
+/// everything.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct TransferDigest {
+    sha256: String,
+    /// How much was sent, if the receiver didn't know beforehand.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+/// A message from the peer, over the mailbox.
+#[derive(Debug)]
+enum Incoming {
+    Message(ApplicationMessage),
+    /// Setting up a dilated connection.
+    Dilation(DilationMessage),
+}
+
+/// The application's end of the wormhole, exchanging messages with the peer through the
+/// client.
+#[derive(Debug)]
+pub(crate) struct Wormhole {
+    app_id: String,
+    side: String,
+    events: UnboundedReceiver<ClientEvent>,
+    requests: UnboundedSender<ClientRequest>,
+    /// Relays to offer the peer for file transfers.
+    relays: Vec<RelayHint>,
+    /// The key agreed with the peer, once connected.
+    key: Option<SessionKey>,
+    /// Whether to hide transfer progress.
+    quiet: bool,
+    /// Whether to report what's happening as JSON on standard output.
+    json: bool,
+    /// What we support.
+    ours: Features,
+    /// What both we and the peer support, once connected.
+    features: Features,
+    /// How fast to transfer over transit, if limited.
+    throttle: Option<Rate>,
+    /// The proxy to make transit connections through, if any.
+    proxy: Option<SocksProxy>,
+}
+
+impl Wormhole {
+    pub(crate) fn new(
+        app_id: String,
+        side: String,
+        events: UnboundedReceiver<ClientEvent>,
+        requests: UnboundedSender<ClientRequest>,
+        relays: Vec<RelayHint>,
+        ours: Features,
+    ) -> Self {
+        Wormhole {
+            app_id,
+            side,
+            events,
+            requests,
+            relays,
+            key: None,
+            quiet: false,
+            json: false,
+            ours,
+            features: Features::default(),
+            throttle: None,
+            proxy: None,
+        }
+    }
+
+    /// Hide the progress of transfers.
+    pub(crate) fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Report what's happening as JSON on standard output, instead of showing progress bars
+    /// or writing received text there.
+    pub(crate) fn with_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// Limit how fast transfers go over transit.
+    pub(crate) fn with_throttle(mut self, throttle: Option<Rate>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Make transit connections through a proxy.
+    pub(crate) fn with_proxy(mut self, proxy: Option<SocksProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Wait until a key has been agreed with the peer, unless the deadline passes first.
+    async fn connected_by(&mut self, deadline: Option<Instant>) -> Result<(), TransferError> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, self.connected())
+                .await
+                .map_err(|_| TransferError::TimedOut)?,
+            None => self.connected().await,
+        }
+    }
+
+    /// Wait until a key has been agreed with the peer.
+    async fn connected(&mut self) -> Result<(), TransferError> {
+        loop {
+            match self
+                .events
+                .next()
+                .await
+                .ok_or(TransferError::Disconnected)?
+            {
+                ClientEvent::Welcome(welcome) => {
+                    // We're welcomed again each time we reconnect
+                    for relay in welcome.transit_relays {
+                        match relay.parse() {
+                            Ok(relay) if self.relays.contains(&relay) => {}
+                            Ok(relay) => self.relays.push(relay),
+                            Err(e) => warn!("Ignoring transit relay from server: {}", e),
+                        }
+                    }
+                }
+                ClientEvent::Connected {
+                    key,
+                    app_versions,
+                    can_dilate,
+                } => {
+                    self.key = Some(key);
+                    self.features = self.ours.shared(&app_versions, can_dilate);
+                    debug!("Using {:?}", self.features);
+                    if self.json {
+                        Event::Connected.emit();
+                        let verifier = self.verifier()?;
+                        Event::Verifier {
+                            verifier: &verifier,
+                        }
+                        .emit();
+                    }
+                    return Ok(());
+                }
+                ClientEvent::Message(msg) => debug!("Ignoring early message {:?}", msg),
+                ClientEvent::Dilation(msg) => debug!("Ignoring early message {:?}", msg),
+            }
+        }
+    }
+
+    /// Wait for the peer's next message, of either kind.
+    async fn next(&mut self) -> Result<Incoming, TransferError> {
+        loop {
+            match self
+                .events
+                .next()
+                .await
+                .ok_or(TransferError::Disconnected)?
+            {
+                ClientEvent::Message(ApplicationMessage::Error(error)) => {
+                    return Err(TransferError::from_peer(error))
+                }
+                ClientEvent::Message(msg) => return Ok(Incoming::Message(msg)),
+                ClientEvent::Dilation(msg) => return Ok(Incoming::Dilation(msg)),
+                ClientEvent::Welcome(_) | ClientEvent::Connected { .. } => {}
+            }
+        }
+    }
+
+    /// Wait for the peer's next application message.
+    async fn receive(&mut self) -> Result<ApplicationMessage, TransferError> {
+        loop {
+            match self.next().await? {
+                Incoming::Message(msg) => return Ok(msg),
+                Incoming::Dilation(msg) => debug!("Ignoring dilation message {:?}", msg),
+            }
+        }
+    }
+
+    /// Wait for the peer's next message setting up a dilated connection.
+    async fn receive_dilation(&mut self) -> Result<DilationMessage, TransferError> {
+        match self.next().await? {
+            Incoming::Dilation(msg) => Ok(msg),
+            Incoming::Message(msg) => Err(TransferError::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Wait for the peer to report an error, ignoring anything else it sends meanwhile.
+    async fn peer_error(&mut self) -> TransferError {
+        loop {
+            match self.next().await {
+                Ok(msg) => debug!("Ignoring message {:?}", msg),
+                Err(e) => return e,
+            }
+        }
+    }
+
+    /// Connect to the peer, unless it gives up on the transfer first.
+    async fn connect<T, E: Into<TransferError>>(
+        &mut self,
+        connecting: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, TransferError> {
+        tokio::select! {
+            connection = connecting => connection.map_err(Into::into),
+            e = self.peer_error() => Err(e),
+        }
+    }
+
+    /// Send the peer a message.
+    fn send(&self, msg: ApplicationMessage) {
+        // If the client has gone, the next receive will fail
+        let _ = self.requests.unbounded_send(ClientRequest::Send(msg));
+    }
+
+    /// Send the peer a message setting up a dilated connection.
+    fn send_dilation(&self, msg: DilationMessage) {
+        let _ = self.requests.unbounded_send(ClientRequest::Dilate(msg));
+    }
+
+    /// Close the mailbox, as we're done with the peer.
+    fn close(&self, mood: Mood) {
+        let _ = self.requests.unbounded_send(ClientRequest::Close(mood));
+    }
+
+    /// Close the mailbox, and open a fresh one for the next peer to use the same code.
+    fn reopen(&self, mood: Mood) {
+        let _ = self.requests.unbounded_send(ClientRequest::Reopen(mood));
+    }
+
+    /// The verifier of the key agreed with the peer, which the peer can show too, for the
+    /// user to check they match.
+    fn verifier(&self) -> Result<String, TransferError> {
+        let verifier = derive_verifier(self.key.as_ref().unwrap().as_bytes())?;
+        Ok(hex::encode(verifier))
+    }
+
+    /// The progress of transferring `size` bytes, or an unknown amount, shown as a bar unless
+    /// we're being quiet or reporting it as JSON.
+    fn progress(&self, size: Option<u64>) -> Progress {
+        let bar = match size {
+            Some(size) => ProgressBar::new(size).with_style(
+                ProgressStyle::with_template(
+                    "{percent:>3}% |{wide_bar}| {bytes}/{total_bytes} [{elapsed}<{eta}, {binary_bytes_per_sec}]",
+                )
+                .unwrap(),
+            ),
+            None => ProgressBar::no_length().with_style(
+                ProgressStyle::with_template("{bytes} [{elapsed}, {binary_bytes_per_sec}]")
+                    .unwrap(),
+            ),
+        };
+        if self.quiet || self.json {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        Progress {
+            bar,
+            json: self.json,
+            reported: None,
+        }
+    }
+
+    /// Something to hold a transfer to the rate it's limited to, if it is.
+    fn throttle(&self) -> Option<Throttle> {
+        self.throttle.map(Throttle::new)
+    }
+
+    /// Start listening for a transit connection with the peer.
+    async fn transit(&self, role: Role) -> Result<Transit, TransferError> {
+        let key = Zeroizing::new(transit_key(
+            self.key.as_ref().unwrap().as_bytes(),
+            &self.app_id,
+        ));
+        let mut transit = Transit::listen(role, &self.side, &key).await?;
+        for relay in &self.relays {
+            transit = transit.with_relay(relay.clone());
+        }
+        if let Some(proxy) = &self.proxy {
+            transit = transit.with_proxy(proxy.clone());
+        }
+        Ok(transit)
+    }
+
+    /// Ask the peer to dilate, unless it's already asked from the given side, then set up a
+    /// dilated connection with it.
+    async fn dilate(
+        &mut self,
+        peer_side: Option<String>,
+    ) -> Result<DilatedConnection, TransferError> {
+        self.send_dilation(DilationMessage::Please {
+            side: self.side.clone(),
+        });
+        let peer_side = match peer_side {
+            Some(peer_side) => peer_side,
+            None => loop {
+                match self.receive_dilation().await? {
+                    DilationMessage::Please { side } => break side,
+                    msg => debug!("Ignoring dilation message {:?}", msg),
+                }
+            },
+        };
+
+        let role = DilationRole::for_sides(&self.side, &peer_side);
+        let key = Zeroizing::new(dilation_key(self.key.as_ref().unwrap().as_bytes()));
+        let mut dilation = Dilation::listen(role, &self.side, &key).await?;
+        for relay in &self.relays {
+            dilation = dilation.with_relay(relay.clone());
+        }
+        if let Some(proxy) = &self.proxy {
+            dilation = dilation.with_proxy(proxy.clone());
+        }
+        self.send_dilation(DilationMessage::ConnectionHints {
+            hints: dilation.hints(),
+        });
+        let peer_hints = loop {
+            match self.receive_dilation().await? {
+                DilationMessage::ConnectionHints { hints } => break hints,
+                msg => debug!("Ignoring dilation message {:?}", msg),
+            }
+        };
+        let connection = self
+            .connect(dilation.connect(&peer_hints, TRANSIT_TIMEOUT))
+            .await?;
+        eprintln!("Connected over {}", connection.route());
+
+        Ok(connection)
+    }
+}
+
+/// The progress of a transfer, shown as a progress bar, or reported as JSON now and then.
+struct Progress {
+    bar: ProgressBar,
+    json: bool,
+    /// When the progress was last reported as JSON.
+    reported: Option<Instant>,
+}
+
+impl Progress {
+    fn set_position(&mut self, position: u64) {
+        self.bar.set_position(position);
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.bar.inc(delta);
+        if self
+            .reported
+            .is_none_or(|reported| reported.elapsed() >= PROGRESS_INTERVAL)
+        {
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.bar.finish();
+        self.report();
+    }
+
+    /// Report how far the transfer has got as JSON, if that's how it's reported.
+    fn report(&mut self) {
+        if self.json {
+            self.reported = Some(Instant::now());
+            Event::Progress {
+                bytes: self.bar.position(),
+                total: self.bar.length(),
+            }
+            .emit();
+        }
+    }
+}
+
+/// Something records can be sent and received on.
+trait Records {
+    async fn send_record(&mut self, record: &[u8]) -> Result<(), TransferError>;
+
+    async fn receive_record(&mut self) -> Result<&[u8], TransferError>;
+}
+
+impl Records for TransitConnection {
+    async fn send_record(&mut self, record: &[u8]) -> Result<(), TransferError> {
+        Ok(TransitConnection::send_record(self, record).await?)
+    }
+
+    async fn receive_record(&mut self) -> Result<&[u8], TransferError> {
+        Ok(TransitConnection::receive_record(self).await?)
+    }
+}
+
+/// Where an offer came from, and so how to answer it and receive what's offered.
+enum Offered<'a> {
+    /// The mailbox, with what's offered to follow over transit, using the hints the peer sent.
+    Mailbox(&'a TransitInfo),
+    /// A subchannel of a dilated connection, which what's offered follows on.
+    Subchannel(&'a mut Subchannel),
+}
+
+impl Offered<'_> {
+    /// Reject the offer, telling the peer why.
+    async fn reject(&mut self, wormhole: &Wormhole, reason: String) -> Result<(), TransferError> {
+        match self {
+            Offered::Mailbox(_) => wormhole.send(ApplicationMessage::Error(reason)),
+            Offered::Subchannel(subchannel) => v2::reject(subchannel, reason).await?,
+        }
+        Ok(())
+    }
+}
+
+/// How a side uses file-transfer-v2.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Mode {
+    /// Only sending files and directories.
+    Send,
+    /// Only receiving them.
+    Receive,
+    /// Both sending and receiving them.
+    Connect,
+}
+
+impl Mode {
+    /// Whether something can be sent between a side using it like this and one using it the
+    /// other way.
+    fn pairs_with(self, other: Mode) -> bool {
+        matches!(
+            (self, other),
+            (Mode::Send, Mode::Receive)
+                | (Mode::Receive, Mode::Send)
+                | (Mode::Connect, _)
+                | (_, Mode::Connect)
+        )
+    }
+}
+
+/// Whether a side accepts what's offered to it without asking the user first.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Permission {
+    Ask,
+    Yes,
+}
+
+/// What a side says about how it uses file-transfer-v2, in the version phase.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Transfer {
+    mode: Mode,
+    features: Vec<String>,
+    permission: Permission,
+}
+
+/// Optional parts of the transfer protocol, each used only if both sides support it. Every
+/// peer can make a transit connection, so that needs no negotiating.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Features {
+    /// Whether records are compressed.
+    compress: bool,
+    /// Whether the receiver checks what it receives against a digest of what was sent, and
+    /// so whether the sender sends the digest in an extra record after the data. Only peers
+    /// which advertise our checksum key ever get the record, so the Python client never does.
+    checksum: bool,
+    /// Whether a file can be sent before its size is known, as it's read.
+    streaming: bool,
+    /// How we use file-transfer-v2, if files and directories are sent with it over a dilated
+    /// connection rather than with the classic protocol.
+    transfer: Option<Transfer>,
+}
+
+impl Features {
+    /// Everything we support, using file-transfer-v2 as given.
+    pub(crate) fn ours(mode: Mode, permission: Permission) -> Self {
+        Features {
+            compress: true,
+            checksum: true,
+            streaming: true,
+            transfer: Some(Transfer {
+                mode,
+                features: vec![TRANSFER_FEATURE.to_string()],
+                permission,
+            }),
+        }
+    }
+
+    /// What we tell the peer's application about ours, in the version phase.
+    pub(crate) fn app_versions(&self) -> HashMap<String, Value> {
+        let mut app_versions = [
+            (self.compress, COMPRESSION_KEY, COMPRESSION),
+            (self.checksum, CHECKSUM_KEY, CHECKSUM),
+            (self.streaming, STREAMING_KEY, STREAMING),
+        ]
+        .into_iter()
+        .filter(|(supported, ..)| *supported)
+        .map(|(_, key, value)| (key.to_string(), Value::from(value)))
+        .collect::<HashMap<_, _>>();
+        if let Some(transfer) = &self.transfer {
+            let transfer = serde_json::to_value(transfer).unwrap();
+            app_versions.insert(TRANSFER_KEY.to_string(), transfer);
+        }
+        app_versions
+    }
+
+    /// What both we and the peer support, from what its application told us about itself
+    /// and whether both sides can dilate. Each is named either on its own, or in a list of
+    /// those the peer supports; anything else the peer says is ignored.
+    fn shared(&self, app_versions: &HashMap<String, Value>, can_dilate: bool) -> Self {
+        let supports = |key: &str, value: &str| match app_versions.get(key) {
+            Some(Value::String(s)) => s == value,
+            Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(value)),
+            _ => false,
+        };
+        Features {
+            compress: self.compress && supports(COMPRESSION_KEY, COMPRESSION),
+            checksum: self.checksum && supports(CHECKSUM_KEY, CHECKSUM),
+            streaming: self.streaming && supports(STREAMING_KEY, STREAMING),
+            transfer: self.transfer.clone().filter(|ours| {
+                let theirs = app_versions.get(TRANSFER_KEY).cloned();
+                can_dilate
+                    && theirs
+                        .and_then(|theirs| serde_json::from_value::<Transfer>(theirs).ok())
+                        .is_some_and(|theirs| {
+                            theirs.features.iter().any(|f| f == TRANSFER_FEATURE)
+                                && ours.mode.pairs_with(theirs.mode)
+                        })
+            }),
+        }
+    }
+}
+
+/// Send the payload to the peer, then close the mailbox in a mood reflecting how it went.
+pub(crate) async fn send(
+    mut wormhole: Wormhole,
+    payload: Payload,
+    options: SendOptions,
+) -> Result<(), TransferError> {
+    if options.many {
+        return send_many(wormhole, payload, options).await;
+    }
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let result = async {
+        wormhole.connected_by(deadline).await?;
+        verify(&wormhole, options.verify).await?;
+        send_payload(&mut wormhole, &payload).await
+    }
+    .await;
+    finish(&mut wormhole, result).await
+}
+
+/// Send the payload to one receiver after another, each using the same code, until enough
+/// have or no more turn up in time. A transfer to one receiver failing doesn't stop it being
+/// sent to the next.
+async fn send_many(
+    mut wormhole: Wormhole,
+    payload: Payload,
+    options: SendOptions,
+) -> Result<(), TransferError> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut receivers = 0;
+    loop {
+        match wormhole.connected_by(deadline).await {
+            Err(TransferError::TimedOut) => {
+                eprintln!("No more receivers in time, sent to {}", receivers);
+                wormhole.close(Mood::Lonely);
+                return Ok(());
+            }
+            connected => connected?,
+        }
+
+        let result = match verify(&wormhole, options.verify).await {
+            Ok(()) => send_payload(&mut wormhole, &payload).await,
+            Err(e) => Err(e),
+        };
+        receivers += 1;
+        let mood = match result {
+            Ok(()) => Mood::Happy,
+            Err(TransferError::Disconnected) => return Err(TransferError::Disconnected),
+            Err(e) => {
+                let e = failed(&mut wormhole, e).await;
+                eprintln!("Sending to receiver {} failed: {}", receivers, e);
+                Mood::Errory
+            }
+        };
+        if options.count.is_some_and(|count| receivers >= count) {
+            wormhole.close(mood);
+            return Ok(());
+        }
+        wormhole.reopen(mood);
+        eprintln!(
+            "Waiting for the next receiver, sent to {} so far",
+            receivers
+        );
+    }
+}
+
+/// Send the payload to the peer, once connected.
+async fn send_payload(wormhole: &mut Wormhole, payload: &Payload) -> Result<(), TransferError> {
+    match payload {
+        Payload::Text(text) => send_text(wormhole, text.clone()).await,
+        Payload::File(path) if wormhole.features.transfer.is_some() => {
+            v2::send(wormhole, slice::from_ref(path)).await
+        }
+        Payload::Files(paths) if wormhole.features.transfer.is_some() => {
+            v2::send(wormhole, paths).await
+        }
+        Payload::File(path) if path.is_dir() => send_directory(wormhole, path).await,
+        Payload::File(path) => send_file(wormhole, path).await,
+        Payload::Files(paths) => send_files(wormhole, paths).await,
+        Payload::Stdin(name) => send_stdin(wormhole, name.clone()).await,
+    }
+}
+
+/// Receive whatever the peer offers, then close the mailbox in a mood reflecting how it went.
+pub(crate) async fn receive(
+    mut wormhole: Wormhole,
+    options: ReceiveOptions,
+) -> Result<(), TransferError> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let result = async {
+        wormhole.connected_by(deadline).await?;
+        verify(&wormhole, options.verify).await?;
+        let mut peer_transit = None::<TransitInfo>;
+        loop {
+            let msg = match wormhole.next().await? {
+                Incoming::Message(msg) => msg,
+                Incoming::Dilation(DilationMessage::Please { side })
+                    if wormhole.features.transfer.is_some() =>
+                {
+                    return v2::receive(&mut wormhole, side, &options).await;
+                }
+                Incoming::Dilation(msg) => {
+                    debug!("Ignoring dilation message {:?}", msg);
+                    continue;
+                }
+            };
+            match msg {
+                // Tells us how to connect to the peer to receive a file
+                ApplicationMessage::Transit(info) => peer_transit = Some(info),
+                ApplicationMessage::Offer(Offer::Message(text)) => {
+                    // We've been sent a message: display to user and reply with ack
+                    if wormhole.json {
+                        Event::Message { text: &text }.emit();
+                    } else {
+                        println!("{}", text);
+                    }
+                    wormhole.send(ApplicationMessage::Answer(Answer::MessageAck("ok".into())));
+                    return Ok(());
+                }
+                ApplicationMessage::Offer(Offer::File { .. } | Offer::Directory(_))
+                    if options.only_text =>
+                {
+                    wormhole.send(ApplicationMessage::Error(ONLY_TEXT.into()));
+                    return Err(TransferError::OnlyText);
+                }
+                ApplicationMessage::Offer(Offer::File {
+                    filename,
+                    filesize,
+                    resume,
+                    stream,
+                }) => {
+                    let peer_transit = peer_transit.ok_or(TransferError::NoTransit)?;
+                    let filesize = (!stream).then_some(filesize);
+                    return receive_file(
+                        &mut wormhole,
+                        &mut Offered::Mailbox(&peer_transit),
+                        &filename,
+                        filesize,
+                        resume,
+                        &options,
+                    )
+                    .await;
+                }
+                ApplicationMessage::Offer(Offer::Directory(offer)) => {
+                    let peer_transit = peer_transit.ok_or(TransferError::NoTransit)?;
+                    let offered = &mut Offered::Mailbox(&peer_transit);
+                    return receive_directory(&mut wormhole, offered, offer, &options).await;
+                }
+                msg => return Err(TransferError::UnexpectedMessage(msg)),
+            }
+        }
+    }
+    .await;
+    finish(&mut wormhole, result).await
+}
+
+/// Close the mailbox, happily if the transfer succeeded, or lonely if no peer turned up.
+async fn finish(
+    wormhole: &mut Wormhole,
+    result: Result<(), TransferError>,
+) -> Result<(), TransferError> {
+    match result {
+        Ok(()) => {
+            wormhole.close(Mood::Happy);
+            Ok(())
+        }
+        Err(TransferError::TimedOut) => {
+            // There's no peer to tell
+            wormhole.close(Mood::Lonely);
+            Err(TransferError::TimedOut)
+        }
+        Err(e) => {
+            let e = failed(wormhole, e).await;
+            wormhole.close(Mood::Errory);
+            Err(e)
+        }
+    }
+}
+
+/// Tell the peer why the transfer failed, unless it knows already. If the connection with
+/// the peer failed, the peer may have given up on the transfer, so give it a moment to say
+/// so, and blame it instead.
+async fn failed(wormhole: &mut Wormhole, error: TransferError) -> TransferError {
+    let error = match error {
+        TransferError::Transit(_) | TransferError::Dilation(_) => {
+            match timeout(PEER_ERROR_TIMEOUT, wormhole.peer_error()).await {
+                Ok(e @ (TransferError::Peer(_) | TransferError::Cancelled)) => e,
+                _ => error,
+            }
+        }
+        error => error,
+    };
+    if !error.is_known_to_peer() {
+        wormhole.send(ApplicationMessage::Error(error.to_string()));
+    }
+    error
+}
+
+/// Offer the peer a text message, and wait for it to be acknowledged.
+async fn send_text(wormhole: &mut Wormhole, text: String) -> Result<(), TransferError> {
+    wormhole.send(ApplicationMessage::Offer(Offer::Message(text)));
+    loop {
+        match wormhole.receive().await? {
+            ApplicationMessage::Transit(_) => {}
+            ApplicationMessage::Answer(Answer::MessageAck(ack)) if ack == "ok" => {
+                // Our message has been ack'ed
+                eprintln!("text message sent");
+                return Ok(());
+            }
+            ApplicationMessage::Answer(Answer::MessageAck(ack)) => {
+                return Err(TransferError::Peer(ack))
+            }
+            msg => return Err(TransferError::UnexpectedMessage(msg)),
+        }
+    }
+}
+
+/// Offer the peer a file, and once it's accepted, send it over transit.
+async fn send_file(wormhole: &mut Wormhole, path: &Path) -> Result<(), TransferError> {
+    let file = File::open(path).await?;
+    let filesize = file.metadata().await?.len();
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+
+    eprintln!("Sending {} ({} bytes)", filename, filesize);
+    let offer = Offer::File {
+        filename,
+        filesize,
+        resume: true,
+        stream: false,
+    };
+    send_offer(wormhole, offer, file, filesize).await?;
+    eprintln!("File sent");
+
+    Ok(())
+}
+
+/// Offer the peer standard input as a file with the given name, and once it's accepted, send
+/// it over transit as it's read. A peer which can't receive a file before its size is known
+/// is offered it once it has all been read.
+async fn send_stdin(wormhole: &mut Wormhole, filename: String) -> Result<(), TransferError> {
+    let mut stdin = tokio::io::stdin();
+    if let Some(offer) = stream_offer(&wormhole.features, &filename) {
+        eprintln!("Sending {} from standard input", filename);
+        let (mut connection, _, hasher) = offer_contents(wormhole, offer, None).await?;
+        let mut progress = wormhole.progress(None);
+        let compress = wormhole.features.compress;
+        let (sha256, sent) = send_contents(
+            &mut connection,
+            stdin,
+            None,
+            hasher,
+            compress,
+            wormhole.throttle(),
+            &mut progress,
+        )
+        .await?;
+        progress.finish();
+        contents_sent(wormhole, &mut connection, sha256, Some(sent)).await?;
+        eprintln!("File sent ({} bytes)", sent);
+        return Ok(());
+    }
+
+    eprintln!("Reading standard input, as the peer needs to know its size first");
+    let mut file = File::from_std(tempfile::tempfile()?);
+    let filesize = tokio::io::copy(&mut stdin, &mut file).await?;
+    file.rewind().await?;
+    eprintln!("Sending {} ({} bytes)", filename, filesize);
+    let offer = Offer::File {
+        filename,
+        filesize,
+        resume: false,
+        stream: false,
+    };
+    send_offer(wormhole, offer, file, filesize).await?;
+    eprintln!("File sent");
+
+    Ok(())
+}
+
+/// The offer of a file to be sent as it's read, before its size is known, if the peer said it
+/// can receive one. Any other would take it for an empty file.
+fn stream_offer(features: &Features, filename: &str) -> Option<Offer> {
+    features.streaming.then(|| Offer::File {
+        filename: filename.to_owned(),
+        filesize: 0,
+        resume: false,
+        stream: true,
+    })
+}
+
+/// Offer the peer a directory, packed into a zip archive, and once it's accepted, send the
+/// archive over transit.
+async fn send_directory(wormhole: &mut Wormhole, path: &Path) -> Result<(), TransferError> {
+    let path = path.canonicalize()?;
+    let dirname = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a directory"))?;
+    send_archive(wormhole, dirname, vec![(path, PathBuf::new())], Vec::new()).await
+}
+
+/// Offer the peer several files and directories at once, packed into a zip archive as if
+/// they were in a directory, listing them so the peer knows what it's getting.
+async fn send_files(wormhole: &mut Wormhole, paths: &[PathBuf]) -> Result<(), TransferError> {
+    let mut names = Vec::new();
+    let mut roots = Vec::new();
+    for path in paths {
+        let path = path.canonicalize()?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "nothing to send"))?;
+        if names.contains(&name) {
+            return Err(TransferError::DuplicateName(name));
+        }
+        roots.push((path, PathBuf::from(&name)));
+        names.push(name);
+    }
+    send_archive(wormhole, FILES_DIRNAME.to_string(), roots, names).await
+}
+
+/// Pack the paths into a zip archive under their names, offer it to the peer as a directory,
+/// and once it's accepted, send the archive over transit.
+async fn send_archive(
+    wormhole: &mut Wormhole,
+    dirname: String,
+    roots: Vec<(PathBuf, PathBuf)>,
+    files: Vec<String>,
+) -> Result<(), TransferError> {
+    eprintln!("Building zipfile of {}", dirname);
+    let (archive, numbytes, numfiles) = tokio::task::spawn_blocking(move || zip_paths(roots))
+        .await
+        .map_err(io::Error::other)??;
+    let file = File::from_std(archive);
+    let zipsize = file.metadata().await?.len();
+
+    eprintln!(
+        "Sending directory ({} bytes compressed, {} files) named {}",
+        zipsize, numfiles, dirname
+    );
+    let offer = Offer::Directory(DirectoryOffer {
+        dirname,
+        mode: ZIP_MODE.to_string(),
+        zipsize,
+        numbytes,
+        numfiles,
+        files,
+    });
+    send_offer(wormhole, offer, file, zipsize).await?;
+    eprintln!("Directory sent");
+
+    Ok(())
+}
+
+/// Pack files and directories into a temporary zip archive under the names given with them,
+/// returning it with the total size and number of files packed. Directories are packed with
+/// everything under them.
+fn zip_paths(
+    mut pending: Vec<(PathBuf, PathBuf)>,
+) -> Result<(std::fs::File, u64, u64), TransferError> {
+    let mut zip = ZipWriter::new(tempfile::tempfile()?);
+    let mut numbytes = 0;
+    let mut numfiles = 0;
+    while let Some((path, name)) = pending.pop() {
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                // Follow links to files, but not to directories, which could loop
+                if entry.file_type()?.is_symlink() && entry.path().is_dir() {
+                    continue;
+                }
+                pending.push((entry.path(), name.join(entry.file_name())));
+            }
+            continue;
+        }
+
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(metadata.len() >= u32::MAX as u64);
+        #[cfg(unix)]
+        let options = options.unix_permissions(metadata.permissions().mode());
+        // Archive paths always use forward slashes
+        let name = name
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        zip.start_file(name, options)?;
+        numbytes += io::copy(&mut std::fs::File::open(&path)?, &mut zip)?;
+        numfiles += 1;
+    }
+    let mut archive = zip.finish()?;
+    archive.rewind()?;
+    Ok((archive, numbytes, numfiles))
+}
+
+/// Make an offer to the peer, and once it's accepted, send the file with it over transit.
+async fn send_offer(
+    wormhole: &mut Wormhole,
+    offer: Offer,
+    mut file: File,
+    size: u64,
+) -> Result<(), TransferError> {
+    let (mut connection, offset, hasher) =
+        offer_contents(wormhole, offer, Some((&mut file, size))).await?;
+    send_from(wormhole, &mut connection, file, size, offset, hasher).await
+}
+
+/// Send the `size` byte file from `offset` on the connection, and wait for the receiver to
+/// acknowledge it. The hasher has already seen everything before the offset.
+async fn send_from<C: Records>(
+    wormhole: &Wormhole,
+    connection: &mut C,
+    mut file: File,
+    size: u64,
+    offset: u64,
+    hasher: Sha256,
+) -> Result<(), TransferError> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut progress = wormhole.progress(Some(size));
+    progress.set_position(offset);
+    let compress = wormhole.features.compress;
+    let (sha256, _) = send_contents(
+        connection,
+        file,
+        Some(size - offset),
+        hasher,
+        compress,
+        wormhole.throttle(),
+        &mut progress,
+    )
+    .await?;
+    progress.finish();
+    contents_sent(wormhole, connection, sha256, None).await
+}
+
+/// Make an offer to the peer, and once it's accepted, connect to it over transit. If the
+/// offer is of a file of known size, the receiver can ask to resume an interrupted transfer
+/// of it, so this returns where to send from and the digest of everything before that.
+async fn offer_contents(
+    wormhole: &mut Wormhole,
+    offer: Offer,
+    mut resumable: Option<(&mut File, u64)>,
+) -> Result<(TransitConnection, u64, Sha256), TransferError> {
+    let transit = wormhole.transit(Role::Sender).await?;
+    wormhole.send(ApplicationMessage::Transit(transit.info()));
+    wormhole.send(ApplicationMessage::Offer(offer));
+    // So the user can check it against what the receiver is shown
+    eprintln!("Verifier {}", wormhole.verifier()?);
+
+    let mut peer_transit = None::<TransitInfo>;
+    let mut offset = 0;
+    let mut hasher = Sha256::new();
+    loop {
+        match wormhole.receive().await? {
+            ApplicationMessage::Transit(info) => peer_transit = Some(info),
+            ApplicationMessage::Resume(resume) => {
+                (offset, hasher) = resume_from(&mut resumable, &resume).await?;
+                wormhole.send(ApplicationMessage::ResumeAck { offset });
+            }
+            ApplicationMessage::Answer(Answer::FileAck(ack)) if ack == "ok" => break,
+            ApplicationMessage::Answer(Answer::FileAck(ack)) => {
+                return Err(TransferError::Peer(ack))
+            }
+            msg => return Err(TransferError::UnexpectedMessage(msg)),
+        }
+    }
+    let peer_transit = peer_transit.ok_or(TransferError::NoTransit)?;
+    let connection = wormhole
+        .connect(transit.connect(&peer_transit, TRANSIT_TIMEOUT))
+        .await?;
+    eprintln!("Sending over {}", connection.route());
+
+    Ok((connection, offset, hasher))
+}
+
+/// Where to resume sending a file from when the receiver asks to, with the digest of
+/// everything before that. Only what the receiver has is skipped, if it's the same as what
+/// we'd send.
+async fn resume_from(
+    resumable: &mut Option<(&mut File, u64)>,
+    resume: &Resume,
+) -> Result<(u64, Sha256), TransferError> {
+    if let Some((file, size)) = resumable {
+        if resume.offset <= *size {
+            let digest = prefix_digest(file, resume.offset).await?;
+            if hex::encode(digest.clone().finalize()) == resume.sha256 {
+                eprintln!("Resuming from {} bytes", resume.offset);
+                return Ok((resume.offset, digest));
+            }
+        }
+    }
+    Ok((0, Sha256::new()))
+}
+
+/// Once everything has been sent, tell the receiver what it should have, if it said it can
+/// check, and wait for it to acknowledge it all. A receiver which didn't, like the Python
+/// client, never gets the digest record, only the data it expects. The size is sent too if the receiver didn't know it
+/// beforehand.
+async fn contents_sent<C: Records>(
+    wormhole: &Wormhole,
+    connection: &mut C,
+    sha256: Vec<u8>,
+    size: Option<u64>,
+) -> Result<(), TransferError> {
+    let sha256 = hex::encode(sha256);
+    if wormhole.features.checksum {
+        let digest = TransferDigest {
+            sha256: sha256.clone(),
+            size,
+        };
+        connection
+            .send_record(&serde_json::to_vec(&digest)?)
+            .await?;
+    }
+
+    let ack = serde_json::from_slice::<TransitAck>(connection.receive_record().await?)?;
+    if ack.ack != "ok" {
+        return Err(TransferError::Peer(ack.ack));
+    }
+    if ack.sha256.is_some_and(|theirs| theirs != sha256) {
+        return Err(TransferError::ChecksumMismatch);
+    }
+
+    Ok(())
+}
+
+/// Accept the peer's offer of a file, receive it over transit, and write it to the current
+/// directory. If the sender can resume transfers, which it can only over the mailbox, anything
+/// received is kept if the transfer is interrupted, so the next attempt can pick up where this
+/// one left off. The file's size
+/// is unknown if the sender is sending it as it reads it.
+async fn receive_file(
+    wormhole: &mut Wormhole,
+    offered: &mut Offered<'_>,
+    filename: &str,
+    filesize: Option<u64>,
+    resume: bool,
+    options: &ReceiveOptions,
+) -> Result<(), TransferError> {
+    let described = match filesize {
+        Some(filesize) => format!("{} bytes", filesize),
+        None => "size unknown".to_string(),
+    };
+    if options.stdout {
+        eprintln!("Receiving file ({}) to standard output", described);
+        consent(wormhole, offered, options.accept).await?;
+        let mut stdout = tokio::io::stdout();
+        return accept_offer(wormhole, offered, &mut stdout, 0, filesize, Sha256::new()).await;
+    }
+
+    let target = offered_target(wormhole, offered, filename, options).await?;
+    eprintln!("Receiving file ({}) into: {}", described, target.display());
+    consent(wormhole, offered, options.accept).await?;
+
+    // Write alongside the target, so it can be renamed into place once it's all arrived
+    let partial = target.with_file_name(format!(
+        ".{}.part",
+        target.file_name().unwrap().to_string_lossy()
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&partial)
+        .await?;
+    let mut offset = 0;
+    let mut hasher = Sha256::new();
+    let existing = file.metadata().await?.len();
+    if resume && existing > 0 && filesize.is_some_and(|filesize| existing <= filesize) {
+        let digest = prefix_digest(&mut file, existing).await?;
+        let resume = Resume {
+            offset: existing,
+            sha256: hex::encode(digest.clone().finalize()),
+        };
+        wormhole.send(ApplicationMessage::Resume(resume));
+        match wormhole.receive().await? {
+            ApplicationMessage::ResumeAck { offset: 0 } => {}
+            ApplicationMessage::ResumeAck { offset: ack } if ack == existing => {
+                eprintln!("Resuming from {} bytes", existing);
+                offset = existing;
+                hasher = digest;
+            }
+            msg => return Err(TransferError::UnexpectedMessage(msg)),
+        }
+    }
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut result = accept_offer(wormhole, offered, &mut file, offset, filesize, hasher).await;
+    if result.is_ok() {
+        result = file.sync_all().await.map_err(Into::into);
+    }
+    drop(file);
+    if let Err(e) = result {
+        // What we have is no use to resume from if it's been corrupted
+        if !resume || matches!(e, TransferError::ChecksumMismatch) {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        return Err(e);
+    }
+    tokio::fs::rename(&partial, &target).await?;
+    eprintln!("Received file written to {}", target.display());
+    if wormhole.json {
+        Event::Received { path: &target }.emit();
+    }
+
+    Ok(())
+}
+
+/// Accept the peer's offer of a directory if the user agrees, receive its archive over
+/// transit, and unpack it into the current directory.
+async fn receive_directory(
+    wormhole: &mut Wormhole,
+    offered: &mut Offered<'_>,
+    offer: DirectoryOffer,
+    options: &ReceiveOptions,
+) -> Result<(), TransferError> {
+    if offer.mode != ZIP_MODE {
+        let error = format!("unsupported directory mode {:?}", offer.mode);
+        offered.reject(wormhole, error).await?;
+        return Err(TransferError::UnsupportedMode(offer.mode));
+    }
+    if options.stdout {
+        // Pass the archive on as it is, for something else to unpack
+        eprintln!(
+            "Receiving directory {} as a zip archive ({} bytes) to standard output",
+            offer.dirname, offer.zipsize
+        );
+        describe_directory(&offer);
+        consent(wormhole, offered, options.accept).await?;
+        let mut stdout = tokio::io::stdout();
+        return accept_offer(
+            wormhole,
+            offered,
+            &mut stdout,
+            0,
+            Some(offer.zipsize),
+            Sha256::new(),
+        )
+        .await;
+    }
+
+    let target = offered_target(wormhole, offered, &offer.dirname, options).await?;
+    eprintln!(
+        "Receiving directory ({} bytes) into: {}/",
+        offer.zipsize,
+        target.display()
+    );
+    describe_directory(&offer);
+    consent(wormhole, offered, options.accept).await?;
+
+    let archive = tempfile::tempfile_in(parent_dir(&target))?;
+    let mut file = File::from_std(archive.try_clone()?);
+    accept_offer(
+        wormhole,
+        offered,
+        &mut file,
+        0,
+        Some(offer.zipsize),
+        Sha256::new(),
+    )
+    .await?;
+    drop(file);
+
+    // Unpack alongside the target, so it can be renamed into place once it's all there
+    let temp = tempfile::Builder::new()
+        .prefix(".wormhole-")
+        .tempdir_in(parent_dir(&target))?;
+    let unpacked = temp.path().to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        unzip_archive(archive, &unpacked, offer.numbytes, offer.numfiles)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    move_into_place(wormhole, temp, &target, options.force).await
+}
+
+/// Move a directory received into a temporary one alongside the target into place, replacing
+/// any directory already there if forced.
+async fn move_into_place(
+    wormhole: &Wormhole,
+    temp: TempDir,
+    target: &Path,
+    force: bool,
+) -> Result<(), TransferError> {
+    if force && target.is_dir() {
+        tokio::fs::remove_dir_all(target).await?;
+    }
+    let unpacked = temp.keep();
+    if let Err(e) = std::fs::rename(&unpacked, target) {
+        let _ = std::fs::remove_dir_all(&unpacked);
+        return Err(e.into());
+    }
+    eprintln!("Received files written to {}/", target.display());
+    if wormhole.json {
+        Event::Received { path: target }.emit();
+    }
+
+    Ok(())
+}
+
+/// Tell the user what's in a directory the peer has offered.
+fn describe_directory(offer: &DirectoryOffer) {
+    for name in &offer.files {
+        eprintln!("  {}", name);
+    }
+    eprintln!(
+        "{} files, {} bytes (uncompressed)",
+        offer.numfiles, offer.numbytes
+    );
+}
+
+/// Where to put something the peer has offered. Unless forced, nothing existing is
+/// overwritten: an output path which exists is refused, and otherwise the offered name is
+/// numbered until it's free.
+async fn offered_target(
+    wormhole: &Wormhole,
+    offered: &mut Offered<'_>,
+    name: &str,
+    options: &ReceiveOptions,
+) -> Result<PathBuf, TransferError> {
+    // Never let the peer choose where it goes, only what it's called
+    let target = match &options.output {
+        Some(output) => output.clone(),
+        None => PathBuf::from(Path::new(name).file_name().unwrap_or_default()),
+    };
+    if target.file_name().is_none() {
+        offered.reject(wormhole, "bad filename".into()).await?;
+        return Err(TransferError::BadFilename(target.display().to_string()));
+    }
+    if options.force || !target.exists() {
+        return Ok(target);
+    }
+    if options.output.is_some() {
+        let error = "receiver refused to overwrite an existing file".into();
+        offered.reject(wormhole, error).await?;
+        return Err(TransferError::FileExists(target));
+    }
+
+    let renamed = (1..)
+        .map(|n| numbered(&target, n))
+        .find(|path| !path.exists())
+        .unwrap();
+    eprintln!(
+        "{} already exists, so using {} instead",
+        target.display(),
+        renamed.display()
+    );
+    Ok(renamed)
+}
+
+/// The path with a number added to its name, before any extension: "name (n).ext".
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, n, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// The directory the path is in.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Unless told to accept anyway, show the user the verifier and ask whether to accept the
+/// offer, telling the peer if they don't.
+async fn consent(
+    wormhole: &mut Wormhole,
+    offered: &mut Offered<'_>,
+    accept: bool,
+) -> Result<(), TransferError> {
+    if accept {
+        return Ok(());
+    }
+    eprintln!("Verifier {}", wormhole.verifier()?);
+    let confirmed = tokio::select! {
+        confirmed = confirm() => confirmed?,
+        // Stop asking if the peer gives up meanwhile
+        e = wormhole.peer_error() => {
+            eprintln!();
+            return Err(e);
+        }
+    };
+    if !confirmed {
+        offered.reject(wormhole, "transfer rejected".into()).await?;
+        return Err(TransferError::Rejected);
+    }
+    Ok(())
+}
+
+/// If asked to, show the user the verifier and wait for them to confirm the peer shows the
+/// same, before anything is sent or accepted. Someone guessing the code would have agreed a
+/// different key with each side, so would show a different verifier to each.
+async fn verify(wormhole: &Wormhole, verify: bool) -> Result<(), TransferError> {
+    if !verify {
+        return Ok(());
+    }
+    eprintln!("Verifier {}", wormhole.verifier()?);
+    if !confirm().await? {
+        return Err(TransferError::Unverified);
+    }
+    Ok(())
+}
+
+/// Ask the user whether to accept an offer, or a verifier.
+async fn confirm() -> Result<bool, TransferError> {
+    let answer = tokio::task::spawn_blocking(|| {
+        eprint!("ok? (y/N): ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok::<_, io::Error>(answer)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Accept the peer's offer, then receive the rest of the `size` bytes from `offset` over
+/// transit into the file and acknowledge them, or if it was offered on a subchannel, receive
+/// them there. The hasher has already seen everything before the offset. Without a size,
+/// everything is received until the sender marks the end.
+async fn accept_offer<W: AsyncWrite + Unpin>(
+    wormhole: &mut Wormhole,
+    offered: &mut Offered<'_>,
+    file: &mut W,
+    offset: u64,
+    size: Option<u64>,
+    hasher: Sha256,
+) -> Result<(), TransferError> {
+    let peer_transit = match offered {
+        Offered::Mailbox(peer_transit) => peer_transit,
+        Offered::Subchannel(subchannel) => {
+            // Offers on a subchannel always say how big they are
+            return v2::accept(wormhole, subchannel, file, size.unwrap_or_default()).await;
+        }
+    };
+    let transit = wormhole.transit(Role::Receiver).await?;
+    wormhole.send(ApplicationMessage::Transit(transit.info()));
+    wormhole.send(ApplicationMessage::Answer(Answer::FileAck("ok".into())));
+
+    let mut connection = wormhole
+        .connect(transit.connect(peer_transit, TRANSIT_TIMEOUT))
+        .await?;
+    eprintln!("Receiving over {}", connection.route());
+    receive_from(wormhole, &mut connection, file, offset, size, hasher).await
+}
+
+/// Receive the rest of the `size` bytes from `offset` on the connection into the file, or
+/// everything until the sender marks the end without a size, and acknowledge them.
+async fn receive_from<C: Records, W: AsyncWrite + Unpin>(
+    wormhole: &Wormhole,
+    connection: &mut C,
+    file: &mut W,
+    offset: u64,
+    size: Option<u64>,
+    hasher: Sha256,
+) -> Result<(), TransferError> {
+    let mut progress = wormhole.progress(size);
+    progress.set_position(offset);
+    let compressed = wormhole.features.compress;
+    let (sha256, received) = receive_contents(
+        connection,
+        file,
+        size.map(|size| size - offset),
+        hasher,
+        compressed,
+        wormhole.throttle(),
+        &mut progress,
+    )
+    .await?;
+    progress.finish();
+
+    let sha256 = hex::encode(sha256);
+    if wormhole.features.checksum {
+        let digest = serde_json::from_slice::<TransferDigest>(connection.receive_record().await?)?;
+        let complete = size.is_some() || digest.size == Some(received);
+        if digest.sha256 != sha256 || !complete {
+            let ack = TransitAck {
+                ack: "checksum mismatch".into(),
+                sha256: Some(sha256),
+            };
+            connection.send_record(&serde_json::to_vec(&ack)?).await?;
+            return Err(TransferError::ChecksumMismatch);
+        }
+    }
+    let ack = TransitAck {
+        ack: "ok".into(),
+        sha256: Some(sha256),
+    };
+    connection.send_record(&serde_json::to_vec(&ack)?).await?;
+
+    Ok(())
+}
+
+/// Unpack a zip archive into a directory, refusing any entries which would end up outside it,
+/// and stopping once it has unpacked more files or bytes than the sender offered.
+fn unzip_archive(
+    archive: std::fs::File,
+    path: &Path,
+    numbytes: u64,
+    numfiles: u64,
+) -> Result<(), TransferError> {
+    let mut archive = ZipArchive::new(archive)?;
+    let (mut bytes, mut files) = (0, 0);
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry
+            .enclosed_name()
+            .ok_or_else(|| TransferError::BadFilename(entry.name().to_string()))?;
+        let target = path.join(name);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        if entry.is_symlink() {
+            warn!("Skipping symlink {:?} in archive", entry.name());
+            continue;
+        }
+        files += 1;
+        if files > numfiles {
+            return Err(TransferError::TooManyFiles(numfiles));
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)?;
+        // Stop a byte past what's left, rather than trusting the sizes the archive claims
+        let left = numbytes - bytes;
+        bytes += io::copy(&mut io::Read::take(&mut entry, left + 1), &mut file)?;
+        if bytes > numbytes {
+            return Err(TransferError::TooManyBytes(numbytes));
+        }
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            // Only keep the permission bits
+            file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+    }
+    Ok(())
+}
+
+/// Receive exactly `size` bytes of records into the file, or without a size, records until
+/// an empty one marks the end. Returns the SHA-256 digest of them added to the hasher, and
+/// how much was received.
+async fn receive_contents<C: Records, W: AsyncWrite + Unpin>(
+    connection: &mut C,
+    file: &mut W,
+    size: Option<u64>,
+    mut hasher: Sha256,
+    compressed: bool,
+    mut throttle: Option<Throttle>,
+    progress: &mut Progress,
+) -> Result<(Vec<u8>, u64), TransferError> {
+    let mut decompressor = zstd::bulk::Decompressor::new()?;
+    let mut received = 0;
+    while size.is_none_or(|size| received < size) {
+        let record = connection.receive_record().await?;
+        if size.is_none() && record.is_empty() {
+            break;
+        }
+        if let Some(throttle) = &mut throttle {
+            // Holding off reading holds off the sender too, once the connection's full
+            throttle.consume(record.len()).await;
+        }
+        let record = if compressed {
+            // Each record was at most a full record before it was compressed
+            Cow::Owned(decompressor.decompress(record, RECORD_SIZE)?)
+        } else {
+            Cow::Borrowed(record)
+        };
+        received += record.len() as u64;
+        if size.is_some_and(|size| received > size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "peer sent too much").into());
+        }
+        hasher.update(&record);
+        file.write_all(&record).await?;
+        progress.inc(record.len() as u64);
+    }
+    file.flush().await?;
+    Ok((hasher.finalize().to_vec(), received))
+}
+
+/// Hash the first `len` bytes of the file.
+async fn prefix_digest(file: &mut File, len: u64) -> Result<Sha256, TransferError> {
+    file.seek(SeekFrom::Start(0)).await?;
+    let mut prefix = (&mut *file).take(len);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; RECORD_SIZE];
+    loop {
+        let read = prefix.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher)
+}
+
+/// Send exactly `size` bytes of the file as records, or without a size, everything until
+/// the file ends followed by an empty record to mark the end. Returns the SHA-256 digest of
+/// them added to the hasher, and how much was sent.
+async fn send_contents<C: Records, R: AsyncRead + Unpin>(
+    connection: &mut C,
+    file: R,
+    size: Option<u64>,
+    mut hasher: Sha256,
+    compress: bool,
+    mut throttle: Option<Throttle>,
+    progress: &mut Progress,
+) -> Result<(Vec<u8>, u64), TransferError> {
+    let mut compressor = zstd::bulk::Compressor::new(ZSTD_LEVEL)?;
+    let mut file = file.take(size.unwrap_or(u64::MAX));
+    let mut buffer = vec![0u8; RECORD_SIZE];
+    let mut sent = 0;
+    while size.is_none_or(|size| sent < size) {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 && size.is_none() {
+            // Compressed records are never empty, so this can't be mistaken for one
+            connection.send_record(&[]).await?;
+            break;
+        }
+        if read == 0 {
+            return Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while sending").into(),
+            );
+        }
+        hasher.update(&buffer[..read]);
+        let record = if compress {
+            Cow::Owned(compressor.compress(&buffer[..read])?)
+        } else {
+            Cow::Borrowed(&buffer[..read])
+        };
+        if let Some(throttle) = &mut throttle {
+            throttle.consume(record.len()).await;
+        }
+        connection.send_record(&record).await?;
+        sent += read as u64;
+        progress.inc(read as u64);
+    }
+    Ok((hasher.finalize().to_vec(), sent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        numbered, stream_offer, unzip_archive, zip_paths, Features, Mode, Offer, Permission,
+        TransferDigest, TransferError, TransitAck, CANCELLED,
+    };
+    use serde_json::json;
+    use std::{
+        collections::HashMap,
+        fs,
+        io::{Read, Seek, Write},
+        path::{Path, PathBuf},
+    };
+    use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+    #[test]
+    fn features() {
+        let ours = Features::ours(Mode::Send, Permission::Ask);
+        let receiver = Features::ours(Mode::Receive, Permission::Yes);
+        assert_eq!(ours.shared(&receiver.app_versions(), true), ours);
+
+        // Clients which don't know about any of them, like the Python one, get none
+        assert_eq!(ours.shared(&HashMap::new(), true), Features::default());
+
+        // Sending with file-transfer-v2 needs a dilated connection too
+        assert_eq!(
+            ours.shared(&receiver.app_versions(), false),
+            Features {
+                transfer: None,
+                ..ours.clone()
+            }
+        );
+
+        // Only what both sides support is used, however the peer lists it
+        let peer = HashMap::from([
+            (
+                "magic-wormhole-rs/compression".to_string(),
+                json!(["lz4", "zstd"]),
+            ),
+            ("magic-wormhole-rs/checksum".to_string(), json!("blake3")),
+            (
+                "magic-wormhole-rs/streaming".to_string(),
+                json!({"mode": "empty-record"}),
+            ),
+        ]);
+        assert_eq!(
+            ours.shared(&peer, true),
+            Features {
+                compress: true,
+                checksum: false,
+                streaming: false,
+                transfer: None,
+            }
+        );
+
+        // Nor is another implementation using the same names without our prefix
+        let other = HashMap::from([
+            ("compression".to_string(), json!("zstd")),
+            ("checksum".to_string(), json!("sha256")),
+            ("streaming".to_string(), json!("empty-record")),
+        ]);
+        assert_eq!(ours.shared(&other, true), Features::default());
+    }
+
+    #[test]
+    fn transfer_negotiation() {
+        let ours = Features::ours(Mode::Send, Permission::Ask);
+        assert_eq!(
+            ours.app_versions()["transfer"],
+            json!({"mode": "send", "features": ["core0"], "permission": "ask"})
+        );
+
+        // Another implementation of file-transfer-v2 is used, if it receives what we send
+        let uses = |transfer| {
+            let app_versions = HashMap::from([("transfer".to_string(), transfer)]);
+            ours.shared(&app_versions, true).transfer.is_some()
+        };
+        assert!(uses(
+            json!({"mode": "receive", "features": ["core0"], "permission": "yes"})
+        ));
+        assert!(uses(
+            json!({"mode": "connect", "features": ["core0", "core1"], "permission": "ask"})
+        ));
+        for transfer in [
+            json!({"mode": "send", "features": ["core0"], "permission": "ask"}),
+            json!({"mode": "receive", "features": [], "permission": "ask"}),
+            json!({"mode": "receive", "features": ["core0"]}),
+            json!({"version": 2, "features": ["core0"]}),
+            json!("core0"),
+        ] {
+            assert!(!uses(transfer.clone()), "{}", transfer);
+        }
+    }
+
+    #[test]
+    fn stream_offers() {
+        let ours = Features::ours(Mode::Send, Permission::Ask);
+        assert!(matches!(
+            stream_offer(&ours.shared(&ours.app_versions(), true), "db.sql"),
+            Some(Offer::File {
+                filesize: 0,
+                stream: true,
+                ..
+            })
+        ));
+
+        // A peer which didn't advertise our streaming key would take it for an empty file
+        for app_versions in [
+            HashMap::new(),
+            HashMap::from([("streaming".to_string(), json!("empty-record"))]),
+            HashMap::from([("magic-wormhole-rs/streaming".to_string(), json!("chunked"))]),
+        ] {
+            assert!(stream_offer(&ours.shared(&app_versions, true), "db.sql").is_none());
+        }
+    }
+
+    #[test]
+    fn zipped_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::create_dir_all(dir.path().join("sub/deeper")).unwrap();
+        fs::write(dir.path().join("sub/deeper/b.txt"), "world!").unwrap();
+
+        let (archive, numbytes, numfiles) =
+            zip_paths(vec![(dir.path().to_path_buf(), PathBuf::new())]).unwrap();
+        assert_eq!(numbytes, 11);
+        assert_eq!(numfiles, 2);
+
+        let mut archive = ZipArchive::new(archive).unwrap();
+        let mut names = archive.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "sub/deeper/b.txt"]);
+        let mut contents = String::new();
+        archive
+            .by_name("sub/deeper/b.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "world!");
+    }
+
+    #[test]
+    fn zipped_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "world!").unwrap();
+
+        let (archive, numbytes, numfiles) = zip_paths(vec![
+            (dir.path().join("a.txt"), PathBuf::from("a.txt")),
+            (dir.path().join("sub"), PathBuf::from("sub")),
+        ])
+        .unwrap();
+        assert_eq!(numbytes, 11);
+        assert_eq!(numfiles, 2);
+        let archive = ZipArchive::new(archive).unwrap();
+        let mut names = archive.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "sub/b.txt"]);
+    }
+
+    #[test]
+    fn unzipped_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "world!").unwrap();
+        let (archive, _, _) = zip_paths(vec![(dir.path().to_path_buf(), PathBuf::new())]).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        unzip_archive(archive, target.path(), 11, 2).unwrap();
+        assert_eq!(fs::read(target.path().join("a.txt")).unwrap(), b"hello");
+        assert_eq!(
+            fs::read(target.path().join("sub/b.txt")).unwrap(),
+            b"world!"
+        );
+    }
+
+    #[test]
+    fn unzip_understated_size() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::write(dir.path().join("b.txt"), vec![0; 100_000]).unwrap();
+        let (mut archive, numbytes, numfiles) =
+            zip_paths(vec![(dir.path().to_path_buf(), PathBuf::new())]).unwrap();
+        assert_eq!((numbytes, numfiles), (100_005, 2));
+
+        // However small the archive, it can't unpack to more than was agreed to
+        let target = tempfile::tempdir().unwrap();
+        let err = unzip_archive(archive.try_clone().unwrap(), target.path(), 1000, 2);
+        assert!(matches!(err, Err(TransferError::TooManyBytes(1000))));
+        let target = tempfile::tempdir().unwrap();
+        archive.rewind().unwrap();
+        let err = unzip_archive(archive, target.path(), numbytes, 1);
+        assert!(matches!(err, Err(TransferError::TooManyFiles(1))));
+    }
+
+    #[test]
+    fn unzip_outside_directory() {
+        let mut zip = ZipWriter::new(tempfile::tempfile().unwrap());
+        zip.start_file("../escaped.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"gotcha").unwrap();
+        let mut archive = zip.finish().unwrap();
+        archive.rewind().unwrap();
+
+        let parent = tempfile::tempdir().unwrap();
+        let target = parent.path().join("target");
+        fs::create_dir(&target).unwrap();
+        assert!(unzip_archive(archive, &target, 6, 1).is_err());
+        assert!(!parent.path().join("escaped.txt").exists());
+    }
+
+    #[test]
+    fn numbered_names() {
+        assert_eq!(numbered(Path::new("a.txt"), 1), Path::new("a (1).txt"));
+        assert_eq!(numbered(Path::new("dir"), 2), Path::new("dir (2)"));
+        assert_eq!(
+            numbered(Path::new("out/a.tar.gz"), 3),
+            Path::new("out/a.tar (3).gz")
+        );
+    }
+
+    #[test]
+    fn transit_ack() {
+        let ack = serde_json::from_str::<TransitAck>(r#"{"ack":"ok","sha256":"abcd"}"#).unwrap();
+        assert_eq!(
+            ack,
+            TransitAck {
+                ack: "ok".into(),
+                sha256: Some("abcd".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn transfer_digest() {
+        let digest = TransferDigest {
+            sha256: "abcd".into(),
+            size: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&digest).unwrap(),
+            r#"{"sha256":"abcd"}"#
+        );
+        let digest = TransferDigest {
+            sha256: "abcd".into(),
+            size: Some(12),
+        };
+        assert_eq!(
+            serde_json::to_string(&digest).unwrap(),
+            r#"{"sha256":"abcd","size":12}"#
+        );
+    }
+
+    #[test]
+    fn peer_errors() {
+        let error = TransferError::from_peer(CANCELLED.into());
+        assert!(matches!(error, TransferError::Cancelled));
+        assert_eq!(error.to_string(), "transfer cancelled by peer");
+        assert!(error.is_known_to_peer());
+
+        let error = TransferError::from_peer("disk full".into());
+        assert!(matches!(&error, TransferError::Peer(e) if e == "disk full"));
+        assert!(error.is_known_to_peer());
+
+        assert!(!TransferError::ChecksumMismatch.is_known_to_peer());
+    }
+}
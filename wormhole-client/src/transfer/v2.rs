@@ -0,0 +1,622 @@
+/// Sending files and directories with file-transfer-v2, over a dilated connection, each offered
+/// on a subchannel of its own, so several can be sent in one session.
+///
+/// Each side says how it will use the protocol under the `transfer` key in the version phase.
+/// Both use it if both can dilate, both support its `core0` features, and one will send what
+/// the other receives. Otherwise files and directories are sent with the classic protocol, as
+/// are text messages and standard input always.
+///
+/// Every record on a subchannel is a byte saying what kind of message it is, followed by the
+/// message packed with msgpack. The sender opens a subchannel for each file or directory and
+/// offers it there. The receiver accepts the offer, or rejects it saying why, which only skips
+/// that one, though the transfer fails once the rest are done. A file's contents follow its
+/// acceptance as data records. A directory's files follow it one after another, each as the
+/// offer of a file saying where it goes in the directory and how big it is, then its data. The
+/// receiver closes the subchannel once it has everything, and the sender closes the control
+/// subchannel once it has nothing more to offer, which ends the session.
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::Metadata,
+    io,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+
+use super::{
+    consent, move_into_place, offered_target, parent_dir, receive_file, Offered, Progress,
+    ReceiveOptions, TransferError, Wormhole, RECORD_SIZE,
+};
+use wormhole_core::dilation::{DilatedConnection, DilationError, Subchannel};
+
+/// The kinds of record, given by their first byte.
+const FILE_OFFER: u8 = 0x01;
+const DIRECTORY_OFFER: u8 = 0x02;
+const OFFER_ACCEPT: u8 = 0x03;
+const OFFER_REJECT: u8 = 0x04;
+const FILE_DATA: u8 = 0x05;
+
+/// The offer of a file, or once a directory's been accepted, of one of its files.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct FileOffer {
+    /// The file's name, or its path within the directory, with forward slashes.
+    filename: String,
+    /// When the file was last modified, in seconds since the Unix epoch.
+    timestamp: u64,
+    /// The size of the file.
+    bytes: u64,
+}
+
+/// The offer of a directory.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct DirectoryOffer {
+    /// The directory's name.
+    base: String,
+    /// The size of all its files together.
+    size: u64,
+    /// The paths of its files within it, with forward slashes, in the order they're sent.
+    files: Vec<String>,
+}
+
+/// Accepting an offer, which needs saying nothing more.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct OfferAccept {}
+
+/// Rejecting an offer, saying why.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct OfferReject {
+    reason: String,
+}
+
+/// Some of a file's contents.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct FileData {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+/// A record on a subchannel.
+#[derive(Debug, PartialEq)]
+enum Record {
+    FileOffer(FileOffer),
+    DirectoryOffer(DirectoryOffer),
+    OfferAccept(OfferAccept),
+    OfferReject(OfferReject),
+    FileData(FileData),
+}
+
+impl Record {
+    fn encode(&self) -> Result<Vec<u8>, TransferError> {
+        let (kind, message) = match self {
+            Record::FileOffer(offer) => (FILE_OFFER, rmp_serde::to_vec_named(offer)),
+            Record::DirectoryOffer(offer) => (DIRECTORY_OFFER, rmp_serde::to_vec_named(offer)),
+            Record::OfferAccept(accept) => (OFFER_ACCEPT, rmp_serde::to_vec_named(accept)),
+            Record::OfferReject(reject) => (OFFER_REJECT, rmp_serde::to_vec_named(reject)),
+            Record::FileData(data) => (FILE_DATA, rmp_serde::to_vec_named(data)),
+        };
+        Ok([&[kind], &message?[..]].concat())
+    }
+
+    fn decode(record: &[u8]) -> Result<Self, TransferError> {
+        let (&kind, message) = record
+            .split_first()
+            .ok_or(TransferError::UnexpectedRecord)?;
+        Ok(match kind {
+            FILE_OFFER => Record::FileOffer(rmp_serde::from_slice(message)?),
+            DIRECTORY_OFFER => Record::DirectoryOffer(rmp_serde::from_slice(message)?),
+            OFFER_ACCEPT => Record::OfferAccept(rmp_serde::from_slice(message)?),
+            OFFER_REJECT => Record::OfferReject(rmp_serde::from_slice(message)?),
+            FILE_DATA => Record::FileData(rmp_serde::from_slice(message)?),
+            _ => return Err(TransferError::UnexpectedRecord),
+        })
+    }
+}
+
+/// Dilate the connection with the peer, then offer it each of the paths in turn, sending
+/// whichever it accepts. If it rejected any, that's the error once the rest have been sent.
+pub(super) async fn send(wormhole: &mut Wormhole, paths: &[PathBuf]) -> Result<(), TransferError> {
+    let mut connection = wormhole.dilate(None).await?;
+    // So the user can check it against what the receiver is shown
+    eprintln!("Verifier {}", wormhole.verifier()?);
+    let control = connection.control().unwrap();
+
+    let mut rejected = None;
+    for path in paths {
+        let result = if path.is_dir() {
+            send_directory(wormhole, &mut connection, path).await
+        } else {
+            send_file(wormhole, &mut connection, path).await
+        };
+        match result {
+            Ok(()) => {}
+            Err(TransferError::Peer(reason)) => {
+                eprintln!("{} wasn't accepted: {}", path.display(), reason);
+                rejected = Some(TransferError::Peer(reason));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    control.close().await?;
+    // The receiver hangs up once it sees that, unless it reports that it failed first
+    if let Err(e @ (TransferError::Peer(_) | TransferError::Cancelled)) =
+        wormhole.connect(connection.accept()).await
+    {
+        return Err(e);
+    }
+
+    rejected.map_or(Ok(()), Err)
+}
+
+/// Offer the peer a file on a subchannel of its own, and once it's accepted, send it there.
+async fn send_file(
+    wormhole: &Wormhole,
+    connection: &mut DilatedConnection,
+    path: &Path,
+) -> Result<(), TransferError> {
+    let file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+
+    eprintln!("Sending {} ({} bytes)", filename, metadata.len());
+    let offer = FileOffer {
+        filename,
+        timestamp: timestamp(&metadata),
+        bytes: metadata.len(),
+    };
+    let mut subchannel = connection.open().await?;
+    make_offer(&mut subchannel, Record::FileOffer(offer)).await?;
+    let mut progress = wormhole.progress(Some(metadata.len()));
+    send_data(
+        wormhole,
+        &mut subchannel,
+        file,
+        metadata.len(),
+        &mut progress,
+    )
+    .await?;
+    progress.finish();
+    received(subchannel).await?;
+    eprintln!("File sent");
+
+    Ok(())
+}
+
+/// Offer the peer a directory on a subchannel of its own, listing its files, and once it's
+/// accepted, send each of them there in turn.
+async fn send_directory(
+    wormhole: &Wormhole,
+    connection: &mut DilatedConnection,
+    path: &Path,
+) -> Result<(), TransferError> {
+    let path = path.canonicalize()?;
+    let base = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a directory"))?;
+    let files = tokio::task::spawn_blocking(move || list_files(path))
+        .await
+        .map_err(io::Error::other)??;
+    let size = files.iter().map(|(_, _, bytes)| bytes).sum();
+
+    eprintln!(
+        "Sending directory ({} bytes, {} files) named {}",
+        size,
+        files.len(),
+        base
+    );
+    let offer = DirectoryOffer {
+        base,
+        size,
+        files: files.iter().map(|(_, name, _)| name.clone()).collect(),
+    };
+    let mut subchannel = connection.open().await?;
+    make_offer(&mut subchannel, Record::DirectoryOffer(offer)).await?;
+    let mut progress = wormhole.progress(Some(size));
+    for (path, filename, bytes) in files {
+        let file = File::open(&path).await?;
+        let offer = FileOffer {
+            filename,
+            timestamp: timestamp(&file.metadata().await?),
+            bytes,
+        };
+        send_record(&mut subchannel, &Record::FileOffer(offer)).await?;
+        send_data(wormhole, &mut subchannel, file, bytes, &mut progress).await?;
+    }
+    progress.finish();
+    received(subchannel).await?;
+    eprintln!("Directory sent");
+
+    Ok(())
+}
+
+/// The files in a directory and everything under it, each with its path within the directory
+/// and its size. Links to files are followed, but not links to directories, which could loop.
+fn list_files(root: PathBuf) -> io::Result<Vec<(PathBuf, String, u64)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![(root, PathBuf::new())];
+    while let Some((path, name)) = pending.pop() {
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_symlink() && entry.path().is_dir() {
+                    continue;
+                }
+                pending.push((entry.path(), name.join(entry.file_name())));
+            }
+            continue;
+        }
+        let name = name
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.push((path, name, metadata.len()));
+    }
+    Ok(files)
+}
+
+/// When a file was last modified, in seconds since the Unix epoch, or the epoch if unknown.
+fn timestamp(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Make an offer on a subchannel, and wait for the peer to accept it.
+async fn make_offer(subchannel: &mut Subchannel, offer: Record) -> Result<(), TransferError> {
+    send_record(subchannel, &offer).await?;
+    match receive_record(subchannel).await? {
+        Record::OfferAccept(_) => Ok(()),
+        Record::OfferReject(reject) => Err(TransferError::from_peer(reject.reason)),
+        _ => Err(TransferError::UnexpectedRecord),
+    }
+}
+
+/// Send exactly `size` bytes of the file as data records.
+async fn send_data<R: AsyncRead + Unpin>(
+    wormhole: &Wormhole,
+    subchannel: &mut Subchannel,
+    file: R,
+    size: u64,
+    progress: &mut Progress,
+) -> Result<(), TransferError> {
+    let mut throttle = wormhole.throttle();
+    let mut file = file.take(size);
+    let mut buffer = vec![0u8; RECORD_SIZE];
+    let mut sent = 0;
+    while sent < size {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while sending").into(),
+            );
+        }
+        if let Some(throttle) = &mut throttle {
+            throttle.consume(read).await;
+        }
+        let data = FileData {
+            data: buffer[..read].to_vec(),
+        };
+        send_record(subchannel, &Record::FileData(data)).await?;
+        sent += read as u64;
+        progress.inc(read as u64);
+    }
+    Ok(())
+}
+
+/// Wait for the receiver to close the subchannel, which it does once it has everything.
+async fn received(mut subchannel: Subchannel) -> Result<(), TransferError> {
+    match subchannel.receive_record().await {
+        Ok(_) => Err(TransferError::UnexpectedRecord),
+        Err(DilationError::Closed) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Dilate the connection with the peer, which has already asked to from the given side, then
+/// receive what it offers on each subchannel it opens, until it closes the control subchannel.
+/// Offers which are rejected, or can't be received where they'd go, are skipped, and the last
+/// of them is the error once the rest have been received.
+pub(super) async fn receive(
+    wormhole: &mut Wormhole,
+    peer_side: String,
+    options: &ReceiveOptions,
+) -> Result<(), TransferError> {
+    let mut connection = wormhole.dilate(Some(peer_side)).await?;
+    let mut control = connection.control().unwrap();
+
+    let mut skipped = None;
+    loop {
+        tokio::select! {
+            biased;
+            subchannel = connection.accept() => {
+                let mut subchannel = subchannel?;
+                match receive_offer(wormhole, &mut subchannel, options).await {
+                    Ok(()) => {}
+                    // The peer has been told, and can carry on with the next offer
+                    Err(
+                        e @ (TransferError::Rejected
+                        | TransferError::BadFilename(_)
+                        | TransferError::FileExists(_)
+                        | TransferError::DirectoryToStdout),
+                    ) => {
+                        eprintln!("Skipped: {}", e);
+                        skipped = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+                subchannel.close().await?;
+            }
+            record = control.receive_record() => match record {
+                // Nothing more will be offered
+                Err(DilationError::Closed) => break,
+                Ok(_) => return Err(TransferError::UnexpectedRecord),
+                Err(e) => return Err(e.into()),
+            },
+        }
+    }
+
+    skipped.map_or(Ok(()), Err)
+}
+
+/// Receive what's offered on a subchannel, if the user accepts it.
+async fn receive_offer(
+    wormhole: &mut Wormhole,
+    subchannel: &mut Subchannel,
+    options: &ReceiveOptions,
+) -> Result<(), TransferError> {
+    match receive_record(subchannel).await? {
+        Record::FileOffer(offer) => {
+            let offered = &mut Offered::Subchannel(subchannel);
+            let filesize = Some(offer.bytes);
+            receive_file(wormhole, offered, &offer.filename, filesize, false, options).await
+        }
+        Record::DirectoryOffer(offer) => {
+            receive_directory(wormhole, subchannel, offer, options).await
+        }
+        _ => Err(TransferError::UnexpectedRecord),
+    }
+}
+
+/// Accept the offer of a file, then receive its `size` bytes into the file.
+pub(super) async fn accept<W: AsyncWrite + Unpin>(
+    wormhole: &Wormhole,
+    subchannel: &mut Subchannel,
+    file: &mut W,
+    size: u64,
+) -> Result<(), TransferError> {
+    send_record(subchannel, &Record::OfferAccept(OfferAccept {})).await?;
+    let mut progress = wormhole.progress(Some(size));
+    receive_data(wormhole, subchannel, file, size, &mut progress).await?;
+    progress.finish();
+    Ok(())
+}
+
+/// Reject an offer, saying why.
+pub(super) async fn reject(
+    subchannel: &mut Subchannel,
+    reason: String,
+) -> Result<(), TransferError> {
+    send_record(subchannel, &Record::OfferReject(OfferReject { reason })).await
+}
+
+/// Accept the peer's offer of a directory if the user agrees, then receive each of its files
+/// into a temporary directory alongside where it goes, and move that into place once they've
+/// all arrived.
+async fn receive_directory(
+    wormhole: &mut Wormhole,
+    subchannel: &mut Subchannel,
+    offer: DirectoryOffer,
+    options: &ReceiveOptions,
+) -> Result<(), TransferError> {
+    let offered = &mut Offered::Subchannel(subchannel);
+    if options.stdout {
+        let reason = TransferError::DirectoryToStdout.to_string();
+        offered.reject(wormhole, reason).await?;
+        return Err(TransferError::DirectoryToStdout);
+    }
+    let target = offered_target(wormhole, offered, &offer.base, options).await?;
+    eprintln!(
+        "Receiving directory ({} bytes) into: {}/",
+        offer.size,
+        target.display()
+    );
+    for name in &offer.files {
+        eprintln!("  {}", name);
+    }
+    eprintln!("{} files, {} bytes", offer.files.len(), offer.size);
+    consent(wormhole, offered, options.accept).await?;
+    send_record(subchannel, &Record::OfferAccept(OfferAccept {})).await?;
+
+    let temp = tempfile::Builder::new()
+        .prefix(".wormhole-")
+        .tempdir_in(parent_dir(&target))?;
+    let mut progress = wormhole.progress(Some(offer.size));
+    let mut received = 0;
+    for _ in &offer.files {
+        let file = match receive_record(subchannel).await? {
+            Record::FileOffer(file) => file,
+            _ => return Err(TransferError::UnexpectedRecord),
+        };
+        // Only what was offered, and never anywhere outside the directory
+        let name = enclosed_name(&file.filename)
+            .filter(|_| offer.files.contains(&file.filename))
+            .ok_or_else(|| {
+                let error = format!("peer sent an unexpected file {:?}", file.filename);
+                io::Error::new(io::ErrorKind::InvalidData, error)
+            })?;
+        received += file.bytes;
+        if received > offer.size {
+            return Err(TransferError::TooManyBytes(offer.size));
+        }
+        let path = temp.path().join(name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut output = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        receive_data(wormhole, subchannel, &mut output, file.bytes, &mut progress).await?;
+        output.sync_all().await?;
+    }
+    progress.finish();
+
+    move_into_place(wormhole, temp, &target, options.force).await
+}
+
+/// Where a file sent in a directory goes within it, unless its path would lead outside it.
+fn enclosed_name(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    let normal = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (normal && path.file_name().is_some()).then(|| path.to_path_buf())
+}
+
+/// Receive exactly `size` bytes of data records into the file.
+async fn receive_data<W: AsyncWrite + Unpin>(
+    wormhole: &Wormhole,
+    subchannel: &mut Subchannel,
+    file: &mut W,
+    size: u64,
+    progress: &mut Progress,
+) -> Result<(), TransferError> {
+    let mut throttle = wormhole.throttle();
+    let mut received = 0;
+    while received < size {
+        let data = match receive_record(subchannel).await? {
+            Record::FileData(FileData { data }) => data,
+            _ => return Err(TransferError::UnexpectedRecord),
+        };
+        if let Some(throttle) = &mut throttle {
+            // Holding off reading holds off the sender too, once the connection's full
+            throttle.consume(data.len()).await;
+        }
+        received += data.len() as u64;
+        if received > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "peer sent too much").into());
+        }
+        file.write_all(&data).await?;
+        progress.inc(data.len() as u64);
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Send the peer a record on a subchannel.
+async fn send_record(subchannel: &mut Subchannel, record: &Record) -> Result<(), TransferError> {
+    subchannel.send_record(&record.encode()?).await?;
+    Ok(())
+}
+
+/// Wait for the peer's next record on a subchannel.
+async fn receive_record(subchannel: &mut Subchannel) -> Result<Record, TransferError> {
+    Record::decode(subchannel.receive_record().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        enclosed_name, list_files, DirectoryOffer, FileData, FileOffer, OfferAccept, OfferReject,
+        Record,
+    };
+    use crate::transfer::TransferError;
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn record_encoding() {
+        // A kind byte, then a msgpack map
+        let accept = Record::OfferAccept(OfferAccept {});
+        assert_eq!(accept.encode().unwrap(), b"\x03\x80");
+        let reject = Record::OfferReject(OfferReject {
+            reason: "no".into(),
+        });
+        assert_eq!(reject.encode().unwrap(), b"\x04\x81\xa6reason\xa2no");
+        // File data is packed as binary, not as a list of numbers
+        let data = Record::FileData(FileData {
+            data: b"hi".to_vec(),
+        });
+        assert_eq!(data.encode().unwrap(), b"\x05\x81\xa4data\xc4\x02hi");
+        let offer = Record::FileOffer(FileOffer {
+            filename: "a.txt".into(),
+            timestamp: 1,
+            bytes: 2,
+        });
+        assert_eq!(
+            offer.encode().unwrap(),
+            b"\x01\x83\xa8filename\xa5a.txt\xa9timestamp\x01\xa5bytes\x02"
+        );
+
+        let directory = Record::DirectoryOffer(DirectoryOffer {
+            base: "photos".into(),
+            size: 3,
+            files: vec!["a.jpg".into(), "trip/b.jpg".into()],
+        });
+        for record in [accept, reject, data, offer, directory] {
+            assert_eq!(Record::decode(&record.encode().unwrap()).unwrap(), record);
+        }
+
+        for unknown in [&b""[..], b"\x00\x80", b"\x06\x80"] {
+            assert!(matches!(
+                Record::decode(unknown),
+                Err(TransferError::UnexpectedRecord)
+            ));
+        }
+    }
+
+    #[test]
+    fn enclosed_names() {
+        assert_eq!(enclosed_name("a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(
+            enclosed_name("trip/b.jpg"),
+            Some(PathBuf::from("trip/b.jpg"))
+        );
+        for name in ["", "/etc/passwd", "../a.txt", "trip/../../a.txt", "./a.txt"] {
+            assert_eq!(enclosed_name(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn listed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("trip/day1")).unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::write(dir.path().join("trip/day1/b.jpg"), "jpg").unwrap();
+
+        let mut files = list_files(dir.path().to_path_buf())
+            .unwrap()
+            .into_iter()
+            .map(|(path, name, bytes)| {
+                (
+                    path.strip_prefix(dir.path()).unwrap().to_owned(),
+                    name,
+                    bytes,
+                )
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("a.txt"), "a.txt".to_string(), 5),
+                (
+                    PathBuf::from("trip/day1/b.jpg"),
+                    "trip/day1/b.jpg".to_string(),
+                    3
+                ),
+            ]
+        );
+    }
+}
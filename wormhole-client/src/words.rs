@@ -6,6 +6,7 @@
 /// https://github.com/warrenguy/javascript-pgp-word-list
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use thiserror::Error;
 
 /// The PGP word list. In each pair, the first word is "even" and the second "odd".
 const WORDS: [(&str, &str); 256] = [
@@ -267,8 +268,29 @@ const WORDS: [(&str, &str); 256] = [
     ("zulu", "yucatan"),
 ];
 
+/// How many words a code has after its nameplate, unless the sender asks for more or fewer.
+pub(crate) const CODE_WORDS: usize = 2;
+
+/// How many letters a word in a code can be away from one in the list to be taken for a typo.
+const TYPO_DISTANCE: usize = 2;
+
+/// A word in a code which looks like a mistyped word from the list.
+#[derive(Error, Debug, PartialEq)]
+#[error("{word:?} isn't in the word list, did you mean {}?", .suggestions.join(" or "))]
+pub(crate) struct Typo {
+    word: String,
+    suggestions: Vec<&'static str>,
+}
+
+/// Make a code for the given nameplate with `length` words, like `7-crossover-clockwork`, in
+/// the same form as the Python client. The whole code is the PAKE password, so both sides
+/// must use it verbatim.
+pub(crate) fn make_code(nameplate_id: usize, length: usize) -> String {
+    format!("{}-{}", nameplate_id, choose_words(length))
+}
+
 /// Select `length` random words and return them concatenated with `-`.
-pub(crate) fn choose_words(length: usize) -> String {
+fn choose_words(length: usize) -> String {
     let mut rng = thread_rng();
     let mut result = String::new();
     for i in 0..length {
@@ -285,9 +307,60 @@ pub(crate) fn choose_words(length: usize) -> String {
     result
 }
 
+/// The words which could be the word at `index` in a code, counting from the first after the
+/// nameplate, and start with `prefix`. Codes start with an "odd" word, and then alternate.
+pub(crate) fn complete_word(index: usize, prefix: &str) -> Vec<&'static str> {
+    WORDS
+        .iter()
+        .map(|w| if index.is_multiple_of(2) { w.1 } else { w.0 })
+        .filter(|word| word.starts_with(prefix))
+        .collect()
+}
+
+/// Check the words of a code typed by the user for any which look like mistyped words from
+/// the list, suggesting the closest words which could go in its place. Words which are nothing
+/// like any in the list are left alone, as the sender may have chosen the code themselves.
+pub(crate) fn check_code(code: &str) -> Result<(), Typo> {
+    for (index, word) in code.split('-').skip(1).enumerate() {
+        if WORDS.iter().any(|w| w.0 == word || w.1 == word) {
+            continue;
+        }
+        let mut closest = complete_word(index, "")
+            .into_iter()
+            .map(|candidate| (edit_distance(word, candidate), candidate))
+            .filter(|(distance, _)| *distance <= TYPO_DISTANCE)
+            .collect::<Vec<_>>();
+        let Some(&(distance, _)) = closest.iter().min() else {
+            continue;
+        };
+        closest.retain(|(d, _)| *d == distance);
+        return Err(Typo {
+            word: word.to_owned(),
+            suggestions: closest.into_iter().map(|(_, word)| word).collect(),
+        });
+    }
+    Ok(())
+}
+
+/// How many single letter insertions, deletions or substitutions it takes to turn one word
+/// into another.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{choose_words, WORDS};
+    use super::{check_code, choose_words, complete_word, edit_distance, make_code, Typo, WORDS};
 
     #[test]
     fn choosing_words() {
@@ -304,4 +377,67 @@ mod tests {
         assert!(odd_words.contains(&words[0]));
         assert!(even_words.contains(&words[1]));
     }
+
+    #[test]
+    fn making_codes() {
+        let odd_words = WORDS.iter().map(|w| w.1).collect::<Vec<&str>>();
+        let even_words = WORDS.iter().map(|w| w.0).collect::<Vec<&str>>();
+
+        let code = make_code(7, 2);
+        let parts = code.split('-').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0], "7");
+        assert!(odd_words.contains(&parts[1]));
+        assert!(even_words.contains(&parts[2]));
+
+        let code = make_code(12, 5);
+        let parts = code.split('-').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 6);
+        assert_eq!(parts[0], "12");
+        assert!(odd_words.contains(&parts[5]));
+    }
+
+    #[test]
+    fn completing_words() {
+        assert_eq!(complete_word(0, "cross"), vec!["crossover"]);
+        assert_eq!(complete_word(1, "clock"), vec!["clockwork"]);
+        assert!(complete_word(1, "cross").is_empty());
+        assert_eq!(complete_word(2, "cross"), vec!["crossover"]);
+        assert_eq!(complete_word(0, "").len(), 256);
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("crossover", "crossover"), 0);
+        assert_eq!(edit_distance("crosover", "crossover"), 1);
+        assert_eq!(edit_distance("Crossover", "crossover"), 1);
+        assert_eq!(edit_distance("clokwrk", "clockwork"), 2);
+        assert_eq!(edit_distance("", "zulu"), 4);
+    }
+
+    #[test]
+    fn checking_codes() {
+        assert_eq!(check_code("7-crossover-clockwork"), Ok(()));
+        // Chosen by the sender, and nothing like any word in the list
+        assert_eq!(check_code("42-purple-sausages"), Ok(()));
+        assert_eq!(
+            check_code("7-crosover-clockwork"),
+            Err(Typo {
+                word: "crosover".into(),
+                suggestions: vec!["crossover"],
+            })
+        );
+        // The second word comes from the even list
+        assert_eq!(
+            check_code("7-crossover-clokwork"),
+            Err(Typo {
+                word: "clokwork".into(),
+                suggestions: vec!["clockwork"],
+            })
+        );
+        assert_eq!(
+            check_code("7-crosover-clockwork").unwrap_err().to_string(),
+            r#""crosover" isn't in the word list, did you mean crossover?"#
+        );
+    }
 }
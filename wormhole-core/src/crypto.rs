@@ -0,0 +1,414 @@
+/// Encrypting the messages peers exchange through the mailbox: agreeing a key from the code
+/// with SPAKE2, then sealing each message in a secretbox, under a key derived from it for the
+/// sending side and the message's phase.
+use crypto_secretbox::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Nonce, XSalsa20Poly1305,
+};
+use hkdf::Hkdf;
+use sha2::{
+    digest::{generic_array::GenericArray, typenum::U32},
+    Digest, Sha256,
+};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::fmt;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::message::Phase;
+
+/// Size of the random nonce each phase message starts with.
+const NONCE_SIZE: usize = 24;
+
+/// Size of the authentication tag following a phase message's nonce.
+const TAG_SIZE: usize = 16;
+
+/// Errors generated while deriving keys, or encrypting or decrypting phase messages.
+#[derive(Error, Debug, PartialEq)]
+pub enum CryptoError {
+    #[error("failed to derive key")]
+    KeyDerivation,
+    #[error("phase {0:?} has no name to derive its key from")]
+    UnnamedPhase(Phase),
+    #[error("failed to encrypt message")]
+    Encryption,
+    #[error("message of {0} bytes is too short to decrypt")]
+    Truncated(usize),
+    #[error("message failed to decrypt")]
+    Decryption,
+}
+
+impl CryptoError {
+    /// Is this the peer's message failing to decrypt, as it would if the peer didn't have our
+    /// key, rather than something going wrong on our side?
+    pub fn is_undecryptable(&self) -> bool {
+        matches!(self, CryptoError::Truncated(_) | CryptoError::Decryption)
+    }
+}
+
+/// The key agreed with the peer, from which every other is derived. It's wiped from memory
+/// once dropped, and never shown in logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKey(Zeroizing<Vec<u8>>);
+
+impl SessionKey {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SessionKey {
+    fn from(key: Vec<u8>) -> Self {
+        SessionKey(Zeroizing::new(key))
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+/// Start agreeing a key with the peer from the code, returning the PAKE in progress and the
+/// message to send the peer. Both sides use symmetric SPAKE2 over Ed25519, with the app ID as
+/// the identity, as python-spake2 does, so any other client using the same code and app ID
+/// arrives at the same key.
+pub fn start_pake(code: &str, app_id: &str) -> (Spake2<Ed25519Group>, Vec<u8>) {
+    Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(code.as_bytes()),
+        &Identity::new(app_id.as_bytes()),
+    )
+}
+
+/// Finish agreeing a key with the peer's PAKE message. If the peer used a different code, the
+/// keys differ, which only shows when its messages can't be decrypted. The PAKE's own secrets
+/// are consumed with it, though wiping them is up to the spake2 crate.
+pub fn finish_pake(
+    spake: Spake2<Ed25519Group>,
+    peer_msg: &[u8],
+) -> Result<SessionKey, spake2::Error> {
+    spake.finish(peer_msg).map(SessionKey::from)
+}
+
+/// Calculate the SHA256 hash of the given string.
+fn sha256_str(input: &str) -> GenericArray<u8, U32> {
+    let mut hasher = Sha256::new();
+    hasher.update(input.as_bytes());
+    hasher.finalize()
+}
+
+/// Construct the "purpose" for the message encryption.
+fn generate_purpose(side: &str, phase: &Phase) -> Result<Vec<u8>, CryptoError> {
+    let phase_name = serde_json::to_value(phase)
+        .ok()
+        .and_then(|name| name.as_str().map(str::to_owned))
+        .ok_or_else(|| CryptoError::UnnamedPhase(phase.clone()))?;
+    let mut result = b"wormhole:phase:".to_vec();
+    result.extend(sha256_str(side));
+    result.extend(sha256_str(&phase_name));
+    Ok(result)
+}
+
+/// Construct the particular key to use for message encryption.
+fn derive_phase_key(
+    key: &[u8],
+    side: &str,
+    phase: &Phase,
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let purpose = generate_purpose(side, phase)?;
+    let hk = Hkdf::<Sha256>::new(None, key);
+    let mut phase_key = Zeroizing::new([0u8; 42]);
+    hk.expand(&purpose, phase_key.as_mut())
+        .map_err(|_| CryptoError::KeyDerivation)?;
+    Ok(Zeroizing::new(
+        phase_key[..crypto_secretbox::SecretBox::<()>::KEY_SIZE].to_vec(),
+    ))
+}
+
+/// Derive the verifier for a key, which both peers can compare to be sure they share it.
+pub fn derive_verifier(key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let hk = Hkdf::<Sha256>::new(None, key);
+    let mut verifier = [0u8; 32];
+    hk.expand(b"wormhole:verifier", &mut verifier)
+        .map_err(|_| CryptoError::KeyDerivation)?;
+    Ok(verifier.to_vec())
+}
+
+/// Encrypt the given message, as the reference client does: the secretbox of the message
+/// under a random nonce, after the nonce itself.
+pub fn encrypt_message(
+    message: &[u8],
+    key: &[u8],
+    side: &str,
+    phase: &Phase,
+) -> Result<Vec<u8>, CryptoError> {
+    let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
+    encrypt_with_nonce(message, key, side, phase, &nonce)
+}
+
+/// Encrypt the given message under the given nonce.
+fn encrypt_with_nonce(
+    message: &[u8],
+    key: &[u8],
+    side: &str,
+    phase: &Phase,
+    nonce: &Nonce,
+) -> Result<Vec<u8>, CryptoError> {
+    let phase_key = derive_phase_key(key, side, phase)?;
+    let cipher = XSalsa20Poly1305::new(crypto_secretbox::Key::from_slice(&phase_key));
+    let cipher_text = cipher
+        .encrypt(nonce, message)
+        .map_err(|_| CryptoError::Encryption)?;
+    {
+        // Concatenate nonce and cipher text
+        let mut result = nonce.to_vec();
+        result.extend(cipher_text);
+        Ok(result)
+    }
+}
+
+/// Descrypt the given message, which needn't be text. Anything too short to hold a nonce and
+/// authentication tag can't be decrypted either.
+pub fn decrypt_message(
+    message: &[u8],
+    key: &[u8],
+    side: &str,
+    phase: &Phase,
+) -> Result<Vec<u8>, CryptoError> {
+    if message.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(CryptoError::Truncated(message.len()));
+    }
+    let phase_key = derive_phase_key(key, side, phase)?;
+    let (nonce, cipher_text) = message.split_at(NONCE_SIZE);
+    let cipher = XSalsa20Poly1305::new(crypto_secretbox::Key::from_slice(&phase_key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), cipher_text)
+        .map_err(|_| CryptoError::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decrypt_message, derive_phase_key, derive_verifier, encrypt_message, encrypt_with_nonce,
+        finish_pake, generate_purpose, start_pake, CryptoError, Phase, NONCE_SIZE, TAG_SIZE,
+    };
+    #[cfg(feature = "net")]
+    use crate::{dilation::dilation_key, transit::transit_key};
+    use crypto_secretbox::Nonce;
+
+    #[test]
+    fn purpose() {
+        let side = "abcd1234";
+        let phase = Phase::Version;
+
+        let purpose = generate_purpose(side, &phase).unwrap();
+        assert_eq!(
+            purpose,
+            vec![
+                119, 111, 114, 109, 104, 111, 108, 101, 58, 112, 104, 97, 115, 101, 58, 233, 206,
+                231, 26, 185, 50, 253, 232, 99, 51, 141, 8, 190, 77, 233, 223, 227, 158, 160, 73,
+                189, 175, 179, 66, 206, 101, 158, 197, 69, 11, 105, 174, 92, 164, 243, 133, 12,
+                204, 51, 26, 175, 138, 37, 125, 96, 134, 229, 38, 163, 180, 42, 99, 225, 140, 177,
+                29, 2, 8, 71, 152, 91, 49, 209, 136
+            ]
+        );
+    }
+
+    #[test]
+    fn phase_key() {
+        let key = b"password";
+        let side = "abcd1234";
+        let phase = Phase::Version;
+
+        let phase_key = derive_phase_key(key, side, &phase).unwrap();
+        assert_eq!(
+            *phase_key,
+            vec![
+                237, 218, 144, 42, 103, 199, 244, 239, 96, 138, 231, 203, 191, 38, 177, 107, 31,
+                230, 31, 159, 77, 193, 128, 177, 171, 179, 160, 36, 244, 251, 193, 42
+            ]
+        );
+    }
+
+    #[test]
+    fn verifier() {
+        assert_eq!(
+            derive_verifier(b"password").unwrap(),
+            vec![
+                249, 204, 209, 128, 245, 224, 113, 144, 125, 50, 66, 213, 65, 57, 0, 52, 219, 139,
+                64, 120, 32, 254, 153, 64, 132, 253, 78, 122, 225, 16, 205, 53
+            ]
+        );
+    }
+
+    /// Key derivation test vectors, computed by an earlier standalone reimplementation of the
+    /// Python implementation's derivations using only the standard library. `testdata/kdf.py`
+    /// derives the same values with magic-wormhole itself, but hasn't been run over them yet.
+    fn kdf_vectors() -> serde_json::Value {
+        serde_json::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/kdf.json"
+        )))
+        .unwrap()
+    }
+
+    #[test]
+    fn reference_derivations() {
+        let vectors = kdf_vectors();
+        let hex = |v: &serde_json::Value| hex::decode(v.as_str().unwrap()).unwrap();
+
+        for vector in vectors["phases"].as_array().unwrap() {
+            let key = hex(&vector["key"]);
+            let side = vector["side"].as_str().unwrap();
+            let phase = serde_json::from_value::<Phase>(vector["phase"].clone()).unwrap();
+            assert_eq!(
+                generate_purpose(side, &phase).unwrap(),
+                hex(&vector["purpose"]),
+                "{}",
+                vector
+            );
+            assert_eq!(
+                *derive_phase_key(&key, side, &phase).unwrap(),
+                hex(&vector["phase_key"]),
+                "{}",
+                vector
+            );
+        }
+        for vector in vectors["verifiers"].as_array().unwrap() {
+            assert_eq!(
+                derive_verifier(&hex(&vector["key"])).unwrap(),
+                hex(&vector["verifier"])
+            );
+        }
+        #[cfg(feature = "net")]
+        for vector in vectors["transit"].as_array().unwrap() {
+            assert_eq!(
+                transit_key(&hex(&vector["key"]), vector["app_id"].as_str().unwrap()),
+                hex(&vector["transit_key"])
+            );
+        }
+        #[cfg(feature = "net")]
+        for vector in vectors["dilation"].as_array().unwrap() {
+            assert_eq!(
+                dilation_key(&hex(&vector["key"])),
+                hex(&vector["dilation_key"])
+            );
+        }
+    }
+
+    #[test]
+    fn roundtrip_encryption() {
+        let key = b"password";
+        let side = "abcd1234";
+        let phase = Phase::Version;
+        let message = b"hello";
+
+        let cipher_text = encrypt_message(message, key, side, &phase).unwrap();
+        assert_eq!(cipher_text.len(), NONCE_SIZE + TAG_SIZE + message.len());
+        let plain_text = decrypt_message(&cipher_text, key, side, &phase).unwrap();
+        assert_eq!(plain_text, message);
+
+        // Each message has its own nonce
+        assert_ne!(
+            cipher_text[..NONCE_SIZE],
+            encrypt_message(message, key, side, &phase).unwrap()[..NONCE_SIZE]
+        );
+
+        // Not everything sent is text
+        let binary = [0xff, 0xfe, 0x00, 0x80];
+        let cipher_text = encrypt_message(&binary, key, side, &phase).unwrap();
+        assert_eq!(
+            decrypt_message(&cipher_text, key, side, &phase).unwrap(),
+            binary
+        );
+    }
+
+    /// Messages sealed by this crate with fixed nonces, laid out as the reference client lays
+    /// them out, with the nonce ahead of the secretbox output. They catch changes here, but
+    /// haven't been checked against another implementation.
+    #[test]
+    fn sealed_messages() {
+        let vectors: serde_json::Value = serde_json::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/messages.json"
+        )))
+        .unwrap();
+        let hex = |v: &serde_json::Value| hex::decode(v.as_str().unwrap()).unwrap();
+
+        for vector in vectors["messages"].as_array().unwrap() {
+            let key = hex(&vector["key"]);
+            let side = vector["side"].as_str().unwrap();
+            let phase = serde_json::from_value::<Phase>(vector["phase"].clone()).unwrap();
+            let message = vector["plaintext"].as_str().unwrap().as_bytes();
+            let expected = hex(&vector["encrypted"]);
+            let nonce = Nonce::from_slice(&expected[..NONCE_SIZE]);
+            assert_eq!(
+                encrypt_with_nonce(message, &key, side, &phase, nonce).unwrap(),
+                expected,
+                "{}",
+                vector
+            );
+            assert_eq!(
+                decrypt_message(&expected, &key, side, &phase).unwrap(),
+                message,
+                "{}",
+                vector
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let key = b"password";
+        let side = "abcd1234";
+        let phase = Phase::Version;
+        let cipher_text = encrypt_message(b"", key, side, &phase).unwrap();
+        assert_eq!(cipher_text.len(), NONCE_SIZE + TAG_SIZE);
+
+        for len in [0, 1, NONCE_SIZE - 1, NONCE_SIZE, NONCE_SIZE + TAG_SIZE - 1] {
+            assert_eq!(
+                decrypt_message(&cipher_text[..len], key, side, &phase),
+                Err(CryptoError::Truncated(len))
+            );
+        }
+        let mut tampered = cipher_text.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert_eq!(
+            decrypt_message(&tampered, key, side, &phase),
+            Err(CryptoError::Decryption)
+        );
+        assert_eq!(
+            decrypt_message(&cipher_text, key, side, &Phase::Pake),
+            Err(CryptoError::Decryption)
+        );
+        assert!(decrypt_message(&cipher_text, key, side, &phase)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn pake() {
+        let app_id = "lothar.com/wormhole/text-or-file-xfer";
+        let (ours, our_msg) = start_pake("7-crossover-clockwork", app_id);
+        let (theirs, their_msg) = start_pake("7-crossover-clockwork", app_id);
+        // An element of the group, prefixed by the symmetric mode's side byte
+        assert_eq!(our_msg.len(), 33);
+        assert_eq!(our_msg[0], b'S');
+        let key = finish_pake(ours, &their_msg).unwrap();
+        assert_eq!(key.as_bytes().len(), 32);
+        // The key itself is kept out of logs
+        assert_eq!(format!("{:?}", key), "SessionKey(..)");
+        assert_eq!(finish_pake(theirs, &our_msg).unwrap(), key);
+
+        // Knowing the app ID isn't enough without the code
+        let (wrong, wrong_msg) = start_pake("7-crossover-clockwerk", app_id);
+        let (ours, our_msg) = start_pake("7-crossover-clockwork", app_id);
+        assert_ne!(
+            finish_pake(ours, &wrong_msg).unwrap(),
+            finish_pake(wrong, &our_msg).unwrap()
+        );
+
+        let (ours, _) = start_pake("7-crossover-clockwork", app_id);
+        assert!(finish_pake(ours, b"not a pake message").is_err());
+    }
+}
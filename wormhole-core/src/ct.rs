@@ -0,0 +1,22 @@
+/// Comparisons of secrets which take as long however much of them matches, so that how quickly
+/// a peer's guess is rejected doesn't tell it how close the guess was.
+use subtle::ConstantTimeEq;
+
+/// Are the two byte strings the same? Only their lengths, which needn't be kept secret, affect
+/// how long this takes.
+pub(crate) fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::eq;
+
+    #[test]
+    fn equality() {
+        assert!(eq(b"", b""));
+        assert!(eq(b"transit sender ready", b"transit sender ready"));
+        assert!(!eq(b"transit sender ready", b"transit sender readx"));
+        assert!(!eq(b"transit sender ready", b"transit sender"));
+    }
+}
@@ -0,0 +1,900 @@
+/// Dilation: a lasting connection between peers, set up over the mailbox like transit, on
+/// which either side can open any number of subchannels.
+///
+/// Once both sides have sent a `please` message over the mailbox, the side with the greater side
+/// ID leads and the other follows. Each side listens on a port and sends the other
+/// `connection-hints`, then dials every hint it was sent while accepting connections, as with
+/// transit, relays included.
+///
+/// Every connection opens with each side sending a prologue naming its role, then a
+/// Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s handshake started by the leader, keyed with the
+/// dilation key derived from the wormhole's shared key. The follower sends a key confirmation
+/// message on every connection whose handshake completes, and the leader uses the first
+/// connection one arrives on, sending its own back to tell the follower which it chose.
+///
+/// From then on, each side sends length-prefixed Noise transport messages, each holding one
+/// record: opening a subchannel, data on one, closing one, acknowledging one of those, or a
+/// ping or its reply. Subchannel 0 is always open, for control messages. The leader numbers the
+/// subchannels it opens with odd numbers and the follower with even ones, and the data sent on
+/// each is a stream of bytes, which `Subchannel` splits into length-prefixed records of its own.
+///
+/// Reconnecting is not supported: once the connection is lost, the dilated connection is closed.
+use log::debug;
+use serde::{Deserialize, Serialize};
+use snow::{params::NoiseParams, StatelessTransportState};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+};
+use zeroize::Zeroizing;
+
+use crate::ct;
+use crate::socks::SocksProxy;
+use crate::transit::{self, Hint, RelayHint, Route};
+
+/// The Noise protocol every connection is encrypted with.
+const NOISE_PROTOCOL: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// What the leader opens every connection with.
+const LEADER_PROLOGUE: &[u8] = b"Magic-Wormhole Dilation Handshake v1 Leader\n\n";
+
+/// What the follower opens every connection with.
+const FOLLOWER_PROLOGUE: &[u8] = b"Magic-Wormhole Dilation Handshake v1 Follower\n\n";
+
+/// Largest Noise message, so the largest frame either side may send.
+const MAX_FRAME_LENGTH: usize = 65535;
+
+/// Size of each Noise message's authentication tag.
+const TAG_LENGTH: usize = 16;
+
+/// Most data a single record can carry, after its type, subchannel and sequence number.
+const MAX_DATA_LENGTH: usize = MAX_FRAME_LENGTH - TAG_LENGTH - 9;
+
+/// Largest record which may be sent on a subchannel.
+const MAX_RECORD_LENGTH: usize = 64 * 1024 * 1024;
+
+/// How many records of data may be waiting to be sent, or to be read from each subchannel,
+/// before whoever is adding them has to wait.
+const QUEUE_LENGTH: usize = 64;
+
+/// The subchannel which is always open, for control messages.
+const CONTROL_SUBCHANNEL: u32 = 0;
+
+/// Errors generated while setting up or using a dilated connection.
+#[derive(Error, Debug)]
+pub enum DilationError {
+    #[error("dilation I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("noise protocol error: {0}")]
+    Noise(#[from] snow::Error),
+    #[error("no dilated connection could be established")]
+    NoConnection,
+    #[error("peer's handshake didn't match, it may have used a different code")]
+    BadHandshake,
+    #[error("invalid record from peer")]
+    BadRecord,
+    #[error("record of {0} bytes is too large")]
+    RecordTooLarge(usize),
+    #[error("dilated connection closed")]
+    Closed,
+}
+
+/// A message sent to the peer over the mailbox, in a `dilate-N` phase, to set up dilation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DilationMessage {
+    /// Asks the peer to dilate, which happens once both sides have asked.
+    Please { side: String },
+    /// How to reach the side sending it.
+    ConnectionHints { hints: Vec<Hint> },
+    /// The leader lost its connection and wants a new one.
+    Reconnect,
+    /// The follower has dropped its connections, in answer to `reconnect`.
+    Reconnecting,
+}
+
+/// Which side of a dilated connection a peer is, which decides who picks the connection to
+/// use and how subchannels are numbered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DilationRole {
+    Leader,
+    Follower,
+}
+
+impl DilationRole {
+    /// The role of the side with ID `ours`, dilating with the side with ID `theirs`: the
+    /// greater one leads.
+    pub fn for_sides(ours: &str, theirs: &str) -> Self {
+        if ours > theirs {
+            DilationRole::Leader
+        } else {
+            DilationRole::Follower
+        }
+    }
+}
+
+/// Derive the dilation key for a wormhole from its shared key.
+pub fn dilation_key(key: &[u8]) -> Vec<u8> {
+    transit::derive(key, b"dilation-v1").to_vec()
+}
+
+/// A record sent on a dilated connection, once decrypted.
+#[derive(Debug, Clone, PartialEq)]
+enum Record {
+    /// Key confirmation, showing the handshake worked.
+    Kcm,
+    Ping(u32),
+    Pong(u32),
+    Open {
+        subchannel: u32,
+        seqnum: u32,
+    },
+    Data {
+        subchannel: u32,
+        seqnum: u32,
+        data: Vec<u8>,
+    },
+    Close {
+        subchannel: u32,
+        seqnum: u32,
+    },
+    /// Acknowledges the peer's record with the given sequence number.
+    Ack {
+        seqnum: u32,
+    },
+}
+
+impl Record {
+    /// The record as sent: its type, then its fields as big-endian integers, then any data.
+    fn encode(&self) -> Vec<u8> {
+        let (ty, fields, data): (u8, &[u32], &[u8]) = match self {
+            Record::Kcm => (0x00, &[], &[]),
+            Record::Ping(id) => (0x01, &[*id], &[]),
+            Record::Pong(id) => (0x02, &[*id], &[]),
+            Record::Open { subchannel, seqnum } => (0x03, &[*subchannel, *seqnum], &[]),
+            Record::Data {
+                subchannel,
+                seqnum,
+                data,
+            } => (0x04, &[*subchannel, *seqnum], data),
+            Record::Close { subchannel, seqnum } => (0x05, &[*subchannel, *seqnum], &[]),
+            Record::Ack { seqnum } => (0x06, &[*seqnum], &[]),
+        };
+        let mut encoded = vec![ty];
+        for field in fields {
+            encoded.extend(field.to_be_bytes());
+        }
+        encoded.extend(data);
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Result<Self, DilationError> {
+        let (&ty, rest) = encoded.split_first().ok_or(DilationError::BadRecord)?;
+        let field = |i: usize| -> Result<u32, DilationError> {
+            rest.get(i * 4..i * 4 + 4)
+                .map(|field| u32::from_be_bytes(field.try_into().unwrap()))
+                .ok_or(DilationError::BadRecord)
+        };
+        let (record, length) = match ty {
+            0x00 => (Record::Kcm, 0),
+            0x01 => (Record::Ping(field(0)?), 4),
+            0x02 => (Record::Pong(field(0)?), 4),
+            0x03 => (
+                Record::Open {
+                    subchannel: field(0)?,
+                    seqnum: field(1)?,
+                },
+                8,
+            ),
+            0x04 => {
+                let record = Record::Data {
+                    subchannel: field(0)?,
+                    seqnum: field(1)?,
+                    data: rest[8..].to_vec(),
+                };
+                return Ok(record);
+            }
+            0x05 => (
+                Record::Close {
+                    subchannel: field(0)?,
+                    seqnum: field(1)?,
+                },
+                8,
+            ),
+            0x06 => (Record::Ack { seqnum: field(0)? }, 4),
+            _ => return Err(DilationError::BadRecord),
+        };
+        if rest.len() != length {
+            return Err(DilationError::BadRecord);
+        }
+        Ok(record)
+    }
+}
+
+/// One side's half of setting up a dilated connection, listening for the peer to connect.
+#[derive(Debug)]
+pub struct Dilation {
+    role: DilationRole,
+    /// Our side, as used on the mailbox, to tell the relay which connection is ours.
+    side: String,
+    /// Dilation key shared with the peer, from which the relay token is derived and which the
+    /// Noise handshake proves both sides know.
+    key: Zeroizing<Vec<u8>>,
+    listener: TcpListener,
+    hints: Vec<Hint>,
+    /// Relays we're willing to use.
+    relays: Vec<RelayHint>,
+    /// The proxy to connect through, if any.
+    proxy: Option<SocksProxy>,
+}
+
+impl Dilation {
+    /// Listen on an arbitrary port of every local address, for a connection with the peer
+    /// sharing the given dilation key.
+    pub async fn listen(role: DilationRole, side: &str, key: &[u8]) -> io::Result<Self> {
+        let (listener, hints) = transit::listen().await?;
+        Ok(Dilation {
+            role,
+            side: side.to_owned(),
+            key: Zeroizing::new(key.to_vec()),
+            listener,
+            hints,
+            relays: Vec::new(),
+            proxy: None,
+        })
+    }
+
+    /// Also offer to meet the peer at the given relay, if connecting directly fails.
+    pub fn with_relay(mut self, relay: RelayHint) -> Self {
+        self.relays.push(relay);
+        self
+    }
+
+    /// Make every connection through the given proxy, without telling the peer our addresses.
+    pub fn with_proxy(mut self, proxy: SocksProxy) -> Self {
+        self.hints.clear();
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// The hints telling the peer how to reach us.
+    pub fn hints(&self) -> Vec<Hint> {
+        self.hints
+            .iter()
+            .cloned()
+            .chain(self.relays.iter().cloned().map(Hint::RelayV1))
+            .collect()
+    }
+
+    /// Connect to the peer, using the hints it sent us while accepting connections from it,
+    /// giving up once the timeout passes.
+    pub async fn connect(
+        self,
+        peer_hints: &[Hint],
+        timeout: Duration,
+    ) -> Result<DilatedConnection, DilationError> {
+        let role = self.role;
+        let key = Arc::<[u8]>::from(self.key.as_slice());
+        let race = transit::race(
+            &self.listener,
+            peer_hints,
+            &self.relays,
+            transit::relay_request(&self.key, &self.side),
+            self.proxy.as_ref(),
+            |stream, route| establish(role, key.clone(), stream, route),
+            |mut link: Link| async move {
+                if role == DilationRole::Leader {
+                    link.send(&Record::Kcm).await?;
+                }
+                Ok::<_, DilationError>(link)
+            },
+        );
+        let link = tokio::time::timeout(timeout, race)
+            .await
+            .map_err(|_| DilationError::NoConnection)??;
+        Ok(DilatedConnection::new(role, link))
+    }
+}
+
+/// An encrypted connection with the peer, before it's split up to carry subchannels.
+struct Link {
+    stream: TcpStream,
+    route: Route,
+    noise: StatelessTransportState,
+    /// Nonce of the next message we send.
+    send_nonce: u64,
+    /// Nonce the peer's next message must have.
+    receive_nonce: u64,
+}
+
+impl Link {
+    async fn send(&mut self, record: &Record) -> Result<(), DilationError> {
+        write_record(&mut self.stream, &self.noise, &mut self.send_nonce, record).await
+    }
+
+    async fn receive(&mut self) -> Result<Record, DilationError> {
+        read_record(&mut self.stream, &self.noise, &mut self.receive_nonce).await
+    }
+}
+
+/// Get a newly established connection ready to be chosen: exchange prologues, then do the
+/// Noise handshake and confirm the key. The follower then waits to hear that the leader chose
+/// it.
+async fn establish(
+    role: DilationRole,
+    key: Arc<[u8]>,
+    mut stream: TcpStream,
+    route: Route,
+) -> Result<Link, DilationError> {
+    let (ours, theirs) = match role {
+        DilationRole::Leader => (LEADER_PROLOGUE, FOLLOWER_PROLOGUE),
+        DilationRole::Follower => (FOLLOWER_PROLOGUE, LEADER_PROLOGUE),
+    };
+    stream.write_all(ours).await?;
+    let mut prologue = vec![0u8; theirs.len()];
+    stream.read_exact(&mut prologue).await?;
+    if !ct::eq(&prologue, theirs) {
+        return Err(DilationError::BadHandshake);
+    }
+
+    let params = NOISE_PROTOCOL.parse::<NoiseParams>()?;
+    let builder = snow::Builder::new(params).psk(0, &key);
+    let mut buffer = vec![0u8; MAX_FRAME_LENGTH];
+    let handshake = match role {
+        DilationRole::Leader => {
+            let mut handshake = builder.build_initiator()?;
+            let length = handshake.write_message(&[], &mut buffer)?;
+            write_frame(&mut stream, &buffer[..length]).await?;
+            let frame = read_frame(&mut stream).await?;
+            handshake
+                .read_message(&frame, &mut buffer)
+                .map_err(|_| DilationError::BadHandshake)?;
+            handshake
+        }
+        DilationRole::Follower => {
+            let mut handshake = builder.build_responder()?;
+            let frame = read_frame(&mut stream).await?;
+            handshake
+                .read_message(&frame, &mut buffer)
+                .map_err(|_| DilationError::BadHandshake)?;
+            let length = handshake.write_message(&[], &mut buffer)?;
+            write_frame(&mut stream, &buffer[..length]).await?;
+            handshake
+        }
+    };
+    let mut link = Link {
+        stream,
+        route,
+        noise: handshake.into_stateless_transport_mode()?,
+        send_nonce: 0,
+        receive_nonce: 0,
+    };
+
+    if role == DilationRole::Follower {
+        link.send(&Record::Kcm).await?;
+    }
+    match link.receive().await? {
+        Record::Kcm => Ok(link),
+        _ => Err(DilationError::BadRecord),
+    }
+}
+
+/// Something for the task writing to the connection to send.
+#[derive(Debug)]
+enum Command {
+    /// We opened the subchannel.
+    Open(u32),
+    /// The peer opened the subchannel.
+    Opened(u32),
+    Data(u32, Vec<u8>),
+    /// Close the subchannel, unless it's already closed.
+    Close(u32),
+    Ack(u32),
+    Pong(u32),
+}
+
+/// Where to deliver the data the peer sends on each subchannel.
+type Inboxes = Arc<Mutex<HashMap<u32, mpsc::Sender<Vec<u8>>>>>;
+
+/// A dilated connection with the peer, ready to open and accept subchannels.
+#[derive(Debug)]
+pub struct DilatedConnection {
+    route: Route,
+    /// Number of the next subchannel we open.
+    next_subchannel: u32,
+    /// Data and subchannels for the connection to send, in order.
+    commands: mpsc::Sender<Command>,
+    inboxes: Inboxes,
+    /// Subchannels the peer opened.
+    opened: mpsc::UnboundedReceiver<Subchannel>,
+    control: Option<Subchannel>,
+}
+
+impl DilatedConnection {
+    /// Start reading and writing records on the connection in the background.
+    fn new(role: DilationRole, link: Link) -> Self {
+        let (commands_tx, commands_rx) = mpsc::channel(QUEUE_LENGTH);
+        let (replies_tx, replies_rx) = mpsc::unbounded_channel();
+        let (opened_tx, opened_rx) = mpsc::unbounded_channel();
+        let (control_tx, control_rx) = mpsc::channel(QUEUE_LENGTH);
+        let inboxes = Arc::new(Mutex::new(HashMap::from([(
+            CONTROL_SUBCHANNEL,
+            control_tx,
+        )])));
+
+        let (read, write) = link.stream.into_split();
+        let noise = Arc::new(link.noise);
+        let reader = tokio::spawn(read_records(
+            read,
+            noise.clone(),
+            link.receive_nonce,
+            inboxes.clone(),
+            replies_tx,
+            opened_tx,
+            commands_tx.downgrade(),
+        ));
+        tokio::spawn(write_records(
+            write,
+            noise,
+            link.send_nonce,
+            commands_rx,
+            replies_rx,
+            inboxes.clone(),
+            reader,
+        ));
+
+        DilatedConnection {
+            route: link.route,
+            next_subchannel: match role {
+                DilationRole::Leader => 1,
+                DilationRole::Follower => 2,
+            },
+            control: Some(Subchannel::new(
+                CONTROL_SUBCHANNEL,
+                commands_tx.clone(),
+                control_rx,
+            )),
+            commands: commands_tx,
+            inboxes,
+            opened: opened_rx,
+        }
+    }
+
+    /// How the connection reached the peer.
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
+    /// The control subchannel, which is always open. It can only be taken once.
+    pub fn control(&mut self) -> Option<Subchannel> {
+        self.control.take()
+    }
+
+    /// Open a new subchannel.
+    pub async fn open(&mut self) -> Result<Subchannel, DilationError> {
+        let id = self.next_subchannel;
+        self.next_subchannel += 2;
+        let (inbox_tx, inbox_rx) = mpsc::channel(QUEUE_LENGTH);
+        self.inboxes.lock().unwrap().insert(id, inbox_tx);
+        self.commands
+            .send(Command::Open(id))
+            .await
+            .map_err(|_| DilationError::Closed)?;
+        Ok(Subchannel::new(id, self.commands.clone(), inbox_rx))
+    }
+
+    /// Wait for the peer to open a subchannel.
+    pub async fn accept(&mut self) -> Result<Subchannel, DilationError> {
+        self.opened.recv().await.ok_or(DilationError::Closed)
+    }
+}
+
+/// A stream of records to and from the peer, carried on a dilated connection.
+#[derive(Debug)]
+pub struct Subchannel {
+    id: u32,
+    commands: mpsc::Sender<Command>,
+    inbox: mpsc::Receiver<Vec<u8>>,
+    /// Data received which hasn't been returned in a record yet.
+    received: Vec<u8>,
+    /// The record last received.
+    record: Vec<u8>,
+}
+
+impl Subchannel {
+    fn new(id: u32, commands: mpsc::Sender<Command>, inbox: mpsc::Receiver<Vec<u8>>) -> Self {
+        Subchannel {
+            id,
+            commands,
+            inbox,
+            received: Vec::new(),
+            record: Vec::new(),
+        }
+    }
+
+    /// Send a record.
+    pub async fn send_record(&mut self, record: &[u8]) -> Result<(), DilationError> {
+        if record.len() > MAX_RECORD_LENGTH {
+            return Err(DilationError::RecordTooLarge(record.len()));
+        }
+        let mut framed = (record.len() as u32).to_be_bytes().to_vec();
+        framed.extend(record);
+        for data in framed.chunks(MAX_DATA_LENGTH) {
+            self.commands
+                .send(Command::Data(self.id, data.to_vec()))
+                .await
+                .map_err(|_| DilationError::Closed)?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next record. It's only valid until the next one is received. If this is
+    /// cancelled, nothing is lost, so it can be raced against other things.
+    pub async fn receive_record(&mut self) -> Result<&[u8], DilationError> {
+        loop {
+            if let Some(length) = self.received.get(..4) {
+                let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                if length > MAX_RECORD_LENGTH {
+                    return Err(DilationError::RecordTooLarge(length));
+                }
+                if self.received.len() >= 4 + length {
+                    self.record.clear();
+                    self.record
+                        .extend(self.received.drain(..4 + length).skip(4));
+                    return Ok(&self.record);
+                }
+            }
+            let data = self.inbox.recv().await.ok_or(DilationError::Closed)?;
+            self.received.extend(data);
+        }
+    }
+
+    /// Close the subchannel, once everything sent on it has been.
+    pub async fn close(self) -> Result<(), DilationError> {
+        self.commands
+            .send(Command::Close(self.id))
+            .await
+            .map_err(|_| DilationError::Closed)
+    }
+}
+
+/// Read records from the peer until the connection ends, handing data to the subchannels it's
+/// for and asking for the replies the peer expects.
+async fn read_records(
+    mut stream: OwnedReadHalf,
+    noise: Arc<StatelessTransportState>,
+    mut nonce: u64,
+    inboxes: Inboxes,
+    replies: mpsc::UnboundedSender<Command>,
+    opened: mpsc::UnboundedSender<Subchannel>,
+    commands: mpsc::WeakSender<Command>,
+) {
+    loop {
+        let record = match read_record(&mut stream, &noise, &mut nonce).await {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("Dilated connection ended: {}", e);
+                break;
+            }
+        };
+        match record {
+            Record::Kcm | Record::Pong(_) | Record::Ack { .. } => {}
+            Record::Ping(id) => {
+                let _ = replies.send(Command::Pong(id));
+            }
+            Record::Open { subchannel, seqnum } => {
+                let _ = replies.send(Command::Ack(seqnum));
+                let (inbox_tx, inbox_rx) = mpsc::channel(QUEUE_LENGTH);
+                inboxes.lock().unwrap().insert(subchannel, inbox_tx);
+                let _ = replies.send(Command::Opened(subchannel));
+                if let Some(commands) = commands.upgrade() {
+                    let _ = opened.send(Subchannel::new(subchannel, commands, inbox_rx));
+                }
+            }
+            Record::Data {
+                subchannel,
+                seqnum,
+                data,
+            } => {
+                let _ = replies.send(Command::Ack(seqnum));
+                let inbox = inboxes.lock().unwrap().get(&subchannel).cloned();
+                match inbox {
+                    // Waiting here holds off the peer until the subchannel's been read from
+                    Some(inbox) => {
+                        let _ = inbox.send(data).await;
+                    }
+                    None => debug!("Ignoring data for closed subchannel {}", subchannel),
+                }
+            }
+            Record::Close { subchannel, seqnum } => {
+                let _ = replies.send(Command::Ack(seqnum));
+                inboxes.lock().unwrap().remove(&subchannel);
+                let _ = replies.send(Command::Close(subchannel));
+            }
+        }
+    }
+    inboxes.lock().unwrap().clear();
+}
+
+/// Send records to the peer until nothing more can be sent, replies first, then close the
+/// connection and stop reading from it.
+async fn write_records(
+    mut stream: OwnedWriteHalf,
+    noise: Arc<StatelessTransportState>,
+    mut nonce: u64,
+    mut commands: mpsc::Receiver<Command>,
+    mut replies: mpsc::UnboundedReceiver<Command>,
+    inboxes: Inboxes,
+    reader: JoinHandle<()>,
+) {
+    let mut open = HashSet::from([CONTROL_SUBCHANNEL]);
+    let mut seqnum = 0u32;
+    let mut next_seqnum = || {
+        seqnum = seqnum.wrapping_add(1);
+        seqnum - 1
+    };
+    loop {
+        let command = tokio::select! {
+            biased;
+            Some(command) = replies.recv() => command,
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+        };
+        let record = match command {
+            Command::Open(subchannel) => {
+                open.insert(subchannel);
+                Record::Open {
+                    subchannel,
+                    seqnum: next_seqnum(),
+                }
+            }
+            Command::Opened(subchannel) => {
+                open.insert(subchannel);
+                continue;
+            }
+            Command::Data(subchannel, data) if open.contains(&subchannel) => Record::Data {
+                subchannel,
+                seqnum: next_seqnum(),
+                data,
+            },
+            Command::Close(subchannel) if open.remove(&subchannel) => Record::Close {
+                subchannel,
+                seqnum: next_seqnum(),
+            },
+            Command::Data(..) | Command::Close(_) => continue,
+            Command::Ack(seqnum) => Record::Ack { seqnum },
+            Command::Pong(id) => Record::Pong(id),
+        };
+        if let Err(e) = write_record(&mut stream, &noise, &mut nonce, &record).await {
+            debug!("Dilated connection failed: {}", e);
+            break;
+        }
+    }
+    reader.abort();
+    inboxes.lock().unwrap().clear();
+    let _ = stream.shutdown().await;
+}
+
+/// Encrypt and send a record in a frame.
+async fn write_record<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    noise: &StatelessTransportState,
+    nonce: &mut u64,
+    record: &Record,
+) -> Result<(), DilationError> {
+    let plaintext = record.encode();
+    let mut frame = vec![0u8; plaintext.len() + TAG_LENGTH];
+    let length = noise.write_message(*nonce, &plaintext, &mut frame)?;
+    *nonce += 1;
+    write_frame(stream, &frame[..length]).await
+}
+
+/// Receive a frame and decrypt the record in it, checking it's the one expected next.
+async fn read_record<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    noise: &StatelessTransportState,
+    nonce: &mut u64,
+) -> Result<Record, DilationError> {
+    let frame = read_frame(stream).await?;
+    let mut plaintext = vec![0u8; frame.len()];
+    let length = noise
+        .read_message(*nonce, &frame, &mut plaintext)
+        .map_err(|_| DilationError::BadRecord)?;
+    *nonce += 1;
+    Record::decode(&plaintext[..length])
+}
+
+/// Send a frame: its length as a big-endian integer, then the frame.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    frame: &[u8],
+) -> Result<(), DilationError> {
+    let mut framed = (frame.len() as u32).to_be_bytes().to_vec();
+    framed.extend(frame);
+    stream.write_all(&framed).await?;
+    Ok(())
+}
+
+/// Receive a frame.
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>, DilationError> {
+    let length = stream.read_u32().await? as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(DilationError::RecordTooLarge(length));
+    }
+    let mut frame = vec![0u8; length];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dilation_key, Dilation, DilationError, DilationMessage, DilationRole, Record,
+        MAX_DATA_LENGTH,
+    };
+    use crate::transit::{DirectHint, Hint, Route};
+    use std::time::Duration;
+
+    #[test]
+    fn message_encoding() {
+        let please = DilationMessage::Please {
+            side: "abcd".into(),
+        };
+        let encoded = r#"{"type":"please","side":"abcd"}"#;
+        assert_eq!(serde_json::to_string(&please).unwrap(), encoded);
+        assert_eq!(
+            serde_json::from_str::<DilationMessage>(encoded).unwrap(),
+            please
+        );
+
+        let hints = DilationMessage::ConnectionHints {
+            hints: vec![Hint::DirectTcpV1(DirectHint {
+                priority: 0.0,
+                hostname: "192.0.2.1".into(),
+                port: 4001,
+            })],
+        };
+        assert_eq!(
+            serde_json::to_string(&hints).unwrap(),
+            r#"{"type":"connection-hints","hints":[{"type":"direct-tcp-v1","priority":0.0,"hostname":"192.0.2.1","port":4001}]}"#
+        );
+    }
+
+    #[test]
+    fn record_encoding() {
+        let records = [
+            (Record::Kcm, vec![0]),
+            (Record::Ping(7), vec![1, 0, 0, 0, 7]),
+            (Record::Pong(7), vec![2, 0, 0, 0, 7]),
+            (
+                Record::Open {
+                    subchannel: 1,
+                    seqnum: 2,
+                },
+                vec![3, 0, 0, 0, 1, 0, 0, 0, 2],
+            ),
+            (
+                Record::Data {
+                    subchannel: 1,
+                    seqnum: 3,
+                    data: b"hi".to_vec(),
+                },
+                vec![4, 0, 0, 0, 1, 0, 0, 0, 3, b'h', b'i'],
+            ),
+            (
+                Record::Close {
+                    subchannel: 1,
+                    seqnum: 4,
+                },
+                vec![5, 0, 0, 0, 1, 0, 0, 0, 4],
+            ),
+            (Record::Ack { seqnum: 4 }, vec![6, 0, 0, 0, 4]),
+        ];
+        for (record, encoded) in records {
+            assert_eq!(record.encode(), encoded);
+            assert_eq!(Record::decode(&encoded).unwrap(), record);
+        }
+        assert!(Record::decode(&[]).is_err());
+        assert!(Record::decode(&[7]).is_err());
+        assert!(Record::decode(&[3, 0, 0, 0, 1]).is_err());
+        assert!(Record::decode(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn roles() {
+        assert_eq!(DilationRole::for_sides("b", "a"), DilationRole::Leader);
+        assert_eq!(DilationRole::for_sides("a", "b"), DilationRole::Follower);
+        assert_eq!(
+            hex::encode(dilation_key(b"key")),
+            hex::encode(crate::transit::derive(b"key", b"dilation-v1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn subchannels() {
+        let leader = Dilation::listen(DilationRole::Leader, "side2", &dilation_key(b"key"))
+            .await
+            .unwrap();
+        let follower = Dilation::listen(DilationRole::Follower, "side1", &dilation_key(b"key"))
+            .await
+            .unwrap();
+        let (leader_hints, follower_hints) = (leader.hints(), follower.hints());
+
+        let timeout = Duration::from_secs(5);
+        let (leader, follower) = tokio::join!(
+            leader.connect(&follower_hints, timeout),
+            follower.connect(&leader_hints, timeout)
+        );
+        let (mut leader, mut follower) = (leader.unwrap(), follower.unwrap());
+        assert!(matches!(leader.route(), Route::Direct(_)));
+
+        // Records bigger than a frame are split up and put back together
+        let big = vec![7u8; MAX_DATA_LENGTH * 2 + 1];
+        let mut opened = follower.open().await.unwrap();
+        opened.send_record(b"hello").await.unwrap();
+        opened.send_record(&big).await.unwrap();
+        let mut accepted = leader.accept().await.unwrap();
+        assert_eq!(accepted.receive_record().await.unwrap(), b"hello");
+        assert_eq!(accepted.receive_record().await.unwrap(), big);
+        accepted.send_record(b"").await.unwrap();
+        assert_eq!(opened.receive_record().await.unwrap(), b"");
+
+        // Either side can use the control subchannel
+        let mut leader_control = leader.control().unwrap();
+        let mut follower_control = follower.control().unwrap();
+        assert!(leader.control().is_none());
+        leader_control.send_record(b"done").await.unwrap();
+        assert_eq!(follower_control.receive_record().await.unwrap(), b"done");
+
+        // Closing a subchannel ends it for the peer
+        opened.close().await.unwrap();
+        assert!(matches!(
+            accepted.receive_record().await,
+            Err(DilationError::Closed)
+        ));
+
+        // As does dropping the connection, once everything's been read
+        follower_control.send_record(b"bye").await.unwrap();
+        drop(follower_control);
+        drop(follower);
+        assert_eq!(leader_control.receive_record().await.unwrap(), b"bye");
+        assert!(matches!(
+            leader_control.receive_record().await,
+            Err(DilationError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_key() {
+        let leader = Dilation::listen(DilationRole::Leader, "side2", &dilation_key(b"key"))
+            .await
+            .unwrap();
+        let follower =
+            Dilation::listen(DilationRole::Follower, "side1", &dilation_key(b"other key"))
+                .await
+                .unwrap();
+        let (leader_hints, follower_hints) = (leader.hints(), follower.hints());
+
+        let timeout = Duration::from_millis(500);
+        let (leader, follower) = tokio::join!(
+            leader.connect(&follower_hints, timeout),
+            follower.connect(&leader_hints, timeout)
+        );
+        assert!(matches!(leader, Err(DilationError::NoConnection)));
+        assert!(matches!(follower, Err(DilationError::NoConnection)));
+    }
+}
@@ -0,0 +1,102 @@
+/// Hashcash proof-of-work stamps, used as a permission method by the mailbox server.
+///
+/// A version 1 stamp looks like `1:bits:date:resource::rand:counter`, and is only valid if its
+/// SHA-1 hash starts with at least `bits` zero bits.
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mint a stamp for the given resource, by searching for a counter which gives a hash with
+/// enough leading zero bits. Takes around `2^bits` hashes.
+pub fn mint(resource: &str, bits: u32) -> String {
+    let rand = {
+        let mut buffer = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut buffer);
+        hex::encode(buffer)
+    };
+    let prefix = format!("1:{}:{}:{}::{}:", bits, today(), resource, rand);
+    (0u64..)
+        .map(|counter| format!("{}{:x}", prefix, counter))
+        .find(|stamp| leading_zero_bits(stamp) >= bits)
+        .expect("some counter gives a valid stamp")
+}
+
+/// Check that a stamp was minted for the given resource, with at least the given number of
+/// bits of work.
+pub fn verify(stamp: &str, resource: &str, bits: u32) -> bool {
+    let fields = stamp.split(':').collect::<Vec<_>>();
+    let [version, claimed_bits, _date, stamp_resource, _ext, _rand, _counter] = fields[..] else {
+        return false;
+    };
+    version == "1"
+        && claimed_bits.parse::<u32>().is_ok_and(|b| b >= bits)
+        && stamp_resource == resource
+        && leading_zero_bits(stamp) >= bits
+}
+
+/// Number of leading zero bits in the SHA-1 hash of the stamp.
+fn leading_zero_bits(stamp: &str) -> u32 {
+    let mut zeros = 0;
+    for byte in Sha1::digest(stamp.as_bytes()) {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Today's date in UTC, as `YYMMDD`.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{:02}{:02}{:02}", year % 100, month, day)
+}
+
+/// Convert days since the Unix epoch into a (year, month, day) date in the proleptic Gregorian
+/// calendar, using Howard Hinnant's algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{civil_from_days, mint, verify};
+
+    #[test]
+    fn minting() {
+        let stamp = mint("resource", 8);
+        assert!(stamp.starts_with("1:8:"));
+        assert!(verify(&stamp, "resource", 8));
+        assert!(!verify(&stamp, "other", 8));
+        assert!(!verify(&stamp.replacen("1:8:", "2:8:", 1), "resource", 8));
+    }
+
+    #[test]
+    fn verification() {
+        // Not enough work, whatever the stamp claims
+        assert!(!verify("1:20:230624:resource::abcd:0", "resource", 20));
+        assert!(!verify("not a stamp", "resource", 0));
+        assert!(verify("1:0:230624:resource::abcd:0", "resource", 0));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19532), (2023, 6, 24));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+}
@@ -0,0 +1,17 @@
+//! The Magic Wormhole protocol, shared by the client and the mailbox server: the messages
+//! exchanged with the mailbox, the encryption of those between peers, and the transit and
+//! dilated connections they use for bulk data.
+//!
+//! The connections are behind the default `net` feature, as they need the tokio runtime; without
+//! it, only the messages, their encryption and hashcash are built.
+pub mod crypto;
+#[cfg(feature = "net")]
+mod ct;
+#[cfg(feature = "net")]
+pub mod dilation;
+pub mod hashcash;
+pub mod message;
+#[cfg(feature = "net")]
+pub mod socks;
+#[cfg(feature = "net")]
+pub mod transit;
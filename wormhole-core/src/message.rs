@@ -1,8 +1,16 @@
 /// Messages sent between the client and mailbox server.
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use serde_with::{serde_as, DisplayFromStr};
-use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Longest prefix of an undecodable message kept when redacting it, in characters.
+const MAX_RAW_CHARS: usize = 1024;
 
 /// A message sent from the mailbox server to the client.
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -37,10 +45,48 @@ pub struct ClientMessage {
 pub enum PermissionMethod {
     /// No permission required, send a normal `bind`.
     None,
+    /// Submit a hashcash stamp for the given resource with at least the given number of bits
+    /// of work, before sending `bind`.
+    Hashcash { bits: u32, resource: String },
+    /// Submit a token shared out by the server's operator, before sending `bind`.
+    Token,
+    /// A method this implementation doesn't know, which newer servers may offer alongside
+    /// ones it does.
+    #[serde(untagged)]
+    Unknown(Value),
+}
+
+impl PermissionMethod {
+    /// The method's name, as the server gives it.
+    pub fn name(&self) -> String {
+        match self {
+            PermissionMethod::None => "none".into(),
+            PermissionMethod::Hashcash { .. } => "hashcash".into(),
+            PermissionMethod::Token => "token".into(),
+            PermissionMethod::Unknown(Value::String(name)) => name.clone(),
+            PermissionMethod::Unknown(Value::Object(method)) if method.len() == 1 => {
+                method.keys().next().unwrap().clone()
+            }
+            PermissionMethod::Unknown(method) => method.to_string(),
+        }
+    }
+}
+
+/// Proof of permission to access the mailbox server, submitted before `bind`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(tag = "method")]
+pub enum Permission {
+    /// No proof offered.
+    None,
+    /// A hashcash stamp, as requested by the server.
+    Hashcash { stamp: String },
+    /// A token shared out by the server's operator.
+    Token { token: String },
 }
 
 /// Welcome information sent from the mailbox server to clients on connection.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub struct WelcomeInfo {
     /// This message is intended to inform users about performance problems, scheduled downtime,
@@ -48,6 +94,10 @@ pub struct WelcomeInfo {
     /// display prominently to the user. The value should be a plain string.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub motd: Option<String>,
+    /// The latest release of the reference command-line client. Clients may compare this with
+    /// their own version, and suggest that the user upgrades if they are behind.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_cli_version: Option<String>,
     /// The client should show this message to the user and then terminate. The value should be a
     /// plain string.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -57,6 +107,11 @@ pub struct WelcomeInfo {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub permission_required: Vec<PermissionMethod>,
+    /// Transit relays run alongside the server, as hints like `tcp:transit.example.com:4001`.
+    /// Clients may use these to relay file transfers instead of their built-in defaults.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub transit_relays: Vec<String>,
 }
 
 /// Information about a nameplate.
@@ -85,7 +140,7 @@ pub enum Mood {
 
 /// Peer to peer message type.
 #[serde_as]
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum Phase {
     /// The initial PAKE message.
@@ -95,8 +150,26 @@ pub enum Phase {
     /// An encrypted application-specific message.
     #[serde(untagged)]
     Message(#[serde_as(as = "DisplayFromStr")] usize),
+    /// An encrypted message setting up a dilated connection, numbered separately from
+    /// application messages: "dilate-0", "dilate-1" and so on.
+    #[serde(untagged)]
+    Dilate(#[serde_as(as = "DilatePhase")] usize),
+    /// A phase this implementation doesn't know, which the server passes on like any other
+    /// and clients ignore, so newer clients can add phases.
+    #[serde(untagged)]
+    Unknown(String),
 }
 
+serde_with::serde_conv!(
+    DilatePhase,
+    usize,
+    |n: &usize| format!("dilate-{}", n),
+    |phase: String| phase
+        .strip_prefix("dilate-")
+        .and_then(|n| n.parse().ok())
+        .ok_or("not a dilation phase")
+);
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -123,8 +196,9 @@ pub enum ServerMessageType {
     Message {
         side: String,
         phase: Phase,
+        /// Shared so that fanning a message out to many subscribers doesn't copy the body.
         #[serde_as(as = "serde_with::hex::Hex")]
-        body: Vec<u8>,
+        body: Arc<[u8]>,
     },
     /// closed
     Closed,
@@ -141,8 +215,9 @@ pub enum ServerMessageType {
 #[serde(rename_all = "lowercase")]
 #[serde(tag = "type")]
 pub enum ClientMessageType {
-    /// submit-permissions {..} (optional)
-    SubmitPermissions,
+    /// submit-permissions {method:, ..} (optional)
+    #[serde(rename = "submit-permissions")]
+    SubmitPermissions(Permission),
     /// bind {appid:, side:, }
     Bind {
         #[serde(rename = "appid")]
@@ -237,12 +312,47 @@ impl ClientMessage {
     }
 }
 
+/// Parse a message for logging, keeping secrets and message contents out of it: bodies are
+/// replaced by their length and hash, and tokens removed. Anything which isn't JSON is cut
+/// short.
+pub fn redact(text: &str) -> Value {
+    match serde_json::from_str(text) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value
+        }
+        Err(_) => Value::String(text.chars().take(MAX_RAW_CHARS).collect()),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match (key.as_str(), &value) {
+                    ("body", Value::String(body)) => {
+                        *value = serde_json::json!({
+                            "len": body.len() / 2,
+                            "sha256": hex::encode(Sha256::digest(body.as_bytes())),
+                        });
+                    }
+                    ("token", Value::String(_)) => *value = Value::String("<redacted>".into()),
+                    _ => redact_value(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
-        ClientMessage, ClientMessageType, Mood, Phase, ServerMessage, ServerMessageType,
-        WelcomeInfo,
+        redact, ClientMessage, ClientMessageType, Mood, Permission, PermissionMethod, Phase,
+        ServerMessage, ServerMessageType, WelcomeInfo, MAX_RAW_CHARS,
     };
+    use serde_json::json;
 
     #[test]
     fn serialization() {
@@ -254,8 +364,10 @@ mod tests {
             ty: ServerMessageType::Welcome {
                 welcome: WelcomeInfo {
                     motd: None,
+                    current_cli_version: None,
                     error: None,
                     permission_required: vec![],
+                    transit_relays: vec![],
                 },
             },
         };
@@ -265,6 +377,99 @@ mod tests {
             "{\"server_tx\":1687594898.0583792,\"type\":\"welcome\",\"welcome\":{}}"
         );
 
+        // welcome, advertising the current client version
+        let msg = ServerMessage {
+            id: None,
+            server_tx: 1687594898.0583792,
+            server_rx: None,
+            ty: ServerMessageType::Welcome {
+                welcome: WelcomeInfo {
+                    current_cli_version: Some("0.2.0".into()),
+                    ..WelcomeInfo::default()
+                },
+            },
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            "{\"server_tx\":1687594898.0583792,\"type\":\"welcome\",\"welcome\":{\"current_cli_version\":\"0.2.0\"}}"
+        );
+
+        // welcome, advertising a transit relay
+        let msg = ServerMessage {
+            id: None,
+            server_tx: 1687594898.0583792,
+            server_rx: None,
+            ty: ServerMessageType::Welcome {
+                welcome: WelcomeInfo {
+                    transit_relays: vec!["tcp:transit.example.com:4001".into()],
+                    ..WelcomeInfo::default()
+                },
+            },
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            "{\"server_tx\":1687594898.0583792,\"type\":\"welcome\",\"welcome\":{\"transit_relays\":[\"tcp:transit.example.com:4001\"]}}"
+        );
+
+        // welcome, requiring hashcash
+        let msg = ServerMessage {
+            id: None,
+            server_tx: 1687594898.0583792,
+            server_rx: None,
+            ty: ServerMessageType::Welcome {
+                welcome: WelcomeInfo {
+                    permission_required: vec![PermissionMethod::Hashcash {
+                        bits: 6,
+                        resource: "abcd".into(),
+                    }],
+                    ..WelcomeInfo::default()
+                },
+            },
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            "{\"server_tx\":1687594898.0583792,\"type\":\"welcome\",\"welcome\":{\"permission_required\":[{\"hashcash\":{\"bits\":6,\"resource\":\"abcd\"}}]}}"
+        );
+
+        // welcome, with methods from a newer server alongside ones we know
+        let json = "{\"server_tx\":1687594898.0583792,\"type\":\"welcome\",\"welcome\":{\"permission_required\":[{\"argon2\":{\"memory\":1024}},\"password\",\"token\"]}}";
+        let msg = serde_json::from_str::<ServerMessage>(json).unwrap();
+        let ServerMessageType::Welcome { welcome } = msg.ty else {
+            panic!("expected welcome");
+        };
+        let names: Vec<_> = welcome
+            .permission_required
+            .iter()
+            .map(PermissionMethod::name)
+            .collect();
+        assert_eq!(names, ["argon2", "password", "token"]);
+        assert!(matches!(
+            welcome.permission_required[..],
+            [
+                PermissionMethod::Unknown(_),
+                PermissionMethod::Unknown(_),
+                PermissionMethod::Token
+            ]
+        ));
+
+        // submit-permissions
+        let msg = ClientMessage {
+            id: "4c2a".into(),
+            ty: ClientMessageType::SubmitPermissions(Permission::Hashcash {
+                stamp: "1:6:230624:abcd::1234:0".into(),
+            }),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, "{\"id\":\"4c2a\",\"type\":\"submit-permissions\",\"method\":\"hashcash\",\"stamp\":\"1:6:230624:abcd::1234:0\"}");
+        let msg = serde_json::from_str::<ClientMessage>(&json).unwrap();
+        assert!(matches!(
+            msg.ty,
+            ClientMessageType::SubmitPermissions(Permission::Hashcash { .. })
+        ));
+
         // bind
         let msg = ClientMessage {
             id: "5d67".into(),
@@ -389,6 +594,40 @@ mod tests {
             "{\"id\":\"d8c1\",\"type\":\"add\",\"phase\":\"0\",\"body\":\"f921\"}"
         );
 
+        // add, while dilating
+        let msg = ClientMessage {
+            id: "d8c2".into(),
+            ty: ClientMessageType::Add {
+                phase: Phase::Dilate(1),
+                body: vec![0xf9, 0x21],
+            },
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            "{\"id\":\"d8c2\",\"type\":\"add\",\"phase\":\"dilate-1\",\"body\":\"f921\"}"
+        );
+        let msg = serde_json::from_str::<ClientMessage>(&json).unwrap();
+        assert!(matches!(
+            msg.ty,
+            ClientMessageType::Add {
+                phase: Phase::Dilate(1),
+                ..
+            }
+        ));
+
+        // add, in a phase from a newer client
+        let json = "{\"id\":\"d8c3\",\"type\":\"add\",\"phase\":\"dilate-x\",\"body\":\"f921\"}";
+        let msg = serde_json::from_str::<ClientMessage>(json).unwrap();
+        assert!(matches!(
+            msg.ty,
+            ClientMessageType::Add {
+                phase: Phase::Unknown(ref phase),
+                ..
+            } if phase == "dilate-x"
+        ));
+        assert_eq!(serde_json::to_string(&msg).unwrap(), json);
+
         // message
         let msg = ServerMessage {
             id: Some("ec1e".into()),
@@ -397,7 +636,7 @@ mod tests {
             ty: ServerMessageType::Message {
                 side: "6d89484e10".into(),
                 phase: Phase::Version,
-                body: vec![0x60, 0x41],
+                body: vec![0x60, 0x41].into(),
             },
         };
         let json = serde_json::to_string(&msg).unwrap();
@@ -431,4 +670,25 @@ mod tests {
             "{\"server_tx\":1687594905.6118436,\"type\":\"closed\"}"
         );
     }
+
+    #[test]
+    fn redaction() {
+        let message = redact(r#"{"type": "add", "phase": "pake", "body": "deadbeef", "id": "1"}"#);
+        assert_eq!(message["type"], "add");
+        assert_eq!(message["body"]["len"], 4);
+        assert_eq!(
+            message["body"]["sha256"],
+            "2baf1f40105d9501fe319a8ec463fdf4325a2a5df445adf3f572f626253678c9"
+        );
+
+        let message =
+            redact(r#"{"type": "submit-permissions", "method": "token", "token": "s3cret"}"#);
+        assert_eq!(message["token"], "<redacted>");
+
+        let message = redact(r#"{"type": "nameplates", "nameplates": [{"id": 1}]}"#);
+        assert_eq!(message["nameplates"], json!([{"id": 1}]));
+
+        let garbage = "x".repeat(MAX_RAW_CHARS * 2);
+        assert_eq!(redact(&garbage).as_str().unwrap().len(), MAX_RAW_CHARS);
+    }
 }
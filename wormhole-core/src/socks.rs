@@ -0,0 +1,196 @@
+/// Connecting through a SOCKS5 proxy, such as the one Tor runs, for networks which only allow
+/// connections out through a proxy, or to hide our address from the mailbox server and peer.
+///
+/// Only what a client needs of RFC 1928 is implemented: no authentication, and `CONNECT` by
+/// hostname, so that names are looked up by the proxy rather than leaking through our own DNS.
+use std::{fmt, io, str::FromStr};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Where Tor listens for SOCKS connections by default.
+pub const TOR: &str = "socks5://127.0.0.1:9050";
+
+/// The version of SOCKS spoken.
+const VERSION: u8 = 5;
+
+/// The only authentication method offered.
+const NO_AUTHENTICATION: u8 = 0;
+
+/// The command asking the proxy to connect to a host.
+const CONNECT: u8 = 1;
+
+/// Address types the proxy may reply with, the address it connected from.
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// Errors generated while parsing a proxy.
+#[derive(Error, Debug)]
+pub enum SocksError {
+    #[error("invalid proxy {0:?}, expected socks5://HOST:PORT")]
+    InvalidProxy(String),
+}
+
+/// A SOCKS5 proxy to make connections through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocksProxy {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for SocksProxy {
+    type Err = SocksError;
+
+    /// Parse a proxy given as `socks5://HOST:PORT`, or `socks5h://HOST:PORT`, which means the
+    /// same here as hostnames are always looked up by the proxy.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SocksError::InvalidProxy(s.to_owned());
+        let (host, port) = s
+            .strip_prefix("socks5://")
+            .or_else(|| s.strip_prefix("socks5h://"))
+            .and_then(|rest| rest.trim_end_matches('/').rsplit_once(':'))
+            .ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SocksProxy {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for SocksProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "socks5://[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "socks5://{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl SocksProxy {
+    /// Connect to the given host and port through the proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+        let mut chosen = [0u8; 2];
+        stream.read_exact(&mut chosen).await?;
+        if chosen != [VERSION, NO_AUTHENTICATION] {
+            return Err(io::Error::other("proxy requires authentication"));
+        }
+
+        let length = u8::try_from(host.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "hostname too long"))?;
+        let mut request = vec![VERSION, CONNECT, 0, ADDRESS_DOMAIN, length];
+        request.extend(host.as_bytes());
+        request.extend(port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // The version, result, a reserved byte, then the address the proxy connected from
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(io::Error::other("unexpected reply from proxy"));
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::other(format!(
+                "proxy couldn't connect to {}:{}: {}",
+                host,
+                port,
+                failure(reply[1])
+            )));
+        }
+        let address_length = match reply[3] {
+            ADDRESS_IPV4 => 4,
+            ADDRESS_IPV6 => 16,
+            ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+            _ => return Err(io::Error::other("unexpected reply from proxy")),
+        };
+        let mut bound = vec![0u8; address_length + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+}
+
+/// What went wrong, from the result in the proxy's reply.
+fn failure(result: u8) -> &'static str {
+    match result {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SocksProxy, TOR};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parsing() {
+        let proxy = TOR.parse::<SocksProxy>().unwrap();
+        assert_eq!(proxy.host, "127.0.0.1");
+        assert_eq!(proxy.port, 9050);
+        assert_eq!(proxy.to_string(), TOR);
+
+        let proxy = "socks5h://[::1]:1080/".parse::<SocksProxy>().unwrap();
+        assert_eq!(proxy.host, "::1");
+        assert_eq!(proxy.to_string(), "socks5://[::1]:1080");
+
+        for invalid in [
+            "127.0.0.1:9050",
+            "http://127.0.0.1:9050",
+            "socks5://:9050",
+            "socks5://host",
+        ] {
+            assert!(invalid.parse::<SocksProxy>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn connecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("socks5://{}", listener.local_addr().unwrap())
+            .parse::<SocksProxy>()
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 5 + 11 + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], [5, 1, 0, 3, 11]);
+            assert_eq!(&request[5..16], b"example.com");
+            assert_eq!(&request[16..], 4001u16.to_be_bytes());
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let mut stream = proxy.connect("example.com", 4001).await.unwrap();
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        server.await.unwrap();
+    }
+}
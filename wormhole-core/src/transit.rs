@@ -0,0 +1,887 @@
+/// Transit: the TCP connection peers use for bulk data such as files, set up by swapping
+/// connection hints over the mailbox.
+///
+/// Each side listens on a port, then sends its peer a `transit` message listing the connection
+/// methods it supports and hints on how to reach it. Both sides then dial every hint the other
+/// offered while accepting connections on their own port. The sender picks the first connection
+/// to be established, writes `go\n` on it and closes the rest, and the receiver uses whichever
+/// connection it sees `go\n` on.
+///
+/// When neither side can reach the other directly, both connect to a transit relay, which pairs
+/// up connections presenting the same token and copies bytes between them.
+///
+/// Every connection opens with each side proving it knows the transit key, derived from the
+/// wormhole's shared key, so only the peer's connections are used. Data is then sent as
+/// length-prefixed records, each encrypted with a key for its direction and a counting nonce.
+use crypto_secretbox::{aead::AeadInPlace, KeyInit, XSalsa20Poly1305};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures_util::future::{BoxFuture, Future};
+use hkdf::Hkdf;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use zeroize::Zeroizing;
+
+use crate::ct;
+use crate::socks::SocksProxy;
+
+/// Longest line the peer may send while agreeing which connection to use.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// How long to give direct connections before also trying relays, if there are any to try.
+const RELAY_DELAY: Duration = Duration::from_secs(2);
+
+/// Largest record the peer may send, including its nonce and authentication tag.
+const MAX_RECORD_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Size of each record's nonce, which counts up from zero.
+const NONCE_LENGTH: usize = 24;
+
+/// Size of each record's authentication tag.
+const TAG_LENGTH: usize = 16;
+
+/// Errors generated while setting up a transit connection.
+#[derive(Error, Debug)]
+pub enum TransitError {
+    #[error("transit I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("no transit connection could be established")]
+    NoConnection,
+    #[error("peer chose a different connection")]
+    NotChosen,
+    #[error("unexpected line from peer: {0:?}")]
+    UnexpectedLine(String),
+    #[error("invalid relay hint {0:?}, expected tcp:HOST:PORT")]
+    InvalidHint(String),
+    #[error("peer's handshake didn't match, it may have used a different code")]
+    BadHandshake,
+    #[error("record of {0} bytes is too large")]
+    RecordTooLarge(usize),
+    #[error("record failed to decrypt, or arrived out of order")]
+    BadRecord,
+}
+
+/// A way of connecting which a side supports.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Ability {
+    /// Connecting directly over TCP.
+    #[serde(rename = "direct-tcp-v1")]
+    DirectTcpV1,
+    /// Connecting through a transit relay.
+    #[serde(rename = "relay-v1")]
+    RelayV1,
+    /// Anything this implementation doesn't support.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A hint on how a side might be reached.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Hint {
+    /// Connect directly over TCP.
+    #[serde(rename = "direct-tcp-v1")]
+    DirectTcpV1(DirectHint),
+    /// Connect through a transit relay.
+    #[serde(rename = "relay-v1")]
+    RelayV1(RelayHint),
+    /// A kind of hint this implementation doesn't support.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A host and port which may accept a direct TCP connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectHint {
+    /// Hints with higher priorities are tried first.
+    #[serde(default)]
+    pub priority: f64,
+    pub hostname: String,
+    pub port: u16,
+}
+
+/// A transit relay, which may be reachable in several ways.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelayHint {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub hints: Vec<Hint>,
+}
+
+impl FromStr for RelayHint {
+    type Err = TransitError;
+
+    /// Parse a relay given as `tcp:HOST:PORT`, as advertised by mailbox servers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransitError::InvalidHint(s.to_owned());
+        let (host, port) = s
+            .strip_prefix("tcp:")
+            .and_then(|rest| rest.rsplit_once(':'))
+            .ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(RelayHint {
+            name: None,
+            hints: vec![Hint::DirectTcpV1(DirectHint {
+                priority: 0.0,
+                hostname: host.to_owned(),
+                port: port.parse().map_err(|_| invalid())?,
+            })],
+        })
+    }
+}
+
+/// The body of a `transit` message, sent to the peer over the mailbox.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransitInfo {
+    #[serde(rename = "abilities-v1")]
+    pub abilities: Vec<Ability>,
+    #[serde(rename = "hints-v1")]
+    pub hints: Vec<Hint>,
+}
+
+/// Which end of the transfer a side is, which decides who picks the connection to use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// How a transit connection reached the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    /// Directly, to or from the given address.
+    Direct(String),
+    /// Through the relay at the given address.
+    Relay(String),
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Direct(addr) => write!(f, "direct connection with {}", addr),
+            Route::Relay(addr) => write!(f, "transit relay at {}", addr),
+        }
+    }
+}
+
+/// An encrypted connection to the peer, ready for use.
+pub struct TransitConnection {
+    stream: TcpStream,
+    route: Route,
+    /// Cipher for the records we send.
+    send_cipher: XSalsa20Poly1305,
+    /// Cipher for the records the peer sends.
+    receive_cipher: XSalsa20Poly1305,
+    /// Nonce for the next record we send.
+    send_nonce: u64,
+    /// Nonce the peer's next record must have.
+    receive_nonce: u64,
+    /// Records are framed, encrypted and decrypted in here, so memory use stays the same
+    /// however much is transferred.
+    buffer: Vec<u8>,
+}
+
+impl fmt::Debug for TransitConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransitConnection")
+            .field("stream", &self.stream)
+            .field("route", &self.route)
+            .field("send_nonce", &self.send_nonce)
+            .field("receive_nonce", &self.receive_nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TransitConnection {
+    /// Set up the record ciphers for a connection, once it has been agreed on.
+    fn new(stream: TcpStream, route: Route, role: Role, key: &[u8]) -> Self {
+        let sender_key = derive(key, b"transit_record_sender_key");
+        let receiver_key = derive(key, b"transit_record_receiver_key");
+        let (send_key, receive_key) = match role {
+            Role::Sender => (sender_key, receiver_key),
+            Role::Receiver => (receiver_key, sender_key),
+        };
+        TransitConnection {
+            stream,
+            route,
+            send_cipher: XSalsa20Poly1305::new(&send_key.into()),
+            receive_cipher: XSalsa20Poly1305::new(&receive_key.into()),
+            send_nonce: 0,
+            receive_nonce: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// How the connection reached the peer.
+    pub fn route(&self) -> &Route {
+        &self.route
+    }
+
+    /// Encrypt and send a record.
+    pub async fn send_record(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
+        let length = NONCE_LENGTH + TAG_LENGTH + plaintext.len();
+        if length > MAX_RECORD_LENGTH {
+            return Err(TransitError::RecordTooLarge(length));
+        }
+        let nonce = record_nonce(self.send_nonce);
+        self.send_nonce += 1;
+
+        // The length, nonce, then the tag before the ciphertext, as secretbox lays them out
+        self.buffer.clear();
+        self.buffer.extend((length as u32).to_be_bytes());
+        self.buffer.extend(nonce);
+        self.buffer.extend([0u8; TAG_LENGTH]);
+        self.buffer.extend(plaintext);
+        let (header, ciphertext) = self.buffer.split_at_mut(4 + NONCE_LENGTH + TAG_LENGTH);
+        let tag = self
+            .send_cipher
+            .encrypt_in_place_detached(&nonce.into(), b"", ciphertext)
+            .map_err(|_| TransitError::BadRecord)?;
+        header[4 + NONCE_LENGTH..].copy_from_slice(&tag);
+        self.stream.write_all(&self.buffer).await?;
+        Ok(())
+    }
+
+    /// Receive and decrypt the next record, checking it's the one expected next. The record is
+    /// only valid until the next one is sent or received.
+    pub async fn receive_record(&mut self) -> Result<&[u8], TransitError> {
+        let length = self.stream.read_u32().await? as usize;
+        if length > MAX_RECORD_LENGTH {
+            return Err(TransitError::RecordTooLarge(length));
+        }
+        if length < NONCE_LENGTH + TAG_LENGTH {
+            return Err(TransitError::BadRecord);
+        }
+        self.buffer.resize(length, 0);
+        self.stream.read_exact(&mut self.buffer).await?;
+        let (header, ciphertext) = self.buffer.split_at_mut(NONCE_LENGTH + TAG_LENGTH);
+        let (nonce, tag) = header.split_at(NONCE_LENGTH);
+        if nonce != record_nonce(self.receive_nonce) {
+            return Err(TransitError::BadRecord);
+        }
+        self.receive_nonce += 1;
+        self.receive_cipher
+            .decrypt_in_place_detached(nonce.into(), b"", ciphertext, tag.into())
+            .map_err(|_| TransitError::BadRecord)?;
+        Ok(ciphertext)
+    }
+}
+
+/// The nonce of the record with the given number: the number as a big-endian integer.
+fn record_nonce(number: u64) -> [u8; NONCE_LENGTH] {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    nonce[NONCE_LENGTH - 8..].copy_from_slice(&number.to_be_bytes());
+    nonce
+}
+
+/// Derive the transit key for a wormhole from its shared key.
+pub fn transit_key(key: &[u8], app_id: &str) -> Vec<u8> {
+    derive(key, format!("{}/transit-key", app_id).as_bytes()).to_vec()
+}
+
+/// One side's half of setting up a transit connection, listening for the peer to connect.
+#[derive(Debug)]
+pub struct Transit {
+    role: Role,
+    /// Our side, as used on the mailbox, to tell the relay which connection is ours.
+    side: String,
+    /// Transit key shared with the peer, from which the relay token, handshakes and record
+    /// keys are derived.
+    key: Zeroizing<Vec<u8>>,
+    listener: TcpListener,
+    hints: Vec<Hint>,
+    /// Relays we're willing to use.
+    relays: Vec<RelayHint>,
+    /// The proxy to connect through, if any.
+    proxy: Option<SocksProxy>,
+}
+
+impl Transit {
+    /// Listen on an arbitrary port of every local address, for a connection with the peer
+    /// sharing the given transit key.
+    pub async fn listen(role: Role, side: &str, key: &[u8]) -> io::Result<Self> {
+        let (listener, hints) = listen().await?;
+        Ok(Transit {
+            role,
+            side: side.to_owned(),
+            key: Zeroizing::new(key.to_vec()),
+            listener,
+            hints,
+            relays: Vec::new(),
+            proxy: None,
+        })
+    }
+
+    /// Also offer to meet the peer at the given relay, if connecting directly fails.
+    pub fn with_relay(mut self, relay: RelayHint) -> Self {
+        self.relays.push(relay);
+        self
+    }
+
+    /// Make every connection through the given proxy. The peer isn't told our addresses, as
+    /// it can't connect through the proxy to us, and they may be meant to stay hidden.
+    pub fn with_proxy(mut self, proxy: SocksProxy) -> Self {
+        self.hints.clear();
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// The message telling the peer how to reach us.
+    pub fn info(&self) -> TransitInfo {
+        TransitInfo {
+            abilities: vec![Ability::DirectTcpV1, Ability::RelayV1],
+            hints: self
+                .hints
+                .iter()
+                .cloned()
+                .chain(self.relays.iter().cloned().map(Hint::RelayV1))
+                .collect(),
+        }
+    }
+
+    /// Connect to the peer, using the hints it sent us while accepting connections from it,
+    /// giving up once the timeout passes.
+    pub async fn connect(
+        self,
+        peer: &TransitInfo,
+        timeout: Duration,
+    ) -> Result<TransitConnection, TransitError> {
+        tokio::time::timeout(timeout, self.race(peer))
+            .await
+            .map_err(|_| TransitError::NoConnection)?
+    }
+
+    /// Try every way of connecting at once, returning the first connection agreed on.
+    async fn race(self, peer: &TransitInfo) -> Result<TransitConnection, TransitError> {
+        let role = self.role;
+        let handshake = Handshake::new(role, &self.key);
+        let (stream, route) = race(
+            &self.listener,
+            &peer.hints,
+            &self.relays,
+            relay_request(&self.key, &self.side),
+            self.proxy.as_ref(),
+            |stream, route| {
+                let handshake = handshake.clone();
+                async move { agree(&handshake, stream, route).await }
+            },
+            |(stream, route)| async move { Ok((choose(role, stream).await?, route)) },
+        )
+        .await?;
+        Ok(TransitConnection::new(stream, route, role, &self.key))
+    }
+}
+
+/// Listen on an arbitrary port of every local address, returning the hints for reaching it.
+pub(crate) async fn listen() -> io::Result<(TcpListener, Vec<Hint>)> {
+    let listener = match TcpListener::bind("[::]:0").await {
+        Ok(listener) => listener,
+        Err(_) => TcpListener::bind("0.0.0.0:0").await?,
+    };
+    let local = listener.local_addr()?;
+    let hints = local_addresses()
+        .into_iter()
+        .filter(|ip| local.is_ipv6() || ip.is_ipv4())
+        .map(|ip| {
+            Hint::DirectTcpV1(DirectHint {
+                priority: 0.0,
+                hostname: ip.to_string(),
+                port: local.port(),
+            })
+        })
+        .collect();
+    Ok((listener, hints))
+}
+
+/// What to ask a relay for, to be paired with the peer's connection presenting the same key.
+pub(crate) fn relay_request(key: &[u8], side: &str) -> String {
+    format!(
+        "please relay {} for side {}\n",
+        hex::encode(derive(key, b"transit_relay_token")),
+        side
+    )
+}
+
+/// Connect to the given host and port, through the proxy if there is one.
+async fn dial(proxy: Option<&SocksProxy>, host: &str, port: u16) -> io::Result<TcpStream> {
+    match proxy {
+        Some(proxy) => proxy.connect(host, port).await,
+        None => TcpStream::connect((host, port)).await,
+    }
+}
+
+/// Dial every hint the peer gave us while accepting connections on the listener, setting up
+/// each connection with `establish`, and return the first one set up once `choose` has picked
+/// it. Relays, ours and the peer's, are asked for a connection with the given request, and
+/// are only tried once direct connections have had a head start. Hints are dialled through
+/// the proxy, if there is one. No connection failing, or failing to be accepted, stops the
+/// others, so the race goes on until one is chosen or the caller gives up.
+pub(crate) async fn race<T, E, Established, Chosen>(
+    listener: &TcpListener,
+    peer_hints: &[Hint],
+    relays: &[RelayHint],
+    relay_request: String,
+    proxy: Option<&SocksProxy>,
+    establish: impl Fn(TcpStream, Route) -> Established,
+    choose: impl Fn(T) -> Chosen,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    Established: Future<Output = Result<T, E>> + Send + 'static,
+    Chosen: Future<Output = Result<T, E>>,
+{
+    let mut connecting =
+        FuturesUnordered::<BoxFuture<Result<(TcpStream, Route), TransitError>>>::new();
+    let mut direct = direct_hints(peer_hints);
+    direct.sort_by(|a, b| b.priority.total_cmp(&a.priority));
+    let relay_delay = if direct.is_empty() {
+        Duration::ZERO
+    } else {
+        RELAY_DELAY
+    };
+    for hint in direct {
+        let proxy = proxy.cloned();
+        connecting.push(Box::pin(async move {
+            let stream = dial(proxy.as_ref(), &hint.hostname, hint.port).await?;
+            let route = Route::Direct(format!("{}:{}", hint.hostname, hint.port));
+            debug!("Connected to {}", route);
+            Ok((stream, route))
+        }));
+    }
+
+    let peer_relays = peer_hints.iter().filter_map(|hint| match hint {
+        Hint::RelayV1(relay) => Some(relay),
+        _ => None,
+    });
+    let mut relay_hints = Vec::<DirectHint>::new();
+    for hint in peer_relays
+        .chain(relays)
+        .flat_map(|relay| direct_hints(&relay.hints))
+    {
+        if !relay_hints
+            .iter()
+            .any(|r| r.hostname == hint.hostname && r.port == hint.port)
+        {
+            relay_hints.push(hint);
+        }
+    }
+    for hint in relay_hints {
+        let request = relay_request.clone();
+        let proxy = proxy.cloned();
+        connecting.push(Box::pin(async move {
+            tokio::time::sleep(relay_delay).await;
+            let mut stream = dial(proxy.as_ref(), &hint.hostname, hint.port).await?;
+            let route = Route::Relay(format!("{}:{}", hint.hostname, hint.port));
+            debug!("Connected to {}", route);
+            stream.write_all(request.as_bytes()).await?;
+            match read_line(&mut stream).await?.as_str() {
+                "ok" => Ok((stream, route)),
+                line => Err(TransitError::UnexpectedLine(line.to_owned())),
+            }
+        }));
+    }
+
+    let mut establishing = FuturesUnordered::<BoxFuture<Result<T, E>>>::new();
+    loop {
+        tokio::select! {
+            Some(result) = connecting.next() => match result {
+                Ok((stream, route)) => establishing.push(Box::pin(establish(stream, route))),
+                Err(e) => debug!("Transit connection failed: {}", e),
+            },
+            Some(result) = establishing.next() => match result {
+                Ok(established) => match choose(established).await {
+                    Ok(chosen) => return Ok(chosen),
+                    Err(e) => debug!("Transit connection failed: {}", e),
+                },
+                Err(e) => debug!("Transit connection failed: {}", e),
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    let route = Route::Direct(addr.to_string());
+                    debug!("Accepted {}", route);
+                    establishing.push(Box::pin(establish(stream, route)));
+                }
+                // Such as a connection reset before we got to it, which needn't stop the others
+                Err(e) => warn!("Accepting a transit connection failed: {}", e),
+            },
+        }
+    }
+}
+
+/// Derive a subkey for the given purpose from the transit key.
+pub(crate) fn derive(key: &[u8], purpose: &[u8]) -> [u8; 32] {
+    let mut derived = [0u8; 32];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(purpose, &mut derived)
+        .expect("32 bytes is a valid HKDF length");
+    derived
+}
+
+/// The lines each side opens a connection with, proving it knows the transit key.
+#[derive(Debug, Clone)]
+struct Handshake {
+    role: Role,
+    ours: Vec<u8>,
+    theirs: Vec<u8>,
+}
+
+impl Handshake {
+    fn new(role: Role, key: &[u8]) -> Self {
+        let sender = format!(
+            "transit sender {} ready\n\n",
+            hex::encode(derive(key, b"transit_sender"))
+        );
+        let receiver = format!(
+            "transit receiver {} ready\n\n",
+            hex::encode(derive(key, b"transit_receiver"))
+        );
+        let (ours, theirs) = match role {
+            Role::Sender => (sender, receiver),
+            Role::Receiver => (receiver, sender),
+        };
+        Handshake {
+            role,
+            ours: ours.into_bytes(),
+            theirs: theirs.into_bytes(),
+        }
+    }
+}
+
+/// Get a newly established connection ready to be chosen, once both sides have shown they
+/// know the transit key. The receiver then waits to hear that the sender chose it.
+async fn agree(
+    handshake: &Handshake,
+    mut stream: TcpStream,
+    route: Route,
+) -> Result<(TcpStream, Route), TransitError> {
+    stream.write_all(&handshake.ours).await?;
+    let mut theirs = vec![0u8; handshake.theirs.len()];
+    stream.read_exact(&mut theirs).await?;
+    if !ct::eq(&theirs, &handshake.theirs) {
+        return Err(TransitError::BadHandshake);
+    }
+    match handshake.role {
+        Role::Sender => Ok((stream, route)),
+        Role::Receiver => match read_line(&mut stream).await?.as_str() {
+            "go" => Ok((stream, route)),
+            "nevermind" => Err(TransitError::NotChosen),
+            line => Err(TransitError::UnexpectedLine(line.to_owned())),
+        },
+    }
+}
+
+/// Use the first connection which is ready. The sender tells the receiver which one it chose.
+async fn choose(role: Role, mut stream: TcpStream) -> Result<TcpStream, TransitError> {
+    if role == Role::Sender {
+        stream.write_all(b"go\n").await?;
+    }
+    Ok(stream)
+}
+
+/// Read a newline-terminated line, without reading anything beyond it.
+pub(crate) async fn read_line(stream: &mut TcpStream) -> Result<String, TransitError> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            return Ok(String::from_utf8_lossy(&line).into_owned());
+        }
+        if line.len() == MAX_LINE_LENGTH {
+            return Err(TransitError::UnexpectedLine(
+                String::from_utf8_lossy(&line).into_owned(),
+            ));
+        }
+        line.push(byte);
+    }
+}
+
+/// The direct TCP hints among the given ones.
+fn direct_hints(hints: &[Hint]) -> Vec<DirectHint> {
+    hints
+        .iter()
+        .filter_map(|hint| match hint {
+            Hint::DirectTcpV1(hint) => Some(hint.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// This machine's addresses which a peer might reach it on. Loopback addresses are only used
+/// if there are no others, and IPv6 link-local addresses never are, as they need a scope.
+fn local_addresses() -> Vec<IpAddr> {
+    let addresses = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| !ip.is_loopback())
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) != 0xfe80,
+        })
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+    } else {
+        addresses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        derive, read_line, record_nonce, transit_key, Ability, DirectHint, Handshake, Hint,
+        RelayHint, Role, Route, Transit, TransitConnection, TransitError, TransitInfo,
+    };
+    use crypto_secretbox::{aead::Aead, KeyInit, XSalsa20Poly1305};
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const KEY: &[u8] = b"transit key";
+
+    #[test]
+    fn reference_derivations() {
+        // Computed by a standalone reimplementation of magic-wormhole's derivations, not by
+        // magic-wormhole itself
+        let vectors: serde_json::Value = serde_json::from_str(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/kdf.json"
+        )))
+        .unwrap();
+        for vector in vectors["transit"].as_array().unwrap() {
+            let key = hex::decode(vector["transit_key"].as_str().unwrap()).unwrap();
+            let handshake = Handshake::new(Role::Sender, &key);
+            assert_eq!(
+                handshake.ours,
+                vector["sender_handshake"].as_str().unwrap().as_bytes()
+            );
+            assert_eq!(
+                handshake.theirs,
+                vector["receiver_handshake"].as_str().unwrap().as_bytes()
+            );
+            for (purpose, name) in [
+                (&b"transit_record_sender_key"[..], "sender_record_key"),
+                (b"transit_record_receiver_key", "receiver_record_key"),
+            ] {
+                assert_eq!(
+                    hex::encode(derive(&key, purpose)),
+                    vector[name].as_str().unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn info_encoding() {
+        let info = TransitInfo {
+            abilities: vec![Ability::DirectTcpV1],
+            hints: vec![Hint::DirectTcpV1(DirectHint {
+                priority: 0.0,
+                hostname: "192.0.2.1".into(),
+                port: 4001,
+            })],
+        };
+        let encoded = r#"{"abilities-v1":[{"type":"direct-tcp-v1"}],"hints-v1":[{"type":"direct-tcp-v1","priority":0.0,"hostname":"192.0.2.1","port":4001}]}"#;
+        assert_eq!(serde_json::to_string(&info).unwrap(), encoded);
+        assert_eq!(serde_json::from_str::<TransitInfo>(encoded).unwrap(), info);
+
+        // Other implementations' abilities and hints are ignored
+        let info = serde_json::from_str::<TransitInfo>(
+            r#"{"abilities-v1":[{"type":"tor-tcp-v1"}],"hints-v1":[{"type":"tor-tcp-v1","hostname":"example.onion","port":80}]}"#,
+        )
+        .unwrap();
+        assert_eq!(info.abilities, vec![Ability::Unknown]);
+        assert_eq!(info.hints, vec![Hint::Unknown]);
+
+        let relay = r#"{"type":"relay-v1","hints":[{"type":"direct-tcp-v1","priority":0.0,"hostname":"relay.example.com","port":4001}]}"#;
+        assert_eq!(
+            serde_json::from_str::<Hint>(relay).unwrap(),
+            Hint::RelayV1("tcp:relay.example.com:4001".parse().unwrap())
+        );
+        assert_eq!(
+            serde_json::to_string(&Hint::RelayV1(
+                "tcp:relay.example.com:4001".parse().unwrap()
+            ))
+            .unwrap(),
+            relay
+        );
+    }
+
+    #[test]
+    fn relay_hint_parsing() {
+        let hint = "tcp:[2001:db8::1]:4001".parse::<RelayHint>().unwrap();
+        assert_eq!(
+            hint.hints,
+            vec![Hint::DirectTcpV1(DirectHint {
+                priority: 0.0,
+                hostname: "2001:db8::1".into(),
+                port: 4001,
+            })]
+        );
+        assert!("relay.example.com:4001".parse::<RelayHint>().is_err());
+        assert!("tcp:relay.example.com".parse::<RelayHint>().is_err());
+        assert!("tcp::4001".parse::<RelayHint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn direct_connection() {
+        let sender = Transit::listen(Role::Sender, "side1", KEY).await.unwrap();
+        let receiver = Transit::listen(Role::Receiver, "side2", KEY).await.unwrap();
+        let (sender_info, receiver_info) = (sender.info(), receiver.info());
+        assert!(!sender_info.hints.is_empty());
+
+        let timeout = Duration::from_secs(5);
+        let (sender, receiver) = tokio::join!(
+            sender.connect(&receiver_info, timeout),
+            receiver.connect(&sender_info, timeout)
+        );
+        let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+        assert!(matches!(sender.route(), Route::Direct(_)));
+        sender.send_record(b"hello").await.unwrap();
+        sender.send_record(b"").await.unwrap();
+        receiver.send_record(b"ok").await.unwrap();
+        assert_eq!(receiver.receive_record().await.unwrap(), b"hello");
+        assert_eq!(receiver.receive_record().await.unwrap(), b"");
+        assert_eq!(sender.receive_record().await.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn record_format() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stream, accepted) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let mut sender = TransitConnection::new(
+            stream.unwrap(),
+            Route::Direct(address.to_string()),
+            Role::Sender,
+            KEY,
+        );
+        let (mut peer, _) = accepted.unwrap();
+
+        // A length, then a nonce and secretbox
+        sender.send_record(b"hello").await.unwrap();
+        sender.send_record(b"world").await.unwrap();
+        let cipher = XSalsa20Poly1305::new(&derive(KEY, b"transit_record_sender_key").into());
+        for (number, expected) in [(0, b"hello"), (1, b"world")] {
+            let length = peer.read_u32().await.unwrap() as usize;
+            let mut record = vec![0u8; length];
+            peer.read_exact(&mut record).await.unwrap();
+            let (nonce, secretbox) = record.split_at(24);
+            assert_eq!(nonce, record_nonce(number));
+            assert_eq!(
+                cipher.decrypt(nonce.into(), secretbox).unwrap(),
+                expected.to_vec()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_key() {
+        let sender = Transit::listen(Role::Sender, "side1", KEY).await.unwrap();
+        let receiver = Transit::listen(Role::Receiver, "side2", b"other key")
+            .await
+            .unwrap();
+        let (sender_info, receiver_info) = (sender.info(), receiver.info());
+
+        let timeout = Duration::from_millis(500);
+        let (sender, receiver) = tokio::join!(
+            sender.connect(&receiver_info, timeout),
+            receiver.connect(&sender_info, timeout)
+        );
+        assert!(matches!(sender, Err(TransitError::NoConnection)));
+        assert!(matches!(receiver, Err(TransitError::NoConnection)));
+    }
+
+    #[test]
+    fn key_derivation() {
+        assert_eq!(
+            hex::encode(transit_key(b"key", "appid")),
+            "a0aae1d973be138deeaefc5d5fdf4d0c453c288dda5dce8f81a96b9164bd4382"
+        );
+        let handshake = Handshake::new(Role::Sender, KEY);
+        assert_eq!(
+            handshake.ours,
+            b"transit sender dde924916808542c5af460e8c508015041afd0e85454e5a094bf0c511fc984ed ready\n\n"
+        );
+        assert_eq!(Handshake::new(Role::Receiver, KEY).theirs, handshake.ours);
+    }
+
+    #[tokio::test]
+    async fn relayed_connection() {
+        // A relay which pairs the first two connections, if they present the same token
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_hint = format!("tcp:127.0.0.1:{}", relay.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let (mut first, _) = relay.accept().await.unwrap();
+            let (mut second, _) = relay.accept().await.unwrap();
+            let first_request = read_line(&mut first).await.unwrap();
+            let second_request = read_line(&mut second).await.unwrap();
+            let token = |request: &str| request.split(' ').nth(2).unwrap().to_owned();
+            assert_eq!(token(&first_request), token(&second_request));
+            assert_ne!(first_request, second_request);
+            first.write_all(b"ok\n").await.unwrap();
+            second.write_all(b"ok\n").await.unwrap();
+            tokio::io::copy_bidirectional(&mut first, &mut second)
+                .await
+                .unwrap();
+        });
+
+        // Neither side offers direct hints, and only the sender knows of the relay
+        let sender = Transit::listen(Role::Sender, "side1", KEY)
+            .await
+            .unwrap()
+            .with_relay(relay_hint.parse().unwrap());
+        let receiver = Transit::listen(Role::Receiver, "side2", KEY).await.unwrap();
+        let only_relays = |info: TransitInfo| TransitInfo {
+            hints: info
+                .hints
+                .into_iter()
+                .filter(|hint| matches!(hint, Hint::RelayV1(_)))
+                .collect(),
+            ..info
+        };
+        let (sender_info, receiver_info) =
+            (only_relays(sender.info()), only_relays(receiver.info()));
+
+        let timeout = Duration::from_secs(5);
+        let (sender, receiver) = tokio::join!(
+            sender.connect(&receiver_info, timeout),
+            receiver.connect(&sender_info, timeout)
+        );
+        let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+        assert_eq!(sender.route(), &Route::Relay(relay_hint[4..].to_owned()));
+        assert_eq!(receiver.route(), sender.route());
+        receiver.send_record(b"hello").await.unwrap();
+        assert_eq!(sender.receive_record().await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn no_connection() {
+        let sender = Transit::listen(Role::Sender, "side1", KEY).await.unwrap();
+        let peer = TransitInfo {
+            abilities: vec![Ability::DirectTcpV1],
+            hints: vec![],
+        };
+        assert!(sender
+            .connect(&peer, Duration::from_millis(100))
+            .await
+            .is_err());
+    }
+}
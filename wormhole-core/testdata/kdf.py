@@ -0,0 +1,104 @@
+"""Generate kdf.json, the key derivation test vectors, from the Python implementation.
+
+Every value comes from magic-wormhole itself: wormhole._key for the phase keys and verifier,
+wormhole.util.HKDF beneath them, wormhole.transit for the transit handshakes and record keys,
+and the dilation manager for the dilation key. Install the pinned releases from
+requirements.txt first, then run from this directory: python3 kdf.py > kdf.json
+"""
+
+import hashlib
+import json
+from importlib.metadata import version
+from types import SimpleNamespace
+
+from wormhole._dilation.manager import Dilator
+from wormhole._key import derive_key, derive_phase_key
+from wormhole.transit import (
+    TRANSIT_KEY_LENGTH,
+    TransitReceiver,
+    TransitSender,
+    build_receiver_handshake,
+    build_sender_handshake,
+)
+from wormhole.util import HKDF
+
+
+def phase_purpose(side, phase):
+    # derive_phase_key builds this inline, so it's checked against it below
+    return (
+        b"wormhole:phase:"
+        + hashlib.sha256(side.encode("ascii")).digest()
+        + hashlib.sha256(phase.encode("ascii")).digest()
+    )
+
+
+def phase_vector(key, side, phase):
+    purpose = phase_purpose(side, phase)
+    phase_key = derive_phase_key(key, side, phase)
+    assert derive_key(key, purpose) == phase_key
+    assert HKDF(key, len(phase_key), CTXinfo=purpose) == phase_key
+    return {
+        "key": key.hex(),
+        "side": side,
+        "phase": phase,
+        "purpose": purpose.hex(),
+        "phase_key": phase_key.hex(),
+    }
+
+
+def transit_vector(key, app_id):
+    # As the sending and receiving commands derive it from the wormhole
+    transit_key = derive_key(key, app_id.encode("ascii") + b"/transit-key", TRANSIT_KEY_LENGTH)
+    sender = TransitSender(None, no_listen=True)
+    sender.set_transit_key(transit_key)
+    receiver = TransitReceiver(None, no_listen=True)
+    receiver.set_transit_key(transit_key)
+    # Each side seals its records with its own key, and opens the other's with the other's
+    assert sender._receiver_record_key() == receiver._sender_record_key()
+    assert receiver._receiver_record_key() == sender._sender_record_key()
+    return {
+        "key": key.hex(),
+        "app_id": app_id,
+        "transit_key": transit_key.hex(),
+        "sender_handshake": build_sender_handshake(transit_key).decode("ascii"),
+        "receiver_handshake": build_receiver_handshake(transit_key).decode("ascii"),
+        "sender_record_key": sender._sender_record_key().hex(),
+        "receiver_record_key": receiver._sender_record_key().hex(),
+    }
+
+
+def dilation_key(key):
+    # Dilator.got_key only derives the key from the wormhole's and keeps it, so it needs none of
+    # the rest of a Dilator
+    dilator = SimpleNamespace()
+    Dilator.got_key(dilator, key)
+    return dilator._transit_key
+
+
+KEYS = [b"password", bytes(range(32)), hashlib.sha256(b"wormhole").digest()]
+SIDES = ["abcd1234", "0123456789abcdef"]
+PHASES = ["pake", "version", "0", "1", "17", "dilate-0", "dilate-3"]
+APP_IDS = ["lothar.com/wormhole/text-or-file-xfer", "example.com/app"]
+
+phases = [phase_vector(key, side, phase) for key in KEYS for side in SIDES for phase in PHASES]
+
+verifiers = [
+    {"key": key.hex(), "verifier": derive_key(key, b"wormhole:verifier").hex()} for key in KEYS
+]
+
+transit = [transit_vector(key, app_id) for key in KEYS for app_id in APP_IDS]
+
+dilation = [{"key": key.hex(), "dilation_key": dilation_key(key).hex()} for key in KEYS]
+
+print(
+    json.dumps(
+        {
+            "generator": {"magic-wormhole": version("magic-wormhole")},
+            "phases": phases,
+            "verifiers": verifiers,
+            "transit": transit,
+            "dilation": dilation,
+        },
+        indent=2,
+    )
+)
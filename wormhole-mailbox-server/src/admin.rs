@@ -0,0 +1,216 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use serde::Serialize;
+use std::{sync::Arc, time::Instant};
+
+use crate::server::{tokens_match, ConnectionInfo, MailboxServer};
+
+/// State shared by the admin routes.
+#[derive(Debug, Clone)]
+struct Admin {
+    server: Arc<MailboxServer>,
+    /// The bearer token every request must carry.
+    token: Arc<str>,
+}
+
+/// An application namespace and everything active in it.
+#[derive(Debug, Serialize)]
+struct AppListing {
+    app_id: String,
+    nameplates: Vec<NameplateListing>,
+    mailboxes: Vec<MailboxListing>,
+}
+
+#[derive(Debug, Serialize)]
+struct NameplateListing {
+    id: usize,
+    mailbox_id: String,
+    sides: Vec<String>,
+    /// Seconds since the nameplate was created.
+    age: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct MailboxListing {
+    id: String,
+    messages: usize,
+    bytes: usize,
+    /// Sides currently subscribed to the mailbox.
+    subscribers: Vec<String>,
+    /// Seconds since the mailbox was last opened or added to.
+    idle: u64,
+}
+
+/// List every application namespace, with its nameplates and mailboxes.
+fn list_apps(server: &MailboxServer) -> Vec<AppListing> {
+    let now = Instant::now();
+    let mut apps = server
+        .all_apps()
+        .iter()
+        .map(|app| {
+            let app = app.lock().unwrap();
+            let mut nameplates = app
+                .nameplates
+                .iter()
+                .map(|(id, nameplate)| NameplateListing {
+                    id: *id,
+                    mailbox_id: nameplate.mailbox_id.clone(),
+                    sides: nameplate.sides.clone(),
+                    age: now.duration_since(nameplate.created).as_secs(),
+                })
+                .collect::<Vec<_>>();
+            nameplates.sort_by_key(|n| n.id);
+            let mut mailboxes = app
+                .mailboxes
+                .iter()
+                .map(|(id, mailbox)| MailboxListing {
+                    id: id.clone(),
+                    messages: mailbox.messages.len(),
+                    bytes: mailbox.stored_bytes,
+                    subscribers: mailbox.subscribers.iter().map(|s| s.side.clone()).collect(),
+                    idle: now.duration_since(mailbox.last_activity).as_secs(),
+                })
+                .collect::<Vec<_>>();
+            mailboxes.sort_by(|a, b| a.id.cmp(&b.id));
+            AppListing {
+                app_id: app.app_id.clone(),
+                nameplates,
+                mailboxes,
+            }
+        })
+        .collect::<Vec<_>>();
+    apps.sort_by(|a, b| a.app_id.cmp(&b.app_id));
+    apps
+}
+
+/// Serve `GET /apps`.
+async fn apps(State(admin): State<Admin>) -> Json<Vec<AppListing>> {
+    Json(list_apps(&admin.server))
+}
+
+/// Serve `GET /connections`.
+async fn connections(State(admin): State<Admin>) -> Json<Vec<ConnectionInfo>> {
+    Json(admin.server.connections())
+}
+
+/// Serve `DELETE /apps/{app_id}/nameplates/{nameplate_id}`.
+async fn expire_nameplate(
+    State(admin): State<Admin>,
+    Path((app_id, nameplate_id)): Path<(String, usize)>,
+) -> StatusCode {
+    if admin.server.expire_nameplate(&app_id, nameplate_id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Serve `DELETE /apps/{app_id}/mailboxes/{mailbox_id}`.
+async fn close_mailbox(
+    State(admin): State<Admin>,
+    Path((app_id, mailbox_id)): Path<(String, String)>,
+) -> StatusCode {
+    if admin.server.close_mailbox(&app_id, &mailbox_id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Does the request carry the admin token?
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|submitted| tokens_match(token, submitted))
+}
+
+/// Turn away any request without the admin token.
+async fn authorize(State(admin): State<Admin>, request: Request, next: Next) -> Response {
+    if authorized(request.headers(), &admin.token) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// The HTTP routes for inspecting and expiring the server's state. Every request must carry
+/// the given token as `Authorization: Bearer <token>`.
+///
+/// App IDs usually contain slashes, so must be percent-encoded in paths.
+pub fn router(server: Arc<MailboxServer>, token: &str) -> Router {
+    let admin = Admin {
+        server,
+        token: token.into(),
+    };
+    Router::new()
+        .route("/apps", get(apps))
+        .route(
+            "/apps/:app_id/nameplates/:nameplate_id",
+            delete(expire_nameplate),
+        )
+        .route("/apps/:app_id/mailboxes/:mailbox_id", delete(close_mailbox))
+        .route("/connections", get(connections))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authorized, list_apps};
+    use crate::server::{Connection, MailboxServer};
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use futures_channel::mpsc::unbounded;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn authorization() {
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, "s3cret"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("s3cret"));
+        assert!(!authorized(&headers, "s3cret"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer wrong"),
+        );
+        assert!(!authorized(&headers, "s3cret"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer s3cret"),
+        );
+        assert!(authorized(&headers, "s3cret"));
+    }
+
+    #[test]
+    fn listing() {
+        let server = MailboxServer::default();
+        let (tx, _rx) = unbounded();
+        let mut conn = Connection::new(tx, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        server.connect(&conn).unwrap();
+        server.bind(&mut conn, "appid", "side1").unwrap();
+        server.allocate(&mut conn).unwrap();
+
+        let apps = list_apps(&server);
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].app_id, "appid");
+        assert_eq!(apps[0].nameplates.len(), 1);
+        assert_eq!(apps[0].nameplates[0].sides, vec!["side1"]);
+
+        let mailbox_id = apps[0].nameplates[0].mailbox_id.clone();
+        server.open(&mut conn, &mailbox_id).unwrap();
+        let apps = list_apps(&server);
+        assert_eq!(apps[0].mailboxes.len(), 1);
+        assert_eq!(apps[0].mailboxes[0].id, mailbox_id);
+        assert_eq!(apps[0].mailboxes[0].subscribers, vec!["side1"]);
+    }
+}
@@ -0,0 +1,1631 @@
+use data_encoding::BASE32;
+use futures_channel::mpsc::UnboundedSender;
+use rand::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error};
+
+use crate::cluster::ClusterEvent;
+use crate::store::{MemoryStore, Store, StoreError, StoredApp};
+use crate::usage::{MailboxUsage, UsageSink};
+use wormhole_core::message::{Mood, Phase, ServerMessage, ServerMessageType};
+
+/// The smallest nameplate ID.
+const MIN_NAMEPLATE_ID: usize = 1;
+
+/// How many sides may open a mailbox, unless an application allows more.
+pub(crate) const DEFAULT_MAILBOX_SIDES: usize = 2;
+
+/// How nameplates are picked for clients which ask to be allocated one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum NameplateAllocation {
+    /// The smallest free nameplate. Codes are short, but active ones are easily guessed.
+    #[default]
+    Sequential,
+    /// A random free nameplate from the smallest pool (1-9, 1-99, 1-999, and so on) with one
+    /// free.
+    Random,
+}
+
+/// What a mailbox does with new messages once it has reached its storage limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum MailboxOverflow {
+    /// Refuse further messages with an error.
+    #[default]
+    Reject,
+    /// Keep forwarding messages to current subscribers, but stop storing them for late
+    /// subscribers.
+    Forward,
+}
+
+/// Limits on how much a single mailbox will store.
+#[derive(Debug, Clone, Default)]
+pub struct MailboxLimits {
+    /// Maximum number of stored messages.
+    pub max_messages: Option<usize>,
+    /// Maximum total size of stored message bodies, in bytes.
+    pub max_bytes: Option<usize>,
+    /// What to do with messages beyond the limits.
+    pub overflow: MailboxOverflow,
+}
+
+/// An application namespace.
+#[derive(Debug)]
+pub(crate) struct App {
+    /// Currently active nameplates, keyed by ID.
+    pub(crate) nameplates: HashMap<usize, Nameplate>,
+    /// Currently allocated mailboxes, keyed by name.
+    pub(crate) mailboxes: HashMap<String, Mailbox>,
+    /// Storage limits applied to each mailbox.
+    pub(crate) limits: MailboxLimits,
+    /// Maximum number of nameplates active at once, if limited.
+    pub(crate) max_nameplates: Option<usize>,
+    /// How nameplates are picked for clients which ask to be allocated one.
+    pub(crate) nameplate_allocation: NameplateAllocation,
+    /// The largest nameplate ID which may be allocated. `None` lets IDs grow as long as needed.
+    pub(crate) max_nameplate_id: Option<usize>,
+    /// Drop messages with the same side and phase as one already in the mailbox, e.g. when a
+    /// client resends after reconnecting.
+    pub(crate) dedup_adds: bool,
+    /// How many sides may open each mailbox before it is crowded. Nameplates are always
+    /// limited to two sides, so more only join by learning the mailbox ID from another side.
+    pub(crate) max_mailbox_sides: usize,
+    /// The namespace's app ID, used to key its records in the store.
+    pub(crate) app_id: String,
+    /// Where to record changes so they survive a restart.
+    pub(crate) store: Arc<dyn Store>,
+    /// Where to record the usage of each mailbox once it is freed, if anywhere.
+    pub(crate) usage_sink: Option<Arc<dyn UsageSink>>,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        App {
+            nameplates: HashMap::default(),
+            mailboxes: HashMap::default(),
+            limits: MailboxLimits::default(),
+            max_nameplates: None,
+            nameplate_allocation: NameplateAllocation::default(),
+            max_nameplate_id: None,
+            dedup_adds: false,
+            max_mailbox_sides: DEFAULT_MAILBOX_SIDES,
+            app_id: String::default(),
+            store: Arc::new(MemoryStore),
+            usage_sink: None,
+        }
+    }
+}
+
+/// A collection of messages.
+#[derive(Debug)]
+pub(crate) struct Mailbox {
+    /// All messages sent by any connected client.
+    pub(crate) messages: Vec<MailboxMessage>,
+    /// Total size of the bodies of the stored messages, in bytes.
+    pub(crate) stored_bytes: usize,
+    /// The clients currently subscribed to the mailbox.
+    pub(crate) subscribers: Vec<Subscriber>,
+    /// Sides subscribed to the mailbox through other server instances in a cluster.
+    pub(crate) remote_sides: Vec<String>,
+    /// When a client last opened the mailbox or added a message to it.
+    pub(crate) last_activity: Instant,
+    /// Statistics for the mailbox's usage record.
+    pub(crate) usage: MailboxUsage,
+}
+
+impl Default for Mailbox {
+    fn default() -> Self {
+        Mailbox {
+            messages: Vec::default(),
+            stored_bytes: 0,
+            subscribers: Vec::default(),
+            remote_sides: Vec::default(),
+            last_activity: Instant::now(),
+            usage: MailboxUsage::default(),
+        }
+    }
+}
+
+/// A two-sided identifier to faciliate connecting clients to a shared mailbox.
+#[derive(Debug)]
+pub(crate) struct Nameplate {
+    /// The associated mailbox ID.
+    pub(crate) mailbox_id: String,
+    /// Sides which have claimed the nameplate.
+    pub(crate) sides: Vec<String>,
+    /// When the nameplate was created (or restored from the store).
+    pub(crate) created: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    /// ID string of the client.
+    pub(crate) side: String,
+    /// A transmission channel for sending messages to the client.
+    pub(crate) sender: UnboundedSender<ServerMessage>,
+}
+
+#[derive(Debug)]
+pub struct MailboxMessage {
+    /// Original ID of the message as sent by the source client.
+    pub(crate) id: String,
+    /// The timestamp at which the server received the original message.
+    pub(crate) timestamp: f64,
+    /// The side (ID string) of the source client.
+    pub(crate) side: String,
+    /// Message phase.
+    pub(crate) phase: Phase,
+    /// Message body, shared with every copy forwarded to subscribers.
+    pub(crate) body: Arc<[u8]>,
+}
+
+impl Mailbox {
+    /// Has the mailbox stored as much as it may, given that `msg` is about to be added?
+    fn is_full(&self, limits: &MailboxLimits, msg: &MailboxMessage) -> bool {
+        limits
+            .max_messages
+            .is_some_and(|max| self.messages.len() >= max)
+            || limits
+                .max_bytes
+                .is_some_and(|max| self.stored_bytes + msg.body.len() > max)
+    }
+
+    /// Add a new message to the mailbox. Returns None if the mailbox is full and the limits
+    /// say to reject the message.
+    #[tracing::instrument(level = "debug", skip_all, fields(id = %msg.id, subscribers = self.subscribers.len()))]
+    fn add_message(&mut self, msg: MailboxMessage, limits: &MailboxLimits) -> Option<()> {
+        self.last_activity = Instant::now();
+        let full = self.is_full(limits, &msg);
+        if full && limits.overflow == MailboxOverflow::Reject {
+            return None;
+        }
+        self.usage.added(msg.body.len());
+
+        // Forward the new message to all subscribers
+        let forward_msg = ServerMessage::new(
+            Some(msg.id.clone()),
+            Some(msg.timestamp),
+            ServerMessageType::Message {
+                side: msg.side.clone(),
+                phase: msg.phase.clone(),
+                body: msg.body.clone(),
+            },
+        );
+        self.subscribers.retain(|subscriber| {
+            debug!(id = %msg.id, side = %subscriber.side, "Forwarding message to subscriber");
+            if subscriber
+                .sender
+                .unbounded_send(forward_msg.clone())
+                .is_err()
+            {
+                // The subscriber's connection has gone away, so stop delivering to it
+                debug!(side = %subscriber.side, "Dropping disconnected subscriber");
+                return false;
+            }
+            true
+        });
+
+        if full {
+            debug!(id = %msg.id, "Mailbox is full, not storing message");
+        } else {
+            self.stored_bytes += msg.body.len();
+            self.messages.push(msg);
+        }
+        Some(())
+    }
+
+    /// Add the given side to the mailbox.
+    #[tracing::instrument(level = "debug", skip(self, sender), fields(stored = self.messages.len()))]
+    fn add_subscriber(&mut self, side: &str, sender: UnboundedSender<ServerMessage>) {
+        self.last_activity = Instant::now();
+        if self.has_subscriber(side) {
+            // Side is already subscribed, do nothing
+            return;
+        }
+
+        // Send the new subscriber any messages that are already in the mailbox
+        for msg in &self.messages {
+            debug!(id = %msg.id, side, "Forwarding message to new subscriber");
+            let forward_msg = ServerMessage::new(
+                Some(msg.id.clone()),
+                Some(msg.timestamp),
+                ServerMessageType::Message {
+                    side: msg.side.clone(),
+                    phase: msg.phase.clone(),
+                    body: msg.body.clone(),
+                },
+            );
+            if sender.unbounded_send(forward_msg).is_err() {
+                // The new subscriber has already disconnected, so don't subscribe it
+                debug!(side, "Not subscribing disconnected side");
+                return;
+            }
+        }
+
+        self.subscribers.push(Subscriber {
+            side: side.to_owned(),
+            sender,
+        });
+        self.usage.opened(side);
+    }
+
+    /// Is the given side subscribed to the mailbox?
+    pub(crate) fn has_subscriber(&self, side: &str) -> bool {
+        self.subscribers.iter().any(|s| s.side == side)
+    }
+
+    /// Remove the given side from the mailbox.
+    fn remove_subscriber(&mut self, side: &str) {
+        self.subscribers.retain(|s| s.side != side);
+    }
+
+    /// Would the given side, if new, take the mailbox past the given number of sides? Sides
+    /// subscribed through other server instances count too.
+    pub(crate) fn is_crowded_for(&self, side: &str, max_sides: usize) -> bool {
+        if self.has_subscriber(side) || self.remote_sides.iter().any(|s| s == side) {
+            return false;
+        }
+        let sides = self.subscribers.len()
+            + self
+                .remote_sides
+                .iter()
+                .filter(|s| !self.has_subscriber(s))
+                .count();
+        sides >= max_sides
+    }
+
+    /// Is nobody subscribed to the mailbox, on this server instance or any other?
+    fn is_unused(&self) -> bool {
+        self.subscribers.is_empty() && self.remote_sides.is_empty()
+    }
+}
+
+impl App {
+    /// Create an empty application namespace whose mailboxes obey the given limits, recording
+    /// changes in the given store and usage in the given sink.
+    pub(crate) fn new(
+        app_id: &str,
+        limits: MailboxLimits,
+        store: Arc<dyn Store>,
+        usage_sink: Option<Arc<dyn UsageSink>>,
+    ) -> Self {
+        App {
+            limits,
+            app_id: app_id.to_owned(),
+            store,
+            usage_sink,
+            ..App::default()
+        }
+    }
+
+    /// Recreate an application namespace from the state loaded from its store. No clients are
+    /// subscribed to its mailboxes until they reconnect and open them again.
+    pub(crate) fn restore(
+        app_id: &str,
+        stored: StoredApp,
+        limits: MailboxLimits,
+        store: Arc<dyn Store>,
+        usage_sink: Option<Arc<dyn UsageSink>>,
+    ) -> Self {
+        App {
+            nameplates: stored.nameplates,
+            mailboxes: stored.mailboxes,
+            ..App::new(app_id, limits, store, usage_sink)
+        }
+    }
+
+    /// Apply a change to the store. Failures are only logged, since the in-memory state remains
+    /// authoritative while the server is running.
+    fn persist(&self, change: impl FnOnce(&dyn Store, &str) -> Result<(), StoreError>) {
+        if let Err(e) = change(&*self.store, &self.app_id) {
+            error!(error = %e, "Failed to update store");
+        }
+    }
+
+    /// Free the given mailbox, recording its usage. `pruned` is set if the server is closing it
+    /// rather than its subscribers.
+    fn free_mailbox(&mut self, mailbox_id: &str, pruned: bool) -> Option<Mailbox> {
+        let mailbox = self.mailboxes.remove(mailbox_id)?;
+        self.persist(|db, app_id| db.remove_mailbox(app_id, mailbox_id));
+        if let Some(usage_sink) = &self.usage_sink {
+            if let Err(e) = usage_sink.record(&mailbox.usage.finish(
+                &self.app_id,
+                pruned,
+                self.max_mailbox_sides,
+            )) {
+                error!(error = %e, "Failed to record usage");
+            }
+        }
+        Some(mailbox)
+    }
+
+    /// Are there already as many active nameplates as the namespace allows?
+    pub(crate) fn nameplates_full(&self) -> bool {
+        self.max_nameplates
+            .is_some_and(|max| self.nameplates.len() >= max)
+    }
+
+    /// Pick an available nameplate according to the allocation strategy, claim it, and return
+    /// it. Returns None if no nameplates are available.
+    pub(crate) fn allocate_nameplate(
+        &mut self,
+        side: &str,
+        sender: UnboundedSender<ServerMessage>,
+    ) -> Option<usize> {
+        let max_id = self.max_nameplate_id.unwrap_or(usize::MAX);
+        let nameplate_id = match self.nameplate_allocation {
+            NameplateAllocation::Sequential => {
+                (MIN_NAMEPLATE_ID..=max_id).find(|i| !self.nameplates.contains_key(i))
+            }
+            NameplateAllocation::Random => {
+                // Only move on to longer IDs once every shorter one is in use
+                let mut pool_max = 9;
+                loop {
+                    let pool = MIN_NAMEPLATE_ID..=pool_max.min(max_id);
+                    let free = pool.filter(|i| !self.nameplates.contains_key(i));
+                    if let Some(nameplate_id) = free.choose(&mut thread_rng()) {
+                        break Some(nameplate_id);
+                    }
+                    match pool_max.checked_mul(10).and_then(|n| n.checked_add(9)) {
+                        Some(next) if pool_max < max_id => pool_max = next,
+                        _ => break None,
+                    }
+                }
+            }
+        }?;
+        self.claim_nameplate(nameplate_id, side, sender);
+        Some(nameplate_id)
+    }
+
+    /// Claim the given nameplate. Returns None if the nameplate is already full.
+    pub(crate) fn claim_nameplate(
+        &mut self,
+        nameplate_id: usize,
+        side: &str,
+        sender: UnboundedSender<ServerMessage>,
+    ) -> Option<String> {
+        if let Some(nameplate) = self.nameplates.get_mut(&nameplate_id) {
+            // This nameplate already has at least one side
+            assert!(!nameplate.sides.is_empty());
+            if nameplate.sides.contains(&side.to_owned()) {
+                // Side is already associated with the nameplate (from an allocate),
+                // so nothing to do
+                Some(nameplate.mailbox_id.clone())
+            } else {
+                // TODO: Handle reclaimed errors, where a side tried to re-claim a nameplate
+                // it already released (since that might cause a new mailbox to be allocated)
+                nameplate.sides.push(side.to_owned());
+                let result = if nameplate.sides.len() >= 3 {
+                    // TODO: Return a CrowdedNameplate error
+                    None
+                } else {
+                    Some(nameplate.mailbox_id.clone())
+                };
+                self.persist(|db, app_id| db.add_nameplate_side(app_id, nameplate_id, side));
+                result
+            }
+        } else {
+            // The nameplate is free, so let's create a mailbox for it
+            // We also add this client to the mailbox and subscribe them
+            let mailbox_id = App::generate_mailbox_id();
+            self.open_mailbox(&mailbox_id, side, sender);
+            self.nameplates.insert(
+                nameplate_id,
+                Nameplate::new(mailbox_id.clone(), vec![side.to_owned()]),
+            );
+            self.persist(|db, app_id| {
+                db.add_nameplate(app_id, nameplate_id, &mailbox_id)?;
+                db.add_nameplate_side(app_id, nameplate_id, side)
+            });
+            Some(mailbox_id)
+        }
+    }
+
+    /// Remove the given side from the given nameplate. If the nameplate is then
+    /// unused, it will be freed. Non-existant nameplates are ignored, as are sides
+    /// which aren't associated with the nameplate.
+    pub(crate) fn release_nameplate(&mut self, nameplate_id: usize, side: &str) {
+        debug!(side, nameplate_id, "Removing side from nameplate");
+        if let Some(nameplate) = self.nameplates.get_mut(&nameplate_id) {
+            nameplate.sides.retain(|s| s != side);
+            if nameplate.is_empty() {
+                debug!(nameplate_id, "Freeing empty nameplate");
+                self.nameplates.remove(&nameplate_id);
+                self.persist(|db, app_id| db.remove_nameplate(app_id, nameplate_id));
+            } else {
+                self.persist(|db, app_id| db.remove_nameplate_side(app_id, nameplate_id, side));
+            }
+        }
+    }
+
+    /// Count the nameplates the given side has claimed.
+    pub(crate) fn nameplates_held_by(&self, side: &str) -> usize {
+        self.nameplates
+            .values()
+            .filter(|n| n.sides.iter().any(|s| s == side))
+            .count()
+    }
+
+    /// Count the mailboxes the given side is subscribed to.
+    pub(crate) fn mailboxes_held_by(&self, side: &str) -> usize {
+        self.mailboxes
+            .values()
+            .filter(|m| m.has_subscriber(side))
+            .count()
+    }
+
+    /// Return the list of active nameplates.
+    pub(crate) fn get_nameplates(&self) -> Vec<usize> {
+        self.nameplates.keys().copied().collect::<Vec<usize>>()
+    }
+
+    /// Subscribe a client to a mailbox, opening it in the process if necessary. Returns None,
+    /// without subscribing the client, if the mailbox is crowded.
+    pub(crate) fn open_mailbox(
+        &mut self,
+        mailbox_id: &str,
+        side: &str,
+        sender: UnboundedSender<ServerMessage>,
+    ) -> Option<()> {
+        if !self.mailboxes.contains_key(mailbox_id) {
+            debug!(mailbox_id, "Creating mailbox");
+            let mailbox = Mailbox::default();
+            self.mailboxes.insert(mailbox_id.to_owned(), mailbox);
+            self.persist(|db, app_id| db.add_mailbox(app_id, mailbox_id));
+        }
+
+        let mailbox = self
+            .mailboxes
+            .get_mut(mailbox_id)
+            .expect("non-existant mailbox");
+        if mailbox.is_crowded_for(side, self.max_mailbox_sides) {
+            return None;
+        }
+        mailbox.add_subscriber(side, sender);
+        self.persist(|db, app_id| db.add_subscriber(app_id, mailbox_id, side));
+        Some(())
+    }
+
+    /// Remove the given side, which is in the given mood, from a mailbox.
+    pub(crate) fn close_mailbox(&mut self, mailbox_id: &str, side: &str, mood: Mood) {
+        let mailbox = self
+            .mailboxes
+            .get_mut(mailbox_id)
+            .expect("non-existant mailbox");
+        mailbox.remove_subscriber(side);
+        mailbox.usage.closed(side, mood);
+        let unused = mailbox.is_unused();
+        self.persist(|db, app_id| db.remove_subscriber(app_id, mailbox_id, side));
+        if unused {
+            self.free_mailbox(mailbox_id, false);
+        }
+    }
+
+    /// Add a new message to the given mailbox. Returns None if the mailbox is full and refusing
+    /// new messages.
+    pub(crate) fn add_message_to_mailbox(
+        &mut self,
+        mailbox_id: &str,
+        message: MailboxMessage,
+    ) -> Option<()> {
+        let mailbox = self
+            .mailboxes
+            .get_mut(mailbox_id)
+            .expect("non-existant mailbox");
+        if self.dedup_adds
+            && mailbox
+                .messages
+                .iter()
+                .any(|m| m.side == message.side && m.phase == message.phase)
+        {
+            debug!(id = %message.id, mailbox_id, "Dropping duplicate message");
+            return Some(());
+        }
+        debug!(id = %message.id, mailbox_id, "Adding message to mailbox");
+        let stored = mailbox.messages.len();
+        mailbox.add_message(message, &self.limits)?;
+        let messages = &self.mailboxes[mailbox_id].messages;
+        if messages.len() > stored {
+            let message = messages.last().unwrap();
+            self.persist(|db, app_id| db.add_message(app_id, mailbox_id, message));
+        }
+        Some(())
+    }
+
+    /// Remove the given side from any active nameplates. Any nameplates that are
+    /// then unused will be freed.
+    pub(crate) fn remove_side_from_nameplates(&mut self, side: &str) {
+        let mut changed = Vec::new();
+        for (nameplate_id, nameplate) in self.nameplates.iter_mut() {
+            nameplate.sides.retain(|s| {
+                if s == side {
+                    debug!(side, nameplate_id, "Removing side from nameplate");
+                    changed.push(*nameplate_id);
+                }
+                s != side
+            });
+        }
+
+        // Remove any now-empty nameplates
+        self.nameplates.retain(|nameplate_id, nameplate| {
+            if nameplate.is_empty() {
+                debug!(nameplate_id, "Removing empty nameplate");
+            }
+            !nameplate.is_empty()
+        });
+
+        for nameplate_id in changed {
+            if self.nameplates.contains_key(&nameplate_id) {
+                self.persist(|db, app_id| db.remove_nameplate_side(app_id, nameplate_id, side));
+            } else {
+                self.persist(|db, app_id| db.remove_nameplate(app_id, nameplate_id));
+            }
+        }
+    }
+
+    /// Free any nameplates which haven't been claimed by a second side within `ttl` of being
+    /// created, telling the side still waiting on each that it has been released. Returns the
+    /// number of nameplates expired.
+    pub(crate) fn expire_nameplates(&mut self, ttl: Duration, now: Instant) -> usize {
+        let expired = self
+            .nameplates
+            .iter()
+            .filter(|(_, nameplate)| {
+                nameplate.sides.len() < 2 && now.saturating_duration_since(nameplate.created) >= ttl
+            })
+            .map(|(nameplate_id, _)| *nameplate_id)
+            .collect::<Vec<_>>();
+
+        for nameplate_id in &expired {
+            debug!(nameplate_id, "Expiring unclaimed nameplate");
+            self.expire_nameplate(*nameplate_id);
+        }
+        expired.len()
+    }
+
+    /// Free the given nameplate, telling its sides it has been released. Returns false if there
+    /// is no such nameplate.
+    pub(crate) fn expire_nameplate(&mut self, nameplate_id: usize) -> bool {
+        let Some(nameplate) = self.nameplates.remove(&nameplate_id) else {
+            return false;
+        };
+        if let Some(mailbox) = self.mailboxes.get(&nameplate.mailbox_id) {
+            for subscriber in mailbox
+                .subscribers
+                .iter()
+                .filter(|s| nameplate.sides.contains(&s.side))
+            {
+                let released_msg = ServerMessage::new(None, None, ServerMessageType::Released);
+                let _ = subscriber.sender.unbounded_send(released_msg);
+            }
+        }
+        self.persist(|db, app_id| db.remove_nameplate(app_id, nameplate_id));
+        true
+    }
+
+    /// Close any mailboxes which nobody has opened or added to within `ttl`, telling their
+    /// remaining subscribers they are closed and freeing any nameplates pointing at them. Returns
+    /// the number of mailboxes closed.
+    pub(crate) fn collect_idle_mailboxes(&mut self, ttl: Duration, now: Instant) -> usize {
+        let idle = self
+            .mailboxes
+            .iter()
+            .filter(|(_, mailbox)| now.saturating_duration_since(mailbox.last_activity) >= ttl)
+            .map(|(mailbox_id, _)| mailbox_id.clone())
+            .collect::<Vec<_>>();
+
+        for mailbox_id in &idle {
+            debug!(mailbox_id = %mailbox_id, "Closing idle mailbox");
+            self.prune_mailbox(mailbox_id);
+        }
+        idle.len()
+    }
+
+    /// Close the given mailbox on behalf of the server, telling its subscribers it is closed and
+    /// freeing any nameplates pointing at it. Returns false if there is no such mailbox.
+    pub(crate) fn prune_mailbox(&mut self, mailbox_id: &str) -> bool {
+        let Some(mailbox) = self.free_mailbox(mailbox_id, true) else {
+            return false;
+        };
+        for subscriber in &mailbox.subscribers {
+            let closed_msg = ServerMessage::new(None, None, ServerMessageType::Closed);
+            let _ = subscriber.sender.unbounded_send(closed_msg);
+        }
+
+        let nameplate_ids = self
+            .nameplates
+            .iter()
+            .filter(|(_, nameplate)| nameplate.mailbox_id == mailbox_id)
+            .map(|(nameplate_id, _)| *nameplate_id)
+            .collect::<Vec<_>>();
+        for nameplate_id in nameplate_ids {
+            debug!(nameplate_id, "Freeing nameplate of closed mailbox");
+            self.nameplates.remove(&nameplate_id);
+            self.persist(|db, app_id| db.remove_nameplate(app_id, nameplate_id));
+        }
+        true
+    }
+
+    /// Remove the given subscriber from any open mailboxes.
+    pub(crate) fn remove_subscriber_from_mailboxes(
+        &mut self,
+        sender: &UnboundedSender<ServerMessage>,
+    ) {
+        let mut removed = Vec::new();
+        let mut emptied = Vec::new();
+        for (mailbox_id, mailbox) in self.mailboxes.iter_mut() {
+            let subscribers = mailbox.subscribers.len();
+            mailbox.subscribers.retain(|s| {
+                if s.sender.same_receiver(sender) {
+                    debug!(side = %s.side, mailbox_id = %mailbox_id, "Removing side from mailbox");
+                    removed.push((mailbox_id.clone(), s.side.clone()));
+                }
+                !s.sender.same_receiver(sender)
+            });
+            if mailbox.subscribers.len() < subscribers && mailbox.is_unused() {
+                emptied.push(mailbox_id.clone());
+            }
+        }
+
+        for (mailbox_id, side) in removed {
+            self.persist(|db, app_id| db.remove_subscriber(app_id, &mailbox_id, &side));
+        }
+
+        // Free any mailboxes that were left with no subscribers
+        for mailbox_id in emptied {
+            debug!(mailbox_id = %mailbox_id, "Removing empty mailbox");
+            self.free_mailbox(&mailbox_id, false);
+        }
+    }
+
+    /// Apply a change made by another server instance in a cluster. The change has already been
+    /// stored, so is only made in memory, though any messages are forwarded to this instance's
+    /// subscribers.
+    pub(crate) fn apply(&mut self, event: ClusterEvent) {
+        debug!(?event, "Applying change from another instance");
+        match event {
+            ClusterEvent::NameplateAdded {
+                nameplate_id,
+                mailbox_id,
+                ..
+            } => {
+                self.nameplates
+                    .entry(nameplate_id)
+                    .or_insert_with(|| Nameplate::new(mailbox_id, Vec::new()));
+            }
+            ClusterEvent::NameplateRemoved { nameplate_id, .. } => {
+                self.nameplates.remove(&nameplate_id);
+            }
+            ClusterEvent::NameplateSideAdded {
+                nameplate_id, side, ..
+            } => {
+                if let Some(nameplate) = self.nameplates.get_mut(&nameplate_id) {
+                    if !nameplate.sides.contains(&side) {
+                        nameplate.sides.push(side);
+                    }
+                }
+            }
+            ClusterEvent::NameplateSideRemoved {
+                nameplate_id, side, ..
+            } => {
+                if let Some(nameplate) = self.nameplates.get_mut(&nameplate_id) {
+                    nameplate.sides.retain(|s| *s != side);
+                }
+            }
+            ClusterEvent::MailboxAdded { mailbox_id, .. } => {
+                self.mailboxes.entry(mailbox_id).or_default();
+            }
+            ClusterEvent::MailboxRemoved { mailbox_id, .. } => {
+                if let Some(mailbox) = self.mailboxes.remove(&mailbox_id) {
+                    for subscriber in &mailbox.subscribers {
+                        let closed_msg = ServerMessage::new(None, None, ServerMessageType::Closed);
+                        let _ = subscriber.sender.unbounded_send(closed_msg);
+                    }
+                }
+            }
+            ClusterEvent::SubscriberAdded {
+                mailbox_id, side, ..
+            } => {
+                let mailbox = self.mailboxes.entry(mailbox_id).or_default();
+                mailbox.last_activity = Instant::now();
+                if !mailbox.remote_sides.contains(&side) {
+                    mailbox.remote_sides.push(side);
+                }
+            }
+            ClusterEvent::SubscriberRemoved {
+                mailbox_id, side, ..
+            } => {
+                if let Some(mailbox) = self.mailboxes.get_mut(&mailbox_id) {
+                    mailbox.remote_sides.retain(|s| *s != side);
+                }
+            }
+            ClusterEvent::MessageAdded {
+                mailbox_id,
+                message,
+                ..
+            } => {
+                // The other instance has already decided to store the message
+                let mailbox = self.mailboxes.entry(mailbox_id).or_default();
+                mailbox.add_message(message.into(), &MailboxLimits::default());
+            }
+        }
+    }
+
+    /// Deliver everything the given subscriber is subscribed to through a different channel,
+    /// e.g. when its client reconnects.
+    pub(crate) fn redirect_subscriber(
+        &mut self,
+        from: &UnboundedSender<ServerMessage>,
+        to: &UnboundedSender<ServerMessage>,
+    ) {
+        for mailbox in self.mailboxes.values_mut() {
+            for subscriber in &mut mailbox.subscribers {
+                if subscriber.sender.same_receiver(from) {
+                    subscriber.sender = to.clone();
+                }
+            }
+        }
+    }
+
+    /// Generate 13 characters of random, base32, lowercase ASCII.
+    fn generate_mailbox_id() -> String {
+        let mut rng = rand::thread_rng();
+        let mut buffer = [0u8; 8];
+        rng.fill_bytes(&mut buffer);
+        BASE32
+            .encode(&buffer)
+            .to_ascii_lowercase()
+            .strip_suffix("===")
+            .unwrap()
+            .to_owned()
+    }
+}
+
+impl Nameplate {
+    /// Create a nameplate for the given mailbox, claimed by the given sides.
+    pub(crate) fn new(mailbox_id: String, sides: Vec<String>) -> Self {
+        Nameplate {
+            mailbox_id,
+            sides,
+            created: Instant::now(),
+        }
+    }
+
+    /// Check if the nameplate has no associated clients.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sides.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        App, ClusterEvent, MailboxLimits, MailboxMessage, MailboxOverflow, MemoryStore, Mood,
+        Nameplate, NameplateAllocation, ServerMessageType,
+    };
+    use futures_channel::mpsc::unbounded;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn nameplate_allocation() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone());
+        assert_eq!(nameplate_id, Some(1));
+
+        let nameplate_id = app.allocate_nameplate("side2", sender.clone());
+        assert_eq!(nameplate_id, Some(2));
+    }
+
+    #[test]
+    fn random_nameplate_allocation() {
+        let mut app = App {
+            nameplate_allocation: NameplateAllocation::Random,
+            ..App::default()
+        };
+        let (sender, _) = unbounded();
+
+        // Single digit nameplates are used up first, in any order
+        let mut nameplate_ids = (0..9)
+            .map(|_| app.allocate_nameplate("side1", sender.clone()).unwrap())
+            .collect::<Vec<_>>();
+        nameplate_ids.sort();
+        assert_eq!(nameplate_ids, (1..10).collect::<Vec<_>>());
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        assert!((10..100).contains(&nameplate_id));
+
+        // A freed nameplate is picked again before moving to a larger pool
+        app.nameplates.remove(&5);
+        app.nameplates.retain(|i, _| *i < 10);
+        assert_eq!(app.allocate_nameplate("side1", sender.clone()), Some(5));
+    }
+
+    #[test]
+    fn full_nameplate_allocation() {
+        let mut app = App {
+            max_nameplate_id: Some(998),
+            ..App::default()
+        };
+        let (sender, _) = unbounded();
+
+        // Fill all nameplate slots
+        for i in 1..=998 {
+            app.nameplates
+                .insert(i, Nameplate::new(format!("mailbox{}", i), Vec::new()));
+        }
+
+        let namplate_id = app.allocate_nameplate("side1", sender.clone());
+        assert_eq!(namplate_id, None);
+
+        app.nameplate_allocation = NameplateAllocation::Random;
+        let namplate_id = app.allocate_nameplate("side1", sender.clone());
+        assert_eq!(namplate_id, None);
+    }
+
+    #[test]
+    fn expanding_nameplate_allocation() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+        for i in 1..=999 {
+            app.nameplates
+                .insert(i, Nameplate::new(format!("mailbox{}", i), Vec::new()));
+        }
+
+        // Without a maximum, IDs grow another digit once the shorter ones are used up
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone());
+        assert_eq!(nameplate_id, Some(1000));
+
+        app.nameplate_allocation = NameplateAllocation::Random;
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        assert!((1001..=9999).contains(&nameplate_id));
+        app.nameplates.remove(&nameplate_id);
+
+        // Pools are capped at the maximum
+        app.max_nameplate_id = Some(1001);
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone());
+        assert_eq!(nameplate_id, Some(1001));
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone());
+        assert_eq!(nameplate_id, None);
+    }
+
+    #[test]
+    fn list_nameplates() {
+        let mut app = App::default();
+        assert!(app.get_nameplates().is_empty());
+
+        let (sender, _) = unbounded();
+        let _ = app.allocate_nameplate("side1", sender.clone());
+        let nameplates = app.get_nameplates();
+        assert_eq!(nameplates.len(), 1);
+        assert_eq!(nameplates[0], 1);
+    }
+
+    #[test]
+    fn claim_nameplate_after_allocation() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        let mailbox_id = app.claim_nameplate(nameplate_id, "side1", sender.clone());
+        assert!(mailbox_id.is_some());
+    }
+
+    #[test]
+    fn claim_nameplate_no_allocation() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+
+        let mailbox_id = app.claim_nameplate(nameplate_id, "side2", sender.clone());
+        assert!(mailbox_id.is_some());
+    }
+
+    #[test]
+    fn claim_nameplate_crowded() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        let _ = app.claim_nameplate(nameplate_id, "side2", sender.clone());
+
+        let mailbox_id = app.claim_nameplate(nameplate_id, "side3", sender.clone());
+        assert_eq!(mailbox_id, None);
+    }
+
+    #[test]
+    fn remove_side() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+        assert!(app.nameplates.is_empty());
+
+        let _ = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        let _ = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        assert_eq!(app.nameplates.len(), 2);
+
+        app.remove_side_from_nameplates("side1");
+        assert!(app.nameplates.is_empty());
+    }
+
+    #[test]
+    fn remove_subscriber() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        let mailbox_id = app
+            .claim_nameplate(nameplate_id, "side1", sender.clone())
+            .unwrap();
+        assert_eq!(
+            app.mailboxes
+                .get(&mailbox_id)
+                .unwrap()
+                .subscribers
+                .iter()
+                .filter(|s| s.side == "side1")
+                .count(),
+            1
+        );
+
+        app.remove_subscriber_from_mailboxes(&sender);
+        // Either the subscriber is removed from the mailbox, or the mailbox is
+        // deallocated completely
+        if let Some(mailbox) = app.mailboxes.get(&mailbox_id) {
+            assert_eq!(
+                mailbox
+                    .subscribers
+                    .iter()
+                    .filter(|s| s.side == "side1")
+                    .count(),
+                0
+            );
+        } else {
+            assert!(app.mailboxes.is_empty());
+        }
+    }
+
+    #[test]
+    fn release_empty_nameplate() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        assert_eq!(app.nameplates.len(), 1);
+
+        app.release_nameplate(nameplate_id, "side1");
+        assert!(app.nameplates.is_empty());
+    }
+
+    #[test]
+    fn release_nonempty_nameplate() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        assert_eq!(app.nameplates.len(), 1);
+        let _ = app.claim_nameplate(nameplate_id, "side2", sender.clone());
+        assert_eq!(app.nameplates.len(), 1);
+
+        app.release_nameplate(nameplate_id, "side1");
+        assert_eq!(app.nameplates.len(), 1);
+    }
+
+    #[test]
+    fn nameplate_is_empty() {
+        let mut nameplate = Nameplate::new("mailbox".into(), Vec::new());
+        assert!(nameplate.is_empty());
+
+        nameplate.sides.push("side1".into());
+        assert!(!nameplate.is_empty());
+    }
+
+    #[test]
+    fn mailbox_id_generation() {
+        let mailbox_id = App::generate_mailbox_id();
+        assert_eq!(mailbox_id.len(), 13);
+        assert!(mailbox_id.is_ascii());
+    }
+
+    #[test]
+    fn nameplate() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let nameplate_id = app.allocate_nameplate("side1", sender.clone()).unwrap();
+        assert_eq!(nameplate_id, 1);
+        assert_eq!(app.get_nameplates(), vec![nameplate_id]);
+
+        // Allocate also does a claim
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        let nameplate_mailbox_id = nameplate.mailbox_id.clone();
+        assert_eq!(nameplate.sides.len(), 1);
+        assert!(nameplate.sides.contains(&"side1".into()));
+
+        // Duplicate claims by the same side are combined
+        let mailbox_id_1 = app
+            .claim_nameplate(nameplate_id, "side1", sender.clone())
+            .unwrap();
+        assert_eq!(mailbox_id_1, nameplate_mailbox_id);
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert!(nameplate.sides.contains(&"side1".into()));
+        assert_eq!(nameplate.mailbox_id, mailbox_id_1);
+
+        // Claim by the second side is new
+        let mailbox_id_2 = app
+            .claim_nameplate(nameplate_id, "side2", sender.clone())
+            .unwrap();
+        assert_eq!(mailbox_id_1, mailbox_id_2);
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert_eq!(nameplate.sides.len(), 2);
+        assert_eq!(nameplate.sides, vec!["side1", "side2"]);
+
+        // A third claim marks the nameplate as "crowded", and adds a third
+        // claim (which must be released later), but leaves the two existing
+        // claims alone
+        let result = app.claim_nameplate(nameplate_id, "side3", sender.clone());
+        assert_eq!(result, None);
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert_eq!(nameplate.sides.len(), 3);
+
+        // Releasing a non-existent nameplate is ignored
+        app.release_nameplate(2, "side4");
+
+        // Releasing a side that never claimed the nameplate is ignored
+        app.release_nameplate(nameplate_id, "side4");
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert_eq!(nameplate.sides.len(), 3);
+
+        // Releasing one side leaves the second claim
+        app.release_nameplate(nameplate_id, "side1");
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert!(!nameplate.sides.contains(&"side1".into()));
+        assert!(nameplate.sides.contains(&"side2".into()));
+        assert!(nameplate.sides.contains(&"side3".into()));
+
+        // Releasing one side multiple times is ignored
+        app.release_nameplate(nameplate_id, "side1");
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert!(!nameplate.sides.contains(&"side1".into()));
+        assert!(nameplate.sides.contains(&"side2".into()));
+        assert!(nameplate.sides.contains(&"side3".into()));
+
+        // Release the second side
+        app.release_nameplate(nameplate_id, "side2");
+        let nameplate = app.nameplates.get(&nameplate_id).unwrap();
+        assert!(!nameplate.sides.contains(&"side1".into()));
+        assert!(!nameplate.sides.contains(&"side2".into()));
+        assert!(nameplate.sides.contains(&"side3".into()));
+
+        // Releasing the third side frees the nameplate
+        app.release_nameplate(nameplate_id, "side3");
+        assert!(!app.nameplates.contains_key(&nameplate_id));
+    }
+
+    #[test]
+    fn mailbox() {
+        let mut app = App::default();
+        let (sender, _) = unbounded();
+
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender.clone());
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 1);
+        assert_eq!(mailbox.subscribers[0].side, "side1");
+
+        // Opening the same mailbox twice, by the same side, does nothing
+        app.open_mailbox(mailbox_id, "side1", sender.clone());
+        assert_eq!(app.mailboxes.len(), 1);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 1);
+        assert_eq!(mailbox.subscribers[0].side, "side1");
+
+        // Opening a second side adds a new subscriber
+        app.open_mailbox(mailbox_id, "side2", sender.clone());
+        assert_eq!(app.mailboxes.len(), 1);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 2);
+        assert!(mailbox.subscribers.iter().any(|s| s.side == "side1"));
+        assert!(mailbox.subscribers.iter().any(|s| s.side == "side2"));
+
+        // A third open is refused, as the mailbox is crowded
+        let result = app.open_mailbox(mailbox_id, "side3", sender.clone());
+        assert_eq!(result, None);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 2);
+
+        // Closing a side that never claimed the mailbox is ignored
+        app.close_mailbox(mailbox_id, "side4", Mood::Happy);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 2);
+
+        // Closing one side leaves the second claim
+        app.close_mailbox(mailbox_id, "side1", Mood::Happy);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 1);
+        assert!(mailbox.subscribers.iter().any(|s| s.side == "side2"));
+
+        // Closing one side multiple times is ignored
+        app.close_mailbox(mailbox_id, "side1", Mood::Happy);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 1);
+        assert!(mailbox.subscribers.iter().any(|s| s.side == "side2"));
+
+        // Closing the second side frees the mailbox
+        app.close_mailbox(mailbox_id, "side2", Mood::Happy);
+        assert!(app.mailboxes.is_empty());
+    }
+
+    #[test]
+    fn messages() {
+        let mut app = App::default();
+
+        let (sender1, mut receiver1) = unbounded();
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender1.clone());
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side1".into(),
+                phase: super::Phase::Message(0),
+                body: b"body1".as_slice().into(),
+            },
+        );
+
+        // Existing subscriber receives the new message
+        let msg = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Message { .. }));
+        match msg.ty {
+            ServerMessageType::Message { side, body, .. } => {
+                assert_eq!(side, "side1");
+                assert_eq!(&*body, b"body1");
+            }
+            _ => unreachable!(),
+        }
+
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side1".into(),
+                phase: super::Phase::Message(1),
+                body: b"body2".as_slice().into(),
+            },
+        );
+        let msg = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Message { .. }));
+        match msg.ty {
+            ServerMessageType::Message { body, .. } => {
+                assert_eq!(&*body, b"body2");
+            }
+            _ => unreachable!(),
+        }
+
+        // New subscribers is forwarded all existing messages
+        let (sender2, mut receiver2) = unbounded();
+        app.open_mailbox(mailbox_id, "side2", sender2.clone());
+        let msg1 = receiver2.try_next().unwrap().unwrap();
+        assert!(matches!(msg1.ty, ServerMessageType::Message { .. }));
+        match msg1.ty {
+            ServerMessageType::Message { body, .. } => {
+                assert_eq!(&*body, b"body1");
+            }
+            _ => unreachable!(),
+        }
+        let msg2 = receiver2.try_next().unwrap().unwrap();
+        assert!(matches!(msg2.ty, ServerMessageType::Message { .. }));
+        match msg2.ty {
+            ServerMessageType::Message { body, .. } => {
+                assert_eq!(&*body, b"body2");
+            }
+            _ => unreachable!(),
+        }
+
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side1".into(),
+                phase: super::Phase::Message(2),
+                body: b"body3".as_slice().into(),
+            },
+        );
+        let msg3 = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg3.ty, ServerMessageType::Message { .. }));
+        match msg3.ty {
+            ServerMessageType::Message { body, .. } => {
+                assert_eq!(&*body, b"body3");
+            }
+            _ => unreachable!(),
+        }
+        let msg3 = receiver2.try_next().unwrap().unwrap();
+        assert!(matches!(msg3.ty, ServerMessageType::Message { .. }));
+        match msg3.ty {
+            ServerMessageType::Message { body, .. } => {
+                assert_eq!(&*body, b"body3");
+            }
+            _ => unreachable!(),
+        }
+
+        app.remove_subscriber_from_mailboxes(&sender1);
+
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side1".into(),
+                phase: super::Phase::Message(3),
+                body: b"body4".as_slice().into(),
+            },
+        );
+        // Error here means there are no messages available, but the channel is still open
+        assert!(receiver1.try_next().is_err());
+        let msg4 = receiver2.try_next().unwrap().unwrap();
+        assert!(matches!(msg4.ty, ServerMessageType::Message { .. }));
+        match msg4.ty {
+            ServerMessageType::Message { body, .. } => {
+                assert_eq!(&*body, b"body4");
+            }
+            _ => unreachable!(),
+        }
+
+        // Message adds are not idempotent: clients filter duplicates
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side1".into(),
+                phase: super::Phase::Message(0),
+                body: b"body1".as_slice().into(),
+            },
+        );
+        assert_eq!(app.mailboxes.get(mailbox_id).unwrap().messages.len(), 5);
+        assert_eq!(
+            &*app
+                .mailboxes
+                .get(mailbox_id)
+                .unwrap()
+                .messages
+                .last()
+                .unwrap()
+                .body,
+            b"body1"
+        );
+    }
+
+    #[test]
+    fn message_bodies_are_shared() {
+        let mut app = App::default();
+        let (sender1, mut receiver1) = unbounded();
+        let (sender2, mut receiver2) = unbounded();
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender1);
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side1".into(),
+                phase: super::Phase::Message(0),
+                body: b"body1".as_slice().into(),
+            },
+        );
+        app.open_mailbox(mailbox_id, "side2", sender2);
+
+        let stored = app.mailboxes.get(mailbox_id).unwrap().messages[0]
+            .body
+            .clone();
+        for receiver in [&mut receiver1, &mut receiver2] {
+            match receiver.try_next().unwrap().unwrap().ty {
+                ServerMessageType::Message { body, .. } => {
+                    assert!(Arc::ptr_eq(&body, &stored));
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn nameplate_expiry() {
+        let mut app = App::default();
+        let ttl = Duration::from_secs(60);
+        let (sender1, mut receiver1) = unbounded();
+        let (sender2, _) = unbounded();
+
+        let unclaimed = app.allocate_nameplate("side1", sender1).unwrap();
+        let claimed = app.allocate_nameplate("side2", sender2.clone()).unwrap();
+        app.claim_nameplate(claimed, "side3", sender2);
+        let created = app.nameplates[&unclaimed].created;
+
+        assert_eq!(app.expire_nameplates(ttl, created), 0);
+        assert_eq!(app.expire_nameplates(ttl, created + ttl), 1);
+        assert!(!app.nameplates.contains_key(&unclaimed));
+        assert!(app.nameplates.contains_key(&claimed));
+
+        // The waiting side is told its nameplate has gone
+        let msg = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Released));
+    }
+
+    #[test]
+    fn idle_mailbox_collection() {
+        let mut app = App::default();
+        let ttl = Duration::from_secs(60);
+        let (sender1, mut receiver1) = unbounded();
+        let (sender2, _) = unbounded();
+
+        let idle = app.allocate_nameplate("side1", sender1).unwrap();
+        let idle_mailbox_id = app.nameplates[&idle].mailbox_id.clone();
+        let busy = app.allocate_nameplate("side2", sender2).unwrap();
+        let busy_mailbox_id = app.nameplates[&busy].mailbox_id.clone();
+        let last_activity = app.mailboxes[&idle_mailbox_id].last_activity;
+        app.mailboxes
+            .get_mut(&busy_mailbox_id)
+            .unwrap()
+            .last_activity = last_activity + ttl;
+
+        assert_eq!(app.collect_idle_mailboxes(ttl, last_activity), 0);
+        assert_eq!(app.collect_idle_mailboxes(ttl, last_activity + ttl), 1);
+        assert!(!app.mailboxes.contains_key(&idle_mailbox_id));
+        assert!(!app.nameplates.contains_key(&idle));
+        assert!(app.mailboxes.contains_key(&busy_mailbox_id));
+        assert!(app.nameplates.contains_key(&busy));
+
+        // The remaining subscriber is told the mailbox has closed
+        let msg = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Closed));
+    }
+
+    #[test]
+    fn closed_subscriber_is_dropped() {
+        let mut app = App::default();
+        let (sender1, receiver1) = unbounded();
+        let (sender2, mut receiver2) = unbounded();
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender1);
+        app.open_mailbox(mailbox_id, "side2", sender2);
+
+        // side1 vanishes without closing its mailbox
+        drop(receiver1);
+        app.add_message_to_mailbox(
+            mailbox_id,
+            MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.0,
+                side: "side2".into(),
+                phase: super::Phase::Message(0),
+                body: b"body1".as_slice().into(),
+            },
+        );
+
+        // The remaining subscriber still gets the message
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 1);
+        assert_eq!(mailbox.subscribers[0].side, "side2");
+        assert!(receiver2.try_next().unwrap().is_some());
+
+        // A subscriber that is already gone isn't added
+        let (sender3, receiver3) = unbounded();
+        drop(receiver3);
+        app.open_mailbox(mailbox_id, "side3", sender3);
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.subscribers.len(), 1);
+    }
+
+    fn test_message(phase: usize) -> MailboxMessage {
+        MailboxMessage {
+            id: "msgid".into(),
+            timestamp: 1.0,
+            side: "side1".into(),
+            phase: super::Phase::Message(phase),
+            body: b"body".as_slice().into(),
+        }
+    }
+
+    #[test]
+    fn mailbox_limit_reject() {
+        let mut app = App::new(
+            "app",
+            MailboxLimits {
+                max_messages: Some(2),
+                max_bytes: None,
+                overflow: MailboxOverflow::Reject,
+            },
+            Arc::new(MemoryStore),
+            None,
+        );
+        let (sender, mut receiver) = unbounded();
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender);
+
+        assert!(app
+            .add_message_to_mailbox(mailbox_id, test_message(0))
+            .is_some());
+        assert!(app
+            .add_message_to_mailbox(mailbox_id, test_message(1))
+            .is_some());
+        assert!(app
+            .add_message_to_mailbox(mailbox_id, test_message(2))
+            .is_none());
+        assert_eq!(app.mailboxes.get(mailbox_id).unwrap().messages.len(), 2);
+
+        // The rejected message isn't forwarded either
+        assert!(receiver.try_next().unwrap().is_some());
+        assert!(receiver.try_next().unwrap().is_some());
+        assert!(receiver.try_next().is_err());
+    }
+
+    #[test]
+    fn duplicate_adds() {
+        let mut app = App::default();
+        let (sender, mut receiver) = unbounded();
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender);
+
+        // Duplicates are kept by default
+        app.add_message_to_mailbox(mailbox_id, test_message(0));
+        app.add_message_to_mailbox(mailbox_id, test_message(0));
+        assert_eq!(app.mailboxes[mailbox_id].messages.len(), 2);
+
+        app.dedup_adds = true;
+        app.add_message_to_mailbox(mailbox_id, test_message(0))
+            .unwrap();
+        app.add_message_to_mailbox(mailbox_id, test_message(1))
+            .unwrap();
+        assert_eq!(app.mailboxes[mailbox_id].messages.len(), 3);
+        let mut resent = test_message(1);
+        resent.side = "side2".into();
+        app.add_message_to_mailbox(mailbox_id, resent).unwrap();
+        assert_eq!(app.mailboxes[mailbox_id].messages.len(), 4);
+
+        // Dropped duplicates aren't forwarded either
+        for _ in 0..4 {
+            assert!(receiver.try_next().unwrap().is_some());
+        }
+        assert!(receiver.try_next().is_err());
+    }
+
+    #[test]
+    fn multi_party_mailbox() {
+        let mut app = App {
+            max_mailbox_sides: 3,
+            ..App::default()
+        };
+        let (sender, _receiver) = unbounded();
+        let mailbox_id = app.claim_nameplate(1, "side1", sender.clone()).unwrap();
+        app.claim_nameplate(1, "side2", sender.clone()).unwrap();
+        app.open_mailbox(&mailbox_id, "side2", sender.clone())
+            .unwrap();
+
+        // The nameplate is still limited to two sides, but the mailbox takes a third
+        assert_eq!(app.claim_nameplate(1, "side3", sender.clone()), None);
+        app.open_mailbox(&mailbox_id, "side3", sender.clone())
+            .unwrap();
+        assert_eq!(app.open_mailbox(&mailbox_id, "side4", sender.clone()), None);
+
+        // Sides on other server instances count too
+        app.close_mailbox(&mailbox_id, "side3", Mood::Happy);
+        app.mailboxes
+            .get_mut(&mailbox_id)
+            .unwrap()
+            .remote_sides
+            .push("side5".into());
+        assert_eq!(app.open_mailbox(&mailbox_id, "side4", sender.clone()), None);
+        app.open_mailbox(&mailbox_id, "side5", sender).unwrap();
+        assert_eq!(app.mailboxes[&mailbox_id].subscribers.len(), 3);
+    }
+
+    #[test]
+    fn mailbox_limit_forward() {
+        let mut app = App::new(
+            "app",
+            MailboxLimits {
+                max_messages: None,
+                max_bytes: Some(8),
+                overflow: MailboxOverflow::Forward,
+            },
+            Arc::new(MemoryStore),
+            None,
+        );
+        let (sender1, mut receiver1) = unbounded();
+        let mailbox_id = "mid";
+        app.open_mailbox(mailbox_id, "side1", sender1);
+
+        for phase in 0..3 {
+            assert!(app
+                .add_message_to_mailbox(mailbox_id, test_message(phase))
+                .is_some());
+        }
+
+        // Every message reaches the current subscriber, but only the first two are stored
+        for _ in 0..3 {
+            assert!(receiver1.try_next().unwrap().is_some());
+        }
+        let mailbox = app.mailboxes.get(mailbox_id).unwrap();
+        assert_eq!(mailbox.messages.len(), 2);
+        assert_eq!(mailbox.stored_bytes, 8);
+
+        // So late subscribers only see what was stored
+        let (sender2, mut receiver2) = unbounded();
+        app.open_mailbox(mailbox_id, "side2", sender2);
+        assert!(receiver2.try_next().unwrap().is_some());
+        assert!(receiver2.try_next().unwrap().is_some());
+        assert!(receiver2.try_next().is_err());
+    }
+
+    #[test]
+    fn cluster_events() {
+        let mut app = App::default();
+        let (sender, mut receiver) = unbounded();
+
+        // Another instance allocates a nameplate and opens its mailbox
+        app.apply(ClusterEvent::NameplateAdded {
+            app_id: "appid".into(),
+            nameplate_id: 4,
+            mailbox_id: "mailbox1".into(),
+        });
+        app.apply(ClusterEvent::NameplateSideAdded {
+            app_id: "appid".into(),
+            nameplate_id: 4,
+            side: "side1".into(),
+        });
+        app.apply(ClusterEvent::SubscriberAdded {
+            app_id: "appid".into(),
+            mailbox_id: "mailbox1".into(),
+            side: "side1".into(),
+        });
+        assert_eq!(app.get_nameplates(), vec![4]);
+        assert_eq!(app.mailboxes["mailbox1"].remote_sides, vec!["side1"]);
+
+        // So a side here can claim it and receive the other side's messages
+        let mailbox_id = app.claim_nameplate(4, "side2", sender.clone()).unwrap();
+        assert_eq!(mailbox_id, "mailbox1");
+        app.open_mailbox(&mailbox_id, "side2", sender.clone())
+            .unwrap();
+        app.apply(ClusterEvent::MessageAdded {
+            app_id: "appid".into(),
+            mailbox_id: "mailbox1".into(),
+            message: (&test_message(0)).into(),
+        });
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap().ty,
+            ServerMessageType::Message { .. }
+        ));
+
+        // The mailbox stays open while the other side is subscribed elsewhere
+        app.close_mailbox(&mailbox_id, "side2", Mood::Happy);
+        assert!(app.mailboxes.contains_key(&mailbox_id));
+        app.open_mailbox(&mailbox_id, "side2", sender).unwrap();
+        app.apply(ClusterEvent::SubscriberRemoved {
+            app_id: "appid".into(),
+            mailbox_id: "mailbox1".into(),
+            side: "side1".into(),
+        });
+        app.close_mailbox(&mailbox_id, "side2", Mood::Happy);
+        assert!(app.mailboxes.is_empty());
+
+        // Mailboxes closed elsewhere are closed here too
+        app.apply(ClusterEvent::MailboxAdded {
+            app_id: "appid".into(),
+            mailbox_id: "mailbox2".into(),
+        });
+        app.apply(ClusterEvent::MailboxRemoved {
+            app_id: "appid".into(),
+            mailbox_id: "mailbox2".into(),
+        });
+        app.apply(ClusterEvent::NameplateRemoved {
+            app_id: "appid".into(),
+            nameplate_id: 4,
+        });
+        assert!(app.mailboxes.is_empty());
+        assert!(app.nameplates.is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+use clap::Parser;
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+
+use telemetry::LogFormat;
+#[cfg(feature = "tls")]
+use wormhole_mailbox_server::TlsConfig;
+use wormhole_mailbox_server::{
+    admin_router, metrics_router, CaptureLog, ClientQuota, ClusterStore, JournalStore,
+    JsonlUsageSink, MailboxLimits, MailboxOverflow, MailboxServer, MemoryStore,
+    NameplateAllocation, Rate, RateLimits, RedisStore, RuntimeSettings, ServerConfig,
+    SettingsError, SqliteStore, SqliteUsageSink, Store, StoreKind, UsageSink,
+    DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+mod telemetry;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Run a Magic Wormhole mailbox server.")]
+struct Cli {
+    /// Address to listen for WebSocket connections on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:4000")]
+    listen: String,
+
+    /// Disconnect clients which send nothing for this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Largest message body clients may add to a mailbox, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+
+    /// Largest WebSocket frame or message accepted from clients, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+
+    /// Maximum number of messages stored in each mailbox
+    #[arg(long, value_name = "COUNT")]
+    max_mailbox_messages: Option<usize>,
+
+    /// Maximum total size of the messages stored in each mailbox, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_mailbox_bytes: Option<usize>,
+
+    /// What to do with messages added to a full mailbox
+    #[arg(long, value_enum, default_value_t = MailboxOverflow::Reject)]
+    mailbox_overflow: MailboxOverflow,
+
+    /// Drop messages with the same side and phase as one already in the mailbox, e.g. when a
+    /// client resends after reconnecting
+    #[arg(long)]
+    dedup_adds: bool,
+
+    /// Maximum nameplates, and separately mailboxes, one side may hold at once
+    #[arg(long, value_name = "COUNT")]
+    max_per_side: Option<usize>,
+
+    /// Maximum nameplates, and separately mailboxes, one IP address may hold at once
+    #[arg(long, value_name = "COUNT")]
+    max_per_ip: Option<usize>,
+
+    /// Allocate, claim and add requests allowed per second on each connection
+    #[arg(long, value_name = "RATE")]
+    rate_limit: Option<f64>,
+
+    /// Allocate, claim and add requests allowed per second from each IP address
+    #[arg(long, value_name = "RATE")]
+    ip_rate_limit: Option<f64>,
+
+    /// Number of requests allowed in a burst above the rate limits
+    #[arg(long, value_name = "COUNT", default_value_t = 10.0)]
+    rate_limit_burst: f64,
+
+    /// Maximum number of simultaneous client connections
+    #[arg(long, value_name = "COUNT")]
+    max_connections: Option<usize>,
+
+    /// Maximum nameplates active at once in each application namespace
+    #[arg(long, value_name = "COUNT")]
+    max_app_nameplates: Option<usize>,
+
+    /// How to pick nameplates for clients which ask to be allocated one
+    #[arg(long, value_enum, default_value_t = NameplateAllocation::Random)]
+    nameplate_allocation: NameplateAllocation,
+
+    /// Largest nameplate ID to allocate; without one, IDs get longer as more are in use
+    #[arg(long, value_name = "ID")]
+    max_nameplate_id: Option<usize>,
+
+    /// Answer list requests with no nameplates, so active ones can't be discovered
+    #[arg(long)]
+    disable_list: bool,
+
+    /// Require clients to solve a hashcash challenge with this many bits of work before binding
+    #[arg(long, value_name = "BITS")]
+    hashcash_bits: Option<u32>,
+
+    /// Free nameplates which no second side has claimed within this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    nameplate_ttl: Option<u64>,
+
+    /// Close mailboxes which nobody has opened or added to within this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    mailbox_ttl: Option<u64>,
+
+    /// Keep the nameplates and mailboxes of clients whose connections drop for this many
+    /// seconds, so they can bind again with the same side and carry on where they left off
+    #[arg(long, value_name = "SECONDS")]
+    reconnect_grace: Option<u64>,
+
+    /// Where to keep nameplates and mailboxes; anything but memory survives a restart
+    #[arg(long, value_enum, default_value_t = StoreKind::Memory)]
+    store: StoreKind,
+
+    /// SQLite database file to use with the sqlite store
+    #[arg(long, value_name = "PATH", required_if_eq("store", "sqlite"))]
+    database: Option<PathBuf>,
+
+    /// Journal file to use with the journal store
+    #[arg(long, value_name = "PATH", required_if_eq("store", "journal"))]
+    journal: Option<PathBuf>,
+
+    /// Redis server to use with the redis store
+    #[arg(long, value_name = "URL", default_value = "redis://127.0.0.1/")]
+    redis_url: String,
+
+    /// Share nameplates and mailboxes with other instances using the same Redis server, so
+    /// they can run behind a load balancer; requires --store redis
+    #[arg(long)]
+    cluster: bool,
+
+    /// Record the usage of each mailbox in this SQLite database
+    #[arg(long, value_name = "PATH", conflicts_with = "usage_log")]
+    usage_db: Option<PathBuf>,
+
+    /// Record the usage of each mailbox as lines of JSON appended to this file
+    #[arg(long, value_name = "PATH")]
+    usage_log: Option<PathBuf>,
+
+    /// Append every protocol message to this file as lines of JSON, with message bodies hashed,
+    /// for debugging
+    #[arg(long, value_name = "PATH")]
+    capture_log: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+
+    /// Address to serve the admin API on, for listing and expiring nameplates, mailboxes and
+    /// connections
+    #[arg(long, value_name = "ADDR", requires = "admin_token_file")]
+    admin_listen: Option<String>,
+
+    /// File containing the bearer token admin API requests must carry
+    #[arg(long, value_name = "PATH")]
+    admin_token_file: Option<PathBuf>,
+
+    /// PEM certificate chain to serve clients over TLS with (needs the tls feature)
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for the TLS certificate
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of CA certificates; clients must present a certificate signed by one of them
+    /// before the WebSocket handshake
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// OpenTelemetry collector to export tracing spans to over OTLP, e.g. http://localhost:4317
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// How to format log output
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Message of the day to show clients when they connect
+    #[arg(long, value_name = "TEXT")]
+    motd: Option<String>,
+
+    /// Latest release of the command-line client, so older clients can suggest upgrading
+    #[arg(long, value_name = "VERSION")]
+    current_cli_version: Option<String>,
+
+    /// Transit relay to advertise to clients, as a hint like tcp:transit.example.com:4001; may be
+    /// given more than once
+    #[arg(long = "transit-relay", value_name = "HINT")]
+    transit_relays: Vec<String>,
+
+    /// TOML file of settings to apply on startup, and again whenever the server receives SIGHUP.
+    /// These take precedence over the equivalent command line options
+    #[arg(long, value_name = "PATH")]
+    settings: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Open the configured usage sink, if any.
+    fn open_usage_sink(&self) -> Option<Arc<dyn UsageSink>> {
+        if let Some(path) = &self.usage_db {
+            Some(Arc::new(
+                SqliteUsageSink::open(path).expect("Failed to open usage database"),
+            ))
+        } else {
+            self.usage_log.as_ref().map(|path| {
+                Arc::new(JsonlUsageSink::open(path).expect("Failed to open usage log"))
+                    as Arc<dyn UsageSink>
+            })
+        }
+    }
+
+    /// Load the runtime settings, from the settings file if there is one.
+    fn load_settings(&self) -> Result<RuntimeSettings, SettingsError> {
+        let defaults = RuntimeSettings {
+            motd: self.motd.clone(),
+            current_cli_version: self.current_cli_version.clone(),
+            transit_relays: self.transit_relays.clone(),
+            ..RuntimeSettings::default()
+        };
+        match &self.settings {
+            Some(path) => Ok(RuntimeSettings::load(path)?.or(defaults)),
+            None => Ok(defaults),
+        }
+    }
+
+    /// Open the configured storage backend.
+    fn open_store(&self) -> Arc<dyn Store> {
+        match self.store {
+            StoreKind::Memory => Arc::new(MemoryStore),
+            StoreKind::Sqlite => {
+                let path = self.database.as_ref().expect("required by clap");
+                Arc::new(SqliteStore::open(path).expect("Failed to open database"))
+            }
+            StoreKind::Redis => {
+                Arc::new(RedisStore::open(&self.redis_url).expect("Failed to connect to Redis"))
+            }
+            StoreKind::Journal => {
+                let path = self.journal.as_ref().expect("required by clap");
+                Arc::new(JournalStore::open(path).expect("Failed to open journal"))
+            }
+        }
+    }
+}
+
+impl From<&Cli> for ServerConfig {
+    fn from(cli: &Cli) -> Self {
+        ServerConfig {
+            idle_timeout: cli.idle_timeout.map(Duration::from_secs),
+            max_message_size: cli.max_message_size,
+            max_frame_size: cli.max_frame_size,
+            mailbox_limits: MailboxLimits {
+                max_messages: cli.max_mailbox_messages,
+                max_bytes: cli.max_mailbox_bytes,
+                overflow: cli.mailbox_overflow,
+            },
+            client_quota: ClientQuota {
+                per_side: cli.max_per_side,
+                per_ip: cli.max_per_ip,
+            },
+            max_connections: cli.max_connections,
+            rate_limits: RateLimits {
+                per_connection: cli.rate_limit.map(|per_second| Rate {
+                    per_second,
+                    burst: cli.rate_limit_burst,
+                }),
+                per_ip: cli.ip_rate_limit.map(|per_second| Rate {
+                    per_second,
+                    burst: cli.rate_limit_burst,
+                }),
+            },
+            max_app_nameplates: cli.max_app_nameplates,
+            nameplate_allocation: cli.nameplate_allocation,
+            max_nameplate_id: cli.max_nameplate_id,
+            disable_list: cli.disable_list,
+            dedup_adds: cli.dedup_adds,
+            hashcash_bits: cli.hashcash_bits,
+            nameplate_ttl: cli.nameplate_ttl.map(Duration::from_secs),
+            mailbox_ttl: cli.mailbox_ttl.map(Duration::from_secs),
+            reconnect_grace: cli.reconnect_grace.map(Duration::from_secs),
+        }
+    }
+}
+
+/// Reload the runtime settings whenever the server receives SIGHUP. If the settings file can't
+/// be loaded, the current settings are kept.
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<MailboxServer>, cli: Arc<Cli>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    while hangups.recv().await.is_some() {
+        match cli.load_settings() {
+            Ok(settings) => {
+                info!(?settings, "Reloaded settings");
+                state.set_settings(settings);
+            }
+            Err(e) => error!(error = %e, "Failed to reload settings, keeping current settings"),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), io::Error> {
+    let cli = Arc::new(Cli::parse());
+    let _telemetry = telemetry::init(cli.log_format, cli.otlp_endpoint.as_deref());
+
+    let listener = TcpListener::bind(&cli.listen)
+        .await
+        .expect("Failed to bind");
+    debug!(addr = %cli.listen, "Listening");
+
+    let config = ServerConfig::from(&*cli);
+    let cluster = cli.cluster.then(|| {
+        assert!(
+            cli.store == StoreKind::Redis,
+            "--cluster requires --store redis"
+        );
+        Arc::new(ClusterStore::open(&cli.redis_url).expect("Failed to connect to Redis"))
+    });
+    let store = match &cluster {
+        Some(cluster) => cluster.clone(),
+        None => cli.open_store(),
+    };
+    let mut state = MailboxServer::with_store(config, store, cli.open_usage_sink())
+        .expect("Failed to load store");
+    if let Some(path) = &cli.capture_log {
+        state = state.with_capture_log(CaptureLog::open(path).expect("Failed to open capture log"));
+    }
+    #[cfg(feature = "tls")]
+    if let Some(cert) = &cli.tls_cert {
+        let key = cli.tls_key.as_ref().expect("required by clap");
+        let tls = TlsConfig::load(cert, key, cli.tls_client_ca.as_deref())
+            .expect("Failed to load TLS certificates");
+        state = state.with_tls(tls);
+    }
+    #[cfg(not(feature = "tls"))]
+    assert!(
+        cli.tls_cert.is_none(),
+        "Built without the tls feature, can't serve over TLS"
+    );
+    let state = Arc::new(state);
+    if let Some(cluster) = &cluster {
+        cluster
+            .subscribe(&state)
+            .expect("Failed to subscribe to cluster changes");
+    }
+    state.set_settings(cli.load_settings().expect("Failed to load settings"));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(state.clone(), cli.clone()));
+
+    if let Some(addr) = &cli.metrics_listen {
+        let metrics_listener = TcpListener::bind(addr)
+            .await
+            .expect("Failed to bind metrics address");
+        debug!(%addr, "Serving metrics");
+        let router = metrics_router(state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(metrics_listener, router).await {
+                error!(error = %e, "Metrics server failed");
+            }
+        });
+    }
+
+    if let Some(addr) = &cli.admin_listen {
+        let path = cli.admin_token_file.as_ref().expect("required by clap");
+        let token = std::fs::read_to_string(path).expect("Failed to read admin token file");
+        let token = token.trim();
+        assert!(!token.is_empty(), "Admin token file is empty");
+        let admin_listener = TcpListener::bind(addr)
+            .await
+            .expect("Failed to bind admin address");
+        debug!(%addr, "Serving admin API");
+        let router = admin_router(state.clone(), token);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(admin_listener, router).await {
+                error!(error = %e, "Admin server failed");
+            }
+        });
+    }
+
+    state.run(listener).await
+}
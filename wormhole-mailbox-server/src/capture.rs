@@ -0,0 +1,72 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use wormhole_core::message::redact;
+
+/// Which way a captured message was travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    /// From the client to the server.
+    In,
+    /// From the server to the client.
+    Out,
+}
+
+/// One line of the capture file.
+#[derive(Debug, Serialize)]
+struct CapturedMessage {
+    /// When the message was sent or received, in seconds since the Unix epoch.
+    time: f64,
+    /// ID of the connection the message was sent on.
+    connection: u64,
+    direction: Direction,
+    /// The message, with bodies replaced by their length and hash and tokens removed, or the
+    /// start of the raw text if it isn't JSON.
+    message: Value,
+}
+
+/// Appends every protocol message to a file as a line of JSON, for replaying and diagnosing
+/// protocol bugs.
+#[derive(Debug)]
+pub struct CaptureLog {
+    file: Mutex<File>,
+}
+
+impl CaptureLog {
+    /// Open (creating if necessary) the file at the given path for appending.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(CaptureLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append a message sent or received on the given connection.
+    pub(crate) fn record(
+        &self,
+        connection: u64,
+        direction: Direction,
+        text: &str,
+    ) -> io::Result<()> {
+        let captured = CapturedMessage {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            connection,
+            direction,
+            message: redact(text),
+        };
+        let mut line = serde_json::to_vec(&captured)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
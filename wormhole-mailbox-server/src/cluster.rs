@@ -0,0 +1,356 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
+use tracing::{debug, error, warn};
+
+use crate::app::MailboxMessage;
+use crate::redis_store::RedisStore;
+use crate::server::MailboxServer;
+use crate::store::{Store, StoreError, StoredApp, StoredMessage};
+
+/// The Redis pub/sub channel every instance publishes its changes on.
+const CHANNEL: &str = "wormhole:events";
+
+/// How long to wait for published changes before checking the server still exists.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before resubscribing after losing the connection to Redis.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// A change to an application namespace made by one server instance, for the others to apply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum ClusterEvent {
+    NameplateAdded {
+        app_id: String,
+        nameplate_id: usize,
+        mailbox_id: String,
+    },
+    NameplateRemoved {
+        app_id: String,
+        nameplate_id: usize,
+    },
+    NameplateSideAdded {
+        app_id: String,
+        nameplate_id: usize,
+        side: String,
+    },
+    NameplateSideRemoved {
+        app_id: String,
+        nameplate_id: usize,
+        side: String,
+    },
+    MailboxAdded {
+        app_id: String,
+        mailbox_id: String,
+    },
+    MailboxRemoved {
+        app_id: String,
+        mailbox_id: String,
+    },
+    SubscriberAdded {
+        app_id: String,
+        mailbox_id: String,
+        side: String,
+    },
+    SubscriberRemoved {
+        app_id: String,
+        mailbox_id: String,
+        side: String,
+    },
+    MessageAdded {
+        app_id: String,
+        mailbox_id: String,
+        message: StoredMessage,
+    },
+}
+
+impl ClusterEvent {
+    /// The application namespace the change was made in.
+    pub(crate) fn app_id(&self) -> &str {
+        match self {
+            ClusterEvent::NameplateAdded { app_id, .. }
+            | ClusterEvent::NameplateRemoved { app_id, .. }
+            | ClusterEvent::NameplateSideAdded { app_id, .. }
+            | ClusterEvent::NameplateSideRemoved { app_id, .. }
+            | ClusterEvent::MailboxAdded { app_id, .. }
+            | ClusterEvent::MailboxRemoved { app_id, .. }
+            | ClusterEvent::SubscriberAdded { app_id, .. }
+            | ClusterEvent::SubscriberRemoved { app_id, .. }
+            | ClusterEvent::MessageAdded { app_id, .. } => app_id,
+        }
+    }
+}
+
+/// A published change, tagged with the instance which made it so it can ignore its own.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    instance: String,
+    #[serde(flatten)]
+    event: ClusterEvent,
+}
+
+/// A Redis store shared by several server instances behind a load balancer, so clients
+/// connected to different instances can still rendezvous.
+///
+/// Each instance keeps its own copy of the state in memory. Every change is written to Redis
+/// as with [`RedisStore`], and also published on the `wormhole:events` channel, which the
+/// other instances apply to their copies, forwarding any new messages to their own
+/// subscribers. Instances don't coordinate before allocating nameplates, so two may pick the
+/// same free nameplate at once; random allocation makes this unlikely. Messages forwarded but
+/// not stored because a mailbox is full don't reach other instances.
+pub struct ClusterStore {
+    store: RedisStore,
+    /// Random ID distinguishing this instance's changes from the others'.
+    instance: String,
+}
+
+impl fmt::Debug for ClusterStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClusterStore")
+            .field("store", &self.store)
+            .field("instance", &self.instance)
+            .finish()
+    }
+}
+
+impl ClusterStore {
+    /// Connect to the Redis server at the given URL, e.g. `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> redis::RedisResult<Self> {
+        let mut buffer = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut buffer);
+        Ok(ClusterStore {
+            store: RedisStore::open(url)?,
+            instance: hex::encode(buffer),
+        })
+    }
+
+    /// Apply the changes other instances publish to the given server, on a background thread
+    /// which runs until the server is dropped. Changes published before this is called, or
+    /// while the connection to Redis is down, are missed.
+    pub fn subscribe(&self, server: &Arc<MailboxServer>) -> redis::RedisResult<()> {
+        let client = redis::Client::open(self.store.url())?;
+        let instance = self.instance.clone();
+        let server = Arc::downgrade(server);
+        thread::Builder::new()
+            .name("cluster-subscriber".into())
+            .spawn(move || {
+                while server.strong_count() > 0 {
+                    if let Err(e) = receive_events(&client, &instance, &server) {
+                        error!(error = %e, "Lost cluster subscription, changes may be missed");
+                        thread::sleep(RESUBSCRIBE_DELAY);
+                    }
+                }
+            })
+            .expect("Failed to spawn cluster subscriber");
+        Ok(())
+    }
+
+    /// Record a change in Redis, then tell the other instances about it.
+    fn publish(
+        &self,
+        change: impl FnOnce(&RedisStore) -> Result<(), StoreError>,
+        event: ClusterEvent,
+    ) -> Result<(), StoreError> {
+        change(&self.store)?;
+        let envelope = Envelope {
+            instance: self.instance.clone(),
+            event,
+        };
+        self.store
+            .publish(CHANNEL, &serde_json::to_string(&envelope)?)
+    }
+}
+
+/// Apply changes published by other instances until the server is dropped or the connection
+/// fails.
+fn receive_events(
+    client: &redis::Client,
+    instance: &str,
+    server: &Weak<MailboxServer>,
+) -> redis::RedisResult<()> {
+    let mut conn = client.get_connection()?;
+    let mut pubsub = conn.as_pubsub();
+    pubsub.subscribe(CHANNEL)?;
+    pubsub.set_read_timeout(Some(POLL_INTERVAL))?;
+    debug!("Subscribed to cluster changes");
+
+    loop {
+        let message = match pubsub.get_message() {
+            Ok(message) => message,
+            Err(e) if e.is_timeout() => {
+                if server.strong_count() == 0 {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let payload: String = message.get_payload()?;
+        let envelope = match serde_json::from_str::<Envelope>(&payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!(error = %e, "Ignoring undecodable cluster change");
+                continue;
+            }
+        };
+        if envelope.instance == instance {
+            continue;
+        }
+        let Some(server) = server.upgrade() else {
+            return Ok(());
+        };
+        server.apply_cluster_event(envelope.event);
+    }
+}
+
+impl Store for ClusterStore {
+    fn load(&self) -> Result<HashMap<String, StoredApp>, StoreError> {
+        self.store.load()
+    }
+
+    fn add_nameplate(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        mailbox_id: &str,
+    ) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.add_nameplate(app_id, nameplate_id, mailbox_id),
+            ClusterEvent::NameplateAdded {
+                app_id: app_id.to_owned(),
+                nameplate_id,
+                mailbox_id: mailbox_id.to_owned(),
+            },
+        )
+    }
+
+    fn remove_nameplate(&self, app_id: &str, nameplate_id: usize) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.remove_nameplate(app_id, nameplate_id),
+            ClusterEvent::NameplateRemoved {
+                app_id: app_id.to_owned(),
+                nameplate_id,
+            },
+        )
+    }
+
+    fn add_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.add_nameplate_side(app_id, nameplate_id, side),
+            ClusterEvent::NameplateSideAdded {
+                app_id: app_id.to_owned(),
+                nameplate_id,
+                side: side.to_owned(),
+            },
+        )
+    }
+
+    fn remove_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.remove_nameplate_side(app_id, nameplate_id, side),
+            ClusterEvent::NameplateSideRemoved {
+                app_id: app_id.to_owned(),
+                nameplate_id,
+                side: side.to_owned(),
+            },
+        )
+    }
+
+    fn add_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.add_mailbox(app_id, mailbox_id),
+            ClusterEvent::MailboxAdded {
+                app_id: app_id.to_owned(),
+                mailbox_id: mailbox_id.to_owned(),
+            },
+        )
+    }
+
+    fn remove_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.remove_mailbox(app_id, mailbox_id),
+            ClusterEvent::MailboxRemoved {
+                app_id: app_id.to_owned(),
+                mailbox_id: mailbox_id.to_owned(),
+            },
+        )
+    }
+
+    fn add_message(
+        &self,
+        app_id: &str,
+        mailbox_id: &str,
+        message: &MailboxMessage,
+    ) -> Result<(), StoreError> {
+        self.publish(
+            |store| store.add_message(app_id, mailbox_id, message),
+            ClusterEvent::MessageAdded {
+                app_id: app_id.to_owned(),
+                mailbox_id: mailbox_id.to_owned(),
+                message: message.into(),
+            },
+        )
+    }
+
+    fn add_subscriber(&self, app_id: &str, mailbox_id: &str, side: &str) -> Result<(), StoreError> {
+        self.publish(
+            |_| Ok(()),
+            ClusterEvent::SubscriberAdded {
+                app_id: app_id.to_owned(),
+                mailbox_id: mailbox_id.to_owned(),
+                side: side.to_owned(),
+            },
+        )
+    }
+
+    fn remove_subscriber(
+        &self,
+        app_id: &str,
+        mailbox_id: &str,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.publish(
+            |_| Ok(()),
+            ClusterEvent::SubscriberRemoved {
+                app_id: app_id.to_owned(),
+                mailbox_id: mailbox_id.to_owned(),
+                side: side.to_owned(),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClusterEvent, Envelope};
+
+    #[test]
+    fn event_encoding() {
+        let encoded = r#"{"instance":"abcd","event":"nameplate-side-added","app_id":"appid","nameplate_id":4,"side":"side1"}"#;
+        let envelope = serde_json::from_str::<Envelope>(encoded).unwrap();
+        assert_eq!(envelope.instance, "abcd");
+        assert_eq!(envelope.event.app_id(), "appid");
+        assert!(matches!(
+            envelope.event,
+            ClusterEvent::NameplateSideAdded { nameplate_id: 4, ref side, .. } if side == "side1"
+        ));
+        assert_eq!(serde_json::to_string(&envelope).unwrap(), encoded);
+    }
+}
@@ -0,0 +1,503 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::app::{Mailbox, MailboxMessage, Nameplate};
+use crate::store::{Store, StoreError, StoredApp, StoredMessage};
+
+/// Don't bother compacting journals with fewer entries than this.
+const MIN_COMPACTION_ENTRIES: usize = 1024;
+
+/// A single change, as recorded on one line of the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    AddNameplate {
+        app_id: String,
+        nameplate_id: usize,
+        mailbox_id: String,
+    },
+    RemoveNameplate {
+        app_id: String,
+        nameplate_id: usize,
+    },
+    AddNameplateSide {
+        app_id: String,
+        nameplate_id: usize,
+        side: String,
+    },
+    RemoveNameplateSide {
+        app_id: String,
+        nameplate_id: usize,
+        side: String,
+    },
+    AddMailbox {
+        app_id: String,
+        mailbox_id: String,
+    },
+    RemoveMailbox {
+        app_id: String,
+        mailbox_id: String,
+    },
+    AddMessage {
+        app_id: String,
+        mailbox_id: String,
+        message: StoredMessage,
+    },
+}
+
+/// What the journal currently describes for one application namespace.
+#[derive(Debug, Default)]
+struct JournalApp {
+    /// Mailbox ID and claiming sides of each nameplate.
+    nameplates: HashMap<usize, (String, Vec<String>)>,
+    /// Stored messages of each mailbox.
+    mailboxes: HashMap<String, Vec<StoredMessage>>,
+}
+
+/// The open journal file, along with the state it describes.
+#[derive(Debug)]
+struct Journal {
+    path: PathBuf,
+    file: File,
+    apps: HashMap<String, JournalApp>,
+    /// Number of entries in the file, live or not.
+    entries: usize,
+}
+
+impl Journal {
+    /// Update the described state with the given entry.
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::AddNameplate {
+                app_id,
+                nameplate_id,
+                mailbox_id,
+            } => {
+                self.apps
+                    .entry(app_id)
+                    .or_default()
+                    .nameplates
+                    .insert(nameplate_id, (mailbox_id, Vec::new()));
+            }
+            Entry::RemoveNameplate {
+                app_id,
+                nameplate_id,
+            } => {
+                if let Some(app) = self.apps.get_mut(&app_id) {
+                    app.nameplates.remove(&nameplate_id);
+                }
+            }
+            Entry::AddNameplateSide {
+                app_id,
+                nameplate_id,
+                side,
+            } => {
+                if let Some((_, sides)) = self
+                    .apps
+                    .get_mut(&app_id)
+                    .and_then(|app| app.nameplates.get_mut(&nameplate_id))
+                {
+                    if !sides.contains(&side) {
+                        sides.push(side);
+                    }
+                }
+            }
+            Entry::RemoveNameplateSide {
+                app_id,
+                nameplate_id,
+                side,
+            } => {
+                if let Some((_, sides)) = self
+                    .apps
+                    .get_mut(&app_id)
+                    .and_then(|app| app.nameplates.get_mut(&nameplate_id))
+                {
+                    sides.retain(|s| *s != side);
+                }
+            }
+            Entry::AddMailbox { app_id, mailbox_id } => {
+                self.apps
+                    .entry(app_id)
+                    .or_default()
+                    .mailboxes
+                    .entry(mailbox_id)
+                    .or_default();
+            }
+            Entry::RemoveMailbox { app_id, mailbox_id } => {
+                if let Some(app) = self.apps.get_mut(&app_id) {
+                    app.mailboxes.remove(&mailbox_id);
+                }
+            }
+            Entry::AddMessage {
+                app_id,
+                mailbox_id,
+                message,
+            } => {
+                if let Some(messages) = self
+                    .apps
+                    .get_mut(&app_id)
+                    .and_then(|app| app.mailboxes.get_mut(&mailbox_id))
+                {
+                    messages.push(message);
+                }
+            }
+        }
+    }
+
+    /// The smallest set of entries which describe the current state.
+    fn live_entries(&self) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        for (app_id, app) in &self.apps {
+            for (mailbox_id, messages) in &app.mailboxes {
+                entries.push(Entry::AddMailbox {
+                    app_id: app_id.clone(),
+                    mailbox_id: mailbox_id.clone(),
+                });
+                entries.extend(messages.iter().map(|message| Entry::AddMessage {
+                    app_id: app_id.clone(),
+                    mailbox_id: mailbox_id.clone(),
+                    message: message.clone(),
+                }));
+            }
+            for (nameplate_id, (mailbox_id, sides)) in &app.nameplates {
+                entries.push(Entry::AddNameplate {
+                    app_id: app_id.clone(),
+                    nameplate_id: *nameplate_id,
+                    mailbox_id: mailbox_id.clone(),
+                });
+                entries.extend(sides.iter().map(|side| Entry::AddNameplateSide {
+                    app_id: app_id.clone(),
+                    nameplate_id: *nameplate_id,
+                    side: side.clone(),
+                }));
+            }
+        }
+        entries
+    }
+
+    /// How many entries `live_entries` would return.
+    fn live_count(&self) -> usize {
+        self.apps
+            .values()
+            .map(|app| {
+                app.mailboxes.values().map(|m| 1 + m.len()).sum::<usize>()
+                    + app
+                        .nameplates
+                        .values()
+                        .map(|(_, s)| 1 + s.len())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Append an entry to the journal, and wait for it to reach the disk.
+    fn append(&mut self, entry: Entry) -> Result<(), StoreError> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.entries += 1;
+        self.apply(entry);
+        Ok(())
+    }
+
+    /// Rewrite the journal with only its live entries. The new journal is written alongside
+    /// and renamed into place, so a crash part way through leaves the old one intact.
+    fn compact(&mut self) -> Result<(), StoreError> {
+        let entries = self.live_entries();
+        let compact_path = self.path.with_extension("compact");
+        {
+            let mut file = File::create(&compact_path)?;
+            for entry in &entries {
+                let mut line = serde_json::to_vec(entry)?;
+                line.push(b'\n');
+                file.write_all(&line)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&compact_path, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries = entries.len();
+        Ok(())
+    }
+}
+
+/// A store keeping an append-only journal of changes to nameplates and mailboxes in a file.
+///
+/// Every change is synced to disk before the store returns, and the journal is replayed on
+/// startup. Once enough mailboxes have closed that most of the journal describes state which
+/// no longer exists, it is compacted down to just the live entries.
+#[derive(Debug)]
+pub struct JournalStore {
+    journal: Mutex<Journal>,
+}
+
+impl JournalStore {
+    /// Open (creating if necessary) the journal at the given path, and replay it. Only the
+    /// final entry may be unreadable, as the write of it was torn by a crash.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut journal = Journal {
+            path,
+            file: file.try_clone()?,
+            apps: HashMap::new(),
+            entries: 0,
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        let mut number = 0;
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            number += 1;
+            match serde_json::from_slice(&line) {
+                Ok(entry) => journal.apply(entry),
+                // A write torn by a crash can only be the final entry, which never got its
+                // newline. Anything else unreadable would lose every later entry if skipped.
+                Err(e) if !line.ends_with(b"\n") => {
+                    warn!(error = %e, "Ignoring torn final journal entry");
+                }
+                Err(source) => {
+                    return Err(StoreError::CorruptJournal {
+                        line: number,
+                        source,
+                    })
+                }
+            }
+            line.clear();
+        }
+
+        // Start from a clean journal, which also drops any torn entry
+        journal.compact()?;
+        Ok(JournalStore {
+            journal: Mutex::new(journal),
+        })
+    }
+
+    fn append(&self, entry: Entry) -> Result<(), StoreError> {
+        self.journal.lock().unwrap().append(entry)
+    }
+}
+
+impl Store for JournalStore {
+    fn load(&self) -> Result<HashMap<String, StoredApp>, StoreError> {
+        let journal = self.journal.lock().unwrap();
+        let mut apps = HashMap::new();
+        for (app_id, app) in &journal.apps {
+            let mut stored = StoredApp::default();
+            for (mailbox_id, messages) in &app.mailboxes {
+                let mut mailbox = Mailbox::default();
+                for message in messages {
+                    mailbox.stored_bytes += message.body.len();
+                    mailbox.messages.push(MailboxMessage::from(message.clone()));
+                }
+                stored.mailboxes.insert(mailbox_id.clone(), mailbox);
+            }
+            for (nameplate_id, (mailbox_id, sides)) in &app.nameplates {
+                stored.nameplates.insert(
+                    *nameplate_id,
+                    Nameplate::new(mailbox_id.clone(), sides.clone()),
+                );
+            }
+            apps.insert(app_id.clone(), stored);
+        }
+        Ok(apps)
+    }
+
+    fn add_nameplate(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        mailbox_id: &str,
+    ) -> Result<(), StoreError> {
+        self.append(Entry::AddNameplate {
+            app_id: app_id.to_owned(),
+            nameplate_id,
+            mailbox_id: mailbox_id.to_owned(),
+        })
+    }
+
+    fn remove_nameplate(&self, app_id: &str, nameplate_id: usize) -> Result<(), StoreError> {
+        self.append(Entry::RemoveNameplate {
+            app_id: app_id.to_owned(),
+            nameplate_id,
+        })
+    }
+
+    fn add_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.append(Entry::AddNameplateSide {
+            app_id: app_id.to_owned(),
+            nameplate_id,
+            side: side.to_owned(),
+        })
+    }
+
+    fn remove_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.append(Entry::RemoveNameplateSide {
+            app_id: app_id.to_owned(),
+            nameplate_id,
+            side: side.to_owned(),
+        })
+    }
+
+    fn add_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        self.append(Entry::AddMailbox {
+            app_id: app_id.to_owned(),
+            mailbox_id: mailbox_id.to_owned(),
+        })
+    }
+
+    fn remove_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        let mut journal = self.journal.lock().unwrap();
+        journal.append(Entry::RemoveMailbox {
+            app_id: app_id.to_owned(),
+            mailbox_id: mailbox_id.to_owned(),
+        })?;
+
+        if journal.entries >= MIN_COMPACTION_ENTRIES && journal.entries > 2 * journal.live_count() {
+            journal.compact()?;
+        }
+        Ok(())
+    }
+
+    fn add_message(
+        &self,
+        app_id: &str,
+        mailbox_id: &str,
+        message: &MailboxMessage,
+    ) -> Result<(), StoreError> {
+        self.append(Entry::AddMessage {
+            app_id: app_id.to_owned(),
+            mailbox_id: mailbox_id.to_owned(),
+            message: message.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JournalStore, MailboxMessage, Store, StoreError, MIN_COMPACTION_ENTRIES};
+    use std::{fs, io::Write};
+    use wormhole_core::message::Phase;
+
+    fn test_message() -> MailboxMessage {
+        MailboxMessage {
+            id: "msgid".into(),
+            timestamp: 1.5,
+            side: "side1".into(),
+            phase: Phase::Pake,
+            body: b"body".as_slice().into(),
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let path =
+            std::env::temp_dir().join(format!("wormhole-replay-{}.journal", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = JournalStore::open(&path).unwrap();
+        store.add_mailbox("app1", "mid").unwrap();
+        store.add_nameplate("app1", 4, "mid").unwrap();
+        store.add_nameplate_side("app1", 4, "side1").unwrap();
+        store.add_nameplate_side("app1", 4, "side2").unwrap();
+        store.remove_nameplate_side("app1", 4, "side1").unwrap();
+        store.add_message("app1", "mid", &test_message()).unwrap();
+        drop(store);
+
+        // A write torn by a crash is ignored
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"op":"add_mailbox","app_"#).unwrap();
+        drop(file);
+
+        let apps = JournalStore::open(&path).unwrap().load().unwrap();
+        let app = &apps["app1"];
+        assert_eq!(app.nameplates[&4].mailbox_id, "mid");
+        assert_eq!(app.nameplates[&4].sides, vec!["side2"]);
+        let mailbox = &app.mailboxes["mid"];
+        assert_eq!(mailbox.messages.len(), 1);
+        assert_eq!(&*mailbox.messages[0].body, b"body");
+        assert_eq!(mailbox.stored_bytes, 4);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corruption() {
+        let path =
+            std::env::temp_dir().join(format!("wormhole-corrupt-{}.journal", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = JournalStore::open(&path).unwrap();
+        store.add_mailbox("app1", "mid").unwrap();
+        drop(store);
+
+        // An unreadable entry before others isn't a torn write, so the journal isn't trusted
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"op\":\"add_mailbox\",\"app_\n").unwrap();
+        file.write_all(b"{\"op\":\"add_mailbox\",\"app_id\":\"app1\",\"mailbox_id\":\"mid2\"}\n")
+            .unwrap();
+        drop(file);
+
+        assert!(matches!(
+            JournalStore::open(&path),
+            Err(StoreError::CorruptJournal { line: 2, .. })
+        ));
+        // Nor is it compacted away
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let path =
+            std::env::temp_dir().join(format!("wormhole-compact-{}.journal", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = JournalStore::open(&path).unwrap();
+        store.add_mailbox("app1", "kept").unwrap();
+        for i in 0..MIN_COMPACTION_ENTRIES {
+            let mailbox_id = format!("mid{}", i);
+            store.add_mailbox("app1", &mailbox_id).unwrap();
+            store.remove_mailbox("app1", &mailbox_id).unwrap();
+        }
+
+        // Only the surviving mailbox is left in the journal
+        let journal = fs::read_to_string(&path).unwrap();
+        assert!(journal.lines().count() < MIN_COMPACTION_ENTRIES);
+        assert_eq!(
+            store.load().unwrap()["app1"]
+                .mailboxes
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["kept"]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! A Magic Wormhole mailbox server, which other programs can run in-process as well as through
+//! the `wormhole-mailbox` binary.
+//!
+//! ```no_run
+//! use wormhole_mailbox_server::{MailboxServer, ServerConfig};
+//! use tokio::net::TcpListener;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let listener = TcpListener::bind("127.0.0.1:4000").await?;
+//! MailboxServer::serve(listener, ServerConfig::default()).await
+//! # }
+//! ```
+mod admin;
+mod app;
+mod capture;
+mod cluster;
+mod journal_store;
+mod metrics;
+mod rate_limit;
+mod redis_store;
+mod server;
+mod settings;
+mod sqlite_store;
+mod store;
+#[cfg(feature = "tls")]
+mod tls;
+mod usage;
+mod websocket;
+
+pub use admin::router as admin_router;
+pub use app::{MailboxLimits, MailboxMessage, MailboxOverflow, NameplateAllocation};
+pub use capture::CaptureLog;
+pub use cluster::ClusterStore;
+pub use journal_store::JournalStore;
+pub use metrics::router as metrics_router;
+pub use rate_limit::Rate;
+pub use redis_store::RedisStore;
+pub use server::{
+    ClientQuota, MailboxServer, RateLimits, ServerConfig, DEFAULT_MAX_FRAME_SIZE,
+    DEFAULT_MAX_MESSAGE_SIZE,
+};
+pub use settings::{AppSettings, InvalidIpRange, IpRange, RuntimeSettings, SettingsError};
+pub use sqlite_store::SqliteStore;
+pub use store::{MemoryStore, Store, StoreError, StoreKind, StoredApp};
+#[cfg(feature = "tls")]
+pub use tls::{TlsConfig, TlsError};
+pub use usage::{JsonlUsageSink, SqliteUsageSink, UsageRecord, UsageResult, UsageSink};
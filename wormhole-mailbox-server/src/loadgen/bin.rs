@@ -0,0 +1,466 @@
+use clap::Parser;
+use futures_util::{stream, SinkExt, StreamExt};
+use log::{debug, warn};
+use rand::RngCore;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async_with_config,
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use wormhole_core::hashcash;
+use wormhole_core::message::{
+    ClientMessage, ClientMessageType, Mood, Permission, PermissionMethod, Phase, ServerMessage,
+    ServerMessageType,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Load test a Magic Wormhole mailbox server with many simulated wormholes."
+)]
+struct Cli {
+    /// Mailbox server to test
+    #[arg(long, value_name = "URL", default_value = "ws://127.0.0.1:4000/")]
+    relay_url: String,
+
+    /// Application namespace ID the simulated clients use
+    #[arg(long, default_value = "nickjhughes.com/wormhole/loadgen")]
+    app_id: String,
+
+    /// Token to present to mailbox servers which require one
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// Number of client pairs to simulate
+    #[arg(long, value_name = "COUNT", default_value_t = 100)]
+    pairs: usize,
+
+    /// Number of pairs running at once
+    #[arg(long, value_name = "COUNT", default_value_t = 10)]
+    concurrency: usize,
+
+    /// Size of the message each client sends its peer, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+    message_size: usize,
+
+    /// Give up on a pair if the server takes longer than this many seconds to respond
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    timeout: u64,
+}
+
+/// A stage of a simulated wormhole whose latency is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    /// Open a WebSocket and receive the welcome message.
+    Connect,
+    /// Allocate a nameplate.
+    Allocate,
+    /// Claim the nameplate, learning its mailbox.
+    Claim,
+    /// Add a message, until the peer receives it.
+    Deliver,
+    /// Release the nameplate.
+    Release,
+    /// Close the mailbox.
+    Close,
+    /// The whole wormhole, from connecting both clients to closing the mailbox.
+    Total,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Step::Connect => "connect",
+            Step::Allocate => "allocate",
+            Step::Claim => "claim",
+            Step::Deliver => "deliver",
+            Step::Release => "release",
+            Step::Close => "close",
+            Step::Total => "total",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Reasons a simulated wormhole fails.
+#[derive(Error, Debug)]
+enum PairError {
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+    #[error("timed out waiting to {0}")]
+    Timeout(Step),
+    #[error("server closed the connection")]
+    Closed,
+    #[error("server refused the client: {0}")]
+    Refused(String),
+    #[error("server error: {0}")]
+    Server(String),
+}
+
+/// One side of a simulated wormhole.
+struct SimClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    side: String,
+    timeout: Duration,
+}
+
+impl SimClient {
+    /// Connect to the server, and submit any permission it asks for.
+    async fn connect(cli: &Cli) -> Result<Self, PairError> {
+        let timeout = Duration::from_secs(cli.timeout);
+        // Disable Nagle's algorithm, so small requests aren't held back and counted as latency
+        let connect = connect_async_with_config(cli.relay_url.as_str(), None, true);
+        let (ws, _) = tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| PairError::Timeout(Step::Connect))??;
+        let mut client = SimClient {
+            ws,
+            side: generate_side(),
+            timeout,
+        };
+
+        let welcome = client
+            .expect(Step::Connect, |ty| match ty {
+                ServerMessageType::Welcome { welcome } => Some(welcome.clone()),
+                _ => None,
+            })
+            .await?;
+        if let Some(error) = welcome.error {
+            return Err(PairError::Refused(error));
+        }
+        let permission = welcome
+            .permission_required
+            .iter()
+            .find_map(|method| match method {
+                PermissionMethod::None | PermissionMethod::Unknown(_) => None,
+                PermissionMethod::Token => {
+                    cli.token.clone().map(|token| Permission::Token { token })
+                }
+                PermissionMethod::Hashcash { bits, resource } => Some(Permission::Hashcash {
+                    stamp: hashcash::mint(resource, *bits),
+                }),
+            });
+        if let Some(permission) = permission {
+            client
+                .send(ClientMessageType::SubmitPermissions(permission))
+                .await?;
+        }
+        Ok(client)
+    }
+
+    async fn send(&mut self, ty: ClientMessageType) -> Result<(), PairError> {
+        let json = serde_json::to_string(&ClientMessage::new(ty)).expect("failed to encode");
+        self.ws.send(Message::Text(json)).await?;
+        Ok(())
+    }
+
+    /// Wait for the first message `matches` picks something out of, skipping any others.
+    async fn expect<T>(
+        &mut self,
+        step: Step,
+        mut matches: impl FnMut(&ServerMessageType) -> Option<T>,
+    ) -> Result<T, PairError> {
+        let receive = async {
+            loop {
+                let ws_msg = match self.ws.next().await {
+                    Some(ws_msg) => ws_msg?,
+                    None => return Err(PairError::Closed),
+                };
+                let Message::Text(text) = ws_msg else {
+                    continue;
+                };
+                let msg = match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("Failed to decode message: {}", e);
+                        continue;
+                    }
+                };
+                if let ServerMessageType::Error { error, .. } = msg.ty {
+                    return Err(PairError::Server(error));
+                }
+                if let Some(value) = matches(&msg.ty) {
+                    return Ok(value);
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, receive)
+            .await
+            .map_err(|_| PairError::Timeout(step))?
+    }
+}
+
+/// Generate a random side, as real clients do.
+fn generate_side() -> String {
+    let mut buffer = [0u8; 5];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    hex::encode(buffer)
+}
+
+/// Latencies measured during one simulated wormhole.
+type Timings = Vec<(Step, Duration)>;
+
+/// Run one wormhole from start to finish: both clients bind, one allocates a nameplate, both
+/// claim it and open its mailbox, each sends the other a message, and both release and close.
+async fn run_pair(cli: &Cli) -> Result<Timings, PairError> {
+    let mut timings = Timings::new();
+    let start = Instant::now();
+
+    let started = Instant::now();
+    let mut a = SimClient::connect(cli).await?;
+    timings.push((Step::Connect, started.elapsed()));
+    let started = Instant::now();
+    let mut b = SimClient::connect(cli).await?;
+    timings.push((Step::Connect, started.elapsed()));
+    for client in [&mut a, &mut b] {
+        let side = client.side.clone();
+        client
+            .send(ClientMessageType::Bind {
+                app_id: cli.app_id.clone(),
+                side,
+            })
+            .await?;
+    }
+
+    let started = Instant::now();
+    a.send(ClientMessageType::Allocate).await?;
+    let nameplate_id = a
+        .expect(Step::Allocate, |ty| match ty {
+            ServerMessageType::Allocated { nameplate_id } => Some(*nameplate_id),
+            _ => None,
+        })
+        .await?;
+    timings.push((Step::Allocate, started.elapsed()));
+    debug!("Allocated nameplate {}", nameplate_id);
+
+    let mut mailbox_id = String::new();
+    for client in [&mut a, &mut b] {
+        let started = Instant::now();
+        client
+            .send(ClientMessageType::Claim { nameplate_id })
+            .await?;
+        mailbox_id = client
+            .expect(Step::Claim, |ty| match ty {
+                ServerMessageType::Claimed { mailbox_id } => Some(mailbox_id.clone()),
+                _ => None,
+            })
+            .await?;
+        timings.push((Step::Claim, started.elapsed()));
+        client
+            .send(ClientMessageType::Open {
+                mailbox_id: mailbox_id.clone(),
+            })
+            .await?;
+    }
+
+    let body = vec![0u8; cli.message_size];
+    timings.push((Step::Deliver, deliver(&mut a, &mut b, &body).await?));
+    timings.push((Step::Deliver, deliver(&mut b, &mut a, &body).await?));
+
+    for client in [&mut a, &mut b] {
+        let started = Instant::now();
+        client
+            .send(ClientMessageType::Release { nameplate_id: None })
+            .await?;
+        client
+            .expect(Step::Release, |ty| {
+                matches!(ty, ServerMessageType::Released).then_some(())
+            })
+            .await?;
+        timings.push((Step::Release, started.elapsed()));
+
+        let started = Instant::now();
+        client
+            .send(ClientMessageType::Close {
+                mailbox_id: mailbox_id.clone(),
+                mood: Mood::Happy,
+            })
+            .await?;
+        client
+            .expect(Step::Close, |ty| {
+                matches!(ty, ServerMessageType::Closed).then_some(())
+            })
+            .await?;
+        timings.push((Step::Close, started.elapsed()));
+    }
+    timings.push((Step::Total, start.elapsed()));
+
+    for client in [&mut a, &mut b] {
+        let _ = client.ws.close(None).await;
+    }
+    Ok(timings)
+}
+
+/// Send a message from one client, returning how long it took to reach the other.
+async fn deliver(
+    sender: &mut SimClient,
+    receiver: &mut SimClient,
+    body: &[u8],
+) -> Result<Duration, PairError> {
+    let started = Instant::now();
+    sender
+        .send(ClientMessageType::Add {
+            phase: Phase::Message(0),
+            body: body.to_vec(),
+        })
+        .await?;
+    receiver
+        .expect(Step::Deliver, |ty| match ty {
+            ServerMessageType::Message { side, .. } if *side == sender.side => Some(()),
+            _ => None,
+        })
+        .await?;
+    Ok(started.elapsed())
+}
+
+/// The outcome of a load test.
+#[derive(Debug, Default)]
+struct Report {
+    /// Latencies of every successful step, sorted.
+    latencies: BTreeMap<Step, Vec<Duration>>,
+    /// How many pairs failed with each error.
+    errors: BTreeMap<String, usize>,
+    pairs: usize,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn record(&mut self, result: Result<Timings, PairError>) {
+        self.pairs += 1;
+        match result {
+            Ok(timings) => {
+                for (step, latency) in timings {
+                    self.latencies.entry(step).or_default().push(latency);
+                }
+            }
+            Err(e) => *self.errors.entry(e.to_string()).or_default() += 1,
+        }
+    }
+
+    fn failed(&self) -> usize {
+        self.errors.values().sum()
+    }
+}
+
+/// The latency below which the given fraction of the sorted samples fall, by nearest rank.
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (fraction * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        let failed = self.failed();
+        writeln!(
+            f,
+            "Simulated {} wormholes in {:.2}s ({:.1}/s), {} failed ({:.1}%)",
+            self.pairs,
+            secs,
+            self.pairs as f64 / secs.max(f64::EPSILON),
+            failed,
+            100.0 * failed as f64 / self.pairs.max(1) as f64,
+        )?;
+
+        writeln!(
+            f,
+            "\n{:<10} {:>7} {:>9} {:>9} {:>9} {:>9}",
+            "step", "count", "p50 ms", "p90 ms", "p99 ms", "max ms"
+        )?;
+        for (step, latencies) in &self.latencies {
+            let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+            writeln!(
+                f,
+                "{:<10} {:>7} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+                step.to_string(),
+                latencies.len(),
+                ms(percentile(latencies, 0.5)),
+                ms(percentile(latencies, 0.9)),
+                ms(percentile(latencies, 0.99)),
+                ms(*latencies.last().unwrap()),
+            )?;
+        }
+
+        if !self.errors.is_empty() {
+            writeln!(f, "\nErrors:")?;
+            for (error, count) in &self.errors {
+                writeln!(f, "{:>7}  {}", count, error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the configured number of wormholes against the server, a few at a time.
+async fn run(cli: &Cli) -> Report {
+    let mut report = Report::default();
+    let start = Instant::now();
+    let mut results = stream::iter(0..cli.pairs)
+        .map(|_| run_pair(cli))
+        .buffer_unordered(cli.concurrency.max(1));
+    while let Some(result) = results.next().await {
+        if let Err(e) = &result {
+            debug!("Wormhole failed: {}", e);
+        }
+        report.record(result);
+    }
+    report.elapsed = start.elapsed();
+    for latencies in report.latencies.values_mut() {
+        latencies.sort();
+    }
+    report
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+    let report = run(&cli).await;
+    print!("{}", report);
+    if report.failed() > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percentile, run, Cli, Step};
+    use clap::Parser;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use wormhole_mailbox_server::{MailboxServer, ServerConfig};
+
+    #[test]
+    fn percentiles() {
+        let samples = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&samples, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&samples, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn load_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        tokio::spawn(MailboxServer::serve(listener, ServerConfig::default()));
+
+        let cli = Cli::parse_from(["wormhole-loadgen", "--relay-url", &url, "--pairs", "4"]);
+        let report = run(&cli).await;
+        assert_eq!(report.pairs, 4);
+        assert_eq!(report.failed(), 0, "{}", report);
+        assert_eq!(report.latencies[&Step::Total].len(), 4);
+        assert_eq!(report.latencies[&Step::Deliver].len(), 8);
+    }
+}
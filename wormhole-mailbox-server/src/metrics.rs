@@ -0,0 +1,134 @@
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::sync::Arc;
+
+use crate::server::MailboxServer;
+
+/// Counters and gauges describing a running server, in Prometheus form.
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Currently connected WebSocket clients.
+    pub(crate) connections: IntGauge,
+    /// Currently active nameplates, across all applications.
+    pub(crate) nameplates: IntGauge,
+    /// Currently open mailboxes, across all applications.
+    pub(crate) mailboxes: IntGauge,
+    /// Messages added to mailboxes.
+    pub(crate) messages: IntCounter,
+    /// Total size of the bodies of messages added to mailboxes, in bytes.
+    pub(crate) bytes: IntCounter,
+    /// Errors sent to clients, labelled by kind.
+    pub(crate) errors: IntCounterVec,
+    /// Connections which failed the WebSocket handshake.
+    pub(crate) handshake_failures: IntCounter,
+    /// Connections which failed the TLS handshake, including those without an acceptable
+    /// client certificate.
+    pub(crate) tls_handshake_failures: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new_custom(Some("wormhole_mailbox".into()), None)
+            .expect("valid registry prefix");
+        let metrics = Metrics {
+            connections: IntGauge::new("connections", "Connected clients").unwrap(),
+            nameplates: IntGauge::new("nameplates", "Active nameplates").unwrap(),
+            mailboxes: IntGauge::new("mailboxes", "Open mailboxes").unwrap(),
+            messages: IntCounter::new("messages_total", "Messages relayed").unwrap(),
+            bytes: IntCounter::new("message_bytes_total", "Message bytes relayed").unwrap(),
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Errors sent to clients"),
+                &["error"],
+            )
+            .unwrap(),
+            handshake_failures: IntCounter::new(
+                "handshake_failures_total",
+                "Failed WebSocket handshakes",
+            )
+            .unwrap(),
+            tls_handshake_failures: IntCounter::new(
+                "tls_handshake_failures_total",
+                "Failed TLS handshakes",
+            )
+            .unwrap(),
+            registry,
+        };
+        metrics.register_all();
+        metrics
+    }
+}
+
+impl Metrics {
+    fn register_all(&self) {
+        self.registry
+            .register(Box::new(self.connections.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.nameplates.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.mailboxes.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.messages.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.bytes.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.errors.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.handshake_failures.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(self.tls_handshake_failures.clone()))
+            .unwrap();
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics always encode");
+        String::from_utf8(buffer).expect("metrics are UTF-8")
+    }
+}
+
+/// Serve `GET /metrics`, refreshing the gauges which are read from the server's state.
+async fn metrics(State(server): State<Arc<MailboxServer>>) -> impl IntoResponse {
+    let (nameplates, mailboxes) = server.count_nameplates_and_mailboxes();
+    let metrics = server.metrics();
+    metrics.nameplates.set(nameplates as i64);
+    metrics.mailboxes.set(mailboxes as i64);
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
+
+/// The HTTP routes for scraping the server's metrics.
+pub fn router(server: Arc<MailboxServer>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+
+    #[test]
+    fn rendering() {
+        let metrics = Metrics::default();
+        metrics.connections.inc();
+        metrics.errors.with_label_values(&["rate_limited"]).inc();
+
+        let text = metrics.render();
+        assert!(text.contains("wormhole_mailbox_connections 1"));
+        assert!(text.contains("wormhole_mailbox_errors_total{error=\"rate_limited\"} 1"));
+        assert!(text.contains("wormhole_mailbox_messages_total 0"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Instant;
+
+/// A sustained rate and burst allowance for a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    /// Tokens added to the bucket per second.
+    pub per_second: f64,
+    /// Maximum number of tokens the bucket can hold.
+    pub burst: f64,
+}
+
+/// A token bucket rate limiter. Each permitted operation takes one token, and tokens are
+/// replenished continuously at the configured rate.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub(crate) fn new(rate: Rate) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available.
+    pub(crate) fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    /// Take a token if one is available at the given time.
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Has the bucket refilled completely, i.e. is it indistinguishable from a new one?
+    pub(crate) fn is_full(&mut self) -> bool {
+        self.refill(Instant::now());
+        self.tokens >= self.rate.burst
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rate, TokenBucket};
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(Rate {
+            per_second: 2.0,
+            burst: 3.0,
+        });
+        let start = bucket.updated;
+
+        // The initial burst is available immediately
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start));
+
+        // Tokens are replenished at the configured rate
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+
+        // But never beyond the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take_at(much_later));
+        }
+        assert!(!bucket.try_take_at(much_later));
+    }
+}
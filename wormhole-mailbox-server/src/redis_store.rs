@@ -0,0 +1,196 @@
+use redis::Commands;
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use crate::app::{Mailbox, MailboxMessage, Nameplate};
+use crate::store::{Store, StoreError, StoredApp, StoredMessage};
+
+/// Prefix for every key the store touches, so it can share a Redis database.
+const KEY_PREFIX: &str = "wormhole";
+
+/// A store keeping nameplates, claims and mailbox messages in Redis.
+///
+/// Each app ID is added to the `wormhole:apps` set, and its state lives under
+/// `wormhole:app:<app_id>:`, in a hash of nameplates, a list of sides per nameplate, a set of
+/// mailboxes, and a list of JSON-encoded messages per mailbox.
+pub struct RedisStore {
+    url: String,
+    conn: Mutex<redis::Connection>,
+}
+
+impl fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    /// Connect to the Redis server at the given URL, e.g. `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> redis::RedisResult<Self> {
+        let conn = redis::Client::open(url)?.get_connection()?;
+        Ok(RedisStore {
+            url: url.to_owned(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// The URL of the Redis server.
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Publish a message on a pub/sub channel.
+    pub(crate) fn publish(&self, channel: &str, message: &str) -> Result<(), StoreError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .publish::<_, _, ()>(channel, message)?;
+        Ok(())
+    }
+}
+
+fn apps_key() -> String {
+    format!("{}:apps", KEY_PREFIX)
+}
+
+fn nameplates_key(app_id: &str) -> String {
+    format!("{}:app:{}:nameplates", KEY_PREFIX, app_id)
+}
+
+fn sides_key(app_id: &str, nameplate_id: usize) -> String {
+    format!(
+        "{}:app:{}:nameplate:{}:sides",
+        KEY_PREFIX, app_id, nameplate_id
+    )
+}
+
+fn mailboxes_key(app_id: &str) -> String {
+    format!("{}:app:{}:mailboxes", KEY_PREFIX, app_id)
+}
+
+fn messages_key(app_id: &str, mailbox_id: &str) -> String {
+    format!(
+        "{}:app:{}:mailbox:{}:messages",
+        KEY_PREFIX, app_id, mailbox_id
+    )
+}
+
+impl Store for RedisStore {
+    fn load(&self) -> Result<HashMap<String, StoredApp>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut apps = HashMap::new();
+
+        let app_ids: Vec<String> = conn.smembers(apps_key())?;
+        for app_id in app_ids {
+            let mut app = StoredApp::default();
+
+            let mailbox_ids: Vec<String> = conn.smembers(mailboxes_key(&app_id))?;
+            for mailbox_id in mailbox_ids {
+                let mut mailbox = Mailbox::default();
+                let messages: Vec<String> =
+                    conn.lrange(messages_key(&app_id, &mailbox_id), 0, -1)?;
+                for message in messages {
+                    let message: StoredMessage = serde_json::from_str(&message)?;
+                    mailbox.stored_bytes += message.body.len();
+                    mailbox.messages.push(message.into());
+                }
+                app.mailboxes.insert(mailbox_id, mailbox);
+            }
+
+            let nameplates: HashMap<usize, String> = conn.hgetall(nameplates_key(&app_id))?;
+            for (nameplate_id, mailbox_id) in nameplates {
+                let sides: Vec<String> = conn.lrange(sides_key(&app_id, nameplate_id), 0, -1)?;
+                app.nameplates
+                    .insert(nameplate_id, Nameplate::new(mailbox_id, sides));
+            }
+
+            apps.insert(app_id, app);
+        }
+
+        Ok(apps)
+    }
+
+    fn add_nameplate(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        mailbox_id: &str,
+    ) -> Result<(), StoreError> {
+        redis::pipe()
+            .atomic()
+            .sadd(apps_key(), app_id)
+            .hset(nameplates_key(app_id), nameplate_id, mailbox_id)
+            .query::<()>(&mut *self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn remove_nameplate(&self, app_id: &str, nameplate_id: usize) -> Result<(), StoreError> {
+        redis::pipe()
+            .atomic()
+            .del(sides_key(app_id, nameplate_id))
+            .hdel(nameplates_key(app_id), nameplate_id)
+            .query::<()>(&mut *self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn add_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        // Lists keep the order sides claimed in; remove any earlier claim to avoid duplicates
+        redis::pipe()
+            .atomic()
+            .lrem(sides_key(app_id, nameplate_id), 0, side)
+            .rpush(sides_key(app_id, nameplate_id), side)
+            .query::<()>(&mut *self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn remove_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .lrem::<_, _, ()>(sides_key(app_id, nameplate_id), 0, side)?;
+        Ok(())
+    }
+
+    fn add_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        redis::pipe()
+            .atomic()
+            .sadd(apps_key(), app_id)
+            .sadd(mailboxes_key(app_id), mailbox_id)
+            .query::<()>(&mut *self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn remove_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        redis::pipe()
+            .atomic()
+            .del(messages_key(app_id, mailbox_id))
+            .srem(mailboxes_key(app_id), mailbox_id)
+            .query::<()>(&mut *self.conn.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn add_message(
+        &self,
+        app_id: &str,
+        mailbox_id: &str,
+        message: &MailboxMessage,
+    ) -> Result<(), StoreError> {
+        let encoded = serde_json::to_string(&StoredMessage::from(message))?;
+        self.conn
+            .lock()
+            .unwrap()
+            .rpush::<_, _, ()>(messages_key(app_id, mailbox_id), encoded)?;
+        Ok(())
+    }
+}
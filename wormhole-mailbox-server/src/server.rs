@@ -0,0 +1,1882 @@
+use futures_channel::mpsc::{unbounded, TrySendError, UnboundedReceiver, UnboundedSender};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::{debug, error, field::Empty, info, Span};
+
+use crate::app::{App, MailboxLimits, MailboxMessage, NameplateAllocation, DEFAULT_MAILBOX_SIDES};
+use crate::capture::{CaptureLog, Direction};
+use crate::cluster::ClusterEvent;
+use crate::metrics::Metrics;
+use crate::rate_limit::{Rate, TokenBucket};
+use crate::settings::RuntimeSettings;
+use crate::store::{MemoryStore, Store, StoreError};
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::usage::UsageSink;
+use wormhole_core::hashcash;
+use wormhole_core::message::{
+    ClientMessage, Mood, NameplateInfo, Permission, PermissionMethod, Phase, ServerMessage,
+    ServerMessageType, WelcomeInfo,
+};
+
+/// Source of connection IDs, which are unique for the lifetime of the server process.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A client connected via WebSocket.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    /// A transmission channel for the connection.
+    pub(crate) sender: UnboundedSender<ServerMessage>,
+    /// Unique ID of the connection, as listed by the admin API.
+    id: u64,
+    /// When the client connected.
+    connected: SystemTime,
+    /// The span covering the connection's lifetime, which records its ID, peer, and (once
+    /// known) hashed app ID, side, nameplate and mailbox.
+    span: Span,
+    /// The IP address the client connected from.
+    peer: IpAddr,
+    /// Rate limiter for expensive requests from this connection.
+    bucket: Option<TokenBucket>,
+    /// Client's Application namespace.
+    app_id: Option<String>,
+    /// The state of the client's application namespace, once bound.
+    app: Option<Arc<Mutex<App>>>,
+    /// Client's ID string.
+    side: Option<String>,
+    /// The currently open mailbox.
+    mailbox_id: Option<String>,
+    /// The currently associated nameplate.
+    nameplate_id: Option<usize>,
+    /// Has the client been allocated a nameplate?
+    allocated: bool,
+    /// Has the client claimed a nameplate?
+    claimed: bool,
+    /// Has the client released a nameplate?
+    released: bool,
+    /// A random resource the client must mint its hashcash stamp for, so stamps can't be
+    /// computed in advance or reused.
+    challenge: String,
+    /// Has the client submitted the permission the server requires?
+    permitted: bool,
+}
+
+impl Connection {
+    /// Create a new connection from the given address with the associated transmission channel.
+    pub(crate) fn new(sender: UnboundedSender<ServerMessage>, peer: IpAddr) -> Self {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        Connection {
+            sender,
+            id,
+            connected: SystemTime::now(),
+            span: tracing::info_span!(
+                "connection",
+                id,
+                %peer,
+                app = Empty,
+                side = Empty,
+                nameplate = Empty,
+                mailbox = Empty,
+            ),
+            peer,
+            bucket: None,
+            app_id: None,
+            app: None,
+            side: None,
+            nameplate_id: None,
+            mailbox_id: None,
+            allocated: false,
+            claimed: false,
+            released: false,
+            challenge: {
+                let mut buffer = [0u8; 8];
+                rand::thread_rng().fill_bytes(&mut buffer);
+                hex::encode(buffer)
+            },
+            permitted: false,
+        }
+    }
+
+    /// The span covering the connection's lifetime.
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// A summary of the connection's state, for the admin API.
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            peer: self.peer,
+            app_id: self.app_id.clone(),
+            side: self.side.clone(),
+            nameplate_id: self.nameplate_id,
+            mailbox_id: self.mailbox_id.clone(),
+            connected: self
+                .connected
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// Has the client bound an application namespace and ID string?
+    fn bound(&self) -> bool {
+        self.app_id.is_some() && self.side.is_some()
+    }
+
+    /// Lock the client's application namespace. Must only be called once bound.
+    fn app(&self) -> MutexGuard<'_, App> {
+        self.app.as_ref().expect("non-existant app").lock().unwrap()
+    }
+
+    /// Has the client been allocated a nameplate?
+    fn allocated(&self) -> bool {
+        self.allocated
+    }
+
+    /// Has the client claimed a nameplate?
+    fn claimed(&self) -> bool {
+        self.claimed
+    }
+}
+
+/// What a connected client is doing, as listed by the admin API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: u64,
+    pub(crate) peer: IpAddr,
+    pub(crate) app_id: Option<String>,
+    pub(crate) side: Option<String>,
+    pub(crate) nameplate_id: Option<usize>,
+    pub(crate) mailbox_id: Option<String>,
+    /// When the client connected, in seconds since the Unix epoch.
+    pub(crate) connected: u64,
+}
+
+/// Errors generated by the server.
+#[derive(Error, Debug)]
+pub(crate) enum ServerError {
+    #[error("only one mailbox per connection")]
+    MailboxAlreadyOpened,
+    #[error("release must match claim")]
+    ReleaseMustMatchClaim,
+    #[error("no nameplate to release")]
+    NoNameplateToRelease,
+    #[error("already released")]
+    AlreadyReleased,
+    #[error("already claimed")]
+    AlreadyClaimed,
+    #[error("already bound")]
+    AlreadyBound,
+    #[error("must bind first")]
+    NotBound,
+    #[error("no open mailbox")]
+    NoOpenMailbox,
+    #[error("already allocated")]
+    AlreadyAllocated,
+    #[error("invalid mailbox")]
+    InvalidMailbox,
+    #[error("could not allocate nameplate")]
+    CouldNotAllocate,
+    #[error("nameplate is crowded")]
+    CrowdedNameplate,
+    #[error("mailbox is crowded")]
+    CrowdedMailbox,
+    #[error("message too large")]
+    MessageTooLarge,
+    #[error("mailbox is full")]
+    MailboxFull,
+    #[error("too many nameplates or mailboxes held")]
+    QuotaExceeded,
+    #[error("rate limit exceeded, slow down")]
+    RateLimited,
+    #[error("server is down for maintenance")]
+    Maintenance,
+    #[error("your address is banned from this server")]
+    Banned,
+    #[error("must submit permissions first")]
+    PermissionRequired,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("application not allowed on this server")]
+    AppNotAllowed,
+    #[error("too many nameplates in use by this application")]
+    AppFull,
+    #[error("failed to create or parse message")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("failed to send websocket message")]
+    ChannelError(Box<TrySendError<ServerMessage>>),
+}
+
+impl ServerError {
+    /// A short, stable name for the kind of error, for labelling metrics.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            ServerError::MailboxAlreadyOpened => "mailbox_already_opened",
+            ServerError::ReleaseMustMatchClaim => "release_must_match_claim",
+            ServerError::NoNameplateToRelease => "no_nameplate_to_release",
+            ServerError::AlreadyReleased => "already_released",
+            ServerError::AlreadyClaimed => "already_claimed",
+            ServerError::AlreadyBound => "already_bound",
+            ServerError::NotBound => "not_bound",
+            ServerError::NoOpenMailbox => "no_open_mailbox",
+            ServerError::AlreadyAllocated => "already_allocated",
+            ServerError::InvalidMailbox => "invalid_mailbox",
+            ServerError::CouldNotAllocate => "could_not_allocate",
+            ServerError::CrowdedNameplate => "crowded_nameplate",
+            ServerError::CrowdedMailbox => "crowded_mailbox",
+            ServerError::MessageTooLarge => "message_too_large",
+            ServerError::MailboxFull => "mailbox_full",
+            ServerError::QuotaExceeded => "quota_exceeded",
+            ServerError::RateLimited => "rate_limited",
+            ServerError::Maintenance => "maintenance",
+            ServerError::Banned => "banned",
+            ServerError::PermissionRequired => "permission_required",
+            ServerError::PermissionDenied => "permission_denied",
+            ServerError::AppNotAllowed => "app_not_allowed",
+            ServerError::AppFull => "app_full",
+            ServerError::SerdeJsonError(_) => "serde_json_error",
+            ServerError::ChannelError(_) => "channel_error",
+        }
+    }
+}
+
+impl From<TrySendError<ServerMessage>> for ServerError {
+    fn from(e: TrySendError<ServerMessage>) -> Self {
+        ServerError::ChannelError(Box::new(e))
+    }
+}
+
+/// A short hash of an app ID, so logs can tell applications apart without naming them.
+pub(crate) fn hash_app_id(app_id: &str) -> String {
+    hex::encode(&Sha256::digest(app_id.as_bytes())[..8])
+}
+
+/// Compare tokens in constant time, so a client can't learn a token by timing failed attempts.
+pub(crate) fn tokens_match(expected: &str, submitted: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let submitted = Sha256::digest(submitted.as_bytes());
+    expected
+        .iter()
+        .zip(submitted.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Sent to clients which connect while the server is in maintenance mode.
+const MAINTENANCE_MESSAGE: &str = "This server is down for maintenance, please try again later.";
+
+/// Default limit on the size of an `add` message body, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// Default limit on the size of an incoming WebSocket frame, in bytes. Bodies are hex-encoded
+/// inside JSON, so this needs to be comfortably more than twice the message size limit.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Limits on how many nameplates and mailboxes a client may hold at once. Each limit applies
+/// to nameplates and mailboxes separately.
+#[derive(Debug, Clone, Default)]
+pub struct ClientQuota {
+    /// Limit for a single side within an application namespace.
+    pub per_side: Option<usize>,
+    /// Limit for all connections from a single IP address.
+    pub per_ip: Option<usize>,
+}
+
+/// Limits on how quickly clients may make allocate, claim and add requests.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Limit for a single connection.
+    pub per_connection: Option<Rate>,
+    /// Limit for all connections from a single IP address.
+    pub per_ip: Option<Rate>,
+}
+
+/// Number of per-IP rate limiters kept before idle ones are pruned.
+const MAX_IDLE_IP_BUCKETS: usize = 1024;
+
+/// Nameplates and mailboxes currently held by connections from one IP address.
+#[derive(Debug, Default)]
+struct IpUsage {
+    nameplates: usize,
+    mailboxes: usize,
+}
+
+/// Operator-controlled server settings.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Disconnect clients which have sent nothing for this long. `None` disables the timeout.
+    pub idle_timeout: Option<Duration>,
+    /// Largest `add` message body accepted, in bytes.
+    pub max_message_size: usize,
+    /// Largest incoming WebSocket frame or message accepted, in bytes.
+    pub max_frame_size: usize,
+    /// How much each mailbox will store.
+    pub mailbox_limits: MailboxLimits,
+    /// How many nameplates and mailboxes each client may hold.
+    pub client_quota: ClientQuota,
+    /// Maximum number of simultaneous client connections. `None` accepts as many as arrive.
+    pub max_connections: Option<usize>,
+    /// How quickly clients may make requests.
+    pub rate_limits: RateLimits,
+    /// Maximum nameplates active at once in each application namespace, unless its settings
+    /// say otherwise. `None` only limits them by `max_nameplate_id`.
+    pub max_app_nameplates: Option<usize>,
+    /// How nameplates are picked for clients which ask to be allocated one.
+    pub nameplate_allocation: NameplateAllocation,
+    /// The largest nameplate ID which may be allocated. `None` lets IDs grow as long as needed,
+    /// so allocation never fails.
+    pub max_nameplate_id: Option<usize>,
+    /// Answer `list` requests with no nameplates, so clients can't use it to find active
+    /// nameplates to guess codes for.
+    pub disable_list: bool,
+    /// Drop messages with the same side and phase as one already in their mailbox, so clients
+    /// resending after a reconnect don't leave duplicates for late subscribers.
+    pub dedup_adds: bool,
+    /// Require clients to submit a hashcash stamp with this many bits of work before binding.
+    /// `None` lets anyone bind.
+    pub hashcash_bits: Option<u32>,
+    /// Free nameplates which no second side has claimed within this long. `None` keeps them
+    /// until their sides release them.
+    pub nameplate_ttl: Option<Duration>,
+    /// Close mailboxes which nobody has opened or added to within this long. `None` keeps them
+    /// until their subscribers close them.
+    pub mailbox_ttl: Option<Duration>,
+    /// Keep the nameplates and mailboxes of clients whose connections drop for this long, so
+    /// they can bind again with the same side and carry on. `None` frees them immediately.
+    pub reconnect_grace: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            idle_timeout: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            mailbox_limits: MailboxLimits::default(),
+            client_quota: ClientQuota::default(),
+            max_connections: None,
+            rate_limits: RateLimits::default(),
+            max_app_nameplates: None,
+            nameplate_allocation: NameplateAllocation::default(),
+            max_nameplate_id: None,
+            disable_list: false,
+            dedup_adds: false,
+            hashcash_bits: None,
+            nameplate_ttl: None,
+            mailbox_ttl: None,
+            reconnect_grace: None,
+        }
+    }
+}
+
+/// A mailbox server. Its connections and contents are separated into
+/// application namespaces.
+///
+/// Each namespace sits behind its own lock, so connections only contend with
+/// other connections bound to the same application.
+#[derive(Debug)]
+pub struct MailboxServer {
+    config: ServerConfig,
+    apps: Mutex<HashMap<String, Arc<Mutex<App>>>>,
+    usage: Mutex<HashMap<IpAddr, IpUsage>>,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    store: Arc<dyn Store>,
+    usage_sink: Option<Arc<dyn UsageSink>>,
+    metrics: Metrics,
+    settings: RwLock<RuntimeSettings>,
+    connections: Mutex<HashMap<u64, ConnectionInfo>>,
+    /// Dropped clients waiting to reconnect, keyed by app ID and side.
+    detached: Mutex<HashMap<(String, String), Detached>>,
+    capture_log: Option<CaptureLog>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+/// A bound client whose connection dropped, kept for the reconnection grace period.
+#[derive(Debug)]
+struct Detached {
+    /// The dropped connection, whose subscriptions now deliver to `buffer`.
+    conn: Connection,
+    /// Messages sent to the client since its connection dropped.
+    buffer: UnboundedReceiver<ServerMessage>,
+    /// When the connection dropped.
+    since: Instant,
+}
+
+impl Default for MailboxServer {
+    fn default() -> Self {
+        MailboxServer::new(ServerConfig::default())
+    }
+}
+
+impl MailboxServer {
+    /// Create a server with the given settings.
+    pub fn new(config: ServerConfig) -> Self {
+        MailboxServer {
+            config,
+            apps: Mutex::default(),
+            usage: Mutex::default(),
+            buckets: Mutex::default(),
+            store: Arc::new(MemoryStore),
+            usage_sink: None,
+            metrics: Metrics::default(),
+            settings: RwLock::default(),
+            connections: Mutex::default(),
+            detached: Mutex::default(),
+            capture_log: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Create a server which records its state in the given store, picking up any nameplates
+    /// and mailboxes left there by a previous run, and records the usage of each mailbox in the
+    /// given sink.
+    pub fn with_store(
+        config: ServerConfig,
+        store: Arc<dyn Store>,
+        usage_sink: Option<Arc<dyn UsageSink>>,
+    ) -> Result<Self, StoreError> {
+        let apps = store
+            .load()?
+            .into_iter()
+            .map(|(app_id, stored)| {
+                debug!(app = %hash_app_id(&app_id), "Restoring app");
+                let app = App::restore(
+                    &app_id,
+                    stored,
+                    config.mailbox_limits.clone(),
+                    store.clone(),
+                    usage_sink.clone(),
+                );
+                (app_id, Arc::new(Mutex::new(app)))
+            })
+            .collect();
+        Ok(MailboxServer {
+            apps: Mutex::new(apps),
+            store,
+            usage_sink,
+            ..MailboxServer::new(config)
+        })
+    }
+
+    /// Record every protocol message sent or received in the given capture log.
+    pub fn with_capture_log(self, capture_log: CaptureLog) -> Self {
+        MailboxServer {
+            capture_log: Some(capture_log),
+            ..self
+        }
+    }
+
+    /// Serve clients over TLS, requiring certificates if the configuration says to.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(self, tls: TlsConfig) -> Self {
+        MailboxServer {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// How to secure client connections, if at all.
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Record a protocol message sent or received on the given connection, if capturing.
+    pub(crate) fn capture(&self, conn: &Connection, direction: Direction, text: &str) {
+        if let Some(capture_log) = &self.capture_log {
+            if let Err(e) = capture_log.record(conn.id, direction, text) {
+                error!(error = %e, "Failed to capture message");
+            }
+        }
+    }
+
+    /// The server's settings.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Replace the server's runtime settings, e.g. after the settings file has been reloaded.
+    /// Only affects clients which connect afterwards.
+    pub fn set_settings(&self, settings: RuntimeSettings) {
+        let apps = self.all_apps();
+        for app in apps {
+            self.configure_app(&mut app.lock().unwrap(), &settings);
+        }
+        *self.settings.write().unwrap() = settings;
+    }
+
+    /// Apply the server-wide limits to an application namespace, overridden by any in its
+    /// settings.
+    fn configure_app(&self, app: &mut App, settings: &RuntimeSettings) {
+        let app_settings = settings.apps.get(&app.app_id).cloned().unwrap_or_default();
+        let defaults = &self.config.mailbox_limits;
+        app.limits = MailboxLimits {
+            max_messages: app_settings.max_mailbox_messages.or(defaults.max_messages),
+            max_bytes: app_settings.max_mailbox_bytes.or(defaults.max_bytes),
+            overflow: defaults.overflow,
+        };
+        app.max_nameplates = app_settings
+            .max_nameplates
+            .or(self.config.max_app_nameplates);
+        app.nameplate_allocation = self.config.nameplate_allocation;
+        app.max_nameplate_id = self.config.max_nameplate_id;
+        app.dedup_adds = self.config.dedup_adds;
+        app.max_mailbox_sides = app_settings
+            .max_mailbox_sides
+            .unwrap_or(DEFAULT_MAILBOX_SIDES);
+    }
+
+    /// Every application namespace, each behind its own lock.
+    pub(crate) fn all_apps(&self) -> Vec<Arc<Mutex<App>>> {
+        self.apps.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The server's metrics.
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Count the active nameplates and open mailboxes across every application namespace.
+    pub(crate) fn count_nameplates_and_mailboxes(&self) -> (usize, usize) {
+        let apps = self.all_apps();
+        apps.iter().fold((0, 0), |(nameplates, mailboxes), app| {
+            let app = app.lock().unwrap();
+            (
+                nameplates + app.nameplates.len(),
+                mailboxes + app.mailboxes.len(),
+            )
+        })
+    }
+
+    /// Expire unclaimed nameplates across every application namespace, if a TTL is configured.
+    pub(crate) fn expire_nameplates(&self) {
+        let Some(ttl) = self.config.nameplate_ttl else {
+            return;
+        };
+        let now = Instant::now();
+        let apps = self.all_apps();
+        let expired: usize = apps
+            .iter()
+            .map(|app| app.lock().unwrap().expire_nameplates(ttl, now))
+            .sum();
+        if expired > 0 {
+            debug!(expired, "Expired unclaimed nameplates");
+        }
+    }
+
+    /// Close idle mailboxes across every application namespace, if a TTL is configured.
+    pub(crate) fn collect_idle_mailboxes(&self) {
+        let Some(ttl) = self.config.mailbox_ttl else {
+            return;
+        };
+        let now = Instant::now();
+        let apps = self.all_apps();
+        let closed: usize = apps
+            .iter()
+            .map(|app| app.lock().unwrap().collect_idle_mailboxes(ttl, now))
+            .sum();
+        if closed > 0 {
+            debug!(closed, "Closed idle mailboxes");
+        }
+    }
+
+    /// Forcibly free a nameplate, e.g. at an operator's request. Returns false if there is no
+    /// such nameplate.
+    pub(crate) fn expire_nameplate(&self, app_id: &str, nameplate_id: usize) -> bool {
+        let Some(app) = self.apps.lock().unwrap().get(app_id).cloned() else {
+            return false;
+        };
+        let expired = app.lock().unwrap().expire_nameplate(nameplate_id);
+        if expired {
+            info!(app = %hash_app_id(app_id), nameplate_id, "Expired nameplate");
+        }
+        expired
+    }
+
+    /// Forcibly close a mailbox, e.g. at an operator's request. Returns false if there is no
+    /// such mailbox.
+    pub(crate) fn close_mailbox(&self, app_id: &str, mailbox_id: &str) -> bool {
+        let Some(app) = self.apps.lock().unwrap().get(app_id).cloned() else {
+            return false;
+        };
+        let closed = app.lock().unwrap().prune_mailbox(mailbox_id);
+        if closed {
+            info!(app = %hash_app_id(app_id), mailbox_id, "Closed mailbox");
+        }
+        closed
+    }
+
+    /// Every connected client, in the order they connected.
+    pub(crate) fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    /// Update the listing of a connected client, after it has sent a message.
+    pub(crate) fn track(&self, conn: &Connection) {
+        if let Some(info) = self.connections.lock().unwrap().get_mut(&conn.id) {
+            *info = conn.info();
+        }
+    }
+
+    /// Record that the client now holds another nameplate (or mailbox), failing if that would
+    /// exceed the per-IP quota.
+    fn reserve(&self, conn: &Connection, mailbox: bool) -> Result<(), ServerError> {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(conn.peer).or_default();
+        let held = if mailbox {
+            &mut usage.mailboxes
+        } else {
+            &mut usage.nameplates
+        };
+        if self
+            .config
+            .client_quota
+            .per_ip
+            .is_some_and(|max| *held >= max)
+        {
+            return Err(ServerError::QuotaExceeded);
+        }
+        *held += 1;
+        Ok(())
+    }
+
+    /// Record that the client has given up a nameplate (or mailbox).
+    fn unreserve(&self, conn: &Connection, mailbox: bool) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(entry) = usage.get_mut(&conn.peer) {
+            if mailbox {
+                entry.mailboxes = entry.mailboxes.saturating_sub(1);
+            } else {
+                entry.nameplates = entry.nameplates.saturating_sub(1);
+            }
+            if entry.nameplates == 0 && entry.mailboxes == 0 {
+                usage.remove(&conn.peer);
+            }
+        }
+    }
+
+    /// Check whether the client's side may hold another nameplate (or mailbox) in its app.
+    fn check_side_quota(&self, held: usize) -> Result<(), ServerError> {
+        if self
+            .config
+            .client_quota
+            .per_side
+            .is_some_and(|max| held >= max)
+        {
+            return Err(ServerError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// Charge the client for an expensive request, failing if it is making them too quickly.
+    fn check_rate(&self, conn: &mut Connection) -> Result<(), ServerError> {
+        if let Some(rate) = self.config.rate_limits.per_connection {
+            let bucket = conn.bucket.get_or_insert_with(|| TokenBucket::new(rate));
+            if !bucket.try_take() {
+                return Err(ServerError::RateLimited);
+            }
+        }
+        if let Some(rate) = self.config.rate_limits.per_ip {
+            let mut buckets = self.buckets.lock().unwrap();
+            if buckets.len() > MAX_IDLE_IP_BUCKETS {
+                buckets.retain(|_, bucket| !bucket.is_full());
+            }
+            let bucket = buckets
+                .entry(conn.peer)
+                .or_insert_with(|| TokenBucket::new(rate));
+            if !bucket.try_take() {
+                return Err(ServerError::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    /// The welcome information sent to newly connected clients.
+    pub(crate) fn welcome(&self) -> WelcomeInfo {
+        let settings = self.settings.read().unwrap();
+        WelcomeInfo {
+            motd: settings.motd.clone(),
+            current_cli_version: settings.current_cli_version.clone(),
+            transit_relays: settings.transit_relays.clone(),
+            error: settings.maintenance.then(|| MAINTENANCE_MESSAGE.to_owned()),
+            permission_required: vec![PermissionMethod::None],
+        }
+    }
+
+    /// Connect a new client. Will send them the welcome message, including any challenge it
+    /// must solve before binding.
+    pub(crate) fn connect(&self, conn: &Connection) -> Result<(), ServerError> {
+        let mut welcome = self.welcome();
+        if self.permission_required() {
+            welcome.permission_required.clear();
+            if !self.settings.read().unwrap().tokens.is_empty() {
+                welcome.permission_required.push(PermissionMethod::Token);
+            }
+            if let Some(bits) = self.config.hashcash_bits {
+                welcome
+                    .permission_required
+                    .push(PermissionMethod::Hashcash {
+                        bits,
+                        resource: conn.challenge.clone(),
+                    });
+            }
+        }
+        let welcome_msg = ServerMessage::new(None, None, ServerMessageType::Welcome { welcome });
+        debug!(ty = ?welcome_msg.ty, "Sent message");
+        conn.sender.unbounded_send(welcome_msg)?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(conn.id, conn.info());
+        Ok(())
+    }
+
+    /// Handle a client disconnection. Removes them from any nameplates or mailboxes, unless
+    /// they have a grace period to reconnect in.
+    pub(crate) fn disconnect(&self, conn: Connection) {
+        self.connections.lock().unwrap().remove(&conn.id);
+        if !conn.bound() {
+            debug!("Unbound client disconnected");
+            return;
+        }
+        debug!("Client disconnected");
+
+        if self.config.reconnect_grace.is_some() {
+            self.detach(conn);
+        } else {
+            self.release_connection(&conn);
+        }
+    }
+
+    /// Hold on to a dropped client's nameplates and mailboxes in case it reconnects, buffering
+    /// any messages sent to it meanwhile.
+    fn detach(&self, mut conn: Connection) {
+        let (sender, buffer) = unbounded();
+        conn.app().redirect_subscriber(&conn.sender, &sender);
+        conn.sender = sender;
+        let key = (conn.app_id.clone().unwrap(), conn.side.clone().unwrap());
+        debug!("Holding state for reconnection");
+        let detached = Detached {
+            conn,
+            buffer,
+            since: Instant::now(),
+        };
+        let replaced = self.detached.lock().unwrap().insert(key, detached);
+        if let Some(replaced) = replaced {
+            self.release_connection(&replaced.conn);
+        }
+    }
+
+    /// Move a dropped connection's state to the client's new connection, and send it anything
+    /// which arrived in between.
+    fn resume(&self, conn: &mut Connection, mut detached: Detached) {
+        let old = &detached.conn;
+        conn.app().redirect_subscriber(&old.sender, &conn.sender);
+        if old.peer != conn.peer {
+            // Charge the new address for what the client holds
+            for (held, mailbox) in [
+                (old.nameplate_id.is_some(), false),
+                (old.mailbox_id.is_some(), true),
+            ] {
+                if held {
+                    self.unreserve(old, mailbox);
+                    let mut usage = self.usage.lock().unwrap();
+                    let usage = usage.entry(conn.peer).or_default();
+                    if mailbox {
+                        usage.mailboxes += 1;
+                    } else {
+                        usage.nameplates += 1;
+                    }
+                }
+            }
+        }
+        conn.nameplate_id = old.nameplate_id;
+        conn.mailbox_id = old.mailbox_id.clone();
+        conn.allocated = old.allocated;
+        conn.claimed = old.claimed;
+        conn.released = old.released;
+        if let Some(nameplate_id) = conn.nameplate_id {
+            conn.span.record("nameplate", nameplate_id);
+        }
+        if let Some(mailbox_id) = &conn.mailbox_id {
+            conn.span.record("mailbox", mailbox_id);
+        }
+        while let Ok(Some(msg)) = detached.buffer.try_next() {
+            let _ = conn.sender.unbounded_send(msg);
+        }
+        debug!("Resumed after reconnection");
+    }
+
+    /// Free the state held by dropped clients which haven't reconnected within the grace
+    /// period.
+    pub(crate) fn expire_detached(&self) {
+        let Some(grace) = self.config.reconnect_grace else {
+            return;
+        };
+        let now = Instant::now();
+        let expired = {
+            let mut detached = self.detached.lock().unwrap();
+            let keys = detached
+                .iter()
+                .filter(|(_, d)| now.saturating_duration_since(d.since) >= grace)
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            keys.iter()
+                .filter_map(|key| detached.remove(key))
+                .collect::<Vec<_>>()
+        };
+        for detached in &expired {
+            let _enter = detached.conn.span.enter();
+            debug!("Client didn't reconnect in time");
+            self.release_connection(&detached.conn);
+        }
+        if !expired.is_empty() {
+            debug!(expired = expired.len(), "Released dropped clients");
+        }
+    }
+
+    /// Remove a client from any nameplates or mailboxes it holds.
+    fn release_connection(&self, conn: &Connection) {
+        let side = conn.side.as_ref().unwrap();
+        if conn.nameplate_id.is_some() {
+            self.unreserve(conn, false);
+        }
+        if conn.mailbox_id.is_some() {
+            self.unreserve(conn, true);
+        }
+
+        let mut app = conn.app();
+
+        // Remove connection from any pending nameplates
+        app.remove_side_from_nameplates(side);
+
+        // Remove connection from any open mailboxes
+        app.remove_subscriber_from_mailboxes(&conn.sender);
+    }
+
+    /// Send an Ack message in the response to the given message.
+    pub(crate) fn ack(&self, conn: &Connection, msg: &ClientMessage) -> Result<(), ServerError> {
+        let ack_msg = ServerMessage::ack(msg.id.clone());
+        conn.sender.unbounded_send(ack_msg)?;
+        debug!(ty = ?msg.ty, "Sent ack");
+        Ok(())
+    }
+
+    /// Is the given address banned? Banned clients are turned away at the WebSocket handshake,
+    /// or at `bind` if banned after connecting.
+    pub(crate) fn banned(&self, ip: IpAddr) -> bool {
+        self.settings.read().unwrap().ip_banned(ip)
+    }
+
+    /// Must clients submit permission before binding?
+    fn permission_required(&self) -> bool {
+        self.config.hashcash_bits.is_some() || !self.settings.read().unwrap().tokens.is_empty()
+    }
+
+    /// Handle a client submitting permission to use the server.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn submit_permissions(
+        &self,
+        conn: &mut Connection,
+        permission: &Permission,
+    ) -> Result<(), ServerError> {
+        if conn.bound() {
+            return Err(ServerError::AlreadyBound);
+        }
+        conn.permitted = !self.permission_required()
+            || match permission {
+                Permission::None => false,
+                Permission::Hashcash { stamp } => self
+                    .config
+                    .hashcash_bits
+                    .is_some_and(|bits| hashcash::verify(stamp, &conn.challenge, bits)),
+                Permission::Token { token } => self
+                    .settings
+                    .read()
+                    .unwrap()
+                    .tokens
+                    .iter()
+                    .any(|t| tokens_match(t, token)),
+            };
+        if !conn.permitted {
+            return Err(ServerError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Get the given application namespace, creating it if it doesn't exist yet.
+    fn spawn_app(&self, app_id: &str, settings: &RuntimeSettings) -> Arc<Mutex<App>> {
+        self.apps
+            .lock()
+            .unwrap()
+            .entry(app_id.to_owned())
+            .or_insert_with(|| {
+                debug!("Spawning app");
+                let mut app = App::new(
+                    app_id,
+                    self.config.mailbox_limits.clone(),
+                    self.store.clone(),
+                    self.usage_sink.clone(),
+                );
+                self.configure_app(&mut app, settings);
+                Arc::new(Mutex::new(app))
+            })
+            .clone()
+    }
+
+    /// Apply a change made by another server instance sharing this one's store.
+    pub(crate) fn apply_cluster_event(&self, event: ClusterEvent) {
+        let settings = self.settings.read().unwrap();
+        let app = self.spawn_app(event.app_id(), &settings);
+        drop(settings);
+        app.lock().unwrap().apply(event);
+    }
+
+    /// Handle a client bind.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn bind(
+        &self,
+        conn: &mut Connection,
+        app_id: &str,
+        side: &str,
+    ) -> Result<(), ServerError> {
+        if conn.bound() {
+            return Err(ServerError::AlreadyBound);
+        }
+        if self.settings.read().unwrap().maintenance {
+            return Err(ServerError::Maintenance);
+        }
+        if self.banned(conn.peer) {
+            return Err(ServerError::Banned);
+        }
+        if self.permission_required() && !conn.permitted {
+            return Err(ServerError::PermissionRequired);
+        }
+        let settings = self.settings.read().unwrap();
+        if !settings.app_allowed(app_id) {
+            return Err(ServerError::AppNotAllowed);
+        }
+        let app = self.spawn_app(app_id, &settings);
+        drop(settings);
+        conn.app = Some(app);
+        conn.app_id = Some(app_id.to_owned());
+        conn.side = Some(side.to_owned());
+        conn.span.record("app", hash_app_id(app_id));
+        conn.span.record("side", side);
+
+        let detached = self
+            .detached
+            .lock()
+            .unwrap()
+            .remove(&(app_id.to_owned(), side.to_owned()));
+        if let Some(detached) = detached {
+            self.resume(conn, detached);
+        }
+        Ok(())
+    }
+
+    /// Handle a client request for the list of active nameplates.
+    pub(crate) fn list(&self, conn: &Connection) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+
+        let nameplates = if self.config.disable_list {
+            Vec::new()
+        } else {
+            conn.app()
+                .get_nameplates()
+                .iter()
+                .map(|n| NameplateInfo { id: *n })
+                .collect::<Vec<NameplateInfo>>()
+        };
+        let list_msg = ServerMessage::new(None, None, ServerMessageType::Nameplates { nameplates });
+        debug!(ty = ?list_msg.ty, "Sent message");
+        conn.sender.unbounded_send(list_msg)?;
+
+        Ok(())
+    }
+
+    /// Handle a client request for nameplate allocation.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) fn allocate(&self, conn: &mut Connection) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+        if conn.allocated() {
+            return Err(ServerError::AlreadyAllocated);
+        }
+        self.check_rate(conn)?;
+
+        if conn.app().nameplates_full() {
+            return Err(ServerError::AppFull);
+        }
+        self.check_side_quota(conn.app().nameplates_held_by(conn.side.as_ref().unwrap()))?;
+        self.reserve(conn, false)?;
+        let nameplate_id = conn
+            .app()
+            .allocate_nameplate(conn.side.as_ref().unwrap(), conn.sender.clone());
+        conn.nameplate_id = match nameplate_id {
+            Some(nameplate_id) => Some(nameplate_id),
+            None => {
+                self.unreserve(conn, false);
+                return Err(ServerError::CouldNotAllocate);
+            }
+        };
+        conn.allocated = true;
+        conn.span.record("nameplate", conn.nameplate_id);
+
+        let allocated_msg = ServerMessage::new(
+            None,
+            None,
+            ServerMessageType::Allocated {
+                nameplate_id: *conn.nameplate_id.as_ref().unwrap(),
+            },
+        );
+        debug!(ty = ?allocated_msg.ty, "Sent message");
+        conn.sender.unbounded_send(allocated_msg)?;
+
+        Ok(())
+    }
+
+    /// Handle a client request to claim a nameplate.
+    #[tracing::instrument(level = "debug", skip(self, conn))]
+    pub(crate) fn claim(
+        &self,
+        conn: &mut Connection,
+        nameplate_id: usize,
+    ) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+        if conn.claimed() {
+            return Err(ServerError::AlreadyClaimed);
+        }
+        self.check_rate(conn)?;
+
+        // Claiming the nameplate we were just allocated doesn't take up any more quota
+        let already_held = conn.nameplate_id == Some(nameplate_id);
+        if !already_held {
+            if conn.nameplate_id.is_some() {
+                return Err(ServerError::AlreadyClaimed);
+            }
+            {
+                let app = conn.app();
+                if !app.nameplates.contains_key(&nameplate_id) && app.nameplates_full() {
+                    return Err(ServerError::AppFull);
+                }
+            }
+            self.check_side_quota(conn.app().nameplates_held_by(conn.side.as_ref().unwrap()))?;
+            self.reserve(conn, false)?;
+        }
+        let mailbox_id = conn.app().claim_nameplate(
+            nameplate_id,
+            conn.side.as_ref().unwrap(),
+            conn.sender.clone(),
+        );
+        let mailbox_id = match mailbox_id {
+            Some(mailbox_id) => mailbox_id,
+            None => {
+                if !already_held {
+                    self.unreserve(conn, false);
+                }
+                return Err(ServerError::CrowdedNameplate);
+            }
+        };
+        conn.nameplate_id = Some(nameplate_id);
+        conn.claimed = true;
+        conn.span.record("nameplate", nameplate_id);
+
+        let claimed_msg = ServerMessage::new(None, None, ServerMessageType::Claimed { mailbox_id });
+        debug!(ty = ?claimed_msg.ty, "Sent message");
+        conn.sender.unbounded_send(claimed_msg)?;
+
+        Ok(())
+    }
+
+    /// Handle client request to release a nameplate it.
+    #[tracing::instrument(level = "debug", skip(self, conn))]
+    pub(crate) fn release(
+        &self,
+        conn: &mut Connection,
+        nameplate_id: Option<usize>,
+    ) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+        if conn.released {
+            return Err(ServerError::AlreadyReleased);
+        }
+        if conn.nameplate_id.is_none() {
+            return Err(ServerError::NoNameplateToRelease);
+        }
+
+        let nameplate_id = if let Some(nameplate_id) = nameplate_id {
+            if conn.nameplate_id != Some(nameplate_id) {
+                return Err(ServerError::ReleaseMustMatchClaim);
+            }
+            nameplate_id
+        } else {
+            *conn.nameplate_id.as_ref().unwrap()
+        };
+
+        conn.app()
+            .release_nameplate(nameplate_id, conn.side.as_ref().unwrap());
+        self.unreserve(conn, false);
+        conn.released = true;
+        conn.nameplate_id = None;
+
+        let released_msg = ServerMessage::new(None, None, ServerMessageType::Released);
+        debug!(ty = ?released_msg.ty, "Sent message");
+        conn.sender.unbounded_send(released_msg)?;
+
+        Ok(())
+    }
+
+    /// Handle a client request to open (i.e., subscribe to) a mailbox, creating it if it doesn't
+    /// exist, as it won't once freed for a nameplate which is still claimed. Any messages already
+    /// in the mailbox will be forwarded to the client immediately.
+    #[tracing::instrument(level = "debug", skip(self, conn))]
+    pub(crate) fn open(&self, conn: &mut Connection, mailbox_id: &str) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+        if conn.mailbox_id.is_some() {
+            return Err(ServerError::MailboxAlreadyOpened);
+        }
+
+        {
+            let mut app = conn.app();
+            let side = conn.side.as_ref().unwrap();
+            let mailbox = app.mailboxes.get(mailbox_id);
+            if mailbox.is_some_and(|mailbox| mailbox.is_crowded_for(side, app.max_mailbox_sides)) {
+                return Err(ServerError::CrowdedMailbox);
+            }
+            if !mailbox.is_some_and(|mailbox| mailbox.has_subscriber(side)) {
+                self.check_side_quota(app.mailboxes_held_by(side))?;
+            }
+            self.reserve(conn, true)?;
+            app.open_mailbox(mailbox_id, side, conn.sender.clone());
+        }
+        conn.mailbox_id = Some(mailbox_id.to_owned());
+        conn.span.record("mailbox", mailbox_id);
+
+        Ok(())
+    }
+
+    /// Handle a client adding a new message to their open mailbox. Will forward the message
+    /// immediately to all connected clients (including the sender themselves).
+    #[tracing::instrument(level = "debug", skip(self, conn, body), fields(len = body.len()))]
+    pub(crate) fn add(
+        &self,
+        conn: &mut Connection,
+        id: &str,
+        phase: &Phase,
+        body: &[u8],
+    ) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+        if conn.mailbox_id.is_none() {
+            return Err(ServerError::NoOpenMailbox);
+        }
+        if body.len() > self.config.max_message_size {
+            return Err(ServerError::MessageTooLarge);
+        }
+        self.check_rate(conn)?;
+
+        let mailbox_msg = MailboxMessage {
+            id: id.to_owned(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+            side: conn.side.as_ref().unwrap().to_owned(),
+            phase: phase.to_owned(),
+            body: Arc::from(body),
+        };
+        let mut app = conn.app();
+        let mailbox_id = conn.mailbox_id.as_ref().unwrap();
+        if !app.mailboxes.contains_key(mailbox_id) {
+            // Closed for being idle
+            return Err(ServerError::InvalidMailbox);
+        }
+        app.add_message_to_mailbox(mailbox_id, mailbox_msg)
+            .ok_or(ServerError::MailboxFull)?;
+        self.metrics.messages.inc();
+        self.metrics.bytes.inc_by(body.len() as u64);
+
+        Ok(())
+    }
+
+    /// Handle client close request.
+    #[tracing::instrument(level = "debug", skip(self, conn))]
+    pub(crate) fn close(
+        &self,
+        conn: &mut Connection,
+        mailbox_id: &str,
+        mood: &Mood,
+    ) -> Result<(), ServerError> {
+        if !conn.bound() {
+            return Err(ServerError::NotBound);
+        }
+
+        let exists = {
+            let mut app = conn.app();
+            let exists = app.mailboxes.contains_key(mailbox_id);
+            if exists {
+                app.close_mailbox(mailbox_id, conn.side.as_ref().unwrap(), mood.clone());
+            }
+            exists
+        };
+        // The client's own mailbox may already have been closed for being idle
+        if !exists && conn.mailbox_id.as_deref() != Some(mailbox_id) {
+            return Err(ServerError::InvalidMailbox);
+        }
+        if conn.mailbox_id.as_deref() == Some(mailbox_id) {
+            self.unreserve(conn, true);
+            conn.mailbox_id = None;
+        }
+
+        let closed_msg = ServerMessage::new(None, None, ServerMessageType::Closed);
+        debug!(ty = ?closed_msg.ty, "Sent message");
+        conn.sender.unbounded_send(closed_msg)?;
+
+        Ok(())
+    }
+
+    /// Respond to client ping.
+    pub(crate) fn ping(
+        &self,
+        conn: &Connection,
+        msg_id: &str,
+        ping: u32,
+    ) -> Result<(), ServerError> {
+        let pong_msg = ServerMessage::new(
+            Some(msg_id.to_owned()),
+            None,
+            ServerMessageType::Pong { ping },
+        );
+        debug!(ty = ?pong_msg.ty, "Sent message");
+        conn.sender.unbounded_send(pong_msg)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hash_app_id, ClientQuota, Connection, MailboxServer, Mood, Phase, Rate, RateLimits,
+        ServerConfig, ServerError, ServerMessageType,
+    };
+    use crate::settings::{AppSettings, RuntimeSettings};
+    use crate::sqlite_store::SqliteStore;
+    use crate::store::Store;
+    use futures_channel::mpsc::unbounded;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+        time::Duration,
+    };
+    use wormhole_core::{hashcash, message::Permission};
+
+    const PEER1: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const PEER2: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    // TODO: Tests for MailboxServer
+
+    #[test]
+    fn test() {
+        // let mut server = MailboxServer::default();
+    }
+
+    #[test]
+    fn apps_are_independent() {
+        let server = MailboxServer::default();
+        let (sender, _receiver) = unbounded();
+
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+
+        let mut conn2 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn2, "app2", "side1").unwrap();
+        server.allocate(&mut conn2).unwrap();
+
+        // Each namespace hands out its own nameplates
+        assert_eq!(conn1.nameplate_id, Some(1));
+        assert_eq!(conn2.nameplate_id, Some(1));
+        assert_eq!(server.apps.lock().unwrap().len(), 2);
+
+        // Connections bound to the same app share its state
+        let mut conn3 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn3, "app1", "side2").unwrap();
+        server.allocate(&mut conn3).unwrap();
+        assert_eq!(conn3.nameplate_id, Some(2));
+        assert_eq!(server.apps.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn oversized_add() {
+        let server = MailboxServer::new(ServerConfig {
+            max_message_size: 4,
+            ..ServerConfig::default()
+        });
+        let (sender, _receiver) = unbounded();
+        let mut conn = Connection::new(sender, PEER1);
+        server.bind(&mut conn, "app1", "side1").unwrap();
+        server.allocate(&mut conn).unwrap();
+        let mailbox_id = conn.app().nameplates[&1].mailbox_id.clone();
+        server.open(&mut conn, &mailbox_id).unwrap();
+
+        server
+            .add(&mut conn, "id1", &Phase::Message(0), b"1234")
+            .unwrap();
+        assert!(matches!(
+            server.add(&mut conn, "id2", &Phase::Message(1), b"12345"),
+            Err(ServerError::MessageTooLarge)
+        ));
+        assert_eq!(conn.app().mailboxes[&mailbox_id].messages.len(), 1);
+    }
+
+    #[test]
+    fn per_ip_quota() {
+        let server = MailboxServer::new(ServerConfig {
+            client_quota: ClientQuota {
+                per_side: None,
+                per_ip: Some(1),
+            },
+            ..ServerConfig::default()
+        });
+        let (sender, _receiver) = unbounded();
+
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+        // Claiming our own allocation doesn't count twice
+        server.claim(&mut conn1, 1).unwrap();
+
+        // Another connection from the same address is refused, even in another app
+        let mut conn2 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn2, "app2", "side2").unwrap();
+        assert!(matches!(
+            server.allocate(&mut conn2),
+            Err(ServerError::QuotaExceeded)
+        ));
+
+        // But other addresses are unaffected
+        let mut conn3 = Connection::new(sender.clone(), PEER2);
+        server.bind(&mut conn3, "app1", "side3").unwrap();
+        server.allocate(&mut conn3).unwrap();
+
+        // Releasing frees up the quota again
+        server.release(&mut conn1, None).unwrap();
+        server.allocate(&mut conn2).unwrap();
+    }
+
+    #[test]
+    fn per_side_quota() {
+        let server = MailboxServer::new(ServerConfig {
+            client_quota: ClientQuota {
+                per_side: Some(1),
+                per_ip: None,
+            },
+            ..ServerConfig::default()
+        });
+        let (sender, _receiver) = unbounded();
+
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+
+        // The same side can't hold a second nameplate from another connection
+        let mut conn2 = Connection::new(sender.clone(), PEER2);
+        server.bind(&mut conn2, "app1", "side1").unwrap();
+        assert!(matches!(
+            server.allocate(&mut conn2),
+            Err(ServerError::QuotaExceeded)
+        ));
+
+        // A different side can
+        let mut conn3 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn3, "app1", "side2").unwrap();
+        server.allocate(&mut conn3).unwrap();
+    }
+
+    #[test]
+    fn rate_limits() {
+        let server = MailboxServer::new(ServerConfig {
+            rate_limits: RateLimits {
+                per_connection: Some(Rate {
+                    per_second: 0.001,
+                    burst: 3.0,
+                }),
+                per_ip: Some(Rate {
+                    per_second: 0.001,
+                    burst: 4.0,
+                }),
+            },
+            ..ServerConfig::default()
+        });
+        let (sender, _receiver) = unbounded();
+
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+        let mailbox_id = conn1.app().nameplates[&1].mailbox_id.clone();
+        server.open(&mut conn1, &mailbox_id).unwrap();
+        server
+            .add(&mut conn1, "id1", &Phase::Message(0), b"body")
+            .unwrap();
+        server
+            .add(&mut conn1, "id2", &Phase::Message(1), b"body")
+            .unwrap();
+        // The connection has used up its burst
+        assert!(matches!(
+            server.add(&mut conn1, "id3", &Phase::Message(2), b"body"),
+            Err(ServerError::RateLimited)
+        ));
+
+        // A second connection from the same address gets the rest of the address's burst
+        let mut conn2 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn2, "app1", "side2").unwrap();
+        server.claim(&mut conn2, 1).unwrap();
+        let mut conn3 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn3, "app1", "side3").unwrap();
+        assert!(matches!(
+            server.allocate(&mut conn3),
+            Err(ServerError::RateLimited)
+        ));
+
+        // Other addresses are unaffected
+        let mut conn4 = Connection::new(sender.clone(), PEER2);
+        server.bind(&mut conn4, "app1", "side4").unwrap();
+        server.allocate(&mut conn4).unwrap();
+    }
+
+    #[test]
+    fn restored_from_store() {
+        let path = std::env::temp_dir().join(format!("wormhole-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open_store = || Arc::new(SqliteStore::open(&path).unwrap());
+        let (sender, _receiver) = unbounded();
+
+        let server =
+            MailboxServer::with_store(ServerConfig::default(), open_store(), None).unwrap();
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+        let mailbox_id = conn1.app().nameplates[&1].mailbox_id.clone();
+        server.open(&mut conn1, &mailbox_id).unwrap();
+        server
+            .add(&mut conn1, "id1", &Phase::Pake, b"body")
+            .unwrap();
+        drop(server);
+
+        // A restarted server still knows about the nameplate and its mailbox
+        let server =
+            MailboxServer::with_store(ServerConfig::default(), open_store(), None).unwrap();
+        let mut conn2 = Connection::new(sender.clone(), PEER2);
+        server.bind(&mut conn2, "app1", "side2").unwrap();
+        server.claim(&mut conn2, 1).unwrap();
+        assert_eq!(
+            conn2.app().nameplates[&1].sides,
+            vec!["side1".to_owned(), "side2".to_owned()]
+        );
+        {
+            let app = conn2.app();
+            let messages = &app.mailboxes[&mailbox_id].messages;
+            assert_eq!(messages.len(), 1);
+            assert_eq!(&*messages[0].body, b"body");
+        }
+
+        // Leaving is recorded too
+        server.release(&mut conn2, None).unwrap();
+        server.open(&mut conn2, &mailbox_id).unwrap();
+        server.close(&mut conn2, &mailbox_id, &Mood::Happy).unwrap();
+        drop(server);
+        let apps = open_store().load().unwrap();
+        assert_eq!(apps["app1"].nameplates[&1].sides, vec!["side1".to_owned()]);
+        assert!(apps["app1"].mailboxes.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn app_id_hashing() {
+        let hash = hash_app_id("lothar.com/wormhole/text-or-file-xfer");
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, hash_app_id("lothar.com/wormhole/text-or-file-xfer"));
+        assert_ne!(hash, hash_app_id("lothar.com/wormhole/other"));
+    }
+
+    #[test]
+    fn motd_reload() {
+        let server = MailboxServer::default();
+        assert_eq!(server.welcome().motd, None);
+
+        server.set_settings(RuntimeSettings {
+            motd: Some("Back soon".into()),
+            ..RuntimeSettings::default()
+        });
+        assert_eq!(server.welcome().motd.as_deref(), Some("Back soon"));
+    }
+
+    #[test]
+    fn maintenance_mode() {
+        let server = MailboxServer::default();
+        let (sender, _receiver) = unbounded();
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+        let mailbox_id = conn1.app().nameplates[&1].mailbox_id.clone();
+        server.open(&mut conn1, &mailbox_id).unwrap();
+
+        server.set_settings(RuntimeSettings {
+            maintenance: true,
+            ..RuntimeSettings::default()
+        });
+        assert!(server.welcome().error.is_some());
+
+        // New clients can't bind...
+        let mut conn2 = Connection::new(sender.clone(), PEER2);
+        assert!(matches!(
+            server.bind(&mut conn2, "app1", "side2"),
+            Err(ServerError::Maintenance)
+        ));
+
+        // ...but those already bound carry on
+        server
+            .add(&mut conn1, "id1", &Phase::Message(0), b"body")
+            .unwrap();
+        server.close(&mut conn1, &mailbox_id, &Mood::Happy).unwrap();
+
+        server.set_settings(RuntimeSettings::default());
+        assert!(server.welcome().error.is_none());
+        server.bind(&mut conn2, "app1", "side2").unwrap();
+    }
+
+    #[test]
+    fn hashcash_permission() {
+        let server = MailboxServer::new(ServerConfig {
+            hashcash_bits: Some(4),
+            ..ServerConfig::default()
+        });
+        let (sender, _receiver) = unbounded();
+
+        let mut conn = Connection::new(sender.clone(), PEER1);
+        assert!(matches!(
+            server.bind(&mut conn, "app1", "side1"),
+            Err(ServerError::PermissionRequired)
+        ));
+        assert!(matches!(
+            server.submit_permissions(&mut conn, &Permission::None),
+            Err(ServerError::PermissionDenied)
+        ));
+
+        // Stamps are only good for the connection whose challenge they solve
+        let other = Connection::new(sender.clone(), PEER1);
+        let stamp = hashcash::mint(&other.challenge, 4);
+        assert!(matches!(
+            server.submit_permissions(&mut conn, &Permission::Hashcash { stamp }),
+            Err(ServerError::PermissionDenied)
+        ));
+
+        let stamp = hashcash::mint(&conn.challenge, 4);
+        server
+            .submit_permissions(&mut conn, &Permission::Hashcash { stamp })
+            .unwrap();
+        server.bind(&mut conn, "app1", "side1").unwrap();
+    }
+
+    #[test]
+    fn token_permission() {
+        let server = MailboxServer::default();
+        server.set_settings(RuntimeSettings {
+            tokens: vec!["secret".into()],
+            ..RuntimeSettings::default()
+        });
+        let (sender, _receiver) = unbounded();
+
+        let mut conn = Connection::new(sender.clone(), PEER1);
+        assert!(matches!(
+            server.bind(&mut conn, "app1", "side1"),
+            Err(ServerError::PermissionRequired)
+        ));
+        let wrong = Permission::Token {
+            token: "guess".into(),
+        };
+        assert!(matches!(
+            server.submit_permissions(&mut conn, &wrong),
+            Err(ServerError::PermissionDenied)
+        ));
+        let right = Permission::Token {
+            token: "secret".into(),
+        };
+        server.submit_permissions(&mut conn, &right).unwrap();
+        server.bind(&mut conn, "app1", "side1").unwrap();
+
+        // Without tokens configured, anyone may bind
+        server.set_settings(RuntimeSettings::default());
+        let mut conn = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn, "app1", "side2").unwrap();
+    }
+
+    #[test]
+    fn app_settings() {
+        let server = MailboxServer::default();
+        let (sender, _receiver) = unbounded();
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "app1", "side1").unwrap();
+
+        let mut settings = RuntimeSettings {
+            blocked_apps: vec!["spam".into()],
+            ..RuntimeSettings::default()
+        };
+        settings.apps.insert(
+            "app1".into(),
+            AppSettings {
+                max_nameplates: Some(1),
+                max_mailbox_messages: Some(5),
+                ..AppSettings::default()
+            },
+        );
+        server.set_settings(settings);
+
+        // Blocked apps can't be bound
+        let mut conn2 = Connection::new(sender.clone(), PEER1);
+        assert!(matches!(
+            server.bind(&mut conn2, "spam", "side1"),
+            Err(ServerError::AppNotAllowed)
+        ));
+
+        // Existing apps pick up their new limits
+        assert_eq!(conn1.app().limits.max_messages, Some(5));
+        server.allocate(&mut conn1).unwrap();
+        server.bind(&mut conn2, "app1", "side2").unwrap();
+        assert!(matches!(
+            server.allocate(&mut conn2),
+            Err(ServerError::AppFull)
+        ));
+        assert!(matches!(
+            server.claim(&mut conn2, 2),
+            Err(ServerError::AppFull)
+        ));
+        server.claim(&mut conn2, 1).unwrap();
+
+        // Other apps are unaffected
+        let mut conn3 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn3, "app2", "side1").unwrap();
+        server.allocate(&mut conn3).unwrap();
+        assert_eq!(conn3.app().limits.max_messages, None);
+    }
+
+    #[test]
+    fn admin_operations() {
+        let server = MailboxServer::default();
+        let (sender1, mut receiver1) = unbounded();
+        let (sender2, mut receiver2) = unbounded();
+
+        let mut conn1 = Connection::new(sender1, PEER1);
+        server.connect(&conn1).unwrap();
+        server.bind(&mut conn1, "appid", "side1").unwrap();
+        server.track(&conn1);
+        let mut conn2 = Connection::new(sender2, PEER2);
+        server.connect(&conn2).unwrap();
+
+        let connections = server.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].peer, PEER1);
+        assert_eq!(connections[0].side.as_deref(), Some("side1"));
+        assert_eq!(connections[1].side, None);
+
+        // Expiring a nameplate tells the sides which have opened its mailbox
+        server.allocate(&mut conn1).unwrap();
+        let nameplate_id = conn1.nameplate_id.unwrap();
+        let mailbox_id = conn1.app().nameplates[&nameplate_id].mailbox_id.clone();
+        server.open(&mut conn1, &mailbox_id).unwrap();
+        while receiver1.try_next().is_ok() {}
+        assert!(server.expire_nameplate("appid", nameplate_id));
+        assert!(!server.expire_nameplate("appid", nameplate_id));
+        assert!(!server.expire_nameplate("other", nameplate_id));
+        assert!(conn1.app().nameplates.is_empty());
+        let msg = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Released));
+
+        // Closing a mailbox tells its subscribers
+        server.bind(&mut conn2, "appid", "side2").unwrap();
+        server.open(&mut conn2, &mailbox_id).unwrap();
+        while receiver2.try_next().is_ok() {}
+        assert!(server.close_mailbox("appid", &mailbox_id));
+        assert!(!server.close_mailbox("appid", &mailbox_id));
+        assert!(conn1.app().mailboxes.is_empty());
+        let msg = receiver2.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Closed));
+
+        server.disconnect(conn2);
+        assert_eq!(server.connections().len(), 1);
+    }
+
+    #[test]
+    fn bans() {
+        let server = MailboxServer::default();
+        let (sender, _receiver) = unbounded();
+
+        let mut conn1 = Connection::new(sender.clone(), PEER1);
+        server.bind(&mut conn1, "appid", "side1").unwrap();
+        let mut conn2 = Connection::new(sender.clone(), PEER1);
+        let mut conn3 = Connection::new(sender.clone(), PEER2);
+        assert!(!server.banned(PEER1));
+
+        server.set_settings(RuntimeSettings {
+            banned_ips: vec!["192.0.2.1".parse().unwrap()],
+            ..RuntimeSettings::default()
+        });
+        assert!(server.banned(PEER1));
+        assert!(!server.banned(PEER2));
+        assert!(matches!(
+            server.bind(&mut conn2, "appid", "side2"),
+            Err(ServerError::Banned)
+        ));
+        server.bind(&mut conn3, "appid", "side3").unwrap();
+
+        // Lifting the ban lets the client bind again
+        server.set_settings(RuntimeSettings::default());
+        server.bind(&mut conn2, "appid", "side2").unwrap();
+    }
+
+    #[test]
+    fn disabled_list() {
+        let server = MailboxServer::new(ServerConfig {
+            disable_list: true,
+            ..ServerConfig::default()
+        });
+        let (sender, mut receiver) = unbounded();
+
+        let mut conn = Connection::new(sender, PEER1);
+        server.bind(&mut conn, "appid", "side1").unwrap();
+        server.allocate(&mut conn).unwrap();
+        while receiver.try_next().is_ok() {}
+
+        server.list(&conn).unwrap();
+        let msg = receiver.try_next().unwrap().unwrap();
+        assert!(matches!(
+            msg.ty,
+            ServerMessageType::Nameplates { nameplates } if nameplates.is_empty()
+        ));
+    }
+
+    #[test]
+    fn reopened_mailbox() {
+        let server = MailboxServer::default();
+        let (sender1, _receiver1) = unbounded();
+        let (sender2, _receiver2) = unbounded();
+
+        let mut conn1 = Connection::new(sender1, PEER1);
+        server.bind(&mut conn1, "appid", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+        let nameplate_id = conn1.nameplate_id.unwrap();
+        server.claim(&mut conn1, nameplate_id).unwrap();
+        let mailbox_id = conn1.app().nameplates[&nameplate_id].mailbox_id.clone();
+        server.open(&mut conn1, &mailbox_id).unwrap();
+        server
+            .add(&mut conn1, "id1", &Phase::Message(0), b"body")
+            .unwrap();
+        let mut conn2 = Connection::new(sender2, PEER2);
+        server.bind(&mut conn2, "appid", "side2").unwrap();
+        server.claim(&mut conn2, nameplate_id).unwrap();
+        server.open(&mut conn2, &mailbox_id).unwrap();
+        server.release(&mut conn2, None).unwrap();
+
+        // Once both sides have closed it, the mailbox is freed, though the nameplate is kept
+        server.close(&mut conn1, &mailbox_id, &Mood::Happy).unwrap();
+        server.close(&mut conn2, &mailbox_id, &Mood::Happy).unwrap();
+        assert!(!conn1.app().mailboxes.contains_key(&mailbox_id));
+        assert_eq!(conn1.app().nameplates[&nameplate_id].mailbox_id, mailbox_id);
+
+        // And opening it again creates it afresh
+        server.open(&mut conn1, &mailbox_id).unwrap();
+        assert!(conn1.app().mailboxes[&mailbox_id].messages.is_empty());
+    }
+
+    #[test]
+    fn reconnection() {
+        let server = MailboxServer::new(ServerConfig {
+            reconnect_grace: Some(Duration::ZERO),
+            ..ServerConfig::default()
+        });
+        let (sender1, _receiver1) = unbounded();
+        let (sender2, mut receiver2) = unbounded();
+
+        let mut conn1 = Connection::new(sender1, PEER1);
+        server.bind(&mut conn1, "appid", "side1").unwrap();
+        server.allocate(&mut conn1).unwrap();
+        let nameplate_id = conn1.nameplate_id.unwrap();
+        server.claim(&mut conn1, nameplate_id).unwrap();
+        let mailbox_id = conn1.app().nameplates[&nameplate_id].mailbox_id.clone();
+        server.open(&mut conn1, &mailbox_id).unwrap();
+        let mut conn2 = Connection::new(sender2, PEER2);
+        server.bind(&mut conn2, "appid", "side2").unwrap();
+        server.claim(&mut conn2, nameplate_id).unwrap();
+        server.open(&mut conn2, &mailbox_id).unwrap();
+
+        // The dropped side keeps its place while the other carries on
+        let app = conn1.app.clone().unwrap();
+        server.disconnect(conn1);
+        assert_eq!(app.lock().unwrap().nameplates[&nameplate_id].sides.len(), 2);
+        server
+            .add(&mut conn2, "id1", &Phase::Message(0), b"body")
+            .unwrap();
+
+        // Rebinding the same side picks up where it left off, including anything it missed
+        let (sender1, mut receiver1) = unbounded();
+        let mut conn1 = Connection::new(sender1, PEER2);
+        server.bind(&mut conn1, "appid", "side1").unwrap();
+        assert_eq!(conn1.nameplate_id, Some(nameplate_id));
+        assert_eq!(conn1.mailbox_id.as_ref(), Some(&mailbox_id));
+        let msg = receiver1.try_next().unwrap().unwrap();
+        assert!(matches!(msg.ty, ServerMessageType::Message { ref side, .. } if side == "side2"));
+        while receiver2.try_next().is_ok() {}
+        server
+            .add(&mut conn1, "id2", &Phase::Message(0), b"body")
+            .unwrap();
+        assert!(receiver1.try_next().is_ok());
+        assert!(receiver2.try_next().is_ok());
+
+        // Until the grace period runs out
+        server.disconnect(conn1);
+        server.expire_detached();
+        assert_eq!(
+            app.lock().unwrap().nameplates[&nameplate_id].sides,
+            vec!["side2"]
+        );
+        let (sender1, mut receiver1) = unbounded();
+        let mut conn1 = Connection::new(sender1, PEER1);
+        server.bind(&mut conn1, "appid", "side1").unwrap();
+        assert_eq!(conn1.mailbox_id, None);
+        assert!(receiver1.try_next().is_err());
+    }
+}
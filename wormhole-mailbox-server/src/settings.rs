@@ -0,0 +1,281 @@
+use serde::Deserialize;
+use serde_with::DeserializeFromStr;
+use std::{collections::HashMap, fmt, fs, net::IpAddr, path::Path, str::FromStr};
+use thiserror::Error;
+
+/// Errors generated while loading the settings file.
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("failed to read settings file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid settings file: {0}")]
+    Toml(#[from] toml::de::Error),
+}
+
+/// A single IP address, or a block of them in CIDR notation, e.g. `192.0.2.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DeserializeFromStr)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid IP address or CIDR block: {0}")]
+pub struct InvalidIpRange(String);
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpRange(s.to_owned());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(IpRange { addr, prefix_len })
+    }
+}
+
+impl IpRange {
+    /// Is the given address within this range? IPv4 addresses mapped into IPv6 are treated as
+    /// IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Limits for a single application namespace, overriding the server-wide ones.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppSettings {
+    /// Maximum number of nameplates active at once.
+    pub max_nameplates: Option<usize>,
+    /// Maximum number of messages stored in each mailbox.
+    pub max_mailbox_messages: Option<usize>,
+    /// Maximum total size of the messages stored in each mailbox, in bytes.
+    pub max_mailbox_bytes: Option<usize>,
+    /// Number of sides which may open each mailbox, for experimental applications messaging
+    /// a group rather than a pair. Nameplates are still limited to two sides. Defaults to 2.
+    pub max_mailbox_sides: Option<usize>,
+}
+
+/// Settings which may be changed while the server runs, by editing the settings file and
+/// sending the server `SIGHUP`.
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeSettings {
+    /// Message of the day, shown to every client when it connects.
+    pub motd: Option<String>,
+    /// Latest release of the command-line client, so older clients can suggest upgrading.
+    pub current_cli_version: Option<String>,
+    /// Transit relays to advertise to clients when they connect, as hints like
+    /// `tcp:transit.example.com:4001`.
+    pub transit_relays: Vec<String>,
+    /// Turn away new clients, while letting those already bound finish, so the server can be
+    /// drained before an upgrade.
+    pub maintenance: bool,
+    /// Shared secrets, one of which clients must submit before binding. Empty lets anyone bind
+    /// (subject to any hashcash challenge).
+    pub tokens: Vec<String>,
+    /// App IDs clients may bind to. Empty allows any app ID not blocked.
+    pub allowed_apps: Vec<String>,
+    /// App IDs clients may not bind to.
+    pub blocked_apps: Vec<String>,
+    /// Addresses turned away at the WebSocket handshake, or at `bind` if banned after
+    /// connecting.
+    pub banned_ips: Vec<IpRange>,
+    /// Limits for particular application namespaces, keyed by app ID.
+    pub apps: HashMap<String, AppSettings>,
+}
+
+impl fmt::Debug for RuntimeSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the tokens themselves out of logs
+        f.debug_struct("RuntimeSettings")
+            .field("motd", &self.motd)
+            .field("current_cli_version", &self.current_cli_version)
+            .field("transit_relays", &self.transit_relays)
+            .field("maintenance", &self.maintenance)
+            .field("tokens", &self.tokens.len())
+            .field("allowed_apps", &self.allowed_apps)
+            .field("blocked_apps", &self.blocked_apps)
+            .field("banned_ips", &self.banned_ips)
+            .field("apps", &self.apps)
+            .finish()
+    }
+}
+
+impl RuntimeSettings {
+    /// Load settings from the TOML file at the given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SettingsError> {
+        RuntimeSettings::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse settings from TOML.
+    pub fn parse(toml: &str) -> Result<Self, SettingsError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// May clients bind to the given app ID?
+    pub fn app_allowed(&self, app_id: &str) -> bool {
+        (self.allowed_apps.is_empty() || self.allowed_apps.iter().any(|a| a == app_id))
+            && !self.blocked_apps.iter().any(|a| a == app_id)
+    }
+
+    /// Is the given address banned?
+    pub fn ip_banned(&self, ip: IpAddr) -> bool {
+        self.banned_ips.iter().any(|range| range.contains(ip))
+    }
+
+    /// Fill in anything these settings leave unset from the given defaults.
+    pub fn or(self, defaults: RuntimeSettings) -> Self {
+        RuntimeSettings {
+            motd: self.motd.or(defaults.motd),
+            current_cli_version: self.current_cli_version.or(defaults.current_cli_version),
+            transit_relays: if self.transit_relays.is_empty() {
+                defaults.transit_relays
+            } else {
+                self.transit_relays
+            },
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppSettings, IpRange, RuntimeSettings};
+    use std::net::IpAddr;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            RuntimeSettings::parse("").unwrap(),
+            RuntimeSettings::default()
+        );
+
+        let settings = RuntimeSettings::parse(r#"motd = "Please donate""#).unwrap();
+        assert_eq!(settings.motd.as_deref(), Some("Please donate"));
+        assert!(!settings.maintenance);
+
+        let settings = RuntimeSettings::parse("maintenance = true").unwrap();
+        assert!(settings.maintenance);
+
+        let settings = RuntimeSettings::parse(r#"tokens = ["secret1", "secret2"]"#).unwrap();
+        assert_eq!(settings.tokens, vec!["secret1", "secret2"]);
+
+        assert!(RuntimeSettings::parse(r#"mtod = "typo""#).is_err());
+    }
+
+    #[test]
+    fn app_settings() {
+        let settings = RuntimeSettings::parse(
+            r#"
+            blocked_apps = ["spam"]
+
+            [apps."lothar.com/wormhole/text-or-file-xfer"]
+            max_nameplates = 100
+            max_mailbox_sides = 4
+            "#,
+        )
+        .unwrap();
+        assert!(settings.app_allowed("lothar.com/wormhole/text-or-file-xfer"));
+        assert!(!settings.app_allowed("spam"));
+        assert_eq!(
+            settings.apps["lothar.com/wormhole/text-or-file-xfer"],
+            AppSettings {
+                max_nameplates: Some(100),
+                max_mailbox_sides: Some(4),
+                ..AppSettings::default()
+            }
+        );
+
+        let settings = RuntimeSettings::parse(r#"allowed_apps = ["app1"]"#).unwrap();
+        assert!(settings.app_allowed("app1"));
+        assert!(!settings.app_allowed("app2"));
+    }
+
+    #[test]
+    fn defaults() {
+        let defaults = RuntimeSettings {
+            motd: Some("from the command line".into()),
+            ..RuntimeSettings::default()
+        };
+        let settings = RuntimeSettings::default().or(defaults.clone());
+        assert_eq!(settings, defaults);
+
+        let settings = RuntimeSettings {
+            motd: Some("from the file".into()),
+            ..RuntimeSettings::default()
+        }
+        .or(defaults);
+        assert_eq!(settings.motd.as_deref(), Some("from the file"));
+
+        let defaults = RuntimeSettings {
+            transit_relays: vec!["tcp:default.example.com:4001".into()],
+            ..RuntimeSettings::default()
+        };
+        let settings = RuntimeSettings::parse(r#"transit_relays = ["tcp:file.example.com:4001"]"#)
+            .unwrap()
+            .or(defaults.clone());
+        assert_eq!(settings.transit_relays, vec!["tcp:file.example.com:4001"]);
+        let settings = RuntimeSettings::default().or(defaults);
+        assert_eq!(
+            settings.transit_relays,
+            vec!["tcp:default.example.com:4001"]
+        );
+    }
+
+    #[test]
+    fn ip_ranges() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let range = "192.0.2.0/24".parse::<IpRange>().unwrap();
+        assert!(range.contains(ip("192.0.2.1")));
+        assert!(range.contains(ip("::ffff:192.0.2.255")));
+        assert!(!range.contains(ip("192.0.3.1")));
+        assert!(!range.contains(ip("2001:db8::1")));
+
+        let range = "192.0.2.1".parse::<IpRange>().unwrap();
+        assert!(range.contains(ip("192.0.2.1")));
+        assert!(!range.contains(ip("192.0.2.2")));
+
+        let range = "2001:db8::/32".parse::<IpRange>().unwrap();
+        assert!(range.contains(ip("2001:db8:1::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpRange>()
+            .unwrap()
+            .contains(ip("198.51.100.1")));
+        assert!("192.0.2.0/33".parse::<IpRange>().is_err());
+        assert!("192.0.2.0/x".parse::<IpRange>().is_err());
+        assert!("example.com".parse::<IpRange>().is_err());
+
+        let settings = RuntimeSettings::parse(r#"banned_ips = ["192.0.2.0/24"]"#).unwrap();
+        assert!(settings.ip_banned(ip("192.0.2.7")));
+        assert!(!settings.ip_banned(ip("198.51.100.1")));
+        assert!(RuntimeSettings::parse(r#"banned_ips = ["nonsense"]"#).is_err());
+    }
+}
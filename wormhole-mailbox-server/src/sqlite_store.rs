@@ -0,0 +1,306 @@
+use rusqlite::{params, Connection};
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use crate::app::{Mailbox, MailboxMessage, Nameplate};
+use crate::store::{Store, StoreError, StoredApp};
+use wormhole_core::message::Phase;
+
+/// Schema migrations, applied in order. The database's `user_version` records how many
+/// have been applied.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE nameplates (
+        app_id TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        mailbox_id TEXT NOT NULL,
+        PRIMARY KEY (app_id, id)
+    );
+    CREATE TABLE nameplate_sides (
+        app_id TEXT NOT NULL,
+        nameplate_id INTEGER NOT NULL,
+        side TEXT NOT NULL,
+        PRIMARY KEY (app_id, nameplate_id, side)
+    );
+    CREATE TABLE mailboxes (
+        app_id TEXT NOT NULL,
+        id TEXT NOT NULL,
+        PRIMARY KEY (app_id, id)
+    );
+    CREATE TABLE messages (
+        app_id TEXT NOT NULL,
+        mailbox_id TEXT NOT NULL,
+        id TEXT NOT NULL,
+        timestamp REAL NOT NULL,
+        side TEXT NOT NULL,
+        phase TEXT NOT NULL,
+        body BLOB NOT NULL
+    );
+    CREATE INDEX messages_by_mailbox ON messages (app_id, mailbox_id);
+"];
+
+/// A store keeping nameplates, claims and mailbox messages in a SQLite database file.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (creating if necessary) the database at the given path, and bring its schema up to
+    /// date.
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        SqliteStore::setup(Connection::open(path)?)
+    }
+
+    /// Create a store whose database only lives in memory.
+    #[cfg(test)]
+    pub(crate) fn open_in_memory() -> rusqlite::Result<Self> {
+        SqliteStore::setup(Connection::open_in_memory()?)
+    }
+
+    fn setup(mut conn: Connection) -> rusqlite::Result<Self> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl Store for SqliteStore {
+    fn load(&self) -> Result<HashMap<String, StoredApp>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut apps: HashMap<String, StoredApp> = HashMap::new();
+
+        let mut stmt = conn.prepare("SELECT app_id, id FROM mailboxes")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (app_id, mailbox_id): (String, String) = row?;
+            apps.entry(app_id)
+                .or_default()
+                .mailboxes
+                .insert(mailbox_id, Mailbox::default());
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT app_id, mailbox_id, id, timestamp, side, phase, body FROM messages
+             ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                MailboxMessage {
+                    id: row.get(2)?,
+                    timestamp: row.get(3)?,
+                    side: row.get(4)?,
+                    phase: decode_phase(row.get(5)?),
+                    body: row.get::<_, Vec<u8>>(6)?.into(),
+                },
+            ))
+        })?;
+        for row in rows {
+            let (app_id, mailbox_id, message) = row?;
+            if let Some(mailbox) = apps
+                .get_mut(&app_id)
+                .and_then(|app| app.mailboxes.get_mut(&mailbox_id))
+            {
+                mailbox.stored_bytes += message.body.len();
+                mailbox.messages.push(message);
+            }
+        }
+
+        let mut stmt = conn.prepare("SELECT app_id, id, mailbox_id FROM nameplates")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for row in rows {
+            let (app_id, nameplate_id, mailbox_id): (String, usize, String) = row?;
+            apps.entry(app_id)
+                .or_default()
+                .nameplates
+                .insert(nameplate_id, Nameplate::new(mailbox_id, Vec::new()));
+        }
+
+        let mut stmt =
+            conn.prepare("SELECT app_id, nameplate_id, side FROM nameplate_sides ORDER BY rowid")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for row in rows {
+            let (app_id, nameplate_id, side): (String, usize, String) = row?;
+            if let Some(nameplate) = apps
+                .get_mut(&app_id)
+                .and_then(|app| app.nameplates.get_mut(&nameplate_id))
+            {
+                nameplate.sides.push(side);
+            }
+        }
+
+        Ok(apps)
+    }
+
+    fn add_nameplate(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        mailbox_id: &str,
+    ) -> Result<(), StoreError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO nameplates (app_id, id, mailbox_id) VALUES (?1, ?2, ?3)",
+            params![app_id, nameplate_id, mailbox_id],
+        )?;
+        Ok(())
+    }
+
+    fn remove_nameplate(&self, app_id: &str, nameplate_id: usize) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM nameplate_sides WHERE app_id = ?1 AND nameplate_id = ?2",
+            params![app_id, nameplate_id],
+        )?;
+        tx.execute(
+            "DELETE FROM nameplates WHERE app_id = ?1 AND id = ?2",
+            params![app_id, nameplate_id],
+        )?;
+        Ok(tx.commit()?)
+    }
+
+    fn add_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO nameplate_sides (app_id, nameplate_id, side) VALUES (?1, ?2, ?3)",
+            params![app_id, nameplate_id, side],
+        )?;
+        Ok(())
+    }
+
+    fn remove_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM nameplate_sides WHERE app_id = ?1 AND nameplate_id = ?2 AND side = ?3",
+            params![app_id, nameplate_id, side],
+        )?;
+        Ok(())
+    }
+
+    fn add_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO mailboxes (app_id, id) VALUES (?1, ?2)",
+            params![app_id, mailbox_id],
+        )?;
+        Ok(())
+    }
+
+    fn remove_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM messages WHERE app_id = ?1 AND mailbox_id = ?2",
+            params![app_id, mailbox_id],
+        )?;
+        tx.execute(
+            "DELETE FROM mailboxes WHERE app_id = ?1 AND id = ?2",
+            params![app_id, mailbox_id],
+        )?;
+        Ok(tx.commit()?)
+    }
+
+    fn add_message(
+        &self,
+        app_id: &str,
+        mailbox_id: &str,
+        message: &MailboxMessage,
+    ) -> Result<(), StoreError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO messages (app_id, mailbox_id, id, timestamp, side, phase, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                app_id,
+                mailbox_id,
+                message.id,
+                message.timestamp,
+                message.side,
+                encode_phase(&message.phase),
+                &*message.body,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Phases are stored as they appear on the wire, e.g. "pake" or "0".
+fn encode_phase(phase: &Phase) -> String {
+    match serde_json::to_value(phase).expect("phases always serialize") {
+        serde_json::Value::String(s) => s,
+        _ => unreachable!(),
+    }
+}
+
+fn decode_phase(phase: String) -> Phase {
+    serde_json::from_value(serde_json::Value::String(phase)).expect("invalid stored phase")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_phase, encode_phase, MailboxMessage, SqliteStore, Store};
+    use wormhole_core::message::Phase;
+
+    #[test]
+    fn phase_encoding() {
+        for phase in [Phase::Pake, Phase::Version, Phase::Message(12)] {
+            assert_eq!(decode_phase(encode_phase(&phase)), phase);
+        }
+        assert_eq!(encode_phase(&Phase::Message(3)), "3");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let db = SqliteStore::open_in_memory().unwrap();
+        db.add_mailbox("app1", "mid").unwrap();
+        db.add_nameplate("app1", 4, "mid").unwrap();
+        db.add_nameplate_side("app1", 4, "side1").unwrap();
+        db.add_nameplate_side("app1", 4, "side2").unwrap();
+        db.add_nameplate_side("app1", 4, "side2").unwrap();
+        db.add_message(
+            "app1",
+            "mid",
+            &MailboxMessage {
+                id: "msgid".into(),
+                timestamp: 1.5,
+                side: "side1".into(),
+                phase: Phase::Pake,
+                body: b"body".as_slice().into(),
+            },
+        )
+        .unwrap();
+
+        let apps = db.load().unwrap();
+        let app = &apps["app1"];
+        assert_eq!(app.nameplates[&4].mailbox_id, "mid");
+        assert_eq!(app.nameplates[&4].sides, vec!["side1", "side2"]);
+        let mailbox = &app.mailboxes["mid"];
+        assert_eq!(mailbox.messages.len(), 1);
+        assert_eq!(mailbox.messages[0].phase, Phase::Pake);
+        assert_eq!(&*mailbox.messages[0].body, b"body");
+        assert_eq!(mailbox.stored_bytes, 4);
+
+        db.remove_nameplate_side("app1", 4, "side1").unwrap();
+        assert_eq!(
+            db.load().unwrap()["app1"].nameplates[&4].sides,
+            vec!["side2"]
+        );
+
+        db.remove_nameplate("app1", 4).unwrap();
+        db.remove_mailbox("app1", "mid").unwrap();
+        assert!(db.load().unwrap().is_empty());
+    }
+}
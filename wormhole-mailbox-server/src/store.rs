@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::{collections::HashMap, fmt::Debug};
+use thiserror::Error;
+
+use crate::app::{Mailbox, MailboxMessage, Nameplate};
+use wormhole_core::message::Phase;
+
+/// Which storage backend the server keeps its state in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum StoreKind {
+    /// Keep nothing beyond the server's own memory; everything is lost on restart.
+    #[default]
+    Memory,
+    /// A local SQLite database file.
+    Sqlite,
+    /// A Redis server, which may be shared with other tooling.
+    Redis,
+    /// An append-only journal file, synced on every change and replayed on startup.
+    Journal,
+}
+
+/// Errors generated by a storage backend.
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to encode or decode stored message")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("journal entry on line {line} is unreadable: {source}")]
+    CorruptJournal {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+/// The nameplates and mailboxes of an application namespace, as loaded from a store.
+#[derive(Debug, Default)]
+pub struct StoredApp {
+    pub(crate) nameplates: HashMap<usize, Nameplate>,
+    pub(crate) mailboxes: HashMap<String, Mailbox>,
+}
+
+/// How a mailbox message is encoded by stores which keep messages as JSON.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct StoredMessage {
+    id: String,
+    timestamp: f64,
+    side: String,
+    phase: Phase,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub(crate) body: Vec<u8>,
+}
+
+impl From<&MailboxMessage> for StoredMessage {
+    fn from(message: &MailboxMessage) -> Self {
+        StoredMessage {
+            id: message.id.clone(),
+            timestamp: message.timestamp,
+            side: message.side.clone(),
+            phase: message.phase.clone(),
+            body: message.body.to_vec(),
+        }
+    }
+}
+
+impl From<StoredMessage> for MailboxMessage {
+    fn from(message: StoredMessage) -> Self {
+        MailboxMessage {
+            id: message.id,
+            timestamp: message.timestamp,
+            side: message.side,
+            phase: message.phase,
+            body: message.body.into(),
+        }
+    }
+}
+
+/// Somewhere to record nameplates, claims and mailbox messages as they change, so that pending
+/// wormholes can be picked up again after a restart.
+///
+/// The server's in-memory state is authoritative while it runs; a store only needs to be able
+/// to reproduce it in `load`.
+pub trait Store: Debug + Send + Sync {
+    /// Load every stored application namespace, keyed by app ID.
+    fn load(&self) -> Result<HashMap<String, StoredApp>, StoreError>;
+
+    /// Record a newly created nameplate.
+    fn add_nameplate(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        mailbox_id: &str,
+    ) -> Result<(), StoreError>;
+
+    /// Forget a freed nameplate, along with any claims on it.
+    fn remove_nameplate(&self, app_id: &str, nameplate_id: usize) -> Result<(), StoreError>;
+
+    /// Record a side's claim on a nameplate.
+    fn add_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError>;
+
+    /// Forget a side's claim on a nameplate.
+    fn remove_nameplate_side(
+        &self,
+        app_id: &str,
+        nameplate_id: usize,
+        side: &str,
+    ) -> Result<(), StoreError>;
+
+    /// Record a newly created mailbox.
+    fn add_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError>;
+
+    /// Forget a freed mailbox, along with its messages.
+    fn remove_mailbox(&self, app_id: &str, mailbox_id: &str) -> Result<(), StoreError>;
+
+    /// Record a message stored in a mailbox.
+    fn add_message(
+        &self,
+        app_id: &str,
+        mailbox_id: &str,
+        message: &MailboxMessage,
+    ) -> Result<(), StoreError>;
+
+    /// Note that a side has subscribed to a mailbox. Subscriptions don't survive a restart, so
+    /// only stores shared between server instances need to track them.
+    fn add_subscriber(
+        &self,
+        _app_id: &str,
+        _mailbox_id: &str,
+        _side: &str,
+    ) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Note that a side has unsubscribed from a mailbox.
+    fn remove_subscriber(
+        &self,
+        _app_id: &str,
+        _mailbox_id: &str,
+        _side: &str,
+    ) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// A store which records nothing, leaving the server's state purely in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore;
+
+impl Store for MemoryStore {
+    fn load(&self) -> Result<HashMap<String, StoredApp>, StoreError> {
+        Ok(HashMap::new())
+    }
+
+    fn add_nameplate(&self, _: &str, _: usize, _: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn remove_nameplate(&self, _: &str, _: usize) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn add_nameplate_side(&self, _: &str, _: usize, _: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn remove_nameplate_side(&self, _: &str, _: usize, _: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn add_mailbox(&self, _: &str, _: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn remove_mailbox(&self, _: &str, _: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn add_message(&self, _: &str, _: &str, _: &MailboxMessage) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MailboxMessage, StoredMessage};
+    use wormhole_core::message::Phase;
+
+    #[test]
+    fn message_encoding() {
+        let message = MailboxMessage {
+            id: "msgid".into(),
+            timestamp: 1.5,
+            side: "side1".into(),
+            phase: Phase::Message(2),
+            body: b"body".as_slice().into(),
+        };
+        let encoded = serde_json::to_string(&StoredMessage::from(&message)).unwrap();
+        assert_eq!(
+            encoded,
+            r#"{"id":"msgid","timestamp":1.5,"side":"side1","phase":"2","body":"626f6479"}"#
+        );
+
+        let decoded: MailboxMessage = serde_json::from_str::<StoredMessage>(&encoded)
+            .unwrap()
+            .into();
+        assert_eq!(decoded.phase, Phase::Message(2));
+        assert_eq!(&*decoded.body, b"body");
+    }
+}
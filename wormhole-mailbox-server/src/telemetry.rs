@@ -0,0 +1,100 @@
+use std::io::IsTerminal;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Keeps the OTLP exporter running until dropped, at which point any spans still buffered are
+/// flushed.
+#[derive(Debug)]
+pub(crate) struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+/// How log events are written to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, including the fields of every enclosing span.
+    Json,
+}
+
+/// Install the global `tracing` subscriber, which writes events filtered by `RUST_LOG` to
+/// stderr in the given format and, if an endpoint is given, exports spans to an OpenTelemetry
+/// collector over OTLP.
+///
+/// Records from the `log` crate are forwarded to the subscriber, so they appear within the
+/// span they were emitted in.
+pub(crate) fn init(format: LogFormat, otlp_endpoint: Option<&str>) -> TelemetryGuard {
+    let text = (format == LogFormat::Text).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+    });
+    let json = (format == LogFormat::Json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+    });
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(text)
+        .with(json);
+
+    #[cfg(feature = "otel")]
+    {
+        let provider = otlp_endpoint.map(otlp_provider);
+        let layer = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider as _;
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("wormhole-mailbox"))
+        });
+        registry.with(layer).init();
+        TelemetryGuard { provider }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        if otlp_endpoint.is_some() {
+            tracing::warn!("Built without the otel feature, not exporting spans");
+        }
+        TelemetryGuard {}
+    }
+}
+
+/// Build a tracer provider which batches spans to the OTLP collector at the given endpoint.
+#[cfg(feature = "otel")]
+fn otlp_provider(endpoint: &str) -> opentelemetry_sdk::trace::TracerProvider {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::Config, Resource};
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            Config::default().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                "wormhole-mailbox",
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .expect("Failed to set up OTLP exporter")
+}
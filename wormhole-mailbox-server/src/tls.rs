@@ -0,0 +1,128 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::{VerifierBuilderError, WebPkiClientVerifier},
+        RootCertStore,
+    },
+    TlsAcceptor,
+};
+
+/// Errors generated while loading TLS certificates and keys.
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read certificate or key: {0}")]
+    Io(#[from] io::Error),
+    #[error("no certificates found in {0}")]
+    NoCertificates(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("invalid client CA: {0}")]
+    ClientCa(#[from] VerifierBuilderError),
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// How to secure client connections with TLS.
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    /// Must clients present a certificate?
+    client_auth: bool,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("client_auth", &self.client_auth)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsConfig {
+    /// Serve the certificate chain and private key in the given PEM files. If a file of CA
+    /// certificates is given, clients must present a certificate signed by one of them, and are
+    /// refused before the WebSocket handshake otherwise.
+    pub fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self, TlsError> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for ca in load_certs(client_ca)? {
+                    roots.add(ca)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        Ok(TlsConfig {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            client_auth: client_ca.is_some(),
+        })
+    }
+
+    /// The acceptor performing the server's side of each handshake.
+    pub(crate) fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+}
+
+/// Read every certificate in a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.display().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TlsConfig, TlsError};
+    use std::path::Path;
+
+    #[test]
+    fn missing_certificates() {
+        let dir = std::env::temp_dir().join(format!("wormhole-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+
+        assert!(matches!(
+            TlsConfig::load(Path::new("/nonexistent.pem"), &empty, None),
+            Err(TlsError::Io(_))
+        ));
+        assert!(matches!(
+            TlsConfig::load(&empty, &empty, None),
+            Err(TlsError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            TlsConfig::load(&empty, &empty, Some(&empty)),
+            Err(TlsError::NoCertificates(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::store::StoreError;
+use wormhole_core::message::Mood;
+
+/// How a mailbox's wormhole turned out, judged from who opened it and the moods they reported
+/// when closing it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageResult {
+    /// Both sides opened the mailbox, and neither reported a problem.
+    Happy,
+    /// Nobody opened the mailbox.
+    Quiet,
+    /// Only one side opened the mailbox, or a side gave up waiting.
+    Lonely,
+    /// A side saw a message it couldn't decrypt, e.g. from a mistyped code.
+    Scary,
+    /// A side hit some other error.
+    Errory,
+    /// The server closed the mailbox for being idle.
+    Pruney,
+    /// More than two sides opened the mailbox.
+    Crowded,
+}
+
+/// Statistics about one mailbox, recorded once it has been freed.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRecord {
+    pub app_id: String,
+    /// When the mailbox was created, in seconds since the Unix epoch.
+    pub started: f64,
+    /// How long the mailbox was open for, in seconds.
+    pub total_time: f64,
+    /// Number of messages added to the mailbox.
+    pub messages: usize,
+    /// Total size of the bodies of those messages, in bytes.
+    pub bytes: usize,
+    /// The mood each side reported on closing, in the order they opened the mailbox.
+    pub moods: Vec<Option<Mood>>,
+    pub result: UsageResult,
+}
+
+/// A running tally of a mailbox's usage.
+#[derive(Debug)]
+pub(crate) struct MailboxUsage {
+    started: SystemTime,
+    messages: usize,
+    bytes: usize,
+    /// Each side which has opened the mailbox, and the mood it closed with.
+    sides: Vec<(String, Option<Mood>)>,
+}
+
+impl Default for MailboxUsage {
+    fn default() -> Self {
+        MailboxUsage {
+            started: SystemTime::now(),
+            messages: 0,
+            bytes: 0,
+            sides: Vec::new(),
+        }
+    }
+}
+
+impl MailboxUsage {
+    /// Note that the given side has opened the mailbox.
+    pub(crate) fn opened(&mut self, side: &str) {
+        if !self.sides.iter().any(|(s, _)| s == side) {
+            self.sides.push((side.to_owned(), None));
+        }
+    }
+
+    /// Note that a message of the given size has been added to the mailbox.
+    pub(crate) fn added(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+
+    /// Note that the given side has closed the mailbox in the given mood.
+    pub(crate) fn closed(&mut self, side: &str, mood: Mood) {
+        if let Some((_, m)) = self.sides.iter_mut().find(|(s, _)| s == side) {
+            *m = Some(mood);
+        }
+    }
+
+    /// Summarise the mailbox's usage, now that it has been freed. More sides than the
+    /// application allows on a mailbox mark it as crowded.
+    pub(crate) fn finish(&self, app_id: &str, pruned: bool, max_sides: usize) -> UsageRecord {
+        let moods = self
+            .sides
+            .iter()
+            .map(|(_, mood)| mood.clone())
+            .collect::<Vec<_>>();
+        let reported = |wanted: fn(&Mood) -> bool| moods.iter().flatten().any(wanted);
+
+        let mut result = match moods.len() {
+            0 => UsageResult::Quiet,
+            1 => UsageResult::Lonely,
+            _ => UsageResult::Happy,
+        };
+        if reported(|m| matches!(m, Mood::Lonely)) {
+            result = UsageResult::Lonely;
+        }
+        if reported(|m| matches!(m, Mood::Errory)) {
+            result = UsageResult::Errory;
+        }
+        if reported(|m| matches!(m, Mood::Scary)) {
+            result = UsageResult::Scary;
+        }
+        if pruned {
+            result = UsageResult::Pruney;
+        }
+        if moods.len() > max_sides {
+            result = UsageResult::Crowded;
+        }
+
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        UsageRecord {
+            app_id: app_id.to_owned(),
+            started,
+            total_time: self.started.elapsed().unwrap_or_default().as_secs_f64(),
+            messages: self.messages,
+            bytes: self.bytes,
+            moods,
+            result,
+        }
+    }
+}
+
+/// Somewhere to send usage records, for capacity planning.
+pub trait UsageSink: Debug + Send + Sync {
+    /// Record the usage of a freed mailbox.
+    fn record(&self, record: &UsageRecord) -> Result<(), StoreError>;
+}
+
+/// A sink appending each record as a line of JSON to a file.
+#[derive(Debug)]
+pub struct JsonlUsageSink {
+    file: Mutex<File>,
+}
+
+impl JsonlUsageSink {
+    /// Open (creating if necessary) the file at the given path for appending.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlUsageSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl UsageSink for JsonlUsageSink {
+    fn record(&self, record: &UsageRecord) -> Result<(), StoreError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// A sink inserting each record into the `mailbox_usage` table of a SQLite database.
+#[derive(Debug)]
+pub struct SqliteUsageSink {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteUsageSink {
+    /// Open (creating if necessary) the database at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        SqliteUsageSink::setup(Connection::open(path)?)
+    }
+
+    /// Create a sink whose database only lives in memory.
+    #[cfg(test)]
+    pub(crate) fn open_in_memory() -> Result<Self, StoreError> {
+        SqliteUsageSink::setup(Connection::open_in_memory()?)
+    }
+
+    fn setup(conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS mailbox_usage (
+                app_id TEXT NOT NULL,
+                started REAL NOT NULL,
+                total_time REAL NOT NULL,
+                messages INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                moods TEXT NOT NULL,
+                result TEXT NOT NULL
+            );",
+        )?;
+        Ok(SqliteUsageSink {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl UsageSink for SqliteUsageSink {
+    fn record(&self, record: &UsageRecord) -> Result<(), StoreError> {
+        let result = match serde_json::to_value(record.result)? {
+            serde_json::Value::String(s) => s,
+            _ => unreachable!(),
+        };
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO mailbox_usage (app_id, started, total_time, messages, bytes, moods, result)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                record.app_id,
+                record.started,
+                record.total_time,
+                record.messages,
+                record.bytes,
+                serde_json::to_string(&record.moods)?,
+                result,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MailboxUsage, SqliteUsageSink, UsageResult, UsageSink};
+    use wormhole_core::message::Mood;
+
+    #[test]
+    fn usage_results() {
+        let mut usage = MailboxUsage::default();
+        assert_eq!(usage.finish("app", false, 2).result, UsageResult::Quiet);
+
+        usage.opened("side1");
+        usage.added(10);
+        assert_eq!(usage.finish("app", false, 2).result, UsageResult::Lonely);
+
+        usage.opened("side2");
+        usage.opened("side2");
+        usage.added(5);
+        usage.closed("side1", Mood::Happy);
+        usage.closed("side2", Mood::Happy);
+        let record = usage.finish("app", false, 2);
+        assert_eq!(record.result, UsageResult::Happy);
+        assert_eq!(record.messages, 2);
+        assert_eq!(record.bytes, 15);
+        assert_eq!(record.moods.len(), 2);
+        assert_eq!(usage.finish("app", true, 2).result, UsageResult::Pruney);
+
+        usage.closed("side2", Mood::Scary);
+        assert_eq!(usage.finish("app", false, 2).result, UsageResult::Scary);
+
+        usage.opened("side3");
+        assert_eq!(usage.finish("app", false, 2).result, UsageResult::Crowded);
+        assert_eq!(usage.finish("app", false, 3).result, UsageResult::Scary);
+    }
+
+    #[test]
+    fn sqlite_sink() {
+        let sink = SqliteUsageSink::open_in_memory().unwrap();
+        let mut usage = MailboxUsage::default();
+        usage.opened("side1");
+        usage.closed("side1", Mood::Lonely);
+        sink.record(&usage.finish("app", false, 2)).unwrap();
+
+        let (moods, result): (String, String) = sink
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT moods, result FROM mailbox_usage", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(moods, r#"["lonely"]"#);
+        assert_eq!(result, "lonely");
+    }
+}
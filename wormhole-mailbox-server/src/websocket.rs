@@ -0,0 +1,397 @@
+use futures_channel::mpsc::unbounded;
+use futures_util::{future, SinkExt, StreamExt};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http,
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Error, Message, Result,
+    },
+    WebSocketStream,
+};
+use tracing::{debug, error, warn, Instrument};
+
+use crate::capture::Direction;
+use crate::server::{Connection, MailboxServer, ServerConfig};
+use wormhole_core::message::{
+    ClientMessage, ClientMessageType, ServerMessage, ServerMessageType, WelcomeInfo,
+};
+
+impl MailboxServer {
+    /// Run a mailbox server with the given settings, accepting WebSocket connections from the
+    /// listener until accepting fails.
+    pub async fn serve(listener: TcpListener, config: ServerConfig) -> io::Result<()> {
+        Arc::new(MailboxServer::new(config)).run(listener).await
+    }
+
+    /// Accept WebSocket connections from the listener and serve them, along with expiring stale
+    /// state, until accepting fails.
+    pub async fn run(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        let connection_limit = self
+            .config()
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        // Dropping the set stops housekeeping, even if this future is dropped early
+        let mut background = JoinSet::new();
+        background.spawn(housekeeping(self.clone()));
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let admission = Admission::acquire(&connection_limit);
+            tokio::spawn(accept_connection(self.clone(), peer, stream, admission));
+        }
+    }
+}
+
+/// How often to look for expired server state.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically expire stale server state, for as long as the server runs.
+async fn housekeeping(state: Arc<MailboxServer>) {
+    let mut interval = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.expire_nameplates();
+        state.collect_idle_mailboxes();
+        state.expire_detached();
+    }
+}
+
+/// Whether a new connection fits within the connection limit.
+enum Admission {
+    /// The connection may proceed, holding its slot (if limited) until it finishes.
+    Admitted(Option<OwnedSemaphorePermit>),
+    /// The server is already at its limit.
+    Full,
+}
+
+impl Admission {
+    /// Take a connection slot, if a connection limit is configured.
+    fn acquire(limit: &Option<Arc<Semaphore>>) -> Self {
+        match limit {
+            Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Admission::Admitted(Some(permit)),
+                Err(_) => Admission::Full,
+            },
+            None => Admission::Admitted(None),
+        }
+    }
+}
+
+/// How long a client has to complete the TLS handshake.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn accept_connection(
+    server: Arc<MailboxServer>,
+    peer: SocketAddr,
+    stream: TcpStream,
+    admission: Admission,
+) {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = server.tls() {
+        // Clients without an acceptable certificate are refused before anything else
+        let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor().accept(stream));
+        match handshake.await {
+            Ok(Ok(stream)) => accept_stream(server, peer, stream, admission).await,
+            Ok(Err(e)) => {
+                debug!(%peer, error = %e, "TLS handshake failed");
+                server.metrics().tls_handshake_failures.inc();
+            }
+            Err(_) => {
+                debug!(%peer, "TLS handshake timed out");
+                server.metrics().tls_handshake_failures.inc();
+            }
+        }
+        return;
+    }
+    accept_stream(server, peer, stream, admission).await
+}
+
+/// Serve a client over a connection, once any TLS handshake is complete.
+async fn accept_stream<S>(
+    server: Arc<MailboxServer>,
+    peer: SocketAddr,
+    stream: S,
+    admission: Admission,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let result = if server.banned(peer.ip()) {
+        reject_banned(peer, stream).await
+    } else {
+        match admission {
+            Admission::Admitted(_slot) => handle_connection(server, peer, stream).await,
+            Admission::Full => refuse_connection(server, peer, stream).await,
+        }
+    };
+    if let Err(e) = result {
+        match e {
+            Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8 => (),
+            err => error!(error = %err, "Error processing connection"),
+        }
+    }
+}
+
+/// Fail the WebSocket handshake of a client from a banned address.
+async fn reject_banned<S>(peer: SocketAddr, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!(%peer, "Rejecting connection: address is banned");
+    // The error response type is fixed by tungstenite
+    #[allow(clippy::result_large_err)]
+    let reject = |_: &Request, _: Response| {
+        Err(http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(Some("Your address is banned from this server.".into()))
+            .expect("valid response"))
+    };
+    match tokio_tungstenite::accept_hdr_async(stream, reject).await {
+        Err(Error::Http(_)) => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => unreachable!("handshake always rejected"),
+    }
+}
+
+/// How long a client over the connection limit has to complete the WebSocket handshake and
+/// hear why it's refused, so that refused connections can't be held open.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tell a client the server is too busy to serve it, and hang up.
+async fn refuse_connection<S>(server: Arc<MailboxServer>, peer: SocketAddr, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!(%peer, "Refusing connection: too many connections");
+    let refusal = async {
+        let mut ws_stream = tokio_tungstenite::accept_async(stream).await?;
+        let welcome_msg = ServerMessage::new(
+            None,
+            None,
+            ServerMessageType::Welcome {
+                welcome: WelcomeInfo {
+                    error: Some("This server is at capacity, please try again later.".into()),
+                    ..server.welcome()
+                },
+            },
+        );
+        let json = serde_json::to_string(&welcome_msg).expect("failed to encode message");
+        ws_stream.send(Message::Text(json)).await?;
+        ws_stream.close(None).await
+    };
+    match tokio::time::timeout(REFUSAL_TIMEOUT, refusal).await {
+        Ok(result) => result,
+        Err(_) => {
+            debug!(%peer, "Refused client was too slow, dropping it");
+            Ok(())
+        }
+    }
+}
+
+async fn handle_connection<S>(server: Arc<MailboxServer>, peer: SocketAddr, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (tx, rx) = unbounded();
+    let mut connection = Connection::new(tx, peer.ip());
+    let span = connection.span().clone();
+
+    async move {
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(server.config().max_frame_size),
+            max_frame_size: Some(server.config().max_frame_size),
+            ..WebSocketConfig::default()
+        };
+        let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(ws_config))
+            .await
+            .inspect_err(|_| server.metrics().handshake_failures.inc())?;
+        debug!(%peer, "New WebSocket connection");
+        server
+            .connect(&connection)
+            .expect("failed to setup new connection");
+        server.metrics().connections.inc();
+
+        let result = serve_connection(&server, &mut connection, ws_stream, rx).await;
+
+        server.disconnect(connection);
+        server.metrics().connections.dec();
+
+        result
+    }
+    .instrument(span)
+    .await
+}
+
+/// Pump messages between the WebSocket and the server until either side goes away, or the
+/// client has been idle for longer than the configured timeout.
+async fn serve_connection<S>(
+    server: &MailboxServer,
+    connection: &mut Connection,
+    ws_stream: WebSocketStream<S>,
+    mut rx: futures_channel::mpsc::UnboundedReceiver<ServerMessage>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let idle_timeout = server.config().idle_timeout;
+    let mut deadline = idle_timeout.map(|t| Instant::now() + t);
+
+    loop {
+        let idle = async {
+            match deadline {
+                Some(deadline) => sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            ws_msg = ws_receiver.next() => {
+                let ws_msg = match ws_msg {
+                    Some(ws_msg) => ws_msg?,
+                    None => return Ok(()),
+                };
+                // Any traffic from the client, including pings, counts as activity
+                deadline = idle_timeout.map(|t| Instant::now() + t);
+                if ws_msg.is_text() || ws_msg.is_binary() {
+                    handle_message(server, connection, ws_msg);
+                }
+            }
+            msg = rx.next() => {
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
+                let json = serde_json::to_string(&msg).expect("failed to encode message");
+                server.capture(connection, Direction::Out, &json);
+                ws_sender.send(Message::Text(json)).await?;
+            }
+            _ = idle => {
+                debug!("Closing idle connection");
+                ws_sender
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "idle timeout".into(),
+                    })))
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Decode a single message from the client and dispatch it to the server.
+fn handle_message(server: &MailboxServer, connection: &mut Connection, ws_msg: Message) {
+    let msg = match ws_msg {
+        Message::Text(s) => {
+            server.capture(connection, Direction::In, &s);
+            serde_json::from_str::<ClientMessage>(&s)
+        }
+        Message::Binary(v) => {
+            server.capture(connection, Direction::In, &String::from_utf8_lossy(&v));
+            serde_json::from_slice::<ClientMessage>(&v)
+        }
+        _ => unreachable!(),
+    };
+    if msg.is_err() {
+        warn!("Failed to decode message");
+        return;
+    }
+    let msg = msg.unwrap();
+
+    debug!(ty = ?msg.ty, "Received message");
+
+    match server.ack(connection, &msg) {
+        Ok(()) => {}
+        Err(e) => {
+            let error_msg = ServerMessage::error(&msg, &e.to_string());
+            connection.sender.unbounded_send(error_msg).unwrap();
+        }
+    }
+
+    let result = match &msg.ty {
+        ClientMessageType::Bind { app_id, side } => server.bind(connection, app_id, side),
+        ClientMessageType::SubmitPermissions(permission) => {
+            server.submit_permissions(connection, permission)
+        }
+        ClientMessageType::List => server.list(connection),
+        ClientMessageType::Allocate => server.allocate(connection),
+        ClientMessageType::Claim { nameplate_id } => server.claim(connection, *nameplate_id),
+        ClientMessageType::Release { nameplate_id } => server.release(connection, *nameplate_id),
+        ClientMessageType::Open { mailbox_id } => server.open(connection, mailbox_id),
+        ClientMessageType::Add { phase, body } => server.add(connection, &msg.id, phase, body),
+        ClientMessageType::Close { mailbox_id, mood } => server.close(connection, mailbox_id, mood),
+        ClientMessageType::Ping { ping } => server.ping(connection, &msg.id, *ping),
+    };
+    server.track(connection);
+    match result {
+        Ok(()) => {}
+        Err(e) => {
+            error!(error = ?e, "Failed to handle message");
+            server.metrics().errors.with_label_values(&[e.kind()]).inc();
+            let error_msg = ServerMessage::error(&msg, &e.to_string());
+            connection.sender.unbounded_send(error_msg).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MailboxServer, ServerConfig};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+    use wormhole_core::message::{ServerMessage, ServerMessageType};
+
+    #[tokio::test]
+    async fn embedded_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(MailboxServer::serve(listener, ServerConfig::default()));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let welcome = ws.next().await.unwrap().unwrap();
+        let welcome = serde_json::from_str::<ServerMessage>(welcome.to_text().unwrap()).unwrap();
+        assert!(matches!(welcome.ty, ServerMessageType::Welcome { .. }));
+
+        ws.send(Message::Text(
+            r#"{"type": "ping", "ping": 7, "id": "1"}"#.into(),
+        ))
+        .await
+        .unwrap();
+        let ack = ws.next().await.unwrap().unwrap();
+        let ack = serde_json::from_str::<ServerMessage>(ack.to_text().unwrap()).unwrap();
+        assert!(matches!(ack.ty, ServerMessageType::Ack));
+    }
+
+    #[tokio::test]
+    async fn refused_at_capacity() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            max_connections: Some(0),
+            ..ServerConfig::default()
+        };
+        tokio::spawn(MailboxServer::serve(listener, config));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let welcome = ws.next().await.unwrap().unwrap();
+        let welcome = serde_json::from_str::<ServerMessage>(welcome.to_text().unwrap()).unwrap();
+        let ServerMessageType::Welcome { welcome } = welcome.ty else {
+            panic!("expected a welcome");
+        };
+        assert!(welcome.error.is_some());
+        assert!(matches!(ws.next().await, Some(Ok(Message::Close(_)))));
+    }
+}